@@ -0,0 +1,56 @@
+//! Embed build metadata, exposed by `--version`, `GET /version` and `GET /api/v1/system/info`
+//! The git commit is taken from `GIT_COMMIT` when set (e.g. in CI, or when building from an
+//! archive), and from the repository otherwise. The build time is taken from
+//! `SOURCE_DATE_EPOCH` when set, for reproducible builds.
+
+use std::{process::Command, time::SystemTime};
+
+fn main() {
+    let git_commit = std::env::var("GIT_COMMIT")
+        .ok()
+        .or_else(|| command_output("git", &["rev-parse", "--short=12", "HEAD"]));
+    println!(
+        "cargo:rustc-env=BUILD_GIT_COMMIT={}",
+        git_commit.as_deref().unwrap_or("unknown")
+    );
+
+    let rustc = std::env::var("RUSTC").unwrap_or("rustc".to_string());
+    println!(
+        "cargo:rustc-env=BUILD_RUSTC_VERSION={}",
+        command_output(&rustc, &["--version"])
+            .as_deref()
+            .unwrap_or("unknown")
+    );
+
+    println!(
+        "cargo:rustc-env=BUILD_PROFILE={}",
+        std::env::var("PROFILE").unwrap_or("unknown".to_string())
+    );
+
+    let build_timestamp = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .or_else(|| {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .ok()
+                .map(|elapsed| elapsed.as_secs())
+        })
+        .unwrap_or_default();
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", build_timestamp);
+
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
+
+/// The trimmed standard output of a successful command
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    Some(output.trim().to_string()).filter(|output| !output.is_empty())
+}
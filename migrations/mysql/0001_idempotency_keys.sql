@@ -0,0 +1,11 @@
+CREATE TABLE idempotency_keys (
+    idempotency_key VARCHAR(255) PRIMARY KEY NOT NULL,
+    fingerprint CHAR(64) NOT NULL,
+    status BIGINT NOT NULL,
+    content_type VARCHAR(255),
+    body LONGBLOB NOT NULL,
+    created_at BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL
+);
+
+CREATE INDEX idx_idempotency_keys_expires_at ON idempotency_keys (expires_at);
@@ -0,0 +1,13 @@
+CREATE TABLE audit_log (
+    id BIGINT AUTO_INCREMENT PRIMARY KEY,
+    created_at BIGINT NOT NULL,
+    actor VARCHAR(255),
+    request_id VARCHAR(128),
+    method VARCHAR(16) NOT NULL,
+    route VARCHAR(2048) NOT NULL,
+    status BIGINT NOT NULL,
+    payload LONGTEXT
+);
+
+CREATE INDEX idx_audit_log_created_at ON audit_log (created_at);
+CREATE INDEX idx_audit_log_actor ON audit_log (actor);
@@ -0,0 +1,18 @@
+CREATE TABLE roles (
+    name VARCHAR(32) PRIMARY KEY
+);
+
+INSERT INTO roles (name) VALUES ('viewer'), ('operator'), ('admin');
+
+CREATE TABLE user_roles (
+    user_id BIGINT NOT NULL,
+    role VARCHAR(32) NOT NULL,
+    PRIMARY KEY (user_id, role),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+    FOREIGN KEY (role) REFERENCES roles (name)
+);
+
+ALTER TABLE users ADD COLUMN roles_version BIGINT NOT NULL DEFAULT 0;
+
+-- Room for the `pending_verification` status
+ALTER TABLE users MODIFY status VARCHAR(32) NOT NULL;
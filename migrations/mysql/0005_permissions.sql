@@ -0,0 +1,24 @@
+CREATE TABLE permissions (
+    name VARCHAR(64) PRIMARY KEY
+);
+
+INSERT INTO permissions (name) VALUES
+    ('stats.read'), ('system.read'), ('routes.read'), ('logging.manage'), ('chaos.manage'),
+    ('sessions.read'), ('sessions.revoke'), ('audit.read'), ('debug.read'), ('users.manage'),
+    ('roles.manage');
+
+CREATE TABLE role_permissions (
+    role VARCHAR(32) NOT NULL,
+    permission VARCHAR(64) NOT NULL,
+    PRIMARY KEY (role, permission),
+    FOREIGN KEY (role) REFERENCES roles (name),
+    FOREIGN KEY (permission) REFERENCES permissions (name)
+);
+
+-- The grants of the former role hierarchy
+INSERT INTO role_permissions (role, permission)
+SELECT roles.name, permissions.name FROM roles, permissions
+WHERE roles.name = 'admin'
+    OR (roles.name = 'operator' AND permissions.name IN
+        ('stats.read', 'system.read', 'routes.read', 'logging.manage', 'chaos.manage'))
+    OR (roles.name = 'viewer' AND permissions.name IN ('stats.read', 'system.read', 'routes.read'));
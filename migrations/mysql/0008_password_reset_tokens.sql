@@ -0,0 +1,12 @@
+-- The password reset tokens, by the hex SHA-256 of the token (never stored in clear). A user has
+-- at most one token, replaced whenever a new one is issued, and a token is used at most once.
+CREATE TABLE password_reset_tokens (
+    token_hash VARCHAR(64) PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    created_at BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    used_at BIGINT,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX password_reset_tokens_user ON password_reset_tokens (user_id);
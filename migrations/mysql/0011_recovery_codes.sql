@@ -0,0 +1,11 @@
+-- The single-use recovery codes of the users with two-factor authentication, by the hex SHA-256
+-- of the normalized code (never stored in clear)
+CREATE TABLE recovery_codes (
+    code_hash VARCHAR(64) PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    created_at BIGINT NOT NULL,
+    used_at BIGINT,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX recovery_codes_user ON recovery_codes (user_id);
@@ -0,0 +1,18 @@
+-- The API keys of the automation clients, by the hex SHA-256 of the key (never stored in clear),
+-- with its first characters to tell them apart. A key acts on behalf of its user, or of a service
+-- when it has none, within its space-separated permission scopes
+CREATE TABLE api_keys (
+    id BIGINT AUTO_INCREMENT PRIMARY KEY,
+    name VARCHAR(255) NOT NULL,
+    prefix VARCHAR(16) NOT NULL,
+    key_hash VARCHAR(64) NOT NULL UNIQUE,
+    user_id BIGINT,
+    scopes TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    expires_at BIGINT,
+    last_used_at BIGINT,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+INSERT INTO permissions (name) VALUES ('api_keys.manage');
+INSERT INTO role_permissions (role, permission) VALUES ('admin', 'api_keys.manage');
@@ -0,0 +1,4 @@
+-- The operation an admin request performed (e.g. `sessions.prune`) and the number of records it
+-- affected, when the endpoint reports them
+ALTER TABLE audit_log ADD COLUMN operation VARCHAR(64);
+ALTER TABLE audit_log ADD COLUMN affected BIGINT;
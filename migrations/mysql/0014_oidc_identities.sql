@@ -0,0 +1,11 @@
+-- The subjects of the OpenID Connect providers, linked to the local users on their first login
+CREATE TABLE oidc_identities (
+    issuer VARCHAR(255) NOT NULL,
+    subject VARCHAR(255) NOT NULL,
+    user_id BIGINT NOT NULL,
+    created_at BIGINT NOT NULL,
+    PRIMARY KEY (issuer, subject),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX oidc_identities_user ON oidc_identities (user_id);
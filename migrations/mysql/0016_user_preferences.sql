@@ -0,0 +1,8 @@
+-- The preferences of the users (e.g. of the interface), as a JSON object set through
+-- `/users/me`
+CREATE TABLE user_preferences (
+    user_id BIGINT PRIMARY KEY,
+    preferences TEXT NOT NULL,
+    updated_at BIGINT NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
@@ -0,0 +1,15 @@
+-- The pending email changes, by the hex SHA-256 of their confirmation token (never stored in
+-- clear), with the new email and the (normalized) email of the user when asked: a change no longer
+-- applies once the email changed otherwise
+CREATE TABLE email_change_tokens (
+    token_hash VARCHAR(64) PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    pending_email VARCHAR(320) NOT NULL,
+    current_email VARCHAR(320) NOT NULL,
+    created_at BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    used_at BIGINT,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX email_change_tokens_user ON email_change_tokens (user_id);
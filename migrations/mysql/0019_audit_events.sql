@@ -0,0 +1,11 @@
+-- Events recorded by the domain code (e.g. `user.deactivated`), with the action, its target and
+-- its details as JSON. They aren't tied to a request, so the request columns become optional.
+ALTER TABLE audit_log MODIFY method VARCHAR(16) NULL;
+ALTER TABLE audit_log MODIFY route VARCHAR(2048) NULL;
+ALTER TABLE audit_log MODIFY status BIGINT NULL;
+ALTER TABLE audit_log ADD COLUMN action VARCHAR(128);
+ALTER TABLE audit_log ADD COLUMN target VARCHAR(255);
+ALTER TABLE audit_log ADD COLUMN details LONGTEXT;
+
+CREATE INDEX idx_audit_log_action ON audit_log (action);
+CREATE INDEX idx_audit_log_target ON audit_log (target);
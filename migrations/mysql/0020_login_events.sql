@@ -0,0 +1,20 @@
+-- The logins of the users: when, from which address and user agent, through which method and
+-- with which result. A successful login from a network (/24, or /48 for IPv6) or a user agent
+-- never seen for the user is flagged as from a new device. Pruned after
+-- `LOGIN_HISTORY_RETENTION_DAYS`.
+CREATE TABLE login_events (
+    id BIGINT AUTO_INCREMENT PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    created_at BIGINT NOT NULL,
+    ip VARCHAR(64) NOT NULL,
+    ip_network VARCHAR(64) NOT NULL,
+    user_agent VARCHAR(512),
+    user_agent_hash VARCHAR(64) NOT NULL,
+    method VARCHAR(16) NOT NULL,
+    result VARCHAR(16) NOT NULL,
+    new_device BOOLEAN NOT NULL DEFAULT FALSE,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_login_events_user ON login_events (user_id, created_at);
+CREATE INDEX idx_login_events_created_at ON login_events (created_at);
@@ -0,0 +1,31 @@
+-- The groups of users, granting roles to their members on top of their own. Changing the members
+-- or the roles of a group bumps the `roles_version` of its members.
+-- The table isn't named `groups`, a reserved word of MySQL.
+CREATE TABLE user_groups (
+    id BIGINT AUTO_INCREMENT PRIMARY KEY,
+    name VARCHAR(100) NOT NULL UNIQUE,
+    description VARCHAR(500),
+    created_at BIGINT NOT NULL,
+    updated_at BIGINT NOT NULL
+);
+
+CREATE TABLE group_members (
+    group_id BIGINT NOT NULL,
+    user_id BIGINT NOT NULL,
+    PRIMARY KEY (group_id, user_id),
+    FOREIGN KEY (group_id) REFERENCES user_groups (id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_group_members_user ON group_members (user_id);
+
+CREATE TABLE group_roles (
+    group_id BIGINT NOT NULL,
+    role VARCHAR(32) NOT NULL,
+    PRIMARY KEY (group_id, role),
+    FOREIGN KEY (group_id) REFERENCES user_groups (id) ON DELETE CASCADE,
+    FOREIGN KEY (role) REFERENCES roles (name)
+);
+
+INSERT INTO permissions (name) VALUES ('groups.manage');
+INSERT INTO role_permissions (role, permission) VALUES ('admin', 'groups.manage');
@@ -0,0 +1,17 @@
+-- The invitations of new users, by the hex SHA-256 of their token (never stored in clear), with
+-- the normalized email they were sent to and the space-separated roles the account gets. A new
+-- invitation to an email supersedes its pending ones.
+CREATE TABLE invitations (
+    id BIGINT AUTO_INCREMENT PRIMARY KEY,
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    email VARCHAR(320) NOT NULL,
+    roles VARCHAR(255) NOT NULL,
+    invited_by BIGINT,
+    status VARCHAR(16) NOT NULL,
+    created_at BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    accepted_at BIGINT,
+    FOREIGN KEY (invited_by) REFERENCES users (id) ON DELETE SET NULL
+);
+
+CREATE INDEX idx_invitations_email ON invitations (email, status);
@@ -0,0 +1,9 @@
+-- The hits counted by the rate limiter in the current window of each bucket (the SHA-256 of its
+-- key), when shared by the replicas
+CREATE TABLE rate_limits (
+    bucket CHAR(64) PRIMARY KEY NOT NULL,
+    hits BIGINT NOT NULL,
+    reset_at BIGINT NOT NULL
+);
+
+CREATE INDEX idx_rate_limits_reset_at ON rate_limits (reset_at);
@@ -0,0 +1,32 @@
+-- The webhooks notified of the events of the backend, with the secret signing their deliveries
+-- and the types of the events they want (a JSON array, every type when empty).
+CREATE TABLE webhooks (
+    id BIGINT AUTO_INCREMENT PRIMARY KEY,
+    url VARCHAR(2048) NOT NULL,
+    secret CHAR(64) NOT NULL UNIQUE,
+    enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    events TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    updated_at BIGINT NOT NULL
+);
+
+-- Every attempt to deliver an event to a webhook, with the response or the error.
+CREATE TABLE webhook_deliveries (
+    id BIGINT AUTO_INCREMENT PRIMARY KEY,
+    webhook_id BIGINT NOT NULL,
+    event VARCHAR(64) NOT NULL,
+    payload TEXT NOT NULL,
+    attempt INTEGER NOT NULL,
+    status_code INTEGER,
+    error TEXT,
+    latency_ms BIGINT NOT NULL,
+    response TEXT,
+    created_at BIGINT NOT NULL,
+    FOREIGN KEY (webhook_id) REFERENCES webhooks (id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_webhook_deliveries_webhook ON webhook_deliveries (webhook_id, id);
+CREATE INDEX idx_webhook_deliveries_created_at ON webhook_deliveries (created_at);
+
+INSERT INTO permissions (name) VALUES ('webhooks.manage');
+INSERT INTO role_permissions (role, permission) VALUES ('admin', 'webhooks.manage');
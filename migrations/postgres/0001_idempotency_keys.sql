@@ -0,0 +1,11 @@
+CREATE TABLE idempotency_keys (
+    idempotency_key TEXT PRIMARY KEY NOT NULL,
+    fingerprint TEXT NOT NULL,
+    status BIGINT NOT NULL,
+    content_type TEXT,
+    body BYTEA NOT NULL,
+    created_at BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL
+);
+
+CREATE INDEX idx_idempotency_keys_expires_at ON idempotency_keys (expires_at);
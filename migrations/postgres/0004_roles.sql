@@ -0,0 +1,13 @@
+CREATE TABLE roles (
+    name TEXT PRIMARY KEY
+);
+
+INSERT INTO roles (name) VALUES ('viewer'), ('operator'), ('admin');
+
+CREATE TABLE user_roles (
+    user_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    role TEXT NOT NULL REFERENCES roles (name),
+    PRIMARY KEY (user_id, role)
+);
+
+ALTER TABLE users ADD COLUMN roles_version BIGINT NOT NULL DEFAULT 0;
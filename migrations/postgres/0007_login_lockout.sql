@@ -0,0 +1,14 @@
+-- The consecutive failed logins to an account (the normalized email) from an address ('' when
+-- unknown), whether the account exists or not
+CREATE TABLE login_attempts (
+    account TEXT NOT NULL,
+    ip TEXT NOT NULL,
+    failures BIGINT NOT NULL,
+    last_failure_at BIGINT NOT NULL,
+    PRIMARY KEY (account, ip)
+);
+
+CREATE INDEX login_attempts_ip ON login_attempts (ip, last_failure_at);
+
+ALTER TABLE users ADD COLUMN locked_until BIGINT;
+ALTER TABLE users ADD COLUMN lockouts BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,11 @@
+-- The password reset tokens, by the hex SHA-256 of the token (never stored in clear). A user has
+-- at most one token, replaced whenever a new one is issued, and a token is used at most once.
+CREATE TABLE password_reset_tokens (
+    token_hash TEXT PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    used_at BIGINT
+);
+
+CREATE INDEX password_reset_tokens_user ON password_reset_tokens (user_id);
@@ -0,0 +1,7 @@
+-- The preferences of the users (e.g. of the interface), as a JSON object set through
+-- `/users/me`
+CREATE TABLE user_preferences (
+    user_id BIGINT PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    preferences TEXT NOT NULL,
+    updated_at BIGINT NOT NULL
+);
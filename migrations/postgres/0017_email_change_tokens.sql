@@ -0,0 +1,14 @@
+-- The pending email changes, by the hex SHA-256 of their confirmation token (never stored in
+-- clear), with the new email and the (normalized) email of the user when asked: a change no longer
+-- applies once the email changed otherwise
+CREATE TABLE email_change_tokens (
+    token_hash TEXT PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    pending_email TEXT NOT NULL,
+    current_email TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    used_at BIGINT
+);
+
+CREATE INDEX email_change_tokens_user ON email_change_tokens (user_id);
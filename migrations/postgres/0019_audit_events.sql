@@ -0,0 +1,11 @@
+-- Events recorded by the domain code (e.g. `user.deactivated`), with the action, its target and
+-- its details as JSON. They aren't tied to a request, so the request columns become optional.
+ALTER TABLE audit_log ALTER COLUMN method DROP NOT NULL;
+ALTER TABLE audit_log ALTER COLUMN route DROP NOT NULL;
+ALTER TABLE audit_log ALTER COLUMN status DROP NOT NULL;
+ALTER TABLE audit_log ADD COLUMN action TEXT;
+ALTER TABLE audit_log ADD COLUMN target TEXT;
+ALTER TABLE audit_log ADD COLUMN details TEXT;
+
+CREATE INDEX idx_audit_log_action ON audit_log (action);
+CREATE INDEX idx_audit_log_target ON audit_log (target);
@@ -0,0 +1,11 @@
+-- The settings adjustable at runtime, by namespaced key (e.g. `auth.registration_enabled`), with
+-- their JSON value. The column isn't named `key`, a reserved word of MySQL.
+CREATE TABLE settings (
+    setting_key TEXT PRIMARY KEY NOT NULL,
+    value TEXT NOT NULL,
+    updated_at BIGINT NOT NULL,
+    updated_by TEXT
+);
+
+INSERT INTO permissions (name) VALUES ('settings.manage');
+INSERT INTO role_permissions (role, permission) VALUES ('admin', 'settings.manage');
@@ -0,0 +1,17 @@
+-- The idempotency keys belong to the principal of the request, so the stored responses (which
+-- only live for a day) are dropped rather than assigned to one.
+DROP TABLE idempotency_keys;
+
+CREATE TABLE idempotency_keys (
+    principal TEXT NOT NULL,
+    idempotency_key TEXT NOT NULL,
+    fingerprint TEXT NOT NULL,
+    status BIGINT NOT NULL,
+    content_type TEXT,
+    body BYTEA NOT NULL,
+    created_at BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    PRIMARY KEY (principal, idempotency_key)
+);
+
+CREATE INDEX idx_idempotency_keys_expires_at ON idempotency_keys (expires_at);
@@ -0,0 +1,22 @@
+CREATE TABLE permissions (
+    name TEXT PRIMARY KEY
+);
+
+INSERT INTO permissions (name) VALUES
+    ('stats.read'), ('system.read'), ('routes.read'), ('logging.manage'), ('chaos.manage'),
+    ('sessions.read'), ('sessions.revoke'), ('audit.read'), ('debug.read'), ('users.manage'),
+    ('roles.manage');
+
+CREATE TABLE role_permissions (
+    role TEXT NOT NULL REFERENCES roles (name),
+    permission TEXT NOT NULL REFERENCES permissions (name),
+    PRIMARY KEY (role, permission)
+);
+
+-- The grants of the former role hierarchy
+INSERT INTO role_permissions (role, permission)
+SELECT roles.name, permissions.name FROM roles, permissions
+WHERE roles.name = 'admin'
+    OR (roles.name = 'operator' AND permissions.name IN
+        ('stats.read', 'system.read', 'routes.read', 'logging.manage', 'chaos.manage'))
+    OR (roles.name = 'viewer' AND permissions.name IN ('stats.read', 'system.read', 'routes.read'));
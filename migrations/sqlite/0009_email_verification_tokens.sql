@@ -0,0 +1,12 @@
+-- The email verification tokens, by the hex SHA-256 of the token (never stored in clear), with
+-- the (normalized) email they verify: a token no longer applies once the email changes
+CREATE TABLE email_verification_tokens (
+    token_hash TEXT PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    email TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    used_at BIGINT
+);
+
+CREATE INDEX email_verification_tokens_user ON email_verification_tokens (user_id);
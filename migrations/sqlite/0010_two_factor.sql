@@ -0,0 +1,10 @@
+-- The TOTP secrets of the users, encrypted (base64 of the nonce, tag and ciphertext). 2FA is on
+-- once `enabled_at` is set; `last_used_step` is the time step of the last accepted code, so that
+-- a code can't be replayed
+CREATE TABLE two_factor_secrets (
+    user_id BIGINT PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    secret TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    enabled_at BIGINT,
+    last_used_step BIGINT
+);
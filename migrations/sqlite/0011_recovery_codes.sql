@@ -0,0 +1,10 @@
+-- The single-use recovery codes of the users with two-factor authentication, by the hex SHA-256
+-- of the normalized code (never stored in clear)
+CREATE TABLE recovery_codes (
+    code_hash TEXT PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at BIGINT NOT NULL,
+    used_at BIGINT
+);
+
+CREATE INDEX recovery_codes_user ON recovery_codes (user_id);
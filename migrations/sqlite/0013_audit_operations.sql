@@ -0,0 +1,4 @@
+-- The operation an admin request performed (e.g. `sessions.prune`) and the number of records it
+-- affected, when the endpoint reports them
+ALTER TABLE audit_log ADD COLUMN operation TEXT;
+ALTER TABLE audit_log ADD COLUMN affected BIGINT;
@@ -0,0 +1,10 @@
+-- The subjects of the OpenID Connect providers, linked to the local users on their first login
+CREATE TABLE oidc_identities (
+    issuer TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    user_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at BIGINT NOT NULL,
+    PRIMARY KEY (issuer, subject)
+);
+
+CREATE INDEX oidc_identities_user ON oidc_identities (user_id);
@@ -0,0 +1,3 @@
+-- Where the password of a user is checked: locally, or by the LDAP directory for the users it
+-- created
+ALTER TABLE users ADD COLUMN auth_source TEXT NOT NULL DEFAULT 'local';
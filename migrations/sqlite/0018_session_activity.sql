@@ -0,0 +1,8 @@
+-- When each session was last active, kept when `SESSION_TRACK_LAST_SEEN` is set. The sessions
+-- live in the table of the session store: this one only references their IDs.
+CREATE TABLE session_activity (
+    session_id VARCHAR(128) PRIMARY KEY,
+    last_seen BIGINT NOT NULL
+);
+
+CREATE INDEX session_activity_last_seen ON session_activity (last_seen);
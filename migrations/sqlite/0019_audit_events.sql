@@ -0,0 +1,31 @@
+-- Events recorded by the domain code (e.g. `user.deactivated`), with the action, its target and
+-- its details as JSON. They aren't tied to a request, so the request columns become optional:
+-- SQLite can't drop a NOT NULL constraint, the table is rebuilt.
+CREATE TABLE audit_log_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at BIGINT NOT NULL,
+    actor TEXT,
+    request_id TEXT,
+    method TEXT,
+    route TEXT,
+    status BIGINT,
+    payload TEXT,
+    operation TEXT,
+    affected BIGINT,
+    action TEXT,
+    target TEXT,
+    details TEXT
+);
+
+INSERT INTO audit_log_new
+    (id, created_at, actor, request_id, method, route, status, payload, operation, affected)
+SELECT id, created_at, actor, request_id, method, route, status, payload, operation, affected
+FROM audit_log;
+
+DROP TABLE audit_log;
+ALTER TABLE audit_log_new RENAME TO audit_log;
+
+CREATE INDEX idx_audit_log_created_at ON audit_log (created_at);
+CREATE INDEX idx_audit_log_actor ON audit_log (actor);
+CREATE INDEX idx_audit_log_action ON audit_log (action);
+CREATE INDEX idx_audit_log_target ON audit_log (target);
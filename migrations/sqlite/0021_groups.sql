@@ -0,0 +1,27 @@
+-- The groups of users, granting roles to their members on top of their own. Changing the members
+-- or the roles of a group bumps the `roles_version` of its members.
+-- The table isn't named `groups`, a reserved word of MySQL.
+CREATE TABLE user_groups (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE,
+    description TEXT,
+    created_at BIGINT NOT NULL,
+    updated_at BIGINT NOT NULL
+);
+
+CREATE TABLE group_members (
+    group_id BIGINT NOT NULL REFERENCES user_groups (id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    PRIMARY KEY (group_id, user_id)
+);
+
+CREATE INDEX idx_group_members_user ON group_members (user_id);
+
+CREATE TABLE group_roles (
+    group_id BIGINT NOT NULL REFERENCES user_groups (id) ON DELETE CASCADE,
+    role TEXT NOT NULL REFERENCES roles (name),
+    PRIMARY KEY (group_id, role)
+);
+
+INSERT INTO permissions (name) VALUES ('groups.manage');
+INSERT INTO role_permissions (role, permission) VALUES ('admin', 'groups.manage');
@@ -0,0 +1,16 @@
+-- The invitations of new users, by the hex SHA-256 of their token (never stored in clear), with
+-- the normalized email they were sent to and the space-separated roles the account gets. A new
+-- invitation to an email supersedes its pending ones.
+CREATE TABLE invitations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    token_hash TEXT NOT NULL UNIQUE,
+    email TEXT NOT NULL,
+    roles TEXT NOT NULL,
+    invited_by BIGINT REFERENCES users (id) ON DELETE SET NULL,
+    status TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    accepted_at BIGINT
+);
+
+CREATE INDEX idx_invitations_email ON invitations (email, status);
@@ -0,0 +1,2 @@
+INSERT INTO permissions (name) VALUES ('users.impersonate');
+INSERT INTO role_permissions (role, permission) VALUES ('admin', 'users.impersonate');
@@ -0,0 +1,31 @@
+-- The webhooks notified of the events of the backend, with the secret signing their deliveries
+-- and the types of the events they want (a JSON array, every type when empty).
+CREATE TABLE webhooks (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL UNIQUE,
+    enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    events TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    updated_at BIGINT NOT NULL
+);
+
+-- Every attempt to deliver an event to a webhook, with the response or the error.
+CREATE TABLE webhook_deliveries (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    webhook_id BIGINT NOT NULL REFERENCES webhooks (id) ON DELETE CASCADE,
+    event TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    attempt INTEGER NOT NULL,
+    status_code INTEGER,
+    error TEXT,
+    latency_ms BIGINT NOT NULL,
+    response TEXT,
+    created_at BIGINT NOT NULL
+);
+
+CREATE INDEX idx_webhook_deliveries_webhook ON webhook_deliveries (webhook_id, id);
+CREATE INDEX idx_webhook_deliveries_created_at ON webhook_deliveries (created_at);
+
+INSERT INTO permissions (name) VALUES ('webhooks.manage');
+INSERT INTO role_permissions (role, permission) VALUES ('admin', 'webhooks.manage');
@@ -0,0 +1,290 @@
+//! HTTP access log
+//! When `ACCESS_LOG_PATH` is set, one line is appended per completed request, separately from
+//! the application logs. The lines are handed to a dedicated writer task through a bounded
+//! channel: when the writer falls behind, lines are dropped rather than delaying the responses.
+//! The writer owns the file, so rotations never race with writes.
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use axum::{
+    body::HttpBody,
+    extract::{ConnectInfo, Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+use time::{format_description::FormatItem, macros::format_description, Date, OffsetDateTime};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncWriteExt, BufWriter},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use crate::{
+    config::{AccessLogConfig, AccessLogFormat, AccessLogRotation},
+    request_id::RequestId,
+    state::AppState,
+    tenant::Tenant,
+};
+
+/// The number of lines buffered before lines get dropped
+const CHANNEL_CAPACITY: usize = 4096;
+
+/// The timestamp format of the common log format
+const COMMON_TIMESTAMP: &[FormatItem<'static>] = format_description!(
+    "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] [offset_hour sign:mandatory][offset_minute]"
+);
+
+/// A completed request
+#[derive(Debug, Serialize)]
+pub struct AccessLogEntry {
+    #[serde(with = "time::serde::rfc3339")]
+    pub timestamp: OffsetDateTime,
+    pub client_ip: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub protocol: String,
+    pub status: u16,
+    /// The size of the response body, when known upfront
+    pub bytes: Option<u64>,
+    pub latency_ms: u128,
+    pub request_id: Option<String>,
+    /// The tenant of the request, when tenants are enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+impl AccessLogEntry {
+    /// Render the entry as a line (without the line break)
+    fn format(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Common => format!(
+                "{} - - [{}] \"{} {} {}\" {} {} {} {}",
+                self.client_ip.as_deref().unwrap_or("-"),
+                self.timestamp.format(COMMON_TIMESTAMP).unwrap_or_default(),
+                self.method,
+                self.path,
+                self.protocol,
+                self.status,
+                self.bytes
+                    .map_or("-".to_string(), |bytes| bytes.to_string()),
+                self.latency_ms,
+                self.request_id.as_deref().unwrap_or("-"),
+            ),
+            AccessLogFormat::Json => serde_json::to_string(self).unwrap_or_default(),
+        }
+    }
+}
+
+enum Message {
+    Line(String),
+    Close(oneshot::Sender<()>),
+}
+
+/// A handle to the writer task
+#[derive(Clone)]
+pub struct AccessLog {
+    sender: mpsc::Sender<Message>,
+    format: AccessLogFormat,
+}
+
+impl AccessLog {
+    /// Open the access log and spawn its writer task
+    pub async fn spawn(config: &AccessLogConfig) -> std::io::Result<(AccessLog, JoinHandle<()>)> {
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let writer = Writer::open(config).await?;
+        let task = tokio::task::spawn(writer.run(receiver));
+        Ok((
+            AccessLog {
+                sender,
+                format: config.format,
+            },
+            task,
+        ))
+    }
+
+    /// Queue an entry, dropping it if the writer is behind
+    pub fn record(&self, entry: &AccessLogEntry) {
+        if self
+            .sender
+            .try_send(Message::Line(entry.format(self.format)))
+            .is_err()
+        {
+            tracing::warn!("Access log writer is behind, dropping an entry");
+        }
+    }
+
+    /// Write the queued entries, flush the file and stop the writer task
+    pub async fn close(&self) {
+        let (ack, done) = oneshot::channel();
+        if self.sender.send(Message::Close(ack)).await.is_ok() {
+            let _ = done.await;
+        }
+    }
+}
+
+/// Append an entry to the access log for every request
+pub async fn log_requests(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let Some(access_log) = state.access_log.clone() else {
+        return next.run(req).await;
+    };
+
+    let started_at = Instant::now();
+    let timestamp = OffsetDateTime::now_utc();
+    let client_ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|info| info.0.ip().to_string());
+    let request_id = req.extensions().get::<RequestId>().map(|id| id.0.clone());
+    let tenant = req
+        .extensions()
+        .get::<Tenant>()
+        .and_then(|tenant| tenant.0.clone());
+    let method = req.method().to_string();
+    let protocol = format!("{:?}", req.version());
+    let path = req
+        .uri()
+        .path_and_query()
+        .map_or(req.uri().path(), |path| path.as_str())
+        .to_string();
+
+    let response = next.run(req).await;
+
+    let bytes = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .or_else(|| response.body().size_hint().exact());
+
+    access_log.record(&AccessLogEntry {
+        timestamp,
+        client_ip,
+        method,
+        path,
+        protocol,
+        status: response.status().as_u16(),
+        bytes,
+        latency_ms: started_at.elapsed().as_millis(),
+        request_id,
+        tenant,
+    });
+
+    response
+}
+
+/// The owner of the access log file
+struct Writer {
+    path: PathBuf,
+    rotation: AccessLogRotation,
+    retained: usize,
+    file: BufWriter<File>,
+    /// The size of the current file
+    size: u64,
+    /// The day the current file was opened
+    day: Date,
+}
+
+impl Writer {
+    async fn open(config: &AccessLogConfig) -> std::io::Result<Writer> {
+        let (file, size) = open_append(&config.path).await?;
+        Ok(Writer {
+            path: config.path.clone(),
+            rotation: config.rotation,
+            retained: config.retained,
+            file,
+            size,
+            day: OffsetDateTime::now_utc().date(),
+        })
+    }
+
+    async fn run(mut self, mut receiver: mpsc::Receiver<Message>) {
+        while let Some(message) = receiver.recv().await {
+            match message {
+                Message::Line(line) => {
+                    if let Err(err) = self.write(&line).await {
+                        tracing::error!("Failed to write the access log: {}", err);
+                    }
+                    // Flush once the queue is drained, to batch writes under load
+                    if receiver.is_empty() {
+                        let _ = self.file.flush().await;
+                    }
+                }
+                Message::Close(ack) => {
+                    let _ = self.file.flush().await;
+                    let _ = ack.send(());
+                    return;
+                }
+            }
+        }
+
+        let _ = self.file.flush().await;
+    }
+
+    async fn write(&mut self, line: &str) -> std::io::Result<()> {
+        let today = OffsetDateTime::now_utc().date();
+        let needs_rotation = match self.rotation {
+            AccessLogRotation::Size(max_bytes) => {
+                self.size > 0 && self.size + line.len() as u64 + 1 > max_bytes
+            }
+            AccessLogRotation::Daily => today != self.day,
+        };
+        if needs_rotation {
+            self.rotate().await?;
+            self.day = today;
+        }
+
+        self.file.write_all(line.as_bytes()).await?;
+        self.file.write_all(b"\n").await?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    /// Shift the files (`access.log` to `access.log.1`, `access.log.1` to `access.log.2`, ...),
+    /// dropping the oldest, and start a new file
+    async fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush().await?;
+
+        if self.retained == 0 {
+            tokio::fs::remove_file(&self.path).await?;
+        } else {
+            let _ = tokio::fs::remove_file(rotated_path(&self.path, self.retained)).await;
+            for index in (1..self.retained).rev() {
+                let from = rotated_path(&self.path, index);
+                if tokio::fs::try_exists(&from).await.unwrap_or(false) {
+                    tokio::fs::rename(&from, rotated_path(&self.path, index + 1)).await?;
+                }
+            }
+            tokio::fs::rename(&self.path, rotated_path(&self.path, 1)).await?;
+        }
+
+        let (file, size) = open_append(&self.path).await?;
+        self.file = file;
+        self.size = size;
+        Ok(())
+    }
+}
+
+/// Open a file for appending, returning its current size
+async fn open_append(path: &Path) -> std::io::Result<(BufWriter<File>, u64)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    let size = file.metadata().await?.len();
+    Ok((BufWriter::new(file), size))
+}
+
+/// The path of the `index`-th rotated file
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", index));
+    PathBuf::from(rotated)
+}
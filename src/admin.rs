@@ -0,0 +1,166 @@
+//! Helpers shared by the administration endpoints
+//! Every admin route requires a permission: either the `ADMIN_TOKEN` bearer token, for
+//! automation, which grants every permission, the session of a logged in user with a role
+//! granted the permission, an API key scoped to the permission (see [`crate::api_keys`]), or a
+//! token embedding the permission (see [`crate::auth::jwt`]).
+//! When no token is configured, only users and API keys can reach the endpoints.
+//! Anonymous requests are answered with a `401`, and users without the permission with a `403`.
+//! State-changing requests are recorded in the audit log.
+
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderMap},
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+
+use crate::{
+    api_keys::AuthenticatedKey,
+    audit,
+    auth::{current_user::CurrentUser, jwt::AuthenticatedToken},
+    error::AppError,
+    permissions::{Permission, PermissionRepository},
+    roles::RoleRepository,
+    session_data::AppSession,
+    state::AppState,
+};
+
+/// The maximum number of items returned by a listing
+const MAX_PAGE_SIZE: i64 = 500;
+
+/// Guard the routes of the router as admin endpoints, requiring the given permission
+pub fn protect(
+    router: Router<AppState>,
+    state: AppState,
+    permission: Permission,
+) -> Router<AppState> {
+    router
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), permission),
+            require_permission,
+        ))
+        .route_layer(middleware::from_fn_with_state(state, audit::capture))
+}
+
+/// Reject requests that carry neither the admin token nor the session of a user with the
+/// permission
+async fn require_permission(
+    State((state, permission)): State<(AppState, Permission)>,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    // The user is cached in the request, for the handler to extract it again for free
+    let (mut parts, body) = req.into_parts();
+    if !grants(&state, &mut parts, permission).await? {
+        return Err(AppError::Forbidden);
+    }
+
+    Ok(next.run(Request::from_parts(parts, body)).await)
+}
+
+/// Whether the maker of a request holds a permission, through the admin token, its API key, its
+/// token or the roles of its user, rejecting anonymous requests with a `401`
+///
+/// The handlers check with it the permissions they need beyond the one of their route.
+pub(crate) async fn grants(
+    state: &AppState,
+    parts: &mut Parts,
+    permission: Permission,
+) -> Result<bool, AppError> {
+    if has_admin_token(state, &parts.headers) {
+        return Ok(true);
+    }
+    if let Some(key) = parts.extensions.get::<AuthenticatedKey>() {
+        return key_grants(state, key, permission).await;
+    }
+    if let Some(token) = parts.extensions.get::<AuthenticatedToken>() {
+        return token_grants(state, token, permission).await;
+    }
+
+    let user = CurrentUser::from_request_parts(parts, state).await?;
+    let session = AppSession::from_request_parts(parts, state).await?;
+    let roles = user.roles(&session, state).await?;
+    PermissionRepository::new(state.write_pool().clone())
+        .granted(&roles, permission)
+        .await
+}
+
+/// Whether an API key holds a permission: it must be in its scopes, and held by its user if any
+async fn key_grants(
+    state: &AppState,
+    key: &AuthenticatedKey,
+    permission: Permission,
+) -> Result<bool, AppError> {
+    if !key.key.scopes.contains(&permission) {
+        return Ok(false);
+    }
+    let Some(user) = &key.user else {
+        return Ok(true);
+    };
+    let roles = RoleRepository::new(state.write_pool().clone())
+        .effective_roles_of(user.id)
+        .await?;
+    PermissionRepository::new(state.write_pool().clone())
+        .granted(&roles, permission)
+        .await
+}
+
+/// Whether a token holds a permission: it must embed it, and its user must still hold it
+async fn token_grants(
+    state: &AppState,
+    token: &AuthenticatedToken,
+    permission: Permission,
+) -> Result<bool, AppError> {
+    if !token.claims.permissions.contains(&permission) {
+        return Ok(false);
+    }
+    let roles = RoleRepository::new(state.write_pool().clone())
+        .effective_roles_of(token.user.id)
+        .await?;
+    PermissionRepository::new(state.write_pool().clone())
+        .granted(&roles, permission)
+        .await
+}
+
+/// Whether the request carries the admin token
+pub(crate) fn has_admin_token(state: &AppState, headers: &HeaderMap) -> bool {
+    let Some(expected) = state.config.admin_token.as_deref() else {
+        return false;
+    };
+
+    let provided = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    bool::from(provided.as_bytes().ct_eq(expected.as_bytes()))
+}
+
+/// Pagination parameters of a listing
+#[derive(Deserialize)]
+pub struct Pagination {
+    #[serde(default = "default_limit")]
+    limit: i64,
+    #[serde(default)]
+    offset: i64,
+}
+
+fn default_limit() -> i64 {
+    50
+}
+
+impl Pagination {
+    /// The number of items to return, within bounds
+    pub fn limit(&self) -> i64 {
+        self.limit.clamp(1, MAX_PAGE_SIZE)
+    }
+
+    /// The number of items to skip
+    pub fn offset(&self) -> i64 {
+        self.offset.max(0)
+    }
+}
@@ -0,0 +1,517 @@
+//! API keys of the automation clients
+//! Clients that can't keep a session, such as CI jobs, authenticate with an API key, sent as
+//! `Authorization: Bearer <key>` or `X-Api-Key: <key>`. A key acts on behalf of its user, or of a
+//! service when it isn't bound to one, and only within its scopes: on the admin endpoints, it
+//! holds the permissions of its scopes that its user (if any) holds. Keys are random, shown once
+//! when created, and only their SHA-256 is stored, with their first characters to tell them apart.
+//! Admins manage them through `/admin/api-keys`, within the permissions they hold themselves; a
+//! deleted key is refused on the next request.
+
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{header, request::Parts, HeaderMap, HeaderName, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use time::OffsetDateTime;
+
+use crate::{
+    admin::{self, Pagination},
+    auth::{
+        current_user::OptionalUser,
+        login_history::{self, LoginClient, LoginMethod, LoginResult},
+    },
+    database::{with_pool, SqlxPool},
+    error::AppError,
+    idempotency::NoStore,
+    negotiate::{Format, Negotiated},
+    permissions::Permission,
+    state::AppState,
+    users::{User, UserRepository, UserStatus},
+};
+
+/// The header carrying an API key, as an alternative to `Authorization`
+pub const X_API_KEY: HeaderName = HeaderName::from_static("x-api-key");
+
+/// The start of every key, telling them apart from the other bearer tokens
+pub const KEY_PREFIX: &str = "ack_";
+
+/// The number of random bytes of a key
+const KEY_LEN: usize = 32;
+
+/// The number of characters of a key kept to tell it apart, including [`KEY_PREFIX`]
+const DISPLAYED_LEN: usize = 12;
+
+/// The maximum number of characters of the name of a key
+const MAX_NAME_LENGTH: usize = 100;
+
+/// How often the last use of a key is recorded, in seconds
+const LAST_USED_PRECISION: i64 = 60;
+
+/// The columns of the `api_keys` table, in the order of [`ApiKeyRow`]
+const COLUMNS: &str = "id, name, prefix, key_hash, user_id, scopes, created_at, expires_at, \
+                       last_used_at";
+
+/// An API key, without the key itself
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    /// The first characters of the key
+    pub prefix: String,
+    /// The user the key acts on behalf of, if any (a service otherwise)
+    pub user_id: Option<i64>,
+    /// The permissions the key can use
+    pub scopes: Vec<Permission>,
+    /// When the key was created, in unix seconds
+    pub created_at: i64,
+    /// When the key expires, in unix seconds, if it does
+    pub expires_at: Option<i64>,
+    /// When the key was last used, in unix seconds, to the minute
+    pub last_used_at: Option<i64>,
+    /// The hex SHA-256 of the key, never serialized
+    #[serde(skip)]
+    key_hash: String,
+}
+
+impl ApiKey {
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// A row of the `api_keys` table
+#[derive(sqlx::FromRow)]
+struct ApiKeyRow {
+    id: i64,
+    name: String,
+    prefix: String,
+    key_hash: String,
+    user_id: Option<i64>,
+    scopes: String,
+    created_at: i64,
+    expires_at: Option<i64>,
+    last_used_at: Option<i64>,
+}
+
+impl From<ApiKeyRow> for ApiKey {
+    fn from(row: ApiKeyRow) -> Self {
+        ApiKey {
+            id: row.id,
+            name: row.name,
+            prefix: row.prefix,
+            user_id: row.user_id,
+            // Permissions removed from the backend are dropped
+            scopes: row
+                .scopes
+                .split_whitespace()
+                .filter_map(Permission::parse)
+                .collect(),
+            created_at: row.created_at,
+            expires_at: row.expires_at,
+            last_used_at: row.last_used_at,
+            key_hash: row.key_hash,
+        }
+    }
+}
+
+/// An API key to create
+#[derive(Clone, Debug)]
+pub struct NewApiKey {
+    pub name: String,
+    pub user_id: Option<i64>,
+    pub scopes: Vec<Permission>,
+    pub expires_at: Option<i64>,
+}
+
+/// Generate a key
+fn generate() -> String {
+    let mut bytes = [0u8; KEY_LEN];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format!("{}{}", KEY_PREFIX, URL_SAFE_NO_PAD.encode(bytes))
+}
+
+/// The hex SHA-256 of a key, as stored
+fn hash(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// The API keys, stored in the database
+#[derive(Clone, Debug)]
+pub struct ApiKeyRepository {
+    pool: SqlxPool,
+}
+
+impl ApiKeyRepository {
+    pub fn new(pool: SqlxPool) -> Self {
+        Self { pool }
+    }
+
+    /// Create a key, returning it along with the key itself, which isn't stored
+    pub async fn create(&self, key: NewApiKey) -> Result<(ApiKey, String), sqlx::Error> {
+        let secret = generate();
+        let scopes = key
+            .scopes
+            .iter()
+            .map(|scope| scope.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let insert = self.pool.sql(
+            "INSERT INTO api_keys (name, prefix, key_hash, user_id, scopes, created_at, expires_at) \
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+        );
+        let select = self
+            .pool
+            .sql(&format!(
+                "SELECT {COLUMNS} FROM api_keys WHERE key_hash = ?"
+            ))
+            .into_owned();
+        let key_hash = hash(&secret);
+
+        let row: ApiKeyRow = with_pool!(&self.pool, |p| {
+            sqlx::query(&insert)
+                .bind(&key.name)
+                .bind(&secret[..DISPLAYED_LEN])
+                .bind(&key_hash)
+                .bind(key.user_id)
+                .bind(&scopes)
+                .bind(now())
+                .bind(key.expires_at)
+                .execute(p)
+                .await?;
+            sqlx::query_as(&select).bind(&key_hash).fetch_one(p).await?
+        });
+        Ok((row.into(), secret))
+    }
+
+    /// The key matching the given one, expired or not
+    pub async fn find_by_key(&self, key: &str) -> Result<Option<ApiKey>, sqlx::Error> {
+        let key_hash = hash(key);
+        let sql = self
+            .pool
+            .sql(&format!(
+                "SELECT {COLUMNS} FROM api_keys WHERE key_hash = ?"
+            ))
+            .into_owned();
+        let row: Option<ApiKeyRow> = with_pool!(&self.pool, |p| sqlx::query_as(&sql)
+            .bind(&key_hash)
+            .fetch_optional(p)
+            .await)?;
+
+        // The lookup is by hash, which tells nothing about the key; the hashes are still compared
+        // in constant time, not to rely on how the database compares them
+        Ok(row
+            .map(ApiKey::from)
+            .filter(|found| bool::from(found.key_hash.as_bytes().ct_eq(key_hash.as_bytes()))))
+    }
+
+    /// The keys, most recent first
+    pub async fn list(&self, limit: i64, offset: i64) -> Result<Vec<ApiKey>, sqlx::Error> {
+        let sql = self
+            .pool
+            .sql(&format!(
+                "SELECT {COLUMNS} FROM api_keys ORDER BY id DESC LIMIT ? OFFSET ?"
+            ))
+            .into_owned();
+        let rows: Vec<ApiKeyRow> = with_pool!(&self.pool, |p| sqlx::query_as(&sql)
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(p)
+            .await)?;
+        Ok(rows.into_iter().map(ApiKey::from).collect())
+    }
+
+    /// Delete a key, returning whether it existed
+    pub async fn delete(&self, id: i64) -> Result<bool, sqlx::Error> {
+        let sql = self.pool.sql("DELETE FROM api_keys WHERE id = ?");
+        let deleted = with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(id)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))?;
+        Ok(deleted > 0)
+    }
+
+    /// Record that a key was used, unless it was already recorded in the last minute, returning
+    /// whether it was recorded
+    pub async fn touch(&self, id: i64, now: i64) -> Result<bool, sqlx::Error> {
+        let sql = self.pool.sql(
+            "UPDATE api_keys SET last_used_at = ? \
+             WHERE id = ? AND (last_used_at IS NULL OR last_used_at <= ?)",
+        );
+        let touched = with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(now)
+            .bind(id)
+            .bind(now - LAST_USED_PRECISION)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))?;
+        Ok(touched > 0)
+    }
+}
+
+/// The API key a request is authenticated with, and its user, kept in the request extensions
+#[derive(Clone, Debug)]
+pub struct AuthenticatedKey {
+    pub key: ApiKey,
+    /// The user the key acts on behalf of, `None` for a service key
+    pub user: Option<User>,
+}
+
+impl AuthenticatedKey {
+    /// Who made the request, as recorded in the audit log
+    pub fn actor(&self) -> String {
+        match &self.user {
+            Some(user) => user.id.to_string(),
+            None => format!("api-key:{}", self.key.id),
+        }
+    }
+}
+
+/// The API key the request carries, if any
+///
+/// Bearer tokens that don't look like API keys, such as the `ADMIN_TOKEN`, are left alone.
+pub fn key_of(headers: &HeaderMap) -> Option<&str> {
+    if let Some(key) = headers.get(X_API_KEY) {
+        return key.to_str().ok();
+    }
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .filter(|token| token.starts_with(KEY_PREFIX))
+}
+
+/// Authenticate the requests carrying an API key
+///
+/// Unknown or expired keys, and keys whose user is no longer active, are answered with a `401`.
+/// The key is kept in the request extensions, where [`CurrentUser`](crate::auth::current_user)
+/// and the admin endpoints find it.
+pub async fn authenticate(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let Some(secret) = key_of(req.headers()) else {
+        return next.run(req).await;
+    };
+
+    let client = LoginClient::of(req.extensions(), req.headers());
+    match resolve(&state, secret, &client).await {
+        Ok(Some(key)) => {
+            req.extensions_mut().insert(key);
+            next.run(req).await
+        }
+        Ok(None) => AppError::Unauthorized.into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+/// The usable key matching the given one, recording its use
+///
+/// The uses of the keys of a user are recorded in their login history, at most once per minute
+/// per key like their last use, and the refusals of their expired keys every time.
+async fn resolve(
+    state: &AppState,
+    secret: &str,
+    client: &LoginClient,
+) -> Result<Option<AuthenticatedKey>, AppError> {
+    let keys = ApiKeyRepository::new(state.write_pool().clone());
+    let now = now();
+    let Some(key) = keys.find_by_key(secret).await? else {
+        tracing::info!("Refused an unknown API key");
+        return Ok(None);
+    };
+
+    let user = match key.user_id {
+        Some(user_id) => {
+            let user = UserRepository::new(state.write_pool().clone())
+                .find_by_id(user_id)
+                .await?;
+            if user.is_none() {
+                tracing::info!("Refused the API key {} of an inactive user", key.id);
+                return Ok(None);
+            }
+            user
+        }
+        None => None,
+    };
+    if key.is_expired(now) {
+        tracing::info!("Refused the expired API key {}", key.id);
+        if let Some(user) = &user {
+            login_history::record(
+                state,
+                user,
+                LoginMethod::ApiKey,
+                LoginResult::Failure,
+                client,
+            )
+            .await;
+        }
+        return Ok(None);
+    }
+    if let Some(user) = user
+        .as_ref()
+        .filter(|user| user.status != UserStatus::Active)
+    {
+        tracing::info!("Refused the API key {} of an inactive user", key.id);
+        login_history::record(
+            state,
+            user,
+            LoginMethod::ApiKey,
+            LoginResult::Failure,
+            client,
+        )
+        .await;
+        return Ok(None);
+    }
+
+    // Only write once per minute, without delaying the request
+    if key
+        .last_used_at
+        .is_none_or(|last_used_at| last_used_at <= now - LAST_USED_PRECISION)
+    {
+        let id = key.id;
+        let (state, user, client) = (state.clone(), user.clone(), client.clone());
+        tokio::spawn(async move {
+            let touched = match keys.touch(id, now).await {
+                Ok(touched) => touched,
+                Err(err) => {
+                    tracing::warn!("Failed to record the use of API key {}: {}", id, err);
+                    false
+                }
+            };
+            // Recorded by a concurrent request otherwise
+            if let Some(user) = user.as_ref().filter(|_| touched) {
+                login_history::record(
+                    &state,
+                    user,
+                    LoginMethod::ApiKey,
+                    LoginResult::Success,
+                    &client,
+                )
+                .await;
+            }
+        });
+    }
+
+    Ok(Some(AuthenticatedKey { key, user }))
+}
+
+/// A key to create
+#[derive(Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    /// The user the key acts on behalf of, a service key when missing
+    pub user_id: Option<i64>,
+    pub scopes: Vec<Permission>,
+    /// When the key expires, never when missing
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub expires_at: Option<OffsetDateTime>,
+}
+
+/// A created key, with the key itself, only ever shown once
+#[derive(Serialize)]
+pub struct CreatedApiKey {
+    pub api_key: ApiKey,
+    pub key: String,
+}
+
+/// `GET /admin/api-keys`: list the keys, without the keys themselves
+pub async fn list_api_keys(
+    State(state): State<AppState>,
+    format: Format,
+    Query(pagination): Query<Pagination>,
+) -> Result<Negotiated<Vec<ApiKey>>, AppError> {
+    let keys = ApiKeyRepository::new(state.read_pool().clone())
+        .list(pagination.limit(), pagination.offset())
+        .await?;
+    Ok(Negotiated(format, keys))
+}
+
+/// `POST /admin/api-keys`: create a key
+///
+/// The key can only be given scopes its creator holds, and only be bound to another user than
+/// its creator with [`Permission::USERS_MANAGE`], so that creating a key never grants more than
+/// the creator already has.
+pub async fn create_api_key(
+    State(state): State<AppState>,
+    OptionalUser(creator): OptionalUser,
+    mut parts: Parts,
+    Negotiated(format, request): Negotiated<CreateApiKeyRequest>,
+) -> Result<(StatusCode, NoStore, Negotiated<CreatedApiKey>), AppError> {
+    let name = request.name.trim();
+    let mut violations = Vec::new();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        violations.push(format!(
+            "the name must have between 1 and {} characters",
+            MAX_NAME_LENGTH
+        ));
+    }
+    if request.scopes.is_empty() {
+        violations.push("the key must have at least one scope".to_string());
+    }
+    let expires_at = request.expires_at.map(|at| at.unix_timestamp());
+    if expires_at.is_some_and(|expires_at| expires_at <= now()) {
+        violations.push("the expiry must be in the future".to_string());
+    }
+    if !violations.is_empty() {
+        return Err(AppError::BadRequest(violations.join("; ")));
+    }
+    for scope in &request.scopes {
+        if !admin::grants(&state, &mut parts, *scope).await? {
+            return Err(AppError::Forbidden);
+        }
+    }
+    if let Some(user_id) = request.user_id {
+        if creator.as_ref().map(|creator| creator.id) != Some(user_id)
+            && !admin::grants(&state, &mut parts, Permission::USERS_MANAGE).await?
+        {
+            return Err(AppError::Forbidden);
+        }
+        UserRepository::new(state.write_pool().clone())
+            .find_by_id(user_id)
+            .await?
+            .ok_or_else(|| AppError::BadRequest("the user doesn't exist".to_string()))?;
+    }
+
+    let mut scopes = request.scopes;
+    scopes.sort();
+    scopes.dedup();
+    let (api_key, key) = ApiKeyRepository::new(state.write_pool().clone())
+        .create(NewApiKey {
+            name: name.to_string(),
+            user_id: request.user_id,
+            scopes,
+            expires_at,
+        })
+        .await?;
+    tracing::info!("Created the API key {} ({})", api_key.id, api_key.prefix);
+
+    Ok((
+        StatusCode::CREATED,
+        NoStore,
+        Negotiated(format, CreatedApiKey { api_key, key }),
+    ))
+}
+
+/// `DELETE /admin/api-keys/:id`: revoke a key
+pub async fn revoke_api_key(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, AppError> {
+    if !ApiKeyRepository::new(state.write_pool().clone())
+        .delete(id)
+        .await?
+    {
+        return Err(AppError::NotFound);
+    }
+    tracing::info!("Revoked the API key {}", id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The current time, in unix seconds
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
@@ -0,0 +1,570 @@
+//! Audit log of the admin API
+//! Every state-changing request to the admin endpoints is recorded: who made it, on which route,
+//! with which payload (secrets redacted) and with which outcome. Endpoints acting on many
+//! records at once, such as the session pruning, name their operation and the number of records
+//! affected with an [`AuditOperation`] in their response. The domain code records its own events
+//! (e.g. `user.deactivated`) with their target and details through the [`AuditLogger`] of the
+//! state. Entries are written to the `audit_log` table, or to the `audit` tracing target with
+//! `AUDIT_SINK=log`.
+//!
+//! Entries are queued and written by a background task, so that recording one never delays a
+//! request. When the queue (`AUDIT_QUEUE_CAPACITY`) is full, entries are dropped and counted. With
+//! `AUDIT_RETENTION_DAYS`, older entries are pruned.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    async_trait,
+    body::{to_bytes, Body},
+    extract::{FromRequestParts, OriginalUri, Query, Request, State},
+    http::{header, request::Parts, Extensions, HeaderMap, HeaderValue, Method},
+    middleware::Next,
+    response::{IntoResponse, IntoResponseParts, Response, ResponseParts},
+};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::sync::{mpsc, Mutex};
+use tower_sessions::Session;
+
+use crate::{
+    admin::Pagination,
+    api_keys::AuthenticatedKey,
+    auth::jwt::AuthenticatedToken,
+    config::AuditSink,
+    database::{with_pool, SqlxPool},
+    error::AppError,
+    negotiate::{Format, Negotiated},
+    redact::redact_json,
+    request_id::RequestId,
+    session_data::AppSession,
+    state::AppState,
+};
+
+const CSV: &str = "text/csv";
+
+/// An entry of the audit log
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct AuditEntry {
+    pub id: i64,
+    /// When the request completed or the event happened, in unix seconds
+    pub created_at: i64,
+    /// The user who made the request, if known (`api-key:<id>` for a service API key)
+    pub actor: Option<String>,
+    pub request_id: Option<String>,
+    /// The method of the request, for a request
+    pub method: Option<String>,
+    /// The route of the request, for a request
+    pub route: Option<String>,
+    /// The status of the response, for a request
+    pub status: Option<i64>,
+    /// The redacted JSON payload, if the request had a JSON body small enough to be captured
+    pub payload: Option<String>,
+    /// The operation performed, if the endpoint named it (e.g. `sessions.prune`)
+    pub operation: Option<String>,
+    /// The number of records the operation affected
+    pub affected: Option<i64>,
+    /// The action, for an event (e.g. `user.deactivated`)
+    pub action: Option<String>,
+    /// What the action was taken on, for an event (e.g. the ID of a user)
+    pub target: Option<String>,
+    /// The details of the event, as JSON
+    pub details: Option<String>,
+}
+
+/// The operation an admin endpoint performed, recorded with its request in the audit log
+///
+/// Handlers return it as a part of their response:
+///
+/// ```ignore
+/// Ok((AuditOperation::new("sessions.sweep", swept), Negotiated(format, outcome)))
+/// ```
+#[derive(Clone, Debug)]
+pub struct AuditOperation {
+    pub name: &'static str,
+    pub affected: u64,
+}
+
+impl AuditOperation {
+    pub fn new(name: &'static str, affected: u64) -> Self {
+        Self { name, affected }
+    }
+}
+
+impl IntoResponseParts for AuditOperation {
+    type Error = std::convert::Infallible;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        res.extensions_mut().insert(self);
+        Ok(res)
+    }
+}
+
+/// Who made the request, as recorded in the audit log, if known
+///
+/// ```ignore
+/// state.audit.record(actor.0, "user.deactivated", &user_id.to_string(), json!({}));
+/// ```
+pub struct AuditActor(pub Option<String>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AuditActor {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(AuditActor(actor_of(&parts.extensions).await))
+    }
+}
+
+/// Who made a request, from its API key, its token or its session
+async fn actor_of(extensions: &Extensions) -> Option<String> {
+    match (
+        extensions.get::<AuthenticatedKey>(),
+        extensions.get::<AuthenticatedToken>(),
+        extensions.get::<Session>(),
+    ) {
+        (Some(key), _, _) => Some(key.actor()),
+        (None, Some(token), _) => Some(token.actor()),
+        (None, None, Some(session)) => {
+            let data = AppSession(session.clone()).data().await.ok()?;
+            match (data.impersonator_id, data.user_id) {
+                (Some(impersonator_id), Some(user_id)) => {
+                    Some(format!("{impersonator_id} as {user_id}"))
+                }
+                (_, user_id) => user_id,
+            }
+        }
+        (None, None, None) => None,
+    }
+}
+
+/// The queue of the entries to write to the audit log
+#[derive(Clone)]
+pub struct AuditLogger {
+    pool: SqlxPool,
+    sink: AuditSink,
+    sender: mpsc::Sender<AuditEntry>,
+    /// Shared by the writing task and the final flush
+    receiver: Arc<Mutex<mpsc::Receiver<AuditEntry>>>,
+    /// The number of entries dropped since the start, the queue being full
+    dropped: Arc<AtomicU64>,
+}
+
+/// Counters about the audit log queue
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditStats {
+    /// The entries waiting to be written
+    pub queued: usize,
+    /// The entries dropped since the start, the queue being full
+    pub dropped: u64,
+}
+
+impl AuditLogger {
+    /// Write the entries to the given sink, queuing up to `capacity` of them
+    pub fn new(pool: SqlxPool, sink: AuditSink, capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::channel(capacity);
+        Self {
+            pool,
+            sink,
+            sender,
+            receiver: Arc::new(Mutex::new(receiver)),
+            dropped: Default::default(),
+        }
+    }
+
+    /// Record an event of the domain: `actor` took `action` on `target`
+    ///
+    /// Never waits: the event is dropped when the queue is full.
+    pub fn record(
+        &self,
+        actor: Option<String>,
+        action: &str,
+        target: &str,
+        details: serde_json::Value,
+    ) {
+        self.submit(AuditEntry {
+            id: 0,
+            created_at: OffsetDateTime::now_utc().unix_timestamp(),
+            actor,
+            request_id: None,
+            method: None,
+            route: None,
+            status: None,
+            payload: None,
+            operation: None,
+            affected: None,
+            action: Some(action.to_string()),
+            target: Some(target.to_string()),
+            details: Some(details.to_string()),
+        });
+    }
+
+    /// Queue an entry
+    ///
+    /// Never waits: the entry is dropped when the queue is full.
+    pub fn submit(&self, entry: AuditEntry) {
+        if let Err(err) = self.sender.try_send(entry) {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            tracing::error!(
+                "Dropped an audit log entry ({} so far), the queue is full or closed: {}",
+                dropped,
+                err
+            );
+        }
+    }
+
+    pub fn stats(&self) -> AuditStats {
+        AuditStats {
+            queued: self.sender.max_capacity() - self.sender.capacity(),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Write the queued entries, forever
+    pub async fn continuously_write(self) {
+        loop {
+            let Some(entry) = self.receiver.lock().await.recv().await else {
+                return;
+            };
+            self.write(&entry).await;
+        }
+    }
+
+    /// Write the entries still queued, e.g. once every request is done
+    pub async fn flush(&self) {
+        let mut receiver = self.receiver.lock().await;
+        while let Ok(entry) = receiver.try_recv() {
+            self.write(&entry).await;
+        }
+    }
+
+    async fn write(&self, entry: &AuditEntry) {
+        if let Err(err) = write(&self.pool, self.sink, entry).await {
+            tracing::error!("Failed to write audit log entry: {}", err);
+        }
+    }
+}
+
+/// Record state-changing requests in the audit log
+///
+/// Safe methods are not recorded. The entry is queued once the response is produced, without
+/// delaying it.
+pub async fn capture(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return next.run(req).await;
+    }
+
+    let actor = actor_of(req.extensions()).await;
+    let request_id = req.extensions().get::<RequestId>().map(|id| id.0.clone());
+    let method = req.method().to_string();
+    // Nested routers strip their prefix from the URI: record the full path
+    let route = req
+        .extensions()
+        .get::<OriginalUri>()
+        .map_or(req.uri().path(), |uri| uri.0.path())
+        .to_string();
+
+    // Only buffer bodies that are announced as small enough
+    let max_body_size = state.config.audit.max_body_bytes;
+    let announced_size = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+
+    let (req, payload) = match announced_size {
+        Some(size) if size <= max_body_size => {
+            let (parts, body) = req.into_parts();
+            let bytes = match to_bytes(body, max_body_size).await {
+                Ok(bytes) => bytes,
+                Err(_) => {
+                    return AppError::BadRequest("Invalid request body".to_string()).into_response()
+                }
+            };
+            let payload = redact_json(&bytes, &state.config.audit.redact_fields)
+                .map(|value| value.to_string());
+            (Request::from_parts(parts, Body::from(bytes)), payload)
+        }
+        _ => (req, None),
+    };
+
+    let response = next.run(req).await;
+
+    let operation = response.extensions().get::<AuditOperation>();
+    state.audit.submit(AuditEntry {
+        id: 0,
+        created_at: OffsetDateTime::now_utc().unix_timestamp(),
+        actor,
+        request_id,
+        method: Some(method),
+        route: Some(route),
+        status: Some(response.status().as_u16() as i64),
+        payload,
+        operation: operation.map(|operation| operation.name.to_string()),
+        affected: operation.map(|operation| operation.affected as i64),
+        action: None,
+        target: None,
+        details: None,
+    });
+
+    response
+}
+
+/// Write an entry to the given sink
+async fn write(pool: &SqlxPool, sink: AuditSink, entry: &AuditEntry) -> Result<(), sqlx::Error> {
+    match sink {
+        AuditSink::Db => record(pool, entry).await,
+        AuditSink::Log => {
+            tracing::info!(
+                target: "audit",
+                created_at = entry.created_at,
+                actor = entry.actor.as_deref(),
+                request_id = entry.request_id.as_deref(),
+                method = entry.method.as_deref(),
+                route = entry.route.as_deref(),
+                status = entry.status,
+                operation = entry.operation.as_deref(),
+                affected = entry.affected,
+                payload = entry.payload.as_deref(),
+                action = entry.action.as_deref(),
+                target = entry.target.as_deref(),
+                details = entry.details.as_deref(),
+                "{}",
+                summary(entry)
+            );
+            Ok(())
+        }
+    }
+}
+
+/// A line summing up an entry, for the logs
+fn summary(entry: &AuditEntry) -> String {
+    match (&entry.method, &entry.route, entry.status) {
+        (Some(method), Some(route), Some(status)) => format!("{} {} {}", method, route, status),
+        _ => format!(
+            "{} {}",
+            entry.action.as_deref().unwrap_or_default(),
+            entry.target.as_deref().unwrap_or_default()
+        ),
+    }
+}
+
+/// Write an entry to the `audit_log` table
+pub async fn record(pool: &SqlxPool, entry: &AuditEntry) -> Result<(), sqlx::Error> {
+    let sql = pool.sql(
+        "INSERT INTO audit_log \
+         (created_at, actor, request_id, method, route, status, payload, operation, affected, \
+         action, target, details) \
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    );
+    with_pool!(pool, |p| sqlx::query(&sql)
+        .bind(entry.created_at)
+        .bind(&entry.actor)
+        .bind(&entry.request_id)
+        .bind(&entry.method)
+        .bind(&entry.route)
+        .bind(entry.status)
+        .bind(&entry.payload)
+        .bind(&entry.operation)
+        .bind(entry.affected)
+        .bind(&entry.action)
+        .bind(&entry.target)
+        .bind(&entry.details)
+        .execute(p)
+        .await
+        .map(|r| r.rows_affected()))?;
+    Ok(())
+}
+
+/// Filters of the audit log listing
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditFilter {
+    /// Only entries of this actor
+    pub actor: Option<String>,
+    /// Only entries of this exact route
+    pub route: Option<String>,
+    /// Only events of this action
+    pub action: Option<String>,
+    /// Only events on this target
+    pub target: Option<String>,
+    /// Only entries created at or after this time
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub from: Option<OffsetDateTime>,
+    /// Only entries created before this time
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub to: Option<OffsetDateTime>,
+}
+
+/// List the entries matching the filter, most recent first
+async fn list(
+    pool: &SqlxPool,
+    filter: &AuditFilter,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<AuditEntry>, sqlx::Error> {
+    let sql = pool.sql(
+        "SELECT id, created_at, actor, request_id, method, route, status, payload, operation, \
+         affected, action, target, details FROM audit_log \
+         WHERE (? IS NULL OR actor = ?) AND (? IS NULL OR route = ?) \
+         AND (? IS NULL OR action = ?) AND (? IS NULL OR target = ?) \
+         AND (? IS NULL OR created_at >= ?) AND (? IS NULL OR created_at < ?) \
+         ORDER BY created_at DESC, id DESC LIMIT ? OFFSET ?",
+    );
+    let from = filter.from.map(|t| t.unix_timestamp());
+    let to = filter.to.map(|t| t.unix_timestamp());
+
+    with_pool!(pool, |p| sqlx::query_as(&sql)
+        .bind(&filter.actor)
+        .bind(&filter.actor)
+        .bind(&filter.route)
+        .bind(&filter.route)
+        .bind(&filter.action)
+        .bind(&filter.action)
+        .bind(&filter.target)
+        .bind(&filter.target)
+        .bind(from)
+        .bind(from)
+        .bind(to)
+        .bind(to)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(p)
+        .await)
+}
+
+/// Forget the entries created before the cutoff (in unix seconds)
+pub async fn purge_before(pool: &SqlxPool, cutoff: i64) -> Result<u64, sqlx::Error> {
+    let sql = pool.sql("DELETE FROM audit_log WHERE created_at < ?");
+    with_pool!(pool, |p| sqlx::query(&sql)
+        .bind(cutoff)
+        .execute(p)
+        .await
+        .map(|r| r.rows_affected()))
+}
+
+/// Forget the entries older than the retention, forever at the given interval
+pub async fn continuously_prune(pool: SqlxPool, retention: Duration, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let cutoff = OffsetDateTime::now_utc() - retention;
+        match purge_before(&pool, cutoff.unix_timestamp()).await {
+            Ok(0) => {}
+            Ok(pruned) => tracing::info!("Pruned {} audit log entries", pruned),
+            Err(err) => tracing::warn!("Failed to prune the audit log: {}", err),
+        }
+    }
+}
+
+/// `GET /audit`: list the audit log
+///
+/// Answers in CSV when the client prefers `text/csv` in its `Accept` header, for an export.
+pub async fn list_entries(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(pagination): Query<Pagination>,
+    Query(filter): Query<AuditFilter>,
+) -> Result<Response, AppError> {
+    let format = if prefers_csv(&headers) {
+        None
+    } else {
+        Some(Format::from_accept(&headers)?)
+    };
+    let entries = list(
+        &state.pool,
+        &filter,
+        pagination.limit(),
+        pagination.offset(),
+    )
+    .await?;
+
+    Ok(match format {
+        Some(format) => Negotiated(format, entries).into_response(),
+        None => (
+            [(header::CONTENT_TYPE, HeaderValue::from_static(CSV))],
+            to_csv(&entries),
+        )
+            .into_response(),
+    })
+}
+
+/// Whether the `Accept` header prefers CSV to the other formats
+fn prefers_csv(headers: &HeaderMap) -> bool {
+    let Some(accept) = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+
+    let (mut csv, mut others) = (0.0f32, 0.0f32);
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let media_type = params.next().unwrap_or_default().trim();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if media_type.eq_ignore_ascii_case(CSV) || media_type.eq_ignore_ascii_case("text/*") {
+            csv = csv.max(quality);
+        } else if media_type == "*/*"
+            || media_type == "application/*"
+            || Format::from_media_type(media_type).is_some()
+        {
+            others = others.max(quality);
+        }
+    }
+    csv > 0.0 && csv >= others
+}
+
+/// Encode entries as CSV (RFC 4180), with a header line
+fn to_csv(entries: &[AuditEntry]) -> String {
+    let mut csv = String::from(
+        "id,created_at,actor,request_id,method,route,status,payload,operation,affected,action,\
+         target,details\r\n",
+    );
+    for entry in entries {
+        let fields = [
+            entry.id.to_string(),
+            entry.created_at.to_string(),
+            csv_field(entry.actor.as_deref()),
+            csv_field(entry.request_id.as_deref()),
+            csv_field(entry.method.as_deref()),
+            csv_field(entry.route.as_deref()),
+            entry.status.map(|s| s.to_string()).unwrap_or_default(),
+            csv_field(entry.payload.as_deref()),
+            csv_field(entry.operation.as_deref()),
+            entry.affected.map(|a| a.to_string()).unwrap_or_default(),
+            csv_field(entry.action.as_deref()),
+            csv_field(entry.target.as_deref()),
+            csv_field(entry.details.as_deref()),
+        ];
+        csv.push_str(&fields.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+/// Quote a text field of a CSV line when needed
+///
+/// Fields starting like a formula are prefixed with `'`, so that spreadsheets don't evaluate them.
+fn csv_field(value: Option<&str>) -> String {
+    let Some(value) = value else {
+        return String::new();
+    };
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
@@ -0,0 +1,93 @@
+//! Check of the passwords against the ones leaked in data breaches
+//! With `PASSWORD_BREACH_CHECK` set (and the `breach-check` feature built in), new passwords are
+//! looked up in the Pwned Passwords range API, with its k-anonymity model: only the first five hex
+//! characters of the SHA-1 of a password are sent, and the suffixes of the leaked passwords sharing
+//! them are matched locally. A password leaked at least `PASSWORD_BREACH_MIN_COUNT` times breaks
+//! the `breached` rule of the password policy.
+//!
+//! When the API can't be reached in `PASSWORD_BREACH_TIMEOUT_MS`, the password is accepted with
+//! `PASSWORD_BREACH_FAIL_OPEN` (the default), and the request is refused as unavailable otherwise.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::async_trait;
+
+/// A source of the number of times passwords were leaked
+#[async_trait]
+pub trait BreachChecker: Send + Sync {
+    /// The number of times the password appeared in data breaches (0 if it never did)
+    async fn breach_count(&self, password: &str) -> Result<u64>;
+}
+
+/// How the passwords are checked against the breaches
+#[derive(Clone)]
+pub struct BreachCheck {
+    pub checker: Arc<dyn BreachChecker>,
+    /// The number of breaches from which a password is refused
+    pub min_count: u64,
+    /// Whether a password is accepted when the breaches can't be checked
+    pub fail_open: bool,
+}
+
+#[cfg(feature = "breach-check")]
+pub use pwned::PwnedPasswords;
+
+#[cfg(feature = "breach-check")]
+mod pwned {
+    use std::time::Duration;
+
+    use anyhow::{bail, Context, Result};
+    use axum::async_trait;
+    use sha1::{Digest, Sha1};
+    use url::Url;
+
+    use super::BreachChecker;
+    use crate::http_client;
+
+    /// The number of hex characters of the hash sent to the API
+    const PREFIX_LENGTH: usize = 5;
+
+    /// The Pwned Passwords range API
+    pub struct PwnedPasswords {
+        /// The URL of the range endpoint, to which the prefix of the hash is appended
+        url: Url,
+        timeout: Duration,
+    }
+
+    impl PwnedPasswords {
+        pub fn new(url: Url, timeout: Duration) -> Self {
+            Self { url, timeout }
+        }
+    }
+
+    #[async_trait]
+    impl BreachChecker for PwnedPasswords {
+        async fn breach_count(&self, password: &str) -> Result<u64> {
+            let hash = hex::encode_upper(Sha1::digest(password.as_bytes()));
+            let (prefix, suffix) = hash.split_at(PREFIX_LENGTH);
+
+            let url =
+                Url::parse(&format!("{}{}", self.url, prefix)).context("Invalid breach API URL")?;
+            let response = http_client::get(&url, self.timeout).await?;
+            if !response.is_success() {
+                bail!("The breach API answered {}", response.status);
+            }
+
+            // One `SUFFIX:COUNT` line per leaked password sharing the prefix
+            let body = String::from_utf8_lossy(&response.body);
+            for line in body.lines() {
+                let Some((candidate, count)) = line.trim().split_once(':') else {
+                    continue;
+                };
+                if candidate.eq_ignore_ascii_case(suffix) {
+                    return count
+                        .trim()
+                        .parse()
+                        .context("Invalid count in the breach API response");
+                }
+            }
+            Ok(0)
+        }
+    }
+}
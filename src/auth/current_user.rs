@@ -0,0 +1,125 @@
+//! The user making the request
+//! [`CurrentUser`] and [`OptionalUser`] load the user whose ID is in the session. The user is
+//! loaded once per request: the result is cached in the extensions of the request, so that a
+//! middleware and the handler share it. A user deleted or disabled since their login is treated
+//! as anonymous. The roles of the user are cached in the session, and refreshed once changed.
+//! Requests authenticated with an API key or a token are made by the user of the key (if any) or
+//! of the token, and never read the session.
+
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use tower_sessions::Session;
+
+use crate::{
+    api_keys::AuthenticatedKey,
+    auth::jwt::AuthenticatedToken,
+    error::AppError,
+    roles::{Role, RoleRepository},
+    session_data::AppSession,
+    state::AppState,
+    users::{User, UserRepository, UserStatus},
+};
+
+/// The logged in user, rejecting anonymous requests with a `401`
+#[derive(Clone, Debug)]
+pub struct CurrentUser(pub User);
+
+/// The logged in user, if any
+#[derive(Clone, Debug)]
+pub struct OptionalUser(pub Option<User>);
+
+/// The user loaded for the request, cached in its extensions
+#[derive(Clone)]
+struct LoadedUser(Option<User>);
+
+#[async_trait]
+impl FromRequestParts<AppState> for OptionalUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        if let Some(LoadedUser(user)) = parts.extensions.get::<LoadedUser>() {
+            return Ok(OptionalUser(user.clone()));
+        }
+
+        let user = load(parts, state).await?;
+        parts.extensions.insert(LoadedUser(user.clone()));
+        Ok(OptionalUser(user))
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for CurrentUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let OptionalUser(user) = OptionalUser::from_request_parts(parts, state).await?;
+        user.map(CurrentUser).ok_or(AppError::Unauthorized)
+    }
+}
+
+impl CurrentUser {
+    /// The effective roles of the user, cached in the session until they change
+    ///
+    /// The roles are only cached in the session of the user, not in another one (e.g. empty, the
+    /// request being authenticated with an API key).
+    pub async fn roles(
+        &self,
+        session: &AppSession,
+        state: &AppState,
+    ) -> Result<Vec<Role>, AppError> {
+        let data = session.data().await?;
+        let repository = RoleRepository::new(state.write_pool().clone());
+        if data.user_id != Some(self.0.id.to_string()) {
+            return Ok(repository.effective_roles_of(self.0.id).await?);
+        }
+        if data.roles_version == Some(self.0.roles_version) {
+            return Ok(data
+                .roles
+                .iter()
+                .filter_map(|role| Role::parse(role))
+                .collect());
+        }
+
+        let roles = repository.effective_roles_of(self.0.id).await?;
+        let version = self.0.roles_version;
+        session
+            .update(|data| {
+                data.roles = roles.iter().map(|role| role.to_string()).collect();
+                data.roles_version = Some(version);
+            })
+            .await?;
+        Ok(roles)
+    }
+}
+
+/// Load the user of the API key or token of the request, or the active user whose ID is in the
+/// session
+async fn load(parts: &Parts, state: &AppState) -> Result<Option<User>, AppError> {
+    // The user of the key or token was checked to be active when authenticating it
+    if let Some(key) = parts.extensions.get::<AuthenticatedKey>() {
+        return Ok(key.user.clone());
+    }
+    if let Some(token) = parts.extensions.get::<AuthenticatedToken>() {
+        return Ok(Some(token.user.clone()));
+    }
+    let Some(session) = parts.extensions.get::<Session>() else {
+        return Ok(None);
+    };
+    let Some(user_id) = AppSession(session.clone()).user_id().await? else {
+        return Ok(None);
+    };
+    let Ok(user_id) = user_id.parse::<i64>() else {
+        return Ok(None);
+    };
+
+    // Read from the primary, so that a user disabled a moment ago is seen as such
+    let user = UserRepository::new(state.write_pool().clone())
+        .find_by_id(user_id)
+        .await?;
+    Ok(user.filter(|user| user.status == UserStatus::Active))
+}
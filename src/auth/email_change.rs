@@ -0,0 +1,317 @@
+//! Change of the email of the logged in user, confirmed from the new email
+//! `POST /users/me/email`, given the current password, keeps the new email pending and mails a
+//! random token to it, valid for `EMAIL_CHANGE_TTL_SECS`. Consuming the token through
+//! `POST /auth/email/confirm-change` swaps the emails at once, and notifies the previous email.
+//! Only the SHA-256 of the tokens is stored. A new change replaces the pending one, and a change
+//! no longer applies once the email of the user changed otherwise.
+//!
+//! A change to the email of another account is accepted and kept pending the same way, so that
+//! asking reveals nothing, but no token is sent: it can't be confirmed.
+
+use std::time::Duration;
+
+use axum::{extract::State, http::StatusCode};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{
+    auth::{current_user::CurrentUser, password, token},
+    config::EmailChangeConfig,
+    database::{with_pool, SqlxPool},
+    error::AppError,
+    mailer::{templates, Email},
+    negotiate::Negotiated,
+    state::AppState,
+    users::{is_valid_email, normalize_email, AuthSource, User, UserRepository},
+};
+
+/// An email change waiting for its confirmation
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct PendingEmailChange {
+    /// The new email
+    #[sqlx(rename = "pending_email")]
+    pub email: String,
+    /// When the change expires, in unix seconds
+    pub expires_at: i64,
+}
+
+/// A confirmed email change
+#[derive(Debug)]
+pub struct ConfirmedEmailChange {
+    pub user_id: i64,
+    pub previous_email: String,
+    pub email: String,
+}
+
+/// The pending email changes, stored in the database
+#[derive(Clone, Debug)]
+pub struct EmailChanges {
+    pool: SqlxPool,
+}
+
+impl EmailChanges {
+    pub fn new(pool: SqlxPool) -> Self {
+        Self { pool }
+    }
+
+    /// Keep a change of the email of a user pending, replacing the previous one, and return the
+    /// token confirming it
+    pub async fn issue(
+        &self,
+        user: &User,
+        email: &str,
+        ttl: Duration,
+    ) -> Result<(String, PendingEmailChange), sqlx::Error> {
+        let token = token::generate();
+
+        let clear = self
+            .pool
+            .sql("DELETE FROM email_change_tokens WHERE user_id = ?");
+        let insert = self.pool.sql(
+            "INSERT INTO email_change_tokens \
+             (token_hash, user_id, pending_email, current_email, created_at, expires_at) \
+             VALUES (?, ?, ?, ?, ?, ?)",
+        );
+        let now = now();
+        let pending = PendingEmailChange {
+            email: email.trim().to_string(),
+            expires_at: now + ttl.as_secs() as i64,
+        };
+
+        with_pool!(&self.pool, |p| {
+            let mut tx = p.begin().await?;
+            sqlx::query(&clear).bind(user.id).execute(&mut *tx).await?;
+            sqlx::query(&insert)
+                .bind(token::hash(&token))
+                .bind(user.id)
+                .bind(&pending.email)
+                .bind(normalize_email(&user.email))
+                .bind(now)
+                .bind(pending.expires_at)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+        });
+        Ok((token, pending))
+    }
+
+    /// The change of the email of a user waiting for its confirmation, if any
+    pub async fn pending(&self, user: &User) -> Result<Option<PendingEmailChange>, sqlx::Error> {
+        let sql = self.pool.sql(
+            "SELECT pending_email, expires_at FROM email_change_tokens \
+             WHERE user_id = ? AND current_email = ? AND used_at IS NULL AND expires_at > ?",
+        );
+        with_pool!(&self.pool, |p| sqlx::query_as(&sql)
+            .bind(user.id)
+            .bind(normalize_email(&user.email))
+            .bind(now())
+            .fetch_optional(p)
+            .await)
+    }
+
+    /// Consume a token and swap the email of its user for the pending one
+    ///
+    /// Returns `None`, changing nothing, when the token is unknown, expired or already used, the
+    /// email of the user changed since it was issued, or the new email was taken since.
+    pub async fn redeem(&self, token: &str) -> Result<Option<ConfirmedEmailChange>, sqlx::Error> {
+        let consume = self.pool.sql(
+            "UPDATE email_change_tokens SET used_at = ? \
+             WHERE token_hash = ? AND used_at IS NULL AND expires_at > ?",
+        );
+        let find = self.pool.sql(
+            "SELECT user_id, pending_email, current_email FROM email_change_tokens \
+             WHERE token_hash = ?",
+        );
+        let previous = self
+            .pool
+            .sql("SELECT email FROM users WHERE id = ? AND email_normalized = ?");
+        let swap = self.pool.sql(
+            "UPDATE users SET email = ?, email_normalized = ?, updated_at = ? \
+             WHERE id = ? AND email_normalized = ?",
+        );
+        let token_hash = token::hash(token);
+        let now = now();
+
+        let change = with_pool!(&self.pool, |p| {
+            let mut tx = p.begin().await?;
+            let consumed = sqlx::query(&consume)
+                .bind(now)
+                .bind(&token_hash)
+                .bind(now)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+            if consumed == 0 {
+                return Ok(None);
+            }
+            let (user_id, email, current_email): (i64, String, String) = sqlx::query_as(&find)
+                .bind(&token_hash)
+                .fetch_one(&mut *tx)
+                .await?;
+            let Some(previous_email) = sqlx::query_scalar::<_, String>(&previous)
+                .bind(user_id)
+                .bind(&current_email)
+                .fetch_optional(&mut *tx)
+                .await?
+            else {
+                return Ok(None);
+            };
+            let swapped = sqlx::query(&swap)
+                .bind(&email)
+                .bind(normalize_email(&email))
+                .bind(now)
+                .bind(user_id)
+                .bind(&current_email)
+                .execute(&mut *tx)
+                .await;
+            match swapped {
+                Ok(_) => {}
+                // Taken by another account since the change was asked
+                Err(sqlx::Error::Database(err)) if err.is_unique_violation() => return Ok(None),
+                Err(err) => return Err(err),
+            }
+            tx.commit().await?;
+            ConfirmedEmailChange {
+                user_id,
+                previous_email,
+                email,
+            }
+        });
+        Ok(Some(change))
+    }
+
+    /// Forget the changes expired before the cutoff (in unix seconds)
+    pub async fn purge_before(&self, cutoff: i64) -> Result<u64, sqlx::Error> {
+        let sql = self
+            .pool
+            .sql("DELETE FROM email_change_tokens WHERE expires_at < ?");
+        let purged = with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(cutoff)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))?;
+        Ok(purged)
+    }
+}
+
+/// A change of email
+#[derive(Deserialize)]
+pub struct ChangeEmailRequest {
+    pub new_email: String,
+    pub password: String,
+}
+
+/// The confirmation of a change of email
+#[derive(Deserialize)]
+pub struct ConfirmEmailChangeRequest {
+    pub token: String,
+}
+
+/// `POST /users/me/email`: ask to change the email of the logged in user, mailing a confirmation
+/// token to the new email
+///
+/// Answers `403` when the password is wrong, and `403` (`password_managed_externally`) for a user
+/// of the LDAP directory, whose email comes from the directory.
+pub async fn request_change(
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    Negotiated(format, request): Negotiated<ChangeEmailRequest>,
+) -> Result<(StatusCode, Negotiated<PendingEmailChange>), AppError> {
+    if user.auth_source != AuthSource::Local {
+        return Err(AppError::ExternalPassword);
+    }
+    let valid = password::verify(
+        &request.password,
+        &user.password_hash,
+        &state.config.password,
+    )
+    .await
+    .is_ok_and(|outcome| outcome.is_valid());
+    if !valid {
+        return Err(AppError::Forbidden);
+    }
+    if !is_valid_email(&request.new_email) {
+        return Err(AppError::BadRequest("the email is invalid".to_string()));
+    }
+    if normalize_email(&request.new_email) == normalize_email(&user.email) {
+        return Err(AppError::BadRequest(
+            "The email is already the one of the account".to_string(),
+        ));
+    }
+
+    let config = &state.config.email_change;
+    let (token, pending) = EmailChanges::new(state.write_pool().clone())
+        .issue(&user, &request.new_email, config.ttl)
+        .await?;
+    let taken = UserRepository::new(state.write_pool().clone())
+        .find_by_email(&pending.email)
+        .await?
+        .is_some();
+    if taken {
+        tracing::info!(
+            "User {} asked for the email of another account, no confirmation sent",
+            user.id
+        );
+    } else {
+        state
+            .mailer
+            .enqueue(change_email(&user, &pending.email, &token, config));
+        tracing::info!("Queued an email change confirmation for user {}", user.id);
+    }
+
+    Ok((StatusCode::ACCEPTED, Negotiated(format, pending)))
+}
+
+/// `POST /auth/email/confirm-change`: swap the email of the user for the one the token confirms,
+/// notifying the previous one
+pub async fn confirm_change(
+    State(state): State<AppState>,
+    Negotiated(_, request): Negotiated<ConfirmEmailChangeRequest>,
+) -> Result<StatusCode, AppError> {
+    let change = EmailChanges::new(state.write_pool().clone())
+        .redeem(&request.token)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("The token is invalid or expired".to_string()))?;
+
+    let user = UserRepository::new(state.write_pool().clone())
+        .find_by_id(change.user_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    state.mailer.enqueue(templates::EMAIL_CHANGED.render(
+        &change.previous_email,
+        &[("name", &user.display_name), ("email", &change.email)],
+    ));
+    tracing::info!("Changed the email of user {}", change.user_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The email carrying a confirmation token, to the new email
+fn change_email(user: &User, email: &str, token: &str, config: &EmailChangeConfig) -> Email {
+    let (action, target) = token::instructions(config.url.as_ref(), token);
+    templates::EMAIL_CHANGE.render(
+        email,
+        &[
+            ("name", &user.display_name),
+            ("action", action),
+            ("target", &target),
+            ("hours", &(config.ttl.as_secs() / 3600).max(1).to_string()),
+        ],
+    )
+}
+
+/// Forget the expired changes, forever at the given interval
+pub async fn continuously_purge(pool: SqlxPool, period: Duration) {
+    let changes = EmailChanges::new(pool);
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        if let Err(err) = changes.purge_before(now()).await {
+            tracing::warn!("Failed to purge expired email changes: {}", err);
+        }
+    }
+}
+
+/// The current time, in unix seconds
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
@@ -0,0 +1,255 @@
+//! Verification of the emails of new users
+//! With `REGISTRATION_EMAIL_VERIFICATION`, new users are pending until they prove they own their
+//! email: a random token, valid for `EMAIL_VERIFICATION_TTL_SECS`, is emailed to them, and
+//! consuming it activates the account. Only the SHA-256 of the tokens is stored, along with the
+//! email they verify: a token no longer applies once the email of the user changes. A new token
+//! replaces the previous ones, and users can ask for one at most every
+//! `EMAIL_VERIFICATION_RESEND_SECS`. Asking for one is answered the same way whether the email
+//! is known or not.
+
+use std::time::Duration;
+
+use axum::{extract::State, http::StatusCode};
+use serde::Deserialize;
+use time::OffsetDateTime;
+
+use crate::{
+    auth::token,
+    config::EmailVerificationConfig,
+    database::{with_pool, SqlxPool},
+    error::AppError,
+    mailer::{templates, Email},
+    negotiate::Negotiated,
+    state::AppState,
+    users::{normalize_email, User, UserRepository, UserStatus},
+};
+
+/// The email verification tokens, stored in the database
+#[derive(Clone, Debug)]
+pub struct VerificationTokens {
+    pool: SqlxPool,
+}
+
+impl VerificationTokens {
+    pub fn new(pool: SqlxPool) -> Self {
+        Self { pool }
+    }
+
+    /// Issue a token verifying the current email of a user, invalidating their previous ones
+    pub async fn issue(&self, user: &User, ttl: Duration) -> Result<String, sqlx::Error> {
+        let token = token::generate();
+
+        let clear = self
+            .pool
+            .sql("DELETE FROM email_verification_tokens WHERE user_id = ?");
+        let insert = self.pool.sql(
+            "INSERT INTO email_verification_tokens \
+             (token_hash, user_id, email, created_at, expires_at) VALUES (?, ?, ?, ?, ?)",
+        );
+        let now = now();
+        let expires_at = now + ttl.as_secs() as i64;
+
+        with_pool!(&self.pool, |p| {
+            let mut tx = p.begin().await?;
+            sqlx::query(&clear).bind(user.id).execute(&mut *tx).await?;
+            sqlx::query(&insert)
+                .bind(token::hash(&token))
+                .bind(user.id)
+                .bind(normalize_email(&user.email))
+                .bind(now)
+                .bind(expires_at)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+        });
+        Ok(token)
+    }
+
+    /// When the last token of a user was issued, in unix seconds
+    pub async fn last_issued_at(&self, user_id: i64) -> Result<Option<i64>, sqlx::Error> {
+        let sql = self
+            .pool
+            .sql("SELECT MAX(created_at) FROM email_verification_tokens WHERE user_id = ?");
+        let issued_at = with_pool!(&self.pool, |p| sqlx::query_scalar(&sql)
+            .bind(user_id)
+            .fetch_one(p)
+            .await)?;
+        Ok(issued_at)
+    }
+
+    /// Consume a token and activate its pending user, returning their ID
+    ///
+    /// Returns `None`, changing nothing, when the token is unknown, expired or already used, or
+    /// the email of the user changed since it was issued.
+    pub async fn redeem(&self, token: &str) -> Result<Option<i64>, sqlx::Error> {
+        let consume = self.pool.sql(
+            "UPDATE email_verification_tokens SET used_at = ? \
+             WHERE token_hash = ? AND used_at IS NULL AND expires_at > ?",
+        );
+        let find = self
+            .pool
+            .sql("SELECT user_id, email FROM email_verification_tokens WHERE token_hash = ?");
+        let activate = self.pool.sql(
+            "UPDATE users SET status = ?, updated_at = ? \
+             WHERE id = ? AND email_normalized = ? AND status = ?",
+        );
+        let token_hash = token::hash(token);
+        let now = now();
+
+        let user_id = with_pool!(&self.pool, |p| {
+            let mut tx = p.begin().await?;
+            let consumed = sqlx::query(&consume)
+                .bind(now)
+                .bind(&token_hash)
+                .bind(now)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+            if consumed == 0 {
+                return Ok(None);
+            }
+            let (user_id, email): (i64, String) = sqlx::query_as(&find)
+                .bind(&token_hash)
+                .fetch_one(&mut *tx)
+                .await?;
+            let activated = sqlx::query(&activate)
+                .bind(UserStatus::Active.as_str())
+                .bind(now)
+                .bind(user_id)
+                .bind(&email)
+                .bind(UserStatus::PendingVerification.as_str())
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+            if activated == 0 {
+                return Ok(None);
+            }
+            tx.commit().await?;
+            user_id
+        });
+        Ok(Some(user_id))
+    }
+
+    /// Forget the tokens expired before the cutoff (in unix seconds)
+    pub async fn purge_before(&self, cutoff: i64) -> Result<u64, sqlx::Error> {
+        let sql = self
+            .pool
+            .sql("DELETE FROM email_verification_tokens WHERE expires_at < ?");
+        let purged = with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(cutoff)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))?;
+        Ok(purged)
+    }
+}
+
+/// An email verification
+#[derive(Deserialize)]
+pub struct VerifyEmailRequest {
+    pub token: String,
+}
+
+/// A request for a new verification email
+#[derive(Deserialize)]
+pub struct ResendVerificationRequest {
+    pub email: String,
+}
+
+/// Issue a token to a pending user and email it to them
+pub async fn send_verification(state: &AppState, user: &User) -> anyhow::Result<()> {
+    let config = &state.config.email_verification;
+    let token = VerificationTokens::new(state.write_pool().clone())
+        .issue(user, config.ttl)
+        .await?;
+    state
+        .mailer
+        .enqueue(verification_email(user, &token, config));
+    tracing::info!("Queued an email verification token for user {}", user.id);
+    Ok(())
+}
+
+/// The email carrying a verification token
+fn verification_email(user: &User, token: &str, config: &EmailVerificationConfig) -> Email {
+    let (action, target) = token::instructions(config.url.as_ref(), token);
+    templates::EMAIL_VERIFICATION.render(
+        &user.email,
+        &[
+            ("name", &user.display_name),
+            ("action", action),
+            ("target", &target),
+            ("hours", &(config.ttl.as_secs() / 3600).max(1).to_string()),
+        ],
+    )
+}
+
+/// `POST /auth/email/verify`: activate the account whose email the token verifies
+pub async fn verify_email(
+    State(state): State<AppState>,
+    Negotiated(_, request): Negotiated<VerifyEmailRequest>,
+) -> Result<StatusCode, AppError> {
+    let user_id = VerificationTokens::new(state.write_pool().clone())
+        .redeem(&request.token)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("The token is invalid or expired".to_string()))?;
+    tracing::info!("Verified the email of user {}", user_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `POST /auth/email/resend`: email a new verification token to the user, if the email is known
+/// and pending verification
+///
+/// Always accepted, the token being issued and sent in the background.
+pub async fn resend_verification(
+    State(state): State<AppState>,
+    Negotiated(_, request): Negotiated<ResendVerificationRequest>,
+) -> StatusCode {
+    tokio::spawn(async move {
+        if let Err(err) = resend(&state, &request.email).await {
+            tracing::error!("Failed to resend an email verification token: {:#}", err);
+        }
+    });
+    StatusCode::ACCEPTED
+}
+
+/// Send a new token to the pending user with this email, unless they got one too recently
+async fn resend(state: &AppState, email: &str) -> anyhow::Result<()> {
+    let user = UserRepository::new(state.write_pool().clone())
+        .find_by_email(email)
+        .await?;
+    let Some(user) = user.filter(|user| user.status == UserStatus::PendingVerification) else {
+        tracing::info!("Email verification asked for an unknown or verified account");
+        return Ok(());
+    };
+
+    let interval = state.config.email_verification.resend_interval.as_secs() as i64;
+    let last_issued_at = VerificationTokens::new(state.write_pool().clone())
+        .last_issued_at(user.id)
+        .await?;
+    if last_issued_at.is_some_and(|issued_at| issued_at + interval > now()) {
+        tracing::info!(
+            "Email verification throttled for user {}, a token was sent recently",
+            user.id
+        );
+        return Ok(());
+    }
+
+    send_verification(state, &user).await
+}
+
+/// Forget the expired tokens, forever at the given interval
+pub async fn continuously_purge(pool: SqlxPool, period: Duration) {
+    let tokens = VerificationTokens::new(pool);
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        if let Err(err) = tokens.purge_before(now()).await {
+            tracing::warn!("Failed to purge expired email verification tokens: {}", err);
+        }
+    }
+}
+
+/// The current time, in unix seconds
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
@@ -0,0 +1,216 @@
+//! Impersonation of users by admins
+//! An admin with the `users.impersonate` permission starts impersonating a user through
+//! `POST /admin/users/:id/impersonate`: the session ID is rotated, and the session stores the ID
+//! of the user (seen by [`CurrentUser`]) along with the ID of the admin (seen by
+//! [`Impersonation`]). While impersonating, every response carries the `X-Impersonated-By`
+//! header, and the session may neither change the password, email or 2FA of the user nor mint
+//! tokens for them. `POST /auth/impersonate/stop` returns to the admin. Admins can't be
+//! impersonated unless `IMPERSONATION_ALLOW_ADMINS` is set. Both the start and the stop are
+//! audited.
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path, Request, State},
+    http::{request::Parts, HeaderName, HeaderValue, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tower_sessions::Session;
+
+use crate::{
+    api_keys,
+    auth::{current_user::CurrentUser, jwt},
+    error::AppError,
+    negotiate::{Format, Negotiated},
+    roles::{Role, RoleRepository},
+    session_data::AppSession,
+    state::AppState,
+    users::{User, UserRepository, UserStatus},
+};
+
+/// The header flagging the responses to an impersonating session, with the ID of the admin
+pub const IMPERSONATED_BY: HeaderName = HeaderName::from_static("x-impersonated-by");
+
+/// The endpoints an impersonating session may not reach, relative to the base path
+const REFUSED: &[(Method, &str)] = &[
+    (Method::PUT, "/api/v1/users/me/password"),
+    (Method::POST, "/api/v1/users/me/email"),
+    (Method::POST, "/api/v1/auth/2fa/setup"),
+    (Method::POST, "/api/v1/auth/2fa/confirm"),
+    (Method::DELETE, "/api/v1/auth/2fa"),
+    (Method::POST, "/api/v1/auth/2fa/recovery/regenerate"),
+    (Method::POST, "/api/v1/auth/token"),
+];
+
+/// The admin impersonating the logged in user, if any
+///
+/// Always `None` for requests authenticated with an API key or a token.
+#[derive(Clone, Debug)]
+pub struct Impersonation(pub Option<User>);
+
+#[async_trait]
+impl FromRequestParts<AppState> for Impersonation {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        if api_keys::key_of(&parts.headers).is_some() || jwt::token_of(&parts.headers).is_some() {
+            return Ok(Impersonation(None));
+        }
+        let Some(session) = parts.extensions.get::<Session>() else {
+            return Ok(Impersonation(None));
+        };
+        let impersonator_id = AppSession(session.clone()).data().await?.impersonator_id;
+        let Some(impersonator_id) = impersonator_id.and_then(|id| id.parse::<i64>().ok()) else {
+            return Ok(Impersonation(None));
+        };
+        let impersonator = UserRepository::new(state.write_pool().clone())
+            .find_by_id(impersonator_id)
+            .await?;
+        Ok(Impersonation(impersonator))
+    }
+}
+
+/// Refuse the endpoints changing the credentials of the user to an impersonating session, and
+/// flag its responses with the `X-Impersonated-By` header
+pub async fn guard(req: Request, next: Next) -> Response {
+    if api_keys::key_of(req.headers()).is_some() || jwt::token_of(req.headers()).is_some() {
+        return next.run(req).await;
+    }
+    let Some(session) = req.extensions().get::<Session>().cloned().map(AppSession) else {
+        return next.run(req).await;
+    };
+
+    let impersonating = match session.data().await {
+        Ok(data) => data.impersonator_id.is_some(),
+        Err(err) => return AppError::from(err).into_response(),
+    };
+    if impersonating
+        && REFUSED
+            .iter()
+            .any(|(method, path)| req.method() == method && req.uri().path() == *path)
+    {
+        return AppError::ImpersonationForbidden.into_response();
+    }
+
+    let mut response = next.run(req).await;
+    // The handler may have started or stopped impersonating
+    let impersonator_id = session
+        .data()
+        .await
+        .ok()
+        .and_then(|data| data.impersonator_id);
+    if let Some(value) = impersonator_id.and_then(|id| HeaderValue::from_str(&id).ok()) {
+        response.headers_mut().insert(IMPERSONATED_BY, value);
+    }
+    response
+}
+
+/// `POST /admin/users/:id/impersonate`: act as an active user, until stopping
+///
+/// Only a session login may impersonate, one user at a time. Answers `403` for an admin, unless
+/// `IMPERSONATION_ALLOW_ADMINS` is set.
+pub async fn start(
+    State(state): State<AppState>,
+    format: Format,
+    session: AppSession,
+    CurrentUser(admin): CurrentUser,
+    Path(user_id): Path<i64>,
+) -> Result<Negotiated<User>, AppError> {
+    let data = session.data().await?;
+    if data.user_id != Some(admin.id.to_string()) {
+        return Err(AppError::BadRequest(
+            "Impersonating requires a session login".to_string(),
+        ));
+    }
+    if data.impersonator_id.is_some() {
+        return Err(AppError::Conflict(
+            "A user is already impersonated".to_string(),
+        ));
+    }
+    if user_id == admin.id {
+        return Err(AppError::Conflict(
+            "Users can't impersonate themselves".to_string(),
+        ));
+    }
+
+    let user = UserRepository::new(state.write_pool().clone())
+        .find_by_id(user_id)
+        .await?
+        .filter(|user| user.status == UserStatus::Active)
+        .ok_or(AppError::NotFound)?;
+    if !state.config.impersonate_admins
+        && RoleRepository::new(state.write_pool().clone())
+            .effective_roles_of(user.id)
+            .await?
+            .contains(&Role::Admin)
+    {
+        return Err(AppError::Forbidden);
+    }
+
+    switch(&session, user.id, Some(admin.id)).await?;
+    state.audit.record(
+        Some(admin.id.to_string()),
+        "user.impersonation_started",
+        &user.id.to_string(),
+        serde_json::json!({}),
+    );
+    tracing::info!("User {} impersonates user {}", admin.id, user.id);
+    Ok(Negotiated(format, user))
+}
+
+/// `POST /auth/impersonate/stop`: return to the admin impersonating the user
+pub async fn stop(
+    State(state): State<AppState>,
+    format: Format,
+    session: AppSession,
+) -> Result<Negotiated<User>, AppError> {
+    let data = session.data().await?;
+    let impersonator_id = data
+        .impersonator_id
+        .and_then(|id| id.parse::<i64>().ok())
+        .ok_or_else(|| AppError::BadRequest("No user is impersonated".to_string()))?;
+
+    // The admin may have been disabled meanwhile: the session is then logged out
+    let admin = UserRepository::new(state.write_pool().clone())
+        .find_by_id(impersonator_id)
+        .await?
+        .filter(|admin| admin.status == UserStatus::Active);
+    let Some(admin) = admin else {
+        session.0.flush().await?;
+        return Err(AppError::Unauthorized);
+    };
+
+    switch(&session, admin.id, None).await?;
+    state.audit.record(
+        Some(admin.id.to_string()),
+        "user.impersonation_stopped",
+        data.user_id.as_deref().unwrap_or_default(),
+        serde_json::json!({}),
+    );
+    tracing::info!("User {} stopped impersonating", admin.id);
+    Ok(Negotiated(format, admin))
+}
+
+/// Log the session in as another user, rotating its ID
+async fn switch(
+    session: &AppSession,
+    user_id: i64,
+    impersonator_id: Option<i64>,
+) -> Result<(), AppError> {
+    session.0.cycle_id().await?;
+    // The roles cached for the previous user don't apply, and switching users evicts none of
+    // their sessions
+    session
+        .update(|data| {
+            data.user_id = Some(user_id.to_string());
+            data.impersonator_id = impersonator_id.map(|id| id.to_string());
+            data.roles.clear();
+            data.roles_version = None;
+            data.session_limit = None;
+        })
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,501 @@
+//! Invitations of new users
+//! Deployments without open registration onboard users by invitation: an admin invites an email,
+//! with the roles the account will get, and a random token valid for `INVITATION_TTL_SECS` is
+//! emailed to it. Accepting the invitation with the token, a display name and a password creates
+//! the account, active. Only the SHA-256 of the tokens is stored. Inviting an email again
+//! supersedes its pending invitations, whose tokens are then refused like revoked ones.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use time::OffsetDateTime;
+
+use crate::{
+    admin::Pagination,
+    audit::AuditActor,
+    auth::{
+        current_user::OptionalUser, password, password_policy::UserContext,
+        registration::Registration, token,
+    },
+    config::InvitationConfig,
+    database::{with_pool, SqlxPool},
+    error::AppError,
+    events::AdminEvent,
+    mailer::{templates, Email},
+    negotiate::{Format, Negotiated},
+    roles::{Role, RoleRepository},
+    state::AppState,
+    users::{
+        is_valid_display_name, is_valid_email, normalize_email, AuthSource, NewUser, User,
+        UserRepository, UserStatus, MAX_DISPLAY_NAME_LENGTH,
+    },
+};
+
+/// The columns of the `invitations` table, in the order of [`InvitationRow`]
+const COLUMNS: &str = "id, email, roles, invited_by, status, created_at, expires_at, accepted_at";
+
+/// Where an invitation stands
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InvitationStatus {
+    /// Waiting to be accepted, unless expired
+    Pending,
+    /// The account was created
+    Accepted,
+    /// Revoked by an admin
+    Revoked,
+    /// Replaced by a newer invitation to the same email
+    Superseded,
+}
+
+impl InvitationStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InvitationStatus::Pending => "pending",
+            InvitationStatus::Accepted => "accepted",
+            InvitationStatus::Revoked => "revoked",
+            InvitationStatus::Superseded => "superseded",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        match status {
+            "pending" => Some(InvitationStatus::Pending),
+            "accepted" => Some(InvitationStatus::Accepted),
+            "revoked" => Some(InvitationStatus::Revoked),
+            "superseded" => Some(InvitationStatus::Superseded),
+            _ => None,
+        }
+    }
+}
+
+/// An invitation, without its token
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Invitation {
+    pub id: i64,
+    /// The normalized email the invitation was sent to
+    pub email: String,
+    /// The roles the account gets
+    pub roles: Vec<Role>,
+    /// The user who sent the invitation, if any (the admin token otherwise)
+    pub invited_by: Option<i64>,
+    pub status: InvitationStatus,
+    /// When the invitation was sent, in unix seconds
+    pub created_at: i64,
+    /// When the invitation expires, in unix seconds
+    pub expires_at: i64,
+    /// When the invitation was accepted, in unix seconds
+    pub accepted_at: Option<i64>,
+}
+
+/// A row of the `invitations` table
+#[derive(sqlx::FromRow)]
+struct InvitationRow {
+    id: i64,
+    email: String,
+    roles: String,
+    invited_by: Option<i64>,
+    status: String,
+    created_at: i64,
+    expires_at: i64,
+    accepted_at: Option<i64>,
+}
+
+impl TryFrom<InvitationRow> for Invitation {
+    type Error = sqlx::Error;
+
+    fn try_from(row: InvitationRow) -> Result<Self, Self::Error> {
+        let status = InvitationStatus::parse(&row.status).ok_or_else(|| {
+            sqlx::Error::Decode(format!("unknown invitation status: {}", row.status).into())
+        })?;
+        Ok(Invitation {
+            id: row.id,
+            email: row.email,
+            // Roles unknown to this version are ignored
+            roles: row
+                .roles
+                .split_whitespace()
+                .filter_map(Role::parse)
+                .collect(),
+            invited_by: row.invited_by,
+            status,
+            created_at: row.created_at,
+            expires_at: row.expires_at,
+            accepted_at: row.accepted_at,
+        })
+    }
+}
+
+/// The invitations, stored in the database
+#[derive(Clone, Debug)]
+pub struct Invitations {
+    pool: SqlxPool,
+}
+
+impl Invitations {
+    pub fn new(pool: SqlxPool) -> Self {
+        Self { pool }
+    }
+
+    /// Invite an email, superseding its pending invitations, returning the invitation along with
+    /// its token, which isn't stored
+    pub async fn issue(
+        &self,
+        email: &str,
+        roles: &[Role],
+        invited_by: Option<i64>,
+        ttl: std::time::Duration,
+    ) -> Result<(Invitation, String), sqlx::Error> {
+        let token = token::generate();
+        let token_hash = token::hash(&token);
+        let email = normalize_email(email);
+        let roles = roles
+            .iter()
+            .map(|role| role.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let supersede = self
+            .pool
+            .sql("UPDATE invitations SET status = ? WHERE email = ? AND status = ?");
+        let insert = self.pool.sql(
+            "INSERT INTO invitations \
+             (token_hash, email, roles, invited_by, status, created_at, expires_at) \
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+        );
+        let select = self
+            .pool
+            .sql(&format!(
+                "SELECT {COLUMNS} FROM invitations WHERE token_hash = ?"
+            ))
+            .into_owned();
+        let now = now();
+        let expires_at = now + ttl.as_secs() as i64;
+
+        let row: InvitationRow = with_pool!(&self.pool, |p| {
+            let mut tx = p.begin().await?;
+            sqlx::query(&supersede)
+                .bind(InvitationStatus::Superseded.as_str())
+                .bind(&email)
+                .bind(InvitationStatus::Pending.as_str())
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query(&insert)
+                .bind(&token_hash)
+                .bind(&email)
+                .bind(&roles)
+                .bind(invited_by)
+                .bind(InvitationStatus::Pending.as_str())
+                .bind(now)
+                .bind(expires_at)
+                .execute(&mut *tx)
+                .await?;
+            let row = sqlx::query_as(&select)
+                .bind(&token_hash)
+                .fetch_one(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            row
+        });
+        Ok((row.try_into()?, token))
+    }
+
+    /// The invitation of a token, whatever its status
+    pub async fn find_by_token(&self, token: &str) -> Result<Option<Invitation>, sqlx::Error> {
+        let sql = self
+            .pool
+            .sql(&format!(
+                "SELECT {COLUMNS} FROM invitations WHERE token_hash = ?"
+            ))
+            .into_owned();
+        let row: Option<InvitationRow> = with_pool!(&self.pool, |p| sqlx::query_as(&sql)
+            .bind(token::hash(token))
+            .fetch_optional(p)
+            .await)?;
+        row.map(Invitation::try_from).transpose()
+    }
+
+    /// The pending invitations not expired yet, newest first
+    pub async fn list_pending(
+        &self,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Invitation>, sqlx::Error> {
+        let sql = self
+            .pool
+            .sql(&format!(
+                "SELECT {COLUMNS} FROM invitations WHERE status = ? AND expires_at > ? \
+                 ORDER BY created_at DESC, id DESC LIMIT ? OFFSET ?"
+            ))
+            .into_owned();
+        let rows: Vec<InvitationRow> = with_pool!(&self.pool, |p| sqlx::query_as(&sql)
+            .bind(InvitationStatus::Pending.as_str())
+            .bind(now())
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(p)
+            .await)?;
+        rows.into_iter().map(Invitation::try_from).collect()
+    }
+
+    /// Move a pending invitation to another status, returning whether it was pending
+    ///
+    /// Accepting only succeeds before the expiry, so that two concurrent acceptances can't both
+    /// create an account.
+    pub async fn settle(&self, id: i64, status: InvitationStatus) -> Result<bool, sqlx::Error> {
+        let sql = self.pool.sql(
+            "UPDATE invitations SET status = ?, accepted_at = ? \
+             WHERE id = ? AND status = ? AND expires_at > ?",
+        );
+        let now = now();
+        let accepted_at = (status == InvitationStatus::Accepted).then_some(now);
+        let settled = with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(status.as_str())
+            .bind(accepted_at)
+            .bind(id)
+            .bind(InvitationStatus::Pending.as_str())
+            .bind(now)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))?;
+        Ok(settled > 0)
+    }
+
+    /// Reopen an invitation whose acceptance failed
+    async fn reopen(&self, id: i64) -> Result<(), sqlx::Error> {
+        let sql = self
+            .pool
+            .sql("UPDATE invitations SET status = ?, accepted_at = NULL WHERE id = ?");
+        with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(InvitationStatus::Pending.as_str())
+            .bind(id)
+            .execute(p)
+            .await
+            .map(|_| ()))?;
+        Ok(())
+    }
+}
+
+/// An invitation to send
+#[derive(Deserialize)]
+pub struct CreateInvitationRequest {
+    pub email: String,
+    #[serde(default)]
+    pub roles: Vec<Role>,
+}
+
+/// The acceptance of an invitation
+#[derive(Deserialize)]
+pub struct AcceptInvitationRequest {
+    pub token: String,
+    pub display_name: String,
+    pub password: String,
+}
+
+/// The email carrying an invitation token
+fn invitation_email(
+    email: &str,
+    inviter: Option<&User>,
+    token: &str,
+    config: &InvitationConfig,
+) -> Email {
+    let (action, target) = token::instructions(config.url.as_ref(), token);
+    let inviter = inviter.map_or("An administrator", |user| &user.display_name);
+    templates::INVITATION.render(
+        email,
+        &[
+            ("inviter", inviter),
+            ("action", action),
+            ("target", &target),
+            (
+                "days",
+                &(config.ttl.as_secs() / (24 * 60 * 60)).max(1).to_string(),
+            ),
+        ],
+    )
+}
+
+/// `POST /admin/invitations`: invite an email, superseding its pending invitations
+pub async fn create_invitation(
+    State(state): State<AppState>,
+    OptionalUser(inviter): OptionalUser,
+    AuditActor(audit_actor): AuditActor,
+    Negotiated(format, request): Negotiated<CreateInvitationRequest>,
+) -> Result<(StatusCode, Negotiated<Invitation>), AppError> {
+    let email = request.email.trim();
+    if !is_valid_email(email) {
+        return Err(AppError::BadRequest("the email is invalid".to_string()));
+    }
+    if UserRepository::new(state.write_pool().clone())
+        .find_by_email(email)
+        .await?
+        .is_some()
+    {
+        return Err(AppError::Conflict(
+            "A user with this email already exists".to_string(),
+        ));
+    }
+
+    let mut roles = request.roles;
+    roles.sort();
+    roles.dedup();
+    let config = &state.config.invitation;
+    let (invitation, token) = Invitations::new(state.write_pool().clone())
+        .issue(
+            email,
+            &roles,
+            inviter.as_ref().map(|user| user.id),
+            config.ttl,
+        )
+        .await?;
+    state
+        .mailer
+        .enqueue(invitation_email(email, inviter.as_ref(), &token, config));
+    tracing::info!("Queued the invitation {}", invitation.id);
+    state.audit.record(
+        audit_actor,
+        "invitation.created",
+        &invitation.id.to_string(),
+        json!({ "email": invitation.email, "roles": invitation.roles }),
+    );
+
+    Ok((StatusCode::CREATED, Negotiated(format, invitation)))
+}
+
+/// `GET /admin/invitations`: list the pending invitations, newest first
+pub async fn list_invitations(
+    State(state): State<AppState>,
+    format: Format,
+    Query(pagination): Query<Pagination>,
+) -> Result<Negotiated<Vec<Invitation>>, AppError> {
+    let invitations = Invitations::new(state.read_pool().clone())
+        .list_pending(pagination.limit(), pagination.offset())
+        .await?;
+    Ok(Negotiated(format, invitations))
+}
+
+/// `DELETE /admin/invitations/:id`: revoke a pending invitation
+pub async fn revoke_invitation(
+    State(state): State<AppState>,
+    AuditActor(audit_actor): AuditActor,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, AppError> {
+    if !Invitations::new(state.write_pool().clone())
+        .settle(id, InvitationStatus::Revoked)
+        .await?
+    {
+        return Err(AppError::NotFound);
+    }
+    tracing::info!("Revoked the invitation {}", id);
+    state.audit.record(
+        audit_actor,
+        "invitation.revoked",
+        &id.to_string(),
+        json!({}),
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `POST /auth/invitations/accept`: create the account of an invitation
+///
+/// Expired invitations are refused with `invitation_expired`, revoked or superseded ones with
+/// `invitation_revoked`, and unknown or already accepted ones as invalid.
+pub async fn accept_invitation(
+    State(state): State<AppState>,
+    Negotiated(format, request): Negotiated<AcceptInvitationRequest>,
+) -> Result<(StatusCode, Negotiated<Registration>), AppError> {
+    let invalid = || AppError::BadRequest("The invitation is invalid".to_string());
+    let repository = Invitations::new(state.write_pool().clone());
+    let invitation = repository
+        .find_by_token(&request.token)
+        .await?
+        .ok_or_else(invalid)?;
+    match invitation.status {
+        InvitationStatus::Pending if invitation.expires_at <= now() => {
+            return Err(AppError::InvitationExpired)
+        }
+        InvitationStatus::Pending => {}
+        InvitationStatus::Revoked | InvitationStatus::Superseded => {
+            return Err(AppError::InvitationRevoked)
+        }
+        InvitationStatus::Accepted => return Err(invalid()),
+    }
+
+    let display_name = request.display_name.trim();
+    if !is_valid_display_name(display_name) {
+        return Err(AppError::BadRequest(format!(
+            "the display name must have between 1 and {} characters",
+            MAX_DISPLAY_NAME_LENGTH
+        )));
+    }
+    state
+        .config
+        .password_policy
+        .enforce(
+            &request.password,
+            &UserContext {
+                email: &invitation.email,
+            },
+        )
+        .await?;
+    let password_hash = password::hash(&request.password, &state.config.password).await?;
+
+    // Claimed first, so that a token creates a single account
+    if !repository
+        .settle(invitation.id, InvitationStatus::Accepted)
+        .await?
+    {
+        return Err(invalid());
+    }
+    let created = UserRepository::new(state.write_pool().clone())
+        .create(NewUser {
+            email: invitation.email.clone(),
+            display_name: display_name.to_string(),
+            password_hash,
+            status: UserStatus::Active,
+            must_change_password: false,
+            auth_source: AuthSource::Local,
+        })
+        .await;
+    let user = match created {
+        Ok(user) => user,
+        Err(err) => {
+            repository.reopen(invitation.id).await?;
+            return Err(err.into());
+        }
+    };
+    if !invitation.roles.is_empty() {
+        RoleRepository::new(state.write_pool().clone())
+            .set_roles(user.id, &invitation.roles)
+            .await?;
+    }
+    tracing::info!("User {} accepted the invitation {}", user.id, invitation.id);
+    state
+        .events
+        .publish(AdminEvent::UserCreated { user_id: user.id });
+    state.audit.record(
+        Some(user.id.to_string()),
+        "invitation.accepted",
+        &invitation.id.to_string(),
+        json!({ "user_id": user.id, "roles": invitation.roles }),
+    );
+
+    Ok((
+        StatusCode::CREATED,
+        Negotiated(
+            format,
+            Registration {
+                email: user.email,
+                status: user.status,
+            },
+        ),
+    ))
+}
+
+/// The current time, in unix seconds
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
@@ -0,0 +1,366 @@
+//! Short-lived JSON Web Tokens for service-to-service calls
+//! With `JWT_SECRET` (HS256) or `JWT_PRIVATE_KEY_FILE` (RS256) set, a logged in user, or a client
+//! with an API key, mints a token with `POST /auth/token`. It is valid for `JWT_TTL_SECS`, and
+//! embeds the ID of the user, their roles and their permissions, limited to the scopes of the key
+//! it was minted with. The services it is handed to send it as `Authorization: Bearer <token>`:
+//! its signature, issuer, audience and expiry (within `JWT_LEEWAY_SECS` of clock skew) are
+//! checked, and the request is made by its user, who must still be active. On the admin
+//! endpoints, a token holds the permissions it embeds that its user still holds.
+//!
+//! The tokens are signed and checked with `jsonwebtoken`. There is no refresh token: once a
+//! token expires, the client mints another with its own credentials. A token can't mint another,
+//! which would extend it past its expiry.
+
+use anyhow::{bail, Result};
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{
+    api_keys::AuthenticatedKey,
+    auth::{current_user::CurrentUser, token},
+    config::{JwtConfig, JwtKey},
+    error::AppError,
+    idempotency::NoStore,
+    negotiate::{Format, Negotiated},
+    permissions::{Permission, PermissionRepository},
+    roles::Role,
+    session_data::AppSession,
+    state::AppState,
+    users::{User, UserRepository, UserStatus},
+};
+
+/// The claims of a token
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub iss: String,
+    /// The ID of the user
+    pub sub: String,
+    pub aud: String,
+    /// When the token was issued, as a UNIX timestamp
+    pub iat: i64,
+    /// When the token expires, as a UNIX timestamp
+    pub exp: i64,
+    /// The unique ID of the token
+    pub jti: String,
+    /// The roles of the user when the token was issued
+    pub roles: Vec<Role>,
+    /// The permissions held by the token
+    pub permissions: Vec<Permission>,
+}
+
+/// A request authenticated with a token, kept in its extensions
+#[derive(Clone, Debug)]
+pub struct AuthenticatedToken {
+    pub claims: Claims,
+    /// The user the token was issued to, checked to be active
+    pub user: User,
+}
+
+impl AuthenticatedToken {
+    /// Who made the request, as recorded in the audit log
+    pub fn actor(&self) -> String {
+        self.user.id.to_string()
+    }
+}
+
+/// A minted token
+#[derive(Serialize, Deserialize)]
+pub struct IssuedToken {
+    pub access_token: String,
+    pub token_type: String,
+    /// The number of seconds the token is valid for
+    pub expires_in: u64,
+}
+
+/// The algorithm of a key
+fn algorithm(key: &JwtKey) -> Algorithm {
+    match key {
+        JwtKey::Hs256(_) => Algorithm::HS256,
+        JwtKey::Rs256(_) => Algorithm::RS256,
+    }
+}
+
+/// Sign and encode claims
+pub fn encode(config: &JwtConfig, claims: &Claims) -> Result<String> {
+    let key = match &config.key {
+        JwtKey::Hs256(secret) => EncodingKey::from_secret(secret),
+        JwtKey::Rs256(key) => EncodingKey::from_rsa_der(&key.rsa()?.private_key_to_der()?),
+    };
+    Ok(jsonwebtoken::encode(
+        &Header::new(algorithm(&config.key)),
+        claims,
+        &key,
+    )?)
+}
+
+/// Check a token, returning its claims when valid now
+pub fn decode(config: &JwtConfig, token: &str) -> Result<Claims> {
+    let key = match &config.key {
+        JwtKey::Hs256(secret) => DecodingKey::from_secret(secret),
+        JwtKey::Rs256(key) => DecodingKey::from_rsa_der(&key.rsa()?.public_key_to_der_pkcs1()?),
+    };
+    // The algorithm is the one of the key, whatever the token claims (e.g. `none`)
+    let mut validation = Validation::new(algorithm(&config.key));
+    validation.set_issuer(&[&config.issuer]);
+    validation.set_audience(&[&config.audience]);
+    validation.set_required_spec_claims(&["iss", "sub", "aud", "exp"]);
+    validation.leeway = config.leeway.as_secs();
+    let claims = jsonwebtoken::decode::<Claims>(token, &key, &validation)?.claims;
+
+    if claims.iat - config.leeway.as_secs() as i64 > now() {
+        bail!("Issued in the future, at {}", claims.iat);
+    }
+    Ok(claims)
+}
+
+/// The token the request carries, if any
+///
+/// Only bearer values shaped as a token are considered: API keys and the `ADMIN_TOKEN` are left
+/// alone.
+pub fn token_of(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .filter(|token| token.starts_with("eyJ") && token.matches('.').count() == 2)
+}
+
+/// Authenticate the requests carrying a token
+///
+/// Invalid or expired tokens, and tokens whose user is no longer active, are answered with a
+/// `401`. The token is kept in the request extensions, where
+/// [`CurrentUser`](crate::auth::current_user) and the admin endpoints find it. Without a key
+/// configured, the tokens are ignored.
+pub async fn authenticate(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let Some(config) = &state.config.jwt else {
+        return next.run(req).await;
+    };
+    let Some(token) = token_of(req.headers()) else {
+        return next.run(req).await;
+    };
+
+    match resolve(&state, config, token).await {
+        Ok(Some(token)) => {
+            req.extensions_mut().insert(token);
+            next.run(req).await
+        }
+        Ok(None) => AppError::Unauthorized.into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+/// The claims of a valid token, along with its active user
+async fn resolve(
+    state: &AppState,
+    config: &JwtConfig,
+    token: &str,
+) -> Result<Option<AuthenticatedToken>, AppError> {
+    let claims = match decode(config, token) {
+        Ok(claims) => claims,
+        Err(err) => {
+            tracing::info!("Refused a token: {:#}", err);
+            return Ok(None);
+        }
+    };
+    let Ok(user_id) = claims.sub.parse::<i64>() else {
+        tracing::info!("Refused the token {} without a user", claims.jti);
+        return Ok(None);
+    };
+    let Some(user) = UserRepository::new(state.write_pool().clone())
+        .find_by_id(user_id)
+        .await?
+        .filter(|user| user.status == UserStatus::Active)
+    else {
+        tracing::info!("Refused the token {} of an inactive user", claims.jti);
+        return Ok(None);
+    };
+    Ok(Some(AuthenticatedToken { claims, user }))
+}
+
+/// `POST /auth/token`: mint a token for the logged in user, or the user of the API key
+pub async fn issue(
+    State(state): State<AppState>,
+    session: AppSession,
+    format: Format,
+    key: Option<Extension<AuthenticatedKey>>,
+    token: Option<Extension<AuthenticatedToken>>,
+    user: CurrentUser,
+) -> Result<(NoStore, Negotiated<IssuedToken>), AppError> {
+    let config = state.config.jwt.as_ref().ok_or(AppError::NotFound)?;
+    // No refresh: the clients mint a new token with their own credentials
+    if token.is_some() {
+        return Err(AppError::Forbidden);
+    }
+
+    let roles = user.roles(&session, &state).await?;
+    let repository = PermissionRepository::new(state.write_pool().clone());
+    let mut permissions = Vec::new();
+    for role in &roles {
+        permissions.extend(repository.of_role(*role).await?);
+    }
+    if let Some(Extension(key)) = &key {
+        permissions.retain(|permission| key.key.scopes.contains(permission));
+    }
+    permissions.sort();
+    permissions.dedup();
+
+    let now = now();
+    let claims = Claims {
+        iss: config.issuer.clone(),
+        sub: user.0.id.to_string(),
+        aud: config.audience.clone(),
+        iat: now,
+        exp: now + config.ttl.as_secs() as i64,
+        jti: token::generate(),
+        roles,
+        permissions,
+    };
+    let access_token = encode(config, &claims)?;
+    tracing::info!("Issued the token {} to user {}", claims.jti, user.0.id);
+
+    Ok((
+        NoStore,
+        Negotiated(
+            format,
+            IssuedToken {
+                access_token,
+                token_type: "Bearer".to_string(),
+                expires_in: config.ttl.as_secs(),
+            },
+        ),
+    ))
+}
+
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use openssl::{pkey::PKey, rsa::Rsa};
+
+    use super::*;
+
+    fn config_with(key: JwtKey) -> JwtConfig {
+        JwtConfig {
+            key,
+            issuer: "administration-center".to_string(),
+            audience: "services".to_string(),
+            ttl: Duration::from_secs(300),
+            leeway: Duration::from_secs(30),
+        }
+    }
+
+    fn hs256() -> JwtConfig {
+        config_with(JwtKey::Hs256(b"0123456789abcdef0123456789abcdef".to_vec()))
+    }
+
+    /// The claims of a token issued now, expiring after `ttl` seconds
+    fn claims(config: &JwtConfig, ttl: i64) -> Claims {
+        let now = now();
+        Claims {
+            iss: config.issuer.clone(),
+            sub: "42".to_string(),
+            aud: config.audience.clone(),
+            iat: now,
+            exp: now + ttl,
+            jti: "jti".to_string(),
+            roles: vec![Role::Operator],
+            permissions: vec![Permission::STATS_READ],
+        }
+    }
+
+    #[test]
+    fn round_trips_with_a_secret() {
+        let config = hs256();
+        let token = encode(&config, &claims(&config, 300)).unwrap();
+        assert!(token_of(
+            &[(
+                header::AUTHORIZATION,
+                format!("Bearer {}", token).parse().unwrap()
+            )]
+            .into_iter()
+            .collect()
+        )
+        .is_some());
+
+        let decoded = decode(&config, &token).unwrap();
+        assert_eq!(decoded.sub, "42");
+        assert_eq!(decoded.roles, [Role::Operator]);
+        assert_eq!(decoded.permissions, [Permission::STATS_READ]);
+    }
+
+    #[test]
+    fn round_trips_with_an_rsa_key() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let config = config_with(JwtKey::Rs256(key));
+        let token = encode(&config, &claims(&config, 300)).unwrap();
+        assert_eq!(decode(&config, &token).unwrap().sub, "42");
+    }
+
+    #[test]
+    fn refuses_an_expired_token() {
+        let config = hs256();
+        // Past the leeway
+        let token = encode(&config, &claims(&config, -60)).unwrap();
+        assert!(decode(&config, &token).is_err());
+        // Within the leeway
+        let token = encode(&config, &claims(&config, -10)).unwrap();
+        assert!(decode(&config, &token).is_ok());
+    }
+
+    #[test]
+    fn refuses_a_token_for_another_audience() {
+        let config = hs256();
+        let claims = Claims {
+            aud: "another-service".to_string(),
+            ..claims(&config, 300)
+        };
+        let token = encode(&config, &claims).unwrap();
+        assert!(decode(&config, &token).is_err());
+    }
+
+    #[test]
+    fn refuses_a_tampered_token() {
+        let config = hs256();
+        let token = encode(&config, &claims(&config, 300)).unwrap();
+
+        // Claims swapped for those of another user, keeping the signature
+        let forged = encode(
+            &config,
+            &Claims {
+                sub: "1".to_string(),
+                ..claims(&config, 300)
+            },
+        )
+        .unwrap();
+        let mut segments: Vec<&str> = token.split('.').collect();
+        segments[1] = forged.split('.').nth(1).unwrap();
+        assert!(decode(&config, &segments.join(".")).is_err());
+
+        // Signed with another secret
+        let other = config_with(JwtKey::Hs256(b"fedcba9876543210fedcba9876543210".to_vec()));
+        let token = encode(&other, &claims(&config, 300)).unwrap();
+        assert!(decode(&config, &token).is_err());
+    }
+
+    #[test]
+    fn refuses_an_unsigned_token() {
+        let config = hs256();
+        let token = encode(&config, &claims(&config, 300)).unwrap();
+        let payload = token.split('.').nth(1).unwrap();
+        // {"alg":"none","typ":"JWT"}
+        let unsigned = format!("eyJhbGciOiJub25lIiwidHlwIjoiSldUIn0.{}.", payload);
+        assert!(decode(&config, &unsigned).is_err());
+    }
+}
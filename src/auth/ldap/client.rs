@@ -0,0 +1,224 @@
+//! The LDAP directory, reached with `ldap3`
+//! The connection is always secured with TLS, from the start or after StartTLS, and opened for a
+//! single login: binding as the user (directly, or once found by a search) and reading their entry.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Context, Result};
+use axum::async_trait;
+use ldap3::{
+    dn_escape, ldap_escape, parse_filter, Ldap, LdapConnAsync, LdapConnSettings, Scope,
+    SearchEntry, SearchOptions, SearchResult,
+};
+use native_tls::{Certificate, TlsConnector};
+
+use super::{Authentication, Directory, DirectoryUser};
+use crate::config::{LdapDirectoryConfig, LdapTls};
+
+/// The result code of a bind with a wrong DN or password
+const INVALID_CREDENTIALS: u32 = 49;
+/// The result code of a search that found more entries than asked for
+const SIZE_LIMIT_EXCEEDED: u32 = 4;
+/// The result code of a search under a base that doesn't exist
+const NO_SUCH_OBJECT: u32 = 32;
+
+/// Check that a user filter is valid, once the username is inserted
+pub fn check_filter(filter: &str) -> Result<()> {
+    parse_filter(filter.replace("{username}", "username"))
+        .map(|_| ())
+        .map_err(|_| anyhow!("invalid LDAP filter"))
+}
+
+/// A directory server
+pub struct LdapDirectory {
+    config: LdapDirectoryConfig,
+}
+
+impl LdapDirectory {
+    pub fn new(config: LdapDirectoryConfig) -> Self {
+        Self { config }
+    }
+
+    /// Connect to the directory, securing the connection as configured
+    async fn connect(&self) -> Result<Ldap> {
+        let config = &self.config;
+        let mut builder = TlsConnector::builder();
+        if let Some(pem) = &config.tls_ca {
+            builder.add_root_certificate(Certificate::from_pem(pem)?);
+        }
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(config.timeout)
+            .set_connector(builder.build()?)
+            .set_starttls(config.tls == LdapTls::StartTls);
+        let host = match config.host.contains(':') {
+            true => format!("[{}]", config.host),
+            false => config.host.clone(),
+        };
+        let scheme = match config.tls {
+            LdapTls::Tls => "ldaps",
+            LdapTls::StartTls => "ldap",
+        };
+        let url = format!("{}://{}:{}", scheme, host, config.port);
+        let (connection, mut ldap) = LdapConnAsync::with_settings(settings, &url)
+            .await
+            .with_context(|| format!("Failed to connect to the directory {}", url))?;
+        ldap3::drive!(connection);
+        ldap.with_timeout(config.timeout);
+        Ok(ldap)
+    }
+
+    /// The attributes read from the entries
+    fn attributes(&self) -> [&str; 3] {
+        [
+            self.config.email_attribute.as_str(),
+            self.config.name_attribute.as_str(),
+            self.config.group_attribute.as_str(),
+        ]
+    }
+
+    /// Find the entry of a user, if exactly one matches the filter
+    async fn find_user(
+        &self,
+        ldap: &mut Ldap,
+        base: &str,
+        username: &str,
+    ) -> Result<Option<SearchEntry>> {
+        let filter = self
+            .config
+            .user_filter
+            .replace("{username}", &ldap_escape(username));
+        let mut entries = search(
+            ldap.with_search_options(SearchOptions::new().sizelimit(2)),
+            base,
+            Scope::Subtree,
+            &filter,
+            &self.attributes(),
+        )
+        .await?;
+        if entries.len() > 1 {
+            tracing::warn!("Several directory entries match the login '{}'", username);
+            return Ok(None);
+        }
+        Ok(entries.pop())
+    }
+
+    async fn authenticate_with(
+        &self,
+        ldap: &mut Ldap,
+        username: &str,
+        password: &str,
+    ) -> Result<Authentication> {
+        let config = &self.config;
+        let entry = match &config.user_dn_template {
+            Some(template) => {
+                let dn = template.replace("{username}", &dn_escape(username));
+                if !bind(ldap, &dn, password).await? {
+                    return Ok(Authentication::Refused { email: None });
+                }
+                // Read as the user, who can at least read their own entry
+                let entry = match &config.search_base {
+                    Some(base) => self.find_user(ldap, base, username).await?,
+                    None => search(
+                        ldap,
+                        &dn,
+                        Scope::Base,
+                        "(objectClass=*)",
+                        &self.attributes(),
+                    )
+                    .await?
+                    .pop(),
+                };
+                match entry {
+                    Some(entry) => entry,
+                    None => bail!("The entry of {} can't be read", dn),
+                }
+            }
+            None => {
+                if let Some((dn, bind_password)) = &config.bind {
+                    if !bind(ldap, dn, bind_password).await? {
+                        bail!("The directory refused the credentials of {}", dn);
+                    }
+                }
+                let base = config.search_base.as_deref().unwrap_or_default();
+                let Some(entry) = self.find_user(ldap, base, username).await? else {
+                    return Ok(Authentication::Refused { email: None });
+                };
+                if !bind(ldap, &entry.dn, password).await? {
+                    let email = first_value(&entry.attrs, &config.email_attribute);
+                    return Ok(Authentication::Refused { email });
+                }
+                entry
+            }
+        };
+
+        Ok(Authentication::Accepted(DirectoryUser {
+            email: first_value(&entry.attrs, &config.email_attribute),
+            display_name: first_value(&entry.attrs, &config.name_attribute),
+            groups: values(&entry.attrs, &config.group_attribute).to_vec(),
+            dn: entry.dn,
+        }))
+    }
+}
+
+#[async_trait]
+impl Directory for LdapDirectory {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<Authentication> {
+        let mut ldap = self.connect().await?;
+        let result = self.authenticate_with(&mut ldap, username, password).await;
+        let _ = ldap.unbind().await;
+        result
+    }
+}
+
+/// Authenticate with a DN and password, returning whether the directory accepted them
+///
+/// The password must not be empty: the directory would take it for an anonymous bind.
+async fn bind(ldap: &mut Ldap, dn: &str, password: &str) -> Result<bool> {
+    let result = ldap.simple_bind(dn, password).await?;
+    match result.rc {
+        0 => Ok(true),
+        INVALID_CREDENTIALS => Ok(false),
+        code => Err(anyhow!(
+            "The bind failed with result {}: {}",
+            code,
+            result.text
+        )),
+    }
+}
+
+/// Search the entries matching a filter
+async fn search(
+    ldap: &mut Ldap,
+    base: &str,
+    scope: Scope,
+    filter: &str,
+    attributes: &[&str],
+) -> Result<Vec<SearchEntry>> {
+    let SearchResult(entries, result) = ldap.search(base, scope, filter, attributes).await?;
+    match result.rc {
+        0 | SIZE_LIMIT_EXCEEDED => Ok(entries.into_iter().map(SearchEntry::construct).collect()),
+        NO_SUCH_OBJECT => Ok(Vec::new()),
+        code => Err(anyhow!(
+            "The search failed with result {}: {}",
+            code,
+            result.text
+        )),
+    }
+}
+
+/// The values of an attribute, whose name is compared regardless of its case
+fn values<'a>(attributes: &'a HashMap<String, Vec<String>>, name: &str) -> &'a [String] {
+    attributes
+        .iter()
+        .find(|(attribute, _)| attribute.eq_ignore_ascii_case(name))
+        .map_or(&[], |(_, values)| values)
+}
+
+/// The first value of an attribute, if not blank
+fn first_value(attributes: &HashMap<String, Vec<String>>, name: &str) -> Option<String> {
+    values(attributes, name)
+        .iter()
+        .map(|value| value.trim())
+        .find(|value| !value.is_empty())
+        .map(str::to_string)
+}
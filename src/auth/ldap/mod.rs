@@ -0,0 +1,278 @@
+//! Login against an LDAP directory, such as Active Directory
+//! With `LDAP_URL` set, the password of the users unknown locally, or created by the directory, is
+//! checked by binding to the directory as them: directly with the DN of `LDAP_USER_DN_TEMPLATE`, or
+//! once found by `LDAP_USER_FILTER` under `LDAP_SEARCH_BASE` (searching as `LDAP_BIND_DN`, or
+//! anonymously). The user is then kept locally as a shadow of their entry, with the `ldap` auth
+//! source: created on their first login, with the display name and roles refreshed on the next
+//! ones. The roles come from the groups of the entry through `LDAP_GROUP_ROLES`, the users in none
+//! of them getting `LDAP_DEFAULT_ROLE`. Without mapped groups, the roles of the existing users are
+//! left to the admins.
+//!
+//! The directory keeps owning the passwords: those of its users can't be reset here. A local user
+//! with the same email as an entry isn't taken over: the entry is refused. The lockout and 2FA
+//! apply as to the local users.
+//!
+//! The directory is reached through [`Directory`], implemented with `ldap3` by [`LdapDirectory`]
+//! with the `ldap` feature.
+
+#[cfg(feature = "ldap")]
+mod client;
+
+use std::collections::HashSet;
+
+use anyhow::Result;
+use axum::async_trait;
+
+use crate::{
+    auth::{
+        lockout::{self, LoginAttempts},
+        login_history::{self, LoginClient, LoginMethod, LoginResult},
+        password,
+    },
+    config::LdapConfig,
+    error::AppError,
+    events::AdminEvent,
+    roles::{Role, RoleRepository},
+    state::AppState,
+    users::{
+        is_valid_display_name, is_valid_email, AuthSource, NewUser, User, UserRepository,
+        UserStatus, MAX_DISPLAY_NAME_LENGTH,
+    },
+};
+
+#[cfg(feature = "ldap")]
+pub use self::client::{check_filter, LdapDirectory};
+
+/// A directory checking the passwords of its users
+#[async_trait]
+pub trait Directory: Send + Sync {
+    /// Check the password of a user, returning their entry when accepted
+    ///
+    /// The username is trimmed, and neither it nor the password is empty.
+    async fn authenticate(&self, username: &str, password: &str) -> Result<Authentication>;
+}
+
+/// A user authenticated by the directory
+#[derive(Debug)]
+pub struct DirectoryUser {
+    /// The DN of their entry
+    pub dn: String,
+    pub email: Option<String>,
+    pub display_name: Option<String>,
+    /// The DNs of their groups
+    pub groups: Vec<String>,
+}
+
+/// The outcome of checking a password against the directory
+#[derive(Debug)]
+pub enum Authentication {
+    Accepted(DirectoryUser),
+    /// The password or the login is wrong, the email of the entry being known when it was found
+    Refused {
+        email: Option<String>,
+    },
+}
+
+/// Log a user in against the directory, returning their shadow user
+///
+/// `known` is the local user with the login as email, if any. A wrong password is counted as a
+/// failed login, locking the shadow user as the local ones, and recorded in its login history.
+pub async fn login(
+    state: &AppState,
+    config: &LdapConfig,
+    username: &str,
+    password: &str,
+    known: Option<User>,
+    client: &LoginClient,
+) -> Result<User, AppError> {
+    let ip = client.ip.as_str();
+    if let Some(user) = &known {
+        if lockout::is_locked(user) {
+            lockout::fail(state, user, ip).await?;
+            login_history::record(state, user, LoginMethod::Ldap, LoginResult::Locked, client)
+                .await;
+            return Err(AppError::Unauthorized);
+        }
+    }
+
+    let entry = match authenticate(config.directory.as_ref(), username, password).await {
+        Ok(Authentication::Accepted(entry)) => entry,
+        Ok(Authentication::Refused { email }) => {
+            // The user whose entry was found is the one attacked, whatever their login
+            let user = match (known, email) {
+                (Some(user), _) => Some(user),
+                (None, Some(email)) => UserRepository::new(state.write_pool().clone())
+                    .find_by_email(&email)
+                    .await?
+                    .filter(|user| user.auth_source == AuthSource::Ldap),
+                (None, None) => None,
+            };
+            match user {
+                Some(user) => {
+                    lockout::fail(state, &user, ip).await?;
+                    login_history::record(
+                        state,
+                        &user,
+                        LoginMethod::Ldap,
+                        LoginResult::Failure,
+                        client,
+                    )
+                    .await;
+                }
+                None => {
+                    LoginAttempts::new(state.write_pool().clone())
+                        .record_failure(username, ip)
+                        .await?;
+                }
+            }
+            return Err(AppError::Unauthorized);
+        }
+        Err(err) => {
+            tracing::error!("Failed to authenticate against the directory: {:#}", err);
+            return Err(AppError::Unavailable);
+        }
+    };
+
+    let user = shadow_user(state, config, username, &entry).await?;
+    if lockout::is_locked(&user) {
+        lockout::fail(state, &user, ip).await?;
+        login_history::record(state, &user, LoginMethod::Ldap, LoginResult::Locked, client).await;
+        return Err(AppError::Unauthorized);
+    }
+    Ok(user)
+}
+
+/// Check the password of a user against the directory, returning their entry when accepted
+pub async fn authenticate(
+    directory: &dyn Directory,
+    username: &str,
+    password: &str,
+) -> Result<Authentication> {
+    // An empty password would be an unauthenticated bind, which the directories accept
+    if username.trim().is_empty() || password.is_empty() {
+        return Ok(Authentication::Refused { email: None });
+    }
+    directory.authenticate(username.trim(), password).await
+}
+
+/// The shadow user of an entry, created on their first login and refreshed on the next ones
+async fn shadow_user(
+    state: &AppState,
+    config: &LdapConfig,
+    username: &str,
+    entry: &DirectoryUser,
+) -> Result<User, AppError> {
+    // Without an email in the entry, the login may be one
+    let Some(email) = entry
+        .email
+        .as_deref()
+        .or(Some(username.trim()))
+        .filter(|email| is_valid_email(email))
+    else {
+        tracing::info!("Directory entry {} has no valid email, refused", entry.dn);
+        return Err(AppError::Forbidden);
+    };
+    let display_name = entry
+        .display_name
+        .as_deref()
+        .map(|name| {
+            name.chars()
+                .take(MAX_DISPLAY_NAME_LENGTH)
+                .collect::<String>()
+        })
+        .filter(|name| is_valid_display_name(name))
+        .unwrap_or_else(|| email.chars().take(MAX_DISPLAY_NAME_LENGTH).collect());
+    let roles = mapped_roles(config, &entry.groups);
+
+    let users = UserRepository::new(state.write_pool().clone());
+    let role_repository = RoleRepository::new(state.write_pool().clone());
+    let user = match users.find_by_email(email).await? {
+        Some(user) if user.auth_source != AuthSource::Ldap => {
+            tracing::warn!(
+                "Directory entry {} has the email of the local user {}, refused",
+                entry.dn,
+                user.id
+            );
+            return Err(AppError::Forbidden);
+        }
+        Some(user) => {
+            let user = if user.display_name != display_name {
+                users
+                    .update(&User {
+                        display_name,
+                        ..user
+                    })
+                    .await?
+            } else {
+                user
+            };
+            if let Some(roles) = roles {
+                let mut current = role_repository.roles_of(user.id).await?;
+                current.sort();
+                if current != roles {
+                    role_repository.set_roles(user.id, &roles).await?;
+                    tracing::info!(
+                        "Updated the roles of user {} from their directory groups",
+                        user.id
+                    );
+                }
+            }
+            user
+        }
+        None => {
+            // The password is random and never used: the directory checks it
+            let password_hash = password::hash(
+                &password::generate(&state.config.password_policy),
+                &state.config.password,
+            )
+            .await?;
+            let user = users
+                .create(NewUser {
+                    email: email.to_string(),
+                    display_name,
+                    password_hash,
+                    status: UserStatus::Active,
+                    must_change_password: false,
+                    auth_source: AuthSource::Ldap,
+                })
+                .await?;
+            role_repository
+                .set_roles(user.id, &roles.unwrap_or_else(|| vec![config.default_role]))
+                .await?;
+            tracing::info!("Created user {} from directory entry {}", user.id, entry.dn);
+            state
+                .events
+                .publish(AdminEvent::UserCreated { user_id: user.id });
+            user
+        }
+    };
+    Ok(user)
+}
+
+/// The roles granted by the groups of a user, sorted, or `None` when no group is mapped
+fn mapped_roles(config: &LdapConfig, groups: &[String]) -> Option<Vec<Role>> {
+    if config.group_roles.is_empty() {
+        return None;
+    }
+    let groups: HashSet<String> = groups.iter().map(|group| normalize_dn(group)).collect();
+    let mut roles: Vec<Role> = config
+        .group_roles
+        .iter()
+        .filter(|(group, _)| groups.contains(&normalize_dn(group)))
+        .map(|(_, role)| *role)
+        .collect();
+    if roles.is_empty() {
+        roles.push(config.default_role);
+    }
+    roles.sort();
+    roles.dedup();
+    Some(roles)
+}
+
+/// The form of a DN to compare: lowercased, without spaces around its RDNs
+fn normalize_dn(dn: &str) -> String {
+    dn.split(',')
+        .map(|rdn| rdn.trim().to_lowercase())
+        .collect::<Vec<_>>()
+        .join(",")
+}
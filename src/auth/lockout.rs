@@ -0,0 +1,274 @@
+//! Protection of the login against brute force
+//! Failed logins are counted per account and source address in the `login_attempts` table. After
+//! `LOGIN_LOCKOUT_THRESHOLD` consecutive failures, the account is locked for
+//! `LOGIN_LOCKOUT_SECS`, each further lock (or attempt while locked) doubling it up to
+//! `LOGIN_LOCKOUT_MAX_SECS`. An address is only blocked once it failed to log in to
+//! `LOGIN_LOCKOUT_IP_ACCOUNTS` different accounts, so that one account being attacked doesn't
+//! block everyone behind the same NAT. Locked logins are answered like wrong passwords, not to
+//! reveal the lock, and a successful login resets the counters.
+
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+};
+use serde_json::json;
+use time::OffsetDateTime;
+
+use crate::{
+    config::LockoutConfig,
+    database::{with_pool, SqlxPool},
+    error::AppError,
+    events::AdminEvent,
+    state::AppState,
+    users::{normalize_email, User, UserRepository},
+};
+
+/// The failed logins, stored in the database
+#[derive(Clone, Debug)]
+pub struct LoginAttempts {
+    pool: SqlxPool,
+}
+
+impl LoginAttempts {
+    pub fn new(pool: SqlxPool) -> Self {
+        Self { pool }
+    }
+
+    /// Whether the address failed to log in to too many accounts recently
+    pub async fn is_blocked(&self, ip: &str, config: &LockoutConfig) -> Result<bool, sqlx::Error> {
+        if ip.is_empty() || config.ip_accounts == 0 {
+            return Ok(false);
+        }
+
+        let sql = self
+            .pool
+            .sql("SELECT COUNT(*) FROM login_attempts WHERE ip = ? AND last_failure_at > ?");
+        let since = now() - config.duration.as_secs() as i64;
+        let accounts: i64 = with_pool!(&self.pool, |p| sqlx::query_scalar(&sql)
+            .bind(ip)
+            .bind(since)
+            .fetch_one(p)
+            .await)?;
+        Ok(accounts >= config.ip_accounts as i64)
+    }
+
+    /// Count a failed login, returning the consecutive failures of the account from any address
+    pub async fn record_failure(&self, email: &str, ip: &str) -> Result<i64, sqlx::Error> {
+        let account = normalize_email(email);
+        let update = self.pool.sql(
+            "UPDATE login_attempts SET failures = failures + 1, last_failure_at = ? \
+             WHERE account = ? AND ip = ?",
+        );
+        let insert = self.pool.sql(
+            "INSERT INTO login_attempts (account, ip, failures, last_failure_at) \
+             VALUES (?, ?, 1, ?)",
+        );
+        let count = self
+            .pool
+            .sql("SELECT failures FROM login_attempts WHERE account = ?");
+        let now = now();
+
+        let updated = with_pool!(&self.pool, |p| sqlx::query(&update)
+            .bind(now)
+            .bind(&account)
+            .bind(ip)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))?;
+        if updated == 0 {
+            let inserted = with_pool!(&self.pool, |p| sqlx::query(&insert)
+                .bind(&account)
+                .bind(ip)
+                .bind(now)
+                .execute(p)
+                .await
+                .map(|r| r.rows_affected()));
+            match inserted {
+                Ok(_) => {}
+                // A concurrent failure inserted the row first
+                Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+                    with_pool!(&self.pool, |p| sqlx::query(&update)
+                        .bind(now)
+                        .bind(&account)
+                        .bind(ip)
+                        .execute(p)
+                        .await
+                        .map(|r| r.rows_affected()))?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        let failures: Vec<i64> = with_pool!(&self.pool, |p| sqlx::query_scalar(&count)
+            .bind(&account)
+            .fetch_all(p)
+            .await)?;
+        Ok(failures.iter().sum())
+    }
+
+    /// Forget the failed logins of an account
+    pub async fn clear(&self, email: &str) -> Result<(), sqlx::Error> {
+        let sql = self
+            .pool
+            .sql("DELETE FROM login_attempts WHERE account = ?");
+        with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(normalize_email(email))
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))?;
+        Ok(())
+    }
+
+    /// Forget the failed logins older than the cutoff (in unix seconds)
+    pub async fn purge_before(&self, cutoff: i64) -> Result<u64, sqlx::Error> {
+        let sql = self
+            .pool
+            .sql("DELETE FROM login_attempts WHERE last_failure_at < ?");
+        let purged = with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(cutoff)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))?;
+        Ok(purged)
+    }
+
+    /// Lock a user until the given time, counting the lock
+    pub async fn lock(&self, user: &User, locked_until: i64) -> Result<(), sqlx::Error> {
+        let sql = self
+            .pool
+            .sql("UPDATE users SET locked_until = ?, lockouts = lockouts + 1 WHERE id = ?");
+        with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(locked_until)
+            .bind(user.id)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))?;
+        Ok(())
+    }
+
+    /// Unlock a user and forget their failed logins
+    pub async fn unlock(&self, user: &User) -> Result<(), sqlx::Error> {
+        let sql = self
+            .pool
+            .sql("UPDATE users SET locked_until = NULL, lockouts = 0 WHERE id = ?");
+        with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(user.id)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))?;
+        self.clear(&user.email).await
+    }
+}
+
+/// Whether the user is locked
+pub fn is_locked(user: &User) -> bool {
+    user.locked_until.is_some_and(|until| until > now())
+}
+
+/// How long the lock following `lockouts` previous ones lasts
+fn lock_duration(lockouts: i64, config: &LockoutConfig) -> Duration {
+    let factor = 1u32
+        .checked_shl(lockouts.clamp(0, 31) as u32)
+        .unwrap_or(u32::MAX);
+    config
+        .duration
+        .checked_mul(factor)
+        .unwrap_or(config.max_duration)
+        .min(config.max_duration)
+}
+
+/// Count a failed login to a known user, locking them once they reach the threshold
+///
+/// An attempt while the user is locked extends the lock.
+pub async fn fail(state: &AppState, user: &User, ip: &str) -> Result<(), AppError> {
+    let config = &state.config.lockout;
+    let attempts = LoginAttempts::new(state.write_pool().clone());
+    if is_locked(user) {
+        let locked_until = now() + lock_duration(user.lockouts, config).as_secs() as i64;
+        attempts.lock(user, locked_until).await?;
+        tracing::info!(
+            "Extended the lock of user {} after a login attempt while locked",
+            user.id
+        );
+        return Ok(());
+    }
+
+    let failures = attempts.record_failure(&user.email, ip).await?;
+    if config.threshold == 0 || failures < config.threshold as i64 {
+        return Ok(());
+    }
+
+    let locked_until = now() + lock_duration(user.lockouts, config).as_secs() as i64;
+    attempts.lock(user, locked_until).await?;
+    // The next lock starts counting from scratch
+    attempts.clear(&user.email).await?;
+    tracing::warn!(
+        "Locked user {} until {} after {} failed logins",
+        user.id,
+        locked_until,
+        failures
+    );
+
+    state.events.publish(AdminEvent::AccountLocked {
+        user_id: user.id,
+        locked_until,
+    });
+    state.audit.record(
+        None,
+        "user.locked",
+        &user.id.to_string(),
+        json!({
+            "locked_until": locked_until,
+            "failures": failures,
+        }),
+    );
+
+    Ok(())
+}
+
+/// Reset the counters of a user after a successful login
+pub async fn succeed(state: &AppState, user: &User) -> Result<(), AppError> {
+    let attempts = LoginAttempts::new(state.write_pool().clone());
+    if user.lockouts > 0 || user.locked_until.is_some() {
+        attempts.unlock(user).await?;
+    } else {
+        attempts.clear(&user.email).await?;
+    }
+    Ok(())
+}
+
+/// Forget the failed logins older than the longest lock, forever at the given interval
+pub async fn continuously_purge(pool: SqlxPool, config: LockoutConfig, period: Duration) {
+    let attempts = LoginAttempts::new(pool);
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let cutoff = now() - config.max_duration.as_secs() as i64;
+        if let Err(err) = attempts.purge_before(cutoff).await {
+            tracing::warn!("Failed to purge old login attempts: {}", err);
+        }
+    }
+}
+
+/// `POST /admin/users/:id/unlock`: unlock a user before their lock expires
+pub async fn unlock_user(
+    State(state): State<AppState>,
+    Path(user_id): Path<i64>,
+) -> Result<StatusCode, AppError> {
+    let user = UserRepository::new(state.write_pool().clone())
+        .find_by_id(user_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    LoginAttempts::new(state.write_pool().clone())
+        .unlock(&user)
+        .await?;
+    tracing::info!("Unlocked user {}", user_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The current time, in unix seconds
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
@@ -0,0 +1,425 @@
+//! History of the logins of the users
+//! Every authentication is recorded in the `login_events` table with its address, user agent,
+//! method and result: logins with a password (or against the LDAP directory), completed second
+//! factors, logins through OpenID Connect, and uses of the API keys of a user (at most once per
+//! minute per key, like their last use). Failures are only recorded for existing users.
+//!
+//! A successful login from a network (`/24`, or `/48` for IPv6) or a user agent never seen in the
+//! successful logins of the user is flagged as from a new device, and with
+//! `LOGIN_NEW_DEVICE_EMAIL` notified to them by email. The first login of a user isn't flagged.
+//! Events older than `LOGIN_HISTORY_RETENTION_DAYS` are pruned.
+
+use std::{net::IpAddr, time::Duration};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, Path, Query, State},
+    http::{header, request::Parts, Extensions, HeaderMap},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{
+    admin::Pagination,
+    auth::current_user::CurrentUser,
+    database::{with_pool, SqlxPool},
+    error::AppError,
+    mailer::templates,
+    negotiate::{Format, Negotiated},
+    state::AppState,
+    users::{AuthSource, User, UserRepository},
+};
+
+/// The maximum number of characters of a user agent kept
+const MAX_USER_AGENT_LENGTH: usize = 512;
+
+/// The columns of the `login_events` table, in the order of [`LoginEventRow`]
+const COLUMNS: &str = "id, user_id, created_at, ip, user_agent, method, result, new_device";
+
+/// How a user authenticated
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoginMethod {
+    /// With the password stored with the user
+    Password,
+    /// With their password, checked by the LDAP directory
+    Ldap,
+    /// Through the OpenID Connect provider
+    Oidc,
+    /// With one of their API keys
+    ApiKey,
+}
+
+impl LoginMethod {
+    /// The method checking the password of a user
+    pub fn password_of(user: &User) -> Self {
+        match user.auth_source {
+            AuthSource::Local => LoginMethod::Password,
+            AuthSource::Ldap => LoginMethod::Ldap,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoginMethod::Password => "password",
+            LoginMethod::Ldap => "ldap",
+            LoginMethod::Oidc => "oidc",
+            LoginMethod::ApiKey => "api_key",
+        }
+    }
+
+    fn parse(method: &str) -> Option<Self> {
+        match method {
+            "password" => Some(LoginMethod::Password),
+            "ldap" => Some(LoginMethod::Ldap),
+            "oidc" => Some(LoginMethod::Oidc),
+            "api_key" => Some(LoginMethod::ApiKey),
+            _ => None,
+        }
+    }
+}
+
+/// How an authentication ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoginResult {
+    Success,
+    /// Wrong credentials, or an account that can't be used
+    Failure,
+    /// Refused, the account being locked
+    Locked,
+}
+
+impl LoginResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoginResult::Success => "success",
+            LoginResult::Failure => "failure",
+            LoginResult::Locked => "locked",
+        }
+    }
+
+    fn parse(result: &str) -> Option<Self> {
+        match result {
+            "success" => Some(LoginResult::Success),
+            "failure" => Some(LoginResult::Failure),
+            "locked" => Some(LoginResult::Locked),
+            _ => None,
+        }
+    }
+}
+
+/// A login of a user
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LoginEvent {
+    pub id: i64,
+    pub user_id: i64,
+    /// When the login happened, in unix seconds
+    pub created_at: i64,
+    /// The address of the client (empty when unknown)
+    pub ip: String,
+    pub user_agent: Option<String>,
+    pub method: LoginMethod,
+    pub result: LoginResult,
+    /// Whether the network or the user agent were never seen in the successful logins of the user
+    pub new_device: bool,
+}
+
+/// A row of the `login_events` table
+#[derive(sqlx::FromRow)]
+struct LoginEventRow {
+    id: i64,
+    user_id: i64,
+    created_at: i64,
+    ip: String,
+    user_agent: Option<String>,
+    method: String,
+    result: String,
+    new_device: bool,
+}
+
+impl TryFrom<LoginEventRow> for LoginEvent {
+    type Error = sqlx::Error;
+
+    fn try_from(row: LoginEventRow) -> Result<Self, Self::Error> {
+        let method = LoginMethod::parse(&row.method).ok_or_else(|| {
+            sqlx::Error::Decode(format!("unknown login method '{}'", row.method).into())
+        })?;
+        let result = LoginResult::parse(&row.result).ok_or_else(|| {
+            sqlx::Error::Decode(format!("unknown login result '{}'", row.result).into())
+        })?;
+        Ok(LoginEvent {
+            id: row.id,
+            user_id: row.user_id,
+            created_at: row.created_at,
+            ip: row.ip,
+            user_agent: row.user_agent,
+            method,
+            result,
+            new_device: row.new_device,
+        })
+    }
+}
+
+/// The client authenticating: its address and user agent
+#[derive(Clone, Debug, Default)]
+pub struct LoginClient {
+    /// The address of the client, empty when unknown
+    pub ip: String,
+    pub user_agent: Option<String>,
+}
+
+impl LoginClient {
+    /// The client of a request
+    pub fn of(extensions: &Extensions, headers: &HeaderMap) -> Self {
+        let ip = extensions
+            .get::<ConnectInfo<std::net::SocketAddr>>()
+            .map_or(String::new(), |ConnectInfo(addr)| addr.ip().to_string());
+        let user_agent = headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|agent| agent.chars().take(MAX_USER_AGENT_LENGTH).collect());
+        Self { ip, user_agent }
+    }
+
+    /// The network of the address, to tell apart the devices of a user: its `/24` for IPv4 and
+    /// its `/48` for IPv6, or the address itself when it can't be parsed
+    fn network(&self) -> String {
+        let Ok(addr) = self.ip.parse::<IpAddr>() else {
+            return self.ip.clone();
+        };
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+            IpAddr::V4(_) => addr,
+        };
+        match addr {
+            IpAddr::V4(v4) => {
+                let [a, b, c, _] = v4.octets();
+                format!("{}.{}.{}.0/24", a, b, c)
+            }
+            IpAddr::V6(v6) => {
+                let [a, b, c, ..] = v6.segments();
+                format!("{:x}:{:x}:{:x}::/48", a, b, c)
+            }
+        }
+    }
+
+    /// The hex SHA-256 of the user agent, as compared
+    fn user_agent_hash(&self) -> String {
+        hex::encode(Sha256::digest(
+            self.user_agent.as_deref().unwrap_or_default().as_bytes(),
+        ))
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for LoginClient {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(LoginClient::of(&parts.extensions, &parts.headers))
+    }
+}
+
+/// The login history, stored in the database
+#[derive(Clone, Debug)]
+pub struct LoginHistory {
+    pool: SqlxPool,
+}
+
+impl LoginHistory {
+    pub fn new(pool: SqlxPool) -> Self {
+        Self { pool }
+    }
+
+    /// Record a login of a user, returning whether it is a success from a new device
+    pub async fn record(
+        &self,
+        user_id: i64,
+        method: LoginMethod,
+        result: LoginResult,
+        client: &LoginClient,
+    ) -> Result<bool, sqlx::Error> {
+        let network = client.network();
+        let user_agent_hash = client.user_agent_hash();
+        let new_device = result == LoginResult::Success
+            && self
+                .is_new_device(user_id, &network, &user_agent_hash)
+                .await?;
+
+        let sql = self.pool.sql(
+            "INSERT INTO login_events (user_id, created_at, ip, ip_network, user_agent, \
+             user_agent_hash, method, result, new_device) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        );
+        with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(user_id)
+            .bind(now())
+            .bind(&client.ip)
+            .bind(&network)
+            .bind(&client.user_agent)
+            .bind(&user_agent_hash)
+            .bind(method.as_str())
+            .bind(result.as_str())
+            .bind(new_device)
+            .execute(p)
+            .await
+            .map(|_| ()))?;
+        Ok(new_device)
+    }
+
+    /// Whether the network or the user agent were never seen in the successful logins of a user
+    /// who logged in before
+    async fn is_new_device(
+        &self,
+        user_id: i64,
+        network: &str,
+        user_agent_hash: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let sql = self.pool.sql(
+            "SELECT COUNT(*), COUNT(CASE WHEN ip_network = ? THEN 1 END), \
+             COUNT(CASE WHEN user_agent_hash = ? THEN 1 END) \
+             FROM login_events WHERE user_id = ? AND result = 'success'",
+        );
+        let (logins, same_network, same_agent): (i64, i64, i64) =
+            with_pool!(&self.pool, |p| sqlx::query_as(&sql)
+                .bind(network)
+                .bind(user_agent_hash)
+                .bind(user_id)
+                .fetch_one(p)
+                .await)?;
+        Ok(logins > 0 && (same_network == 0 || same_agent == 0))
+    }
+
+    /// The logins of a user, most recent first
+    pub async fn list(
+        &self,
+        user_id: i64,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<LoginEvent>, sqlx::Error> {
+        let sql = self
+            .pool
+            .sql(&format!(
+                "SELECT {COLUMNS} FROM login_events WHERE user_id = ? \
+                 ORDER BY created_at DESC, id DESC LIMIT ? OFFSET ?"
+            ))
+            .into_owned();
+        let rows: Vec<LoginEventRow> = with_pool!(&self.pool, |p| sqlx::query_as(&sql)
+            .bind(user_id)
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(p)
+            .await)?;
+        rows.into_iter().map(LoginEvent::try_from).collect()
+    }
+
+    /// Forget the logins before the cutoff (in unix seconds)
+    pub async fn purge_before(&self, cutoff: i64) -> Result<u64, sqlx::Error> {
+        let sql = self
+            .pool
+            .sql("DELETE FROM login_events WHERE created_at < ?");
+        let purged = with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(cutoff)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))?;
+        Ok(purged)
+    }
+}
+
+/// Record a login of a user, notifying them of a new device if configured
+///
+/// A failure is logged without failing the login.
+pub async fn record(
+    state: &AppState,
+    user: &User,
+    method: LoginMethod,
+    result: LoginResult,
+    client: &LoginClient,
+) {
+    let new_device = match LoginHistory::new(state.write_pool().clone())
+        .record(user.id, method, result, client)
+        .await
+    {
+        Ok(new_device) => new_device,
+        Err(err) => {
+            tracing::error!("Failed to record a login of user {}: {}", user.id, err);
+            return;
+        }
+    };
+    if !new_device {
+        return;
+    }
+
+    tracing::info!(
+        "User {} logged in from a new device ({})",
+        user.id,
+        client.ip
+    );
+    if state.config.login_history.notify_new_device {
+        let time = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default();
+        state.mailer.enqueue(templates::NEW_DEVICE_LOGIN.render(
+            &user.email,
+            &[
+                ("name", &user.display_name),
+                ("time", &time),
+                ("ip", &client.ip),
+                (
+                    "user_agent",
+                    client.user_agent.as_deref().unwrap_or("unknown"),
+                ),
+            ],
+        ));
+    }
+}
+
+/// `GET /users/me/logins`: list the logins of the logged in user
+pub async fn my_logins(
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    format: Format,
+    Query(pagination): Query<Pagination>,
+) -> Result<Negotiated<Vec<LoginEvent>>, AppError> {
+    let events = LoginHistory::new(state.read_pool().clone())
+        .list(user.id, pagination.limit(), pagination.offset())
+        .await?;
+    Ok(Negotiated(format, events))
+}
+
+/// `GET /admin/users/:id/logins`: list the logins of a user
+pub async fn user_logins(
+    State(state): State<AppState>,
+    Path(user_id): Path<i64>,
+    format: Format,
+    Query(pagination): Query<Pagination>,
+) -> Result<Negotiated<Vec<LoginEvent>>, AppError> {
+    UserRepository::new(state.read_pool().clone())
+        .find_by_id(user_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let events = LoginHistory::new(state.read_pool().clone())
+        .list(user_id, pagination.limit(), pagination.offset())
+        .await?;
+    Ok(Negotiated(format, events))
+}
+
+/// Forget the logins older than the retention, forever at the given interval
+pub async fn continuously_purge(pool: SqlxPool, retention: Duration, period: Duration) {
+    let history = LoginHistory::new(pool);
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let cutoff = now() - retention.as_secs() as i64;
+        if let Err(err) = history.purge_before(cutoff).await {
+            tracing::warn!("Failed to purge the login history: {}", err);
+        }
+    }
+}
+
+/// The current time, in unix seconds
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
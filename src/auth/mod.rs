@@ -0,0 +1,24 @@
+//! Authentication of users
+
+pub mod breach;
+pub mod current_user;
+pub mod email_change;
+pub mod email_verification;
+pub mod impersonation;
+pub mod invitations;
+pub mod jwt;
+pub mod ldap;
+pub mod lockout;
+pub mod login_history;
+pub mod oidc;
+pub mod password;
+pub mod password_change;
+pub mod password_policy;
+pub mod password_reset;
+pub mod profile;
+pub mod recovery_codes;
+pub mod registration;
+pub mod session;
+pub mod token;
+pub mod totp;
+pub mod two_factor;
@@ -0,0 +1,550 @@
+//! Login through an OpenID Connect provider, such as Keycloak
+//! The provider is discovered at startup, from its discovery document. `GET /auth/oidc/login`
+//! redirects the browser to the provider (authorization code flow, with PKCE), keeping the state,
+//! nonce and code verifier in the session. `GET /auth/oidc/callback` checks the state, exchanges
+//! the code for an ID token and validates it with `jsonwebtoken`: signature (RS256, RS384 or
+//! RS512, with the keys of the provider, fetched again when it rotates them), issuer, audience,
+//! expiry and nonce.
+//!
+//! The subject of the token is then mapped to a local user: the one it was linked to, or else the
+//! user with the same email, when the provider verified it, who is linked on the way. Unknown
+//! users are created with the default role when `OIDC_AUTO_PROVISION` is set, and refused
+//! otherwise. The login is then completed as a password one would be: a locked account is
+//! refused, and a user with 2FA enabled is asked for the second factor.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    jwk::{AlgorithmParameters, JwkSet, PublicKeyUse},
+    Algorithm, DecodingKey, Validation,
+};
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use time::OffsetDateTime;
+use tokio::sync::RwLock;
+use url::Url;
+
+use crate::{
+    auth::{
+        lockout,
+        login_history::{self, LoginClient, LoginMethod, LoginResult},
+        password,
+        session::{self, Completion},
+    },
+    config::OidcConfig,
+    database::{with_pool, SqlxPool},
+    error::AppError,
+    events::AdminEvent,
+    http_client,
+    negotiate::{Format, Negotiated},
+    roles::RoleRepository,
+    session_data::{AppSession, OidcPending},
+    state::AppState,
+    users::{
+        is_valid_display_name, is_valid_email, AuthSource, NewUser, User, UserRepository,
+        UserStatus, MAX_DISPLAY_NAME_LENGTH,
+    },
+};
+
+/// The clock skew tolerated on the expiry of the ID tokens, in seconds
+const LEEWAY: u64 = 60;
+
+/// The minimum time between two fetches of the keys of the provider, when a token is signed with
+/// an unknown one
+const KEYS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The endpoints of the provider, from its discovery document
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: Url,
+    token_endpoint: Url,
+    jwks_uri: Url,
+}
+
+/// The signing keys of the provider
+struct Keys {
+    keys: Vec<(Option<String>, DecodingKey)>,
+    fetched_at: Instant,
+}
+
+impl Keys {
+    /// The key with the given ID, or the only key when the token doesn't name one
+    fn find(&self, kid: Option<&str>) -> Option<DecodingKey> {
+        match kid {
+            Some(kid) => self
+                .keys
+                .iter()
+                .find(|(id, _)| id.as_deref() == Some(kid))
+                .map(|(_, key)| key.clone()),
+            None if self.keys.len() == 1 => Some(self.keys[0].1.clone()),
+            None => None,
+        }
+    }
+}
+
+/// An OpenID Connect provider, discovered at startup
+pub struct OidcProvider {
+    config: OidcConfig,
+    discovery: Discovery,
+    keys: RwLock<Keys>,
+}
+
+impl OidcProvider {
+    /// Fetch the discovery document and the signing keys of the provider
+    pub async fn discover(config: &OidcConfig) -> Result<Self> {
+        let url = Url::parse(&format!(
+            "{}/.well-known/openid-configuration",
+            config.issuer.trim_end_matches('/')
+        ))?;
+        let response = http_client::get(&url, config.timeout).await?;
+        if !response.is_success() {
+            bail!("{} answered with a {}", url, response.status);
+        }
+        let discovery: Discovery = response.json()?;
+        if discovery.issuer != config.issuer {
+            bail!(
+                "The discovery document is for the issuer {}, not {}",
+                discovery.issuer,
+                config.issuer
+            );
+        }
+
+        let keys = fetch_keys(&discovery.jwks_uri, config.timeout).await?;
+        tracing::info!(
+            "Discovered the OpenID Connect provider {} ({} signing key(s))",
+            discovery.issuer,
+            keys.keys.len()
+        );
+        Ok(Self {
+            config: config.clone(),
+            discovery,
+            keys: RwLock::new(keys),
+        })
+    }
+
+    /// The URL of the provider the browser is redirected to
+    fn authorization_url(&self, pending: &OidcPending) -> Url {
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(pending.verifier.as_bytes()));
+        let mut url = self.discovery.authorization_endpoint.clone();
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", self.config.redirect_url.as_str())
+            .append_pair("scope", &self.config.scopes.join(" "))
+            .append_pair("state", &pending.state)
+            .append_pair("nonce", &pending.nonce)
+            .append_pair("code_challenge", &challenge)
+            .append_pair("code_challenge_method", "S256");
+        url
+    }
+
+    /// Exchange the code of a callback for an ID token, `None` when the provider refuses it
+    async fn exchange(&self, code: &str, pending: &OidcPending) -> Result<Option<String>> {
+        let redirect_uri = self.config.redirect_url.as_str();
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("code_verifier", pending.verifier.as_str()),
+        ];
+        // Public clients identify themselves in the form, confidential ones authenticate
+        let basic_auth = match &self.config.client_secret {
+            Some(secret) => Some((self.config.client_id.as_str(), secret.as_str())),
+            None => {
+                form.push(("client_id", self.config.client_id.as_str()));
+                None
+            }
+        };
+
+        let response = http_client::post_form(
+            &self.discovery.token_endpoint,
+            &form,
+            basic_auth,
+            self.config.timeout,
+        )
+        .await?;
+        if (400..500).contains(&response.status) {
+            tracing::warn!(
+                "The provider refused the code: {}",
+                String::from_utf8_lossy(&response.body)
+            );
+            return Ok(None);
+        }
+        if !response.is_success() {
+            bail!("The token endpoint answered with a {}", response.status);
+        }
+
+        #[derive(Deserialize)]
+        struct TokenResponse {
+            id_token: Option<String>,
+        }
+        let tokens: TokenResponse = response.json()?;
+        Ok(Some(tokens.id_token.ok_or_else(|| {
+            anyhow!("The token response has no ID token")
+        })?))
+    }
+
+    /// Validate an ID token, returning its claims
+    async fn validate(&self, id_token: &str, nonce: &str) -> Result<IdClaims> {
+        let jwt_header = jsonwebtoken::decode_header(id_token).context("Malformed ID token")?;
+        if !matches!(
+            jwt_header.alg,
+            Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512
+        ) {
+            bail!("Unsupported signing algorithm {:?}", jwt_header.alg);
+        }
+        let key = self.key(jwt_header.kid.as_deref()).await?;
+
+        let mut validation = Validation::new(jwt_header.alg);
+        validation.set_issuer(&[&self.config.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["iss", "sub", "aud", "exp"]);
+        validation.leeway = LEEWAY;
+        let claims = jsonwebtoken::decode::<IdClaims>(id_token, &key, &validation)
+            .context("Invalid ID token")?
+            .claims;
+
+        if claims
+            .azp
+            .as_ref()
+            .is_some_and(|azp| *azp != self.config.client_id)
+        {
+            bail!("The ID token is authorized for another client");
+        }
+        let nonce_matches = claims
+            .nonce
+            .as_deref()
+            .is_some_and(|claimed| bool::from(claimed.as_bytes().ct_eq(nonce.as_bytes())));
+        if !nonce_matches {
+            bail!("The nonce of the ID token doesn't match");
+        }
+        Ok(claims)
+    }
+
+    /// The signing key with the given ID, fetching the keys again if it is unknown
+    async fn key(&self, kid: Option<&str>) -> Result<DecodingKey> {
+        let keys = self.keys.read().await;
+        if let Some(key) = keys.find(kid) {
+            return Ok(key);
+        }
+        if keys.fetched_at.elapsed() < KEYS_REFRESH_INTERVAL {
+            bail!("Unknown signing key {:?}", kid);
+        }
+        drop(keys);
+
+        let mut keys = self.keys.write().await;
+        if keys.fetched_at.elapsed() >= KEYS_REFRESH_INTERVAL {
+            *keys = fetch_keys(&self.discovery.jwks_uri, self.config.timeout).await?;
+        }
+        keys.find(kid)
+            .ok_or_else(|| anyhow!("Unknown signing key {:?}", kid))
+    }
+}
+
+/// Fetch the RSA signing keys of the provider
+async fn fetch_keys(jwks_uri: &Url, timeout: Duration) -> Result<Keys> {
+    let response = http_client::get(jwks_uri, timeout).await?;
+    if !response.is_success() {
+        bail!("{} answered with a {}", jwks_uri, response.status);
+    }
+    let set: JwkSet = response.json()?;
+    let mut keys = Vec::new();
+    for jwk in &set.keys {
+        let is_rsa = matches!(jwk.algorithm, AlgorithmParameters::RSA(_));
+        let signs = jwk
+            .common
+            .public_key_use
+            .as_ref()
+            .is_none_or(|usage| *usage == PublicKeyUse::Signature);
+        if is_rsa && signs {
+            keys.push((jwk.common.key_id.clone(), DecodingKey::from_jwk(jwk)?));
+        }
+    }
+    Ok(Keys {
+        keys,
+        fetched_at: Instant::now(),
+    })
+}
+
+/// The claims of an ID token used by the backend, the registered ones being checked on decoding
+#[derive(Deserialize)]
+struct IdClaims {
+    sub: String,
+    azp: Option<String>,
+    nonce: Option<String>,
+    email: Option<String>,
+    email_verified: Option<bool>,
+    name: Option<String>,
+    preferred_username: Option<String>,
+}
+
+/// The local users linked to the subjects of the providers
+#[derive(Clone, Debug)]
+pub struct OidcIdentities {
+    pool: SqlxPool,
+}
+
+impl OidcIdentities {
+    pub fn new(pool: SqlxPool) -> Self {
+        Self { pool }
+    }
+
+    /// The user linked to a subject, if any
+    pub async fn find(&self, issuer: &str, subject: &str) -> Result<Option<i64>, sqlx::Error> {
+        let sql = self
+            .pool
+            .sql("SELECT user_id FROM oidc_identities WHERE issuer = ? AND subject = ?");
+        with_pool!(&self.pool, |p| sqlx::query_scalar(&sql)
+            .bind(issuer)
+            .bind(subject)
+            .fetch_optional(p)
+            .await)
+    }
+
+    /// Link a subject to a user
+    pub async fn link(&self, issuer: &str, subject: &str, user_id: i64) -> Result<(), sqlx::Error> {
+        let sql = self.pool.sql(
+            "INSERT INTO oidc_identities (issuer, subject, user_id, created_at) VALUES (?, ?, ?, ?)",
+        );
+        with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(issuer)
+            .bind(subject)
+            .bind(user_id)
+            .bind(now())
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))?;
+        Ok(())
+    }
+}
+
+/// The provider of the state, `404` when OpenID Connect isn't configured
+fn provider(state: &AppState) -> Result<&OidcProvider, AppError> {
+    state.oidc.as_deref().ok_or(AppError::NotFound)
+}
+
+/// `GET /auth/oidc/login`: redirect the browser to the provider
+pub async fn login(
+    State(state): State<AppState>,
+    session: AppSession,
+) -> Result<Response, AppError> {
+    let provider = provider(&state)?;
+    let pending = OidcPending {
+        state: random_token(),
+        nonce: random_token(),
+        verifier: random_token(),
+        expires_at: now() + provider.config.pending_ttl.as_secs() as i64,
+    };
+    let url = provider.authorization_url(&pending);
+    session
+        .update(|data| data.oidc_pending = Some(pending))
+        .await?;
+    Ok((StatusCode::SEE_OTHER, [(header::LOCATION, url.to_string())]).into_response())
+}
+
+/// The parameters the provider calls back with
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+/// `GET /auth/oidc/callback`: complete the login, returning the profile or redirecting to
+/// `OIDC_POST_LOGIN_URL`
+///
+/// A callback with another state than the login, or once the login expired, is answered with a
+/// `400`, and one whose code or ID token is invalid, or for a locked account, with a `401`. When
+/// the user has 2FA enabled, answers `202` with `{"mfa_required": true}` instead.
+pub async fn callback(
+    State(state): State<AppState>,
+    session: AppSession,
+    client: LoginClient,
+    format: Format,
+    Query(query): Query<CallbackQuery>,
+) -> Result<Response, AppError> {
+    let provider = provider(&state)?;
+    // A login can only be completed once, whatever the outcome
+    let Some(pending) = session.update(|data| data.oidc_pending.take()).await? else {
+        return Err(AppError::BadRequest("No login in progress".to_string()));
+    };
+    let state_matches = query
+        .state
+        .as_deref()
+        .is_some_and(|received| bool::from(received.as_bytes().ct_eq(pending.state.as_bytes())));
+    if !state_matches {
+        tracing::warn!("OpenID Connect callback with a wrong state");
+        return Err(AppError::BadRequest("Invalid state".to_string()));
+    }
+    if pending.expires_at <= now() {
+        return Err(AppError::BadRequest("The login expired".to_string()));
+    }
+    if let Some(error) = &query.error {
+        tracing::info!(
+            "The provider refused the login: {} ({})",
+            error,
+            query.error_description.as_deref().unwrap_or_default()
+        );
+        return Err(AppError::Unauthorized);
+    }
+    let code = query
+        .code
+        .as_deref()
+        .ok_or_else(|| AppError::BadRequest("Missing code".to_string()))?;
+
+    let id_token = match provider.exchange(code, &pending).await {
+        Ok(Some(id_token)) => id_token,
+        Ok(None) => return Err(AppError::Unauthorized),
+        Err(err) => {
+            tracing::error!("Failed to exchange the code with the provider: {:#}", err);
+            return Err(AppError::Unavailable);
+        }
+    };
+    let claims = match provider.validate(&id_token, &pending.nonce).await {
+        Ok(claims) => claims,
+        Err(err) => {
+            tracing::warn!("Rejected an ID token: {:#}", err);
+            return Err(AppError::Unauthorized);
+        }
+    };
+
+    let user = local_user(&state, provider, &claims).await?;
+    // The provider vouches for the user, not for their account here: a locked one stays locked
+    // until it expires or an admin unlocks it
+    if lockout::is_locked(&user) {
+        login_history::record(
+            &state,
+            &user,
+            LoginMethod::Oidc,
+            LoginResult::Locked,
+            &client,
+        )
+        .await;
+        return Err(AppError::Unauthorized);
+    }
+    let user = match session::complete(&session, &state, user, LoginMethod::Oidc, &client).await? {
+        Completion::LoggedIn(user) => user,
+        completion @ Completion::MfaRequired => return Ok(completion.respond(format)),
+    };
+    tracing::info!("User {} logged in through OpenID Connect", user.id);
+
+    Ok(match &provider.config.post_login_url {
+        Some(url) => (StatusCode::SEE_OTHER, [(header::LOCATION, url.clone())]).into_response(),
+        None => Negotiated(format, user).into_response(),
+    })
+}
+
+/// The local user of the subject of an ID token, linked or created on their first login
+async fn local_user(
+    state: &AppState,
+    provider: &OidcProvider,
+    claims: &IdClaims,
+) -> Result<User, AppError> {
+    let identities = OidcIdentities::new(state.write_pool().clone());
+    let users = UserRepository::new(state.write_pool().clone());
+    let issuer = &provider.config.issuer;
+    if let Some(user_id) = identities.find(issuer, &claims.sub).await? {
+        if let Some(user) = users.find_by_id(user_id).await? {
+            return Ok(user);
+        }
+    }
+
+    // Only an email the provider verified can be trusted to designate a local user
+    let Some(email) = claims
+        .email
+        .as_deref()
+        .filter(|email| claims.email_verified == Some(true) && is_valid_email(email))
+    else {
+        tracing::info!(
+            "OpenID Connect subject {} has no verified email, refused",
+            claims.sub
+        );
+        return Err(AppError::Forbidden);
+    };
+    let user = match users.find_by_email(email).await? {
+        Some(user) => user,
+        None if provider.config.auto_provision => provision(state, provider, claims, email).await?,
+        None => {
+            tracing::info!(
+                "OpenID Connect subject {} has no local user, refused",
+                claims.sub
+            );
+            return Err(AppError::Forbidden);
+        }
+    };
+    identities.link(issuer, &claims.sub, user.id).await?;
+    tracing::info!(
+        "Linked OpenID Connect subject {} to user {}",
+        claims.sub,
+        user.id
+    );
+    Ok(user)
+}
+
+/// Create the user of a subject, with the default role
+///
+/// Their password is random and never shown: they log in through the provider.
+async fn provision(
+    state: &AppState,
+    provider: &OidcProvider,
+    claims: &IdClaims,
+    email: &str,
+) -> Result<User, AppError> {
+    let display_name = [claims.name.as_deref(), claims.preferred_username.as_deref()]
+        .into_iter()
+        .flatten()
+        .map(|name| name.trim().chars().take(MAX_DISPLAY_NAME_LENGTH).collect())
+        .find(|name: &String| is_valid_display_name(name))
+        .unwrap_or_else(|| email.chars().take(MAX_DISPLAY_NAME_LENGTH).collect());
+    let password_hash = password::hash(
+        &password::generate(&state.config.password_policy),
+        &state.config.password,
+    )
+    .await?;
+
+    let user = UserRepository::new(state.write_pool().clone())
+        .create(NewUser {
+            email: email.to_string(),
+            display_name,
+            password_hash,
+            status: UserStatus::Active,
+            must_change_password: false,
+            auth_source: AuthSource::Local,
+        })
+        .await?;
+    RoleRepository::new(state.write_pool().clone())
+        .set_roles(user.id, &[provider.config.default_role])
+        .await?;
+    tracing::info!(
+        "Created user {} for OpenID Connect subject {}",
+        user.id,
+        claims.sub
+    );
+    state
+        .events
+        .publish(AdminEvent::UserCreated { user_id: user.id });
+    Ok(user)
+}
+
+/// A random token for the state, nonce or code verifier (43 URL-safe characters)
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// The current time, in unix seconds
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
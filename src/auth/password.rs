@@ -0,0 +1,238 @@
+//! Hashing of passwords
+//! Passwords are hashed with Argon2id (the `argon2` crate), into PHC strings
+//! (`$argon2id$v=19$m=<KiB>,t=<passes>,p=<lanes>$<salt>$<hash>`) carrying their own cost. Hashes
+//! made with another cost, or with bcrypt (imported from another system), are reported as needing
+//! a rehash so that they can be upgraded once the password is known. Hashing is CPU and memory
+//! heavy: it runs on the blocking thread pool.
+
+use anyhow::{anyhow, Context, Result};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use rand::{Rng, RngCore};
+
+use super::password_policy::PasswordPolicy;
+use crate::config::PasswordConfig;
+
+/// The length of the generated salts
+const SALT_LEN: usize = 16;
+
+/// The length of the generated hashes
+const HASH_LEN: usize = 32;
+
+/// The prefixes of bcrypt hashes, which may be imported from another system
+const BCRYPT_PREFIXES: [&str; 4] = ["$2a$", "$2b$", "$2x$", "$2y$"];
+
+/// The result of the verification of a password
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifyOutcome {
+    /// The password matches, and the hash uses the current scheme and cost
+    Valid,
+    /// The password matches, but the hash should be replaced by a new one
+    ValidNeedsRehash,
+    /// The password doesn't match
+    Invalid,
+}
+
+impl VerifyOutcome {
+    /// Whether the password matches
+    pub fn is_valid(&self) -> bool {
+        !matches!(self, VerifyOutcome::Invalid)
+    }
+}
+
+/// The Argon2id hasher with the configured cost
+fn hasher(config: &PasswordConfig) -> Result<Argon2<'static>> {
+    let params = Params::new(
+        config.memory_kib,
+        config.iterations,
+        config.parallelism,
+        Some(HASH_LEN),
+    )
+    .map_err(|err| anyhow!("invalid Argon2 parameters: {}", err))?;
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+}
+
+/// The number of characters of a generated password, unless the policy requires more
+const GENERATED_LENGTH: usize = 20;
+
+/// The characters of the generated passwords, avoiding the ones easily confused or mistyped
+const GENERATED_CHARSET: &[u8] =
+    b"ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnpqrstuvwxyz23456789-_.!@#%+=";
+
+/// Generate a random password meeting the classes and length of the policy, e.g. a temporary one
+/// given by an admin
+pub fn generate(policy: &PasswordPolicy) -> String {
+    let mut rng = rand::thread_rng();
+    loop {
+        let password: String = (0..GENERATED_LENGTH.max(policy.min_length))
+            .map(|_| char::from(GENERATED_CHARSET[rng.gen_range(0..GENERATED_CHARSET.len())]))
+            .collect();
+        if policy
+            .required_classes
+            .iter()
+            .all(|class| password.chars().any(|c| class.contains(c)))
+        {
+            return password;
+        }
+    }
+}
+
+/// Hash a password with the configured cost and a random salt
+pub async fn hash(plain: &str, config: &PasswordConfig) -> Result<String> {
+    let plain = plain.to_string();
+    let hasher = hasher(config)?;
+    tokio::task::spawn_blocking(move || {
+        let mut salt = [0u8; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let salt = SaltString::encode_b64(&salt)
+            .map_err(|err| anyhow!("failed to encode the salt: {}", err))?;
+        let hash = hasher
+            .hash_password(plain.as_bytes(), &salt)
+            .map_err(|err| anyhow!("failed to hash the password: {}", err))?;
+        Ok(hash.to_string())
+    })
+    .await
+    .context("The password hashing task failed")?
+}
+
+/// Verify a password against a hash
+///
+/// bcrypt hashes verify too, and always need a rehash. Fails when the hash is malformed or uses a
+/// scheme that can't be verified.
+pub async fn verify(plain: &str, hash: &str, config: &PasswordConfig) -> Result<VerifyOutcome> {
+    let plain = plain.to_string();
+    let hash = hash.to_string();
+    if BCRYPT_PREFIXES
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+    {
+        return tokio::task::spawn_blocking(move || {
+            match bcrypt::verify(plain, &hash).context("malformed bcrypt hash")? {
+                true => Ok(VerifyOutcome::ValidNeedsRehash),
+                false => Ok(VerifyOutcome::Invalid),
+            }
+        })
+        .await
+        .context("The password verification task failed")?;
+    }
+
+    let current = hasher(config)?;
+    tokio::task::spawn_blocking(move || {
+        let parsed =
+            PasswordHash::new(&hash).map_err(|err| anyhow!("malformed password hash: {}", err))?;
+        if parsed.algorithm != Algorithm::Argon2id.ident() {
+            return Err(anyhow!(
+                "unsupported password hash scheme '{}'",
+                parsed.algorithm
+            ));
+        }
+        // Verified with the cost of the hash, not the current one
+        match Argon2::default().verify_password(plain.as_bytes(), &parsed) {
+            Ok(()) => {}
+            Err(argon2::password_hash::Error::Password) => return Ok(VerifyOutcome::Invalid),
+            Err(err) => return Err(anyhow!("malformed password hash: {}", err)),
+        }
+        let params =
+            Params::try_from(&parsed).map_err(|err| anyhow!("malformed password hash: {}", err))?;
+        let outdated = parsed.version != Some(Version::V0x13.into())
+            || params.m_cost() != current.params().m_cost()
+            || params.t_cost() != current.params().t_cost()
+            || params.p_cost() != current.params().p_cost()
+            || parsed.hash.map(|hash| hash.len()) != Some(HASH_LEN);
+        Ok(if outdated {
+            VerifyOutcome::ValidNeedsRehash
+        } else {
+            VerifyOutcome::Valid
+        })
+    })
+    .await
+    .context("The password verification task failed")?
+}
+
+/// Spend the time of a verification without a hash to verify against
+///
+/// To be called when the user doesn't exist, so that the response time doesn't reveal whether
+/// an account exists. Always [`VerifyOutcome::Invalid`].
+pub async fn verify_dummy(plain: &str, config: &PasswordConfig) -> Result<VerifyOutcome> {
+    let plain = plain.to_string();
+    let hasher = hasher(config)?;
+    tokio::task::spawn_blocking(move || {
+        let mut output = [0u8; HASH_LEN];
+        // Only fails on parameters already checked
+        let _ = hasher.hash_password_into(plain.as_bytes(), &[0; SALT_LEN], &mut output);
+        VerifyOutcome::Invalid
+    })
+    .await
+    .context("The password verification task failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A cost low enough for the tests
+    fn config() -> PasswordConfig {
+        PasswordConfig {
+            memory_kib: 64,
+            iterations: 1,
+            parallelism: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn verifies_its_hashes() {
+        let hash = hash("correct horse", &config()).await.unwrap();
+        assert!(hash.starts_with("$argon2id$v=19$m=64,t=1,p=1$"));
+
+        let outcome = verify("correct horse", &hash, &config()).await.unwrap();
+        assert_eq!(outcome, VerifyOutcome::Valid);
+        let outcome = verify("battery staple", &hash, &config()).await.unwrap();
+        assert_eq!(outcome, VerifyOutcome::Invalid);
+    }
+
+    #[tokio::test]
+    async fn salts_every_hash() {
+        let first = hash("correct horse", &config()).await.unwrap();
+        let second = hash("correct horse", &config()).await.unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[tokio::test]
+    async fn asks_for_a_rehash_once_the_cost_changed() {
+        let hash = hash("correct horse", &config()).await.unwrap();
+        let stronger = PasswordConfig {
+            iterations: 2,
+            ..config()
+        };
+
+        let outcome = verify("correct horse", &hash, &stronger).await.unwrap();
+        assert_eq!(outcome, VerifyOutcome::ValidNeedsRehash);
+        let outcome = verify("battery staple", &hash, &stronger).await.unwrap();
+        assert_eq!(outcome, VerifyOutcome::Invalid);
+    }
+
+    #[tokio::test]
+    async fn verifies_legacy_bcrypt_hashes() {
+        let hash = bcrypt::hash("correct horse", 4).unwrap();
+
+        let outcome = verify("correct horse", &hash, &config()).await.unwrap();
+        assert_eq!(outcome, VerifyOutcome::ValidNeedsRehash);
+        let outcome = verify("battery staple", &hash, &config()).await.unwrap();
+        assert_eq!(outcome, VerifyOutcome::Invalid);
+    }
+
+    #[tokio::test]
+    async fn refuses_malformed_and_unknown_hashes() {
+        assert!(verify("correct horse", "plain", &config()).await.is_err());
+        let scrypt = "$scrypt$ln=4,r=8,p=1$c2FsdHNhbHQ$aGFzaGhhc2g";
+        assert!(verify("correct horse", scrypt, &config()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn dummy_verification_never_matches() {
+        let outcome = verify_dummy("correct horse", &config()).await.unwrap();
+        assert_eq!(outcome, VerifyOutcome::Invalid);
+    }
+}
@@ -0,0 +1,87 @@
+//! Password change forced on the next request
+//! A user created with a temporary password, or whom an admin asked to through
+//! `POST /admin/users/:id/require-password-change`, has `must_change_password` set. Until they
+//! change their password through `PUT /users/me/password`, which clears it, their session may only
+//! reach that endpoint and logout: anything else is answered with a `403` with the
+//! `password_change_required` code. Requests authenticated with an API key or a token aren't
+//! gated, their credentials having been issued by someone trusted with them.
+
+use axum::{
+    extract::{FromRequestParts, Path, Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    api_keys,
+    audit::AuditActor,
+    auth::{current_user::OptionalUser, jwt},
+    error::AppError,
+    state::AppState,
+    users::{AuthSource, User, UserRepository},
+};
+
+/// The endpoints a user who must change their password may reach, relative to the base path
+const ALLOWED: &[(Method, &str)] = &[
+    (Method::PUT, "/api/v1/users/me/password"),
+    (Method::POST, "/api/v1/auth/logout"),
+];
+
+/// Keep the users who must change their password to the endpoints doing so
+pub async fn enforce(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    if api_keys::key_of(req.headers()).is_some() || jwt::token_of(req.headers()).is_some() {
+        return next.run(req).await;
+    }
+    if ALLOWED
+        .iter()
+        .any(|(method, path)| req.method() == method && req.uri().path() == *path)
+    {
+        return next.run(req).await;
+    }
+
+    // The user is cached in the request, so that the handler doesn't load it again
+    let (mut parts, body) = req.into_parts();
+    let user = match OptionalUser::from_request_parts(&mut parts, &state).await {
+        Ok(OptionalUser(user)) => user,
+        Err(err) => return err.into_response(),
+    };
+    if user.is_some_and(|user| user.must_change_password) {
+        return AppError::PasswordChangeRequired.into_response();
+    }
+    next.run(Request::from_parts(parts, body)).await
+}
+
+/// `POST /admin/users/:id/require-password-change`: make a user change their password before
+/// anything else
+///
+/// Answers `403` (`password_managed_externally`) for a user of the LDAP directory.
+pub async fn require_password_change(
+    State(state): State<AppState>,
+    AuditActor(actor): AuditActor,
+    Path(user_id): Path<i64>,
+) -> Result<StatusCode, AppError> {
+    let repository = UserRepository::new(state.write_pool().clone());
+    let user = repository
+        .find_by_id(user_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    if user.auth_source != AuthSource::Local {
+        return Err(AppError::ExternalPassword);
+    }
+
+    repository
+        .update(&User {
+            must_change_password: true,
+            ..user
+        })
+        .await?;
+    state.audit.record(
+        actor,
+        "user.password_change_required",
+        &user_id.to_string(),
+        serde_json::json!({}),
+    );
+    tracing::info!("User {} must change their password", user_id);
+    Ok(StatusCode::NO_CONTENT)
+}
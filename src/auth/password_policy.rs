@@ -0,0 +1,351 @@
+//! The policy new passwords must meet
+//! A new password must have between `PASSWORD_MIN_LENGTH` and `PASSWORD_MAX_LENGTH` characters,
+//! not be only whitespace, hold a character of each class of `PASSWORD_REQUIRE_CLASSES`, not
+//! contain the local part of the email of its user (with `PASSWORD_DISALLOW_EMAIL`), and not be
+//! one of the common passwords of `PASSWORD_DENYLIST_FILE`, loaded once at startup, nor one leaked
+//! in data breaches (see [`crate::auth::breach`]). Every broken rule is reported, so that a client
+//! can show them all at once.
+//!
+//! Only new passwords are checked: the hashes of the existing ones stay valid whatever the policy,
+//! which applies the next time they are changed.
+
+use std::{collections::HashSet, io, path::Path, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{auth::breach::BreachCheck, error::AppError};
+
+/// The local parts of emails shorter than this aren't looked for in the passwords, as they would
+/// be found in too many of them
+const MIN_EMAIL_PART_LENGTH: usize = 3;
+
+/// A class of characters a password may be required to hold
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CharacterClass {
+    Lowercase,
+    Uppercase,
+    Digit,
+    Symbol,
+}
+
+impl CharacterClass {
+    /// Parse a class from its name, as in `PASSWORD_REQUIRE_CLASSES`
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "lowercase" => Some(CharacterClass::Lowercase),
+            "uppercase" => Some(CharacterClass::Uppercase),
+            "digit" => Some(CharacterClass::Digit),
+            "symbol" => Some(CharacterClass::Symbol),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CharacterClass::Lowercase => "lowercase",
+            CharacterClass::Uppercase => "uppercase",
+            CharacterClass::Digit => "digit",
+            CharacterClass::Symbol => "symbol",
+        }
+    }
+
+    /// Whether a character is of this class
+    pub fn contains(&self, c: char) -> bool {
+        match self {
+            CharacterClass::Lowercase => c.is_lowercase(),
+            CharacterClass::Uppercase => c.is_uppercase(),
+            CharacterClass::Digit => c.is_numeric(),
+            CharacterClass::Symbol => !c.is_alphanumeric() && !c.is_whitespace(),
+        }
+    }
+
+    /// How the class is named in the violations
+    fn description(&self) -> &'static str {
+        match self {
+            CharacterClass::Lowercase => "a lowercase letter",
+            CharacterClass::Uppercase => "an uppercase letter",
+            CharacterClass::Digit => "a digit",
+            CharacterClass::Symbol => "a symbol",
+        }
+    }
+}
+
+/// The policy new passwords must meet
+#[derive(Clone)]
+pub struct PasswordPolicy {
+    /// The minimum number of characters
+    pub min_length: usize,
+    /// The maximum number of characters, bounding the hashing work
+    pub max_length: usize,
+    /// The classes of characters a password must hold one of each
+    pub required_classes: Vec<CharacterClass>,
+    /// Whether a password may not contain the local part of the email of its user
+    pub disallow_email: bool,
+    /// The common passwords that are refused, in lowercase
+    pub denylist: Arc<HashSet<String>>,
+    /// How the passwords are checked against the data breaches, if they are
+    pub breach: Option<BreachCheck>,
+}
+
+/// What is known of the user whose password is checked
+pub struct UserContext<'a> {
+    pub email: &'a str,
+}
+
+/// A rule broken by a password
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicyViolation {
+    /// The machine-readable name of the rule (e.g. `min_length`)
+    pub rule: String,
+    pub message: String,
+}
+
+impl PolicyViolation {
+    fn new(rule: &str, message: impl Into<String>) -> Self {
+        Self {
+            rule: rule.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Check a new password, returning every rule it breaks
+    pub fn check(&self, candidate: &str, user: &UserContext) -> Result<(), Vec<PolicyViolation>> {
+        let length = candidate.chars().count();
+        let mut violations = Vec::new();
+        if length < self.min_length {
+            violations.push(PolicyViolation::new(
+                "min_length",
+                format!(
+                    "the password must have at least {} characters",
+                    self.min_length
+                ),
+            ));
+        }
+        if length > self.max_length {
+            violations.push(PolicyViolation::new(
+                "max_length",
+                format!(
+                    "the password must have at most {} characters",
+                    self.max_length
+                ),
+            ));
+        }
+        if !candidate.is_empty() && candidate.trim().is_empty() {
+            violations.push(PolicyViolation::new(
+                "whitespace",
+                "the password can't be only whitespace",
+            ));
+        }
+        for class in &self.required_classes {
+            if !candidate.chars().any(|c| class.contains(c)) {
+                violations.push(PolicyViolation::new(
+                    class.as_str(),
+                    format!("the password must contain {}", class.description()),
+                ));
+            }
+        }
+
+        let lowercase = candidate.to_lowercase();
+        if self.disallow_email {
+            let local_part = user
+                .email
+                .trim()
+                .rsplit_once('@')
+                .map_or(user.email.trim(), |(local_part, _)| local_part)
+                .to_lowercase();
+            if local_part.chars().count() >= MIN_EMAIL_PART_LENGTH
+                && lowercase.contains(&local_part)
+            {
+                violations.push(PolicyViolation::new(
+                    "email",
+                    "the password can't contain the email",
+                ));
+            }
+        }
+        if self.denylist.contains(&lowercase) {
+            violations.push(PolicyViolation::new(
+                "denylist",
+                "the password is too common",
+            ));
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    /// Check a new password, and then whether it was leaked in data breaches
+    ///
+    /// Fails with `422` (`password_policy`) listing the broken rules, or with `503` when the
+    /// breaches can't be checked and the check fails closed.
+    pub async fn enforce(&self, candidate: &str, user: &UserContext<'_>) -> Result<(), AppError> {
+        let mut violations = self.check(candidate, user).err().unwrap_or_default();
+        if let Some(breach) = &self.breach {
+            match breach.checker.breach_count(candidate).await {
+                Ok(count) if count >= breach.min_count => {
+                    violations.push(PolicyViolation::new(
+                        "breached",
+                        "the password appeared in a data breach",
+                    ));
+                }
+                Ok(_) => {}
+                Err(err) if breach.fail_open || !violations.is_empty() => {
+                    tracing::warn!("Failed to check a password against the breaches: {}", err);
+                }
+                Err(err) => {
+                    tracing::warn!("Failed to check a password against the breaches: {}", err);
+                    return Err(AppError::Unavailable);
+                }
+            }
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::PasswordPolicy(violations))
+        }
+    }
+}
+
+/// Load a denylist of passwords, one per line
+///
+/// The passwords are compared without case. Blank lines and lines starting with `#` are skipped.
+pub fn load_denylist(path: impl AsRef<Path>) -> io::Result<HashSet<String>> {
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_lowercase)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A policy with every rule, denying `password`
+    fn policy() -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: 8,
+            max_length: 16,
+            required_classes: vec![
+                CharacterClass::Lowercase,
+                CharacterClass::Uppercase,
+                CharacterClass::Digit,
+                CharacterClass::Symbol,
+            ],
+            disallow_email: true,
+            denylist: Arc::new(HashSet::from(["password1!".to_string()])),
+            breach: None,
+        }
+    }
+
+    /// The rules broken by a password of the given user
+    fn broken(policy: &PasswordPolicy, candidate: &str, email: &str) -> Vec<String> {
+        match policy.check(candidate, &UserContext { email }) {
+            Ok(()) => Vec::new(),
+            Err(violations) => violations.into_iter().map(|v| v.rule).collect(),
+        }
+    }
+
+    #[test]
+    fn accepts_a_password_meeting_every_rule() {
+        assert!(broken(&policy(), "Tr0ub4dor&3", "alice@example.com").is_empty());
+    }
+
+    #[test]
+    fn reports_each_rule() {
+        let policy = policy();
+        for (candidate, rule) in [
+            ("Ab1!", "min_length"),
+            ("Ab1!Ab1!Ab1!Ab1!x", "max_length"),
+            ("TR0UB4DOR&3", "lowercase"),
+            ("tr0ub4dor&3", "uppercase"),
+            ("Troubador&x", "digit"),
+            ("Tr0ub4dor33", "symbol"),
+        ] {
+            assert_eq!(
+                broken(&policy, candidate, "alice@example.com"),
+                [rule],
+                "{}",
+                candidate
+            );
+        }
+    }
+
+    #[test]
+    fn reports_every_broken_rule_at_once() {
+        let policy = PasswordPolicy {
+            required_classes: vec![CharacterClass::Digit, CharacterClass::Symbol],
+            ..policy()
+        };
+        assert_eq!(
+            broken(&policy, "        ", "alice@example.com"),
+            ["whitespace", "digit", "symbol"]
+        );
+        assert_eq!(
+            broken(&policy, "alice", "alice@example.com"),
+            ["min_length", "digit", "symbol", "email"]
+        );
+    }
+
+    #[test]
+    fn refuses_the_denied_passwords_regardless_of_their_case() {
+        let policy = policy();
+        assert_eq!(
+            broken(&policy, "PassWord1!", "alice@example.com"),
+            ["denylist"]
+        );
+        assert!(broken(&policy, "PassWord2!", "alice@example.com").is_empty());
+    }
+
+    #[test]
+    fn refuses_the_passwords_containing_the_email() {
+        let policy = policy();
+        assert_eq!(
+            broken(&policy, "xxALICE-1x", "Alice@example.com"),
+            ["email"]
+        );
+        // Too short to be looked for
+        assert!(broken(&policy, "xxAL-1xxx", "al@example.com").is_empty());
+
+        let policy = PasswordPolicy {
+            disallow_email: false,
+            ..policy
+        };
+        assert!(broken(&policy, "xxALICE-1x", "alice@example.com").is_empty());
+    }
+
+    #[tokio::test]
+    async fn enforces_the_policy() {
+        let policy = policy();
+        let user = UserContext {
+            email: "alice@example.com",
+        };
+        assert!(policy.enforce("Tr0ub4dor&3", &user).await.is_ok());
+        match policy.enforce("Ab1!", &user).await {
+            Err(AppError::PasswordPolicy(violations)) => {
+                assert_eq!(violations.len(), 1);
+                assert_eq!(violations[0].rule, "min_length");
+            }
+            _ => panic!("the short password was accepted"),
+        }
+    }
+
+    #[test]
+    fn loads_the_denylist() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("denylist.txt");
+        std::fs::write(&path, "# Common passwords\nPassword\n\n  qwerty  \n").unwrap();
+
+        let denylist = load_denylist(&path).unwrap();
+        assert_eq!(
+            denylist,
+            HashSet::from(["password".to_string(), "qwerty".to_string()])
+        );
+    }
+}
@@ -0,0 +1,297 @@
+//! Reset of forgotten passwords
+//! Asking for a reset emails a random token to the user, valid for `PASSWORD_RESET_TTL_SECS`. The
+//! request is answered the same way whether the email is known or not, and the token is issued
+//! in the background, not to reveal who has an account. Only the SHA-256 of the tokens is stored, a
+//! new token replaces the previous ones of the user, and a token is consumed in the same
+//! transaction as the password is changed, so that it can't be used twice. Resetting the password
+//! revokes the sessions of the user, and lifts their lock. The passwords of the users of the LDAP
+//! directory can't be reset: no token is issued to them. The requests for an email are rate
+//! limited (see [`crate::rate_limit`]).
+
+use std::time::Duration;
+
+use axum::{extract::State, http::StatusCode};
+use serde::Deserialize;
+use time::OffsetDateTime;
+
+use crate::{
+    auth::{
+        lockout::LoginAttempts, login_history::LoginClient, password, password_policy::UserContext,
+        token,
+    },
+    config::PasswordResetConfig,
+    database::{with_pool, SqlxPool},
+    error::AppError,
+    events::AdminEvent,
+    mailer::{templates, Email},
+    negotiate::Negotiated,
+    rate_limit,
+    state::AppState,
+    users::{AuthSource, User, UserRepository, UserStatus},
+};
+
+/// The password reset tokens, stored in the database
+#[derive(Clone, Debug)]
+pub struct ResetTokens {
+    pool: SqlxPool,
+}
+
+impl ResetTokens {
+    pub fn new(pool: SqlxPool) -> Self {
+        Self { pool }
+    }
+
+    /// Issue a token for a user, invalidating their previous ones
+    pub async fn issue(&self, user_id: i64, ttl: Duration) -> Result<String, sqlx::Error> {
+        let token = token::generate();
+
+        let clear = self
+            .pool
+            .sql("DELETE FROM password_reset_tokens WHERE user_id = ?");
+        let insert = self.pool.sql(
+            "INSERT INTO password_reset_tokens (token_hash, user_id, created_at, expires_at) \
+             VALUES (?, ?, ?, ?)",
+        );
+        let now = now();
+        let expires_at = now + ttl.as_secs() as i64;
+
+        with_pool!(&self.pool, |p| {
+            let mut tx = p.begin().await?;
+            sqlx::query(&clear).bind(user_id).execute(&mut *tx).await?;
+            sqlx::query(&insert)
+                .bind(token::hash(&token))
+                .bind(user_id)
+                .bind(now)
+                .bind(expires_at)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+        });
+        Ok(token)
+    }
+
+    /// The user of a token that can still be redeemed
+    pub async fn user_of(&self, token: &str) -> Result<Option<i64>, sqlx::Error> {
+        let sql = self.pool.sql(
+            "SELECT user_id FROM password_reset_tokens \
+             WHERE token_hash = ? AND used_at IS NULL AND expires_at > ?",
+        );
+        with_pool!(&self.pool, |p| sqlx::query_scalar(&sql)
+            .bind(token::hash(token))
+            .bind(now())
+            .fetch_optional(p)
+            .await)
+    }
+
+    /// Consume a token and replace the password hash of its user, returning their ID
+    ///
+    /// Returns `None`, changing nothing, when the token is unknown, expired or already used.
+    pub async fn redeem(
+        &self,
+        token: &str,
+        password_hash: &str,
+    ) -> Result<Option<i64>, sqlx::Error> {
+        let consume = self.pool.sql(
+            "UPDATE password_reset_tokens SET used_at = ? \
+             WHERE token_hash = ? AND used_at IS NULL AND expires_at > ?",
+        );
+        let find = self
+            .pool
+            .sql("SELECT user_id FROM password_reset_tokens WHERE token_hash = ?");
+        let update = self.pool.sql(
+            "UPDATE users SET password_hash = ?, must_change_password = ?, updated_at = ? \
+             WHERE id = ?",
+        );
+        let token_hash = token::hash(token);
+        let now = now();
+
+        let user_id = with_pool!(&self.pool, |p| {
+            let mut tx = p.begin().await?;
+            let consumed = sqlx::query(&consume)
+                .bind(now)
+                .bind(&token_hash)
+                .bind(now)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+            if consumed == 0 {
+                return Ok(None);
+            }
+            let user_id: i64 = sqlx::query_scalar(&find)
+                .bind(&token_hash)
+                .fetch_one(&mut *tx)
+                .await?;
+            sqlx::query(&update)
+                .bind(password_hash)
+                .bind(false)
+                .bind(now)
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            user_id
+        });
+        Ok(Some(user_id))
+    }
+
+    /// Forget the tokens expired before the cutoff (in unix seconds)
+    pub async fn purge_before(&self, cutoff: i64) -> Result<u64, sqlx::Error> {
+        let sql = self
+            .pool
+            .sql("DELETE FROM password_reset_tokens WHERE expires_at < ?");
+        let purged = with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(cutoff)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))?;
+        Ok(purged)
+    }
+}
+
+/// A request for a password reset
+#[derive(Deserialize)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+/// A password reset
+#[derive(Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub new_password: String,
+}
+
+/// `POST /auth/password/forgot`: email a reset token to the user, if the email is known
+///
+/// Accepted unless rate limited, the token being issued and sent in the background.
+pub async fn forgot_password(
+    State(state): State<AppState>,
+    client: LoginClient,
+    Negotiated(_, request): Negotiated<ForgotPasswordRequest>,
+) -> Result<StatusCode, AppError> {
+    rate_limit::enforce(
+        &state,
+        state.config.rate_limit.password_forgot.as_ref(),
+        &rate_limit::password_forgot_key(&request.email),
+        &client.ip,
+    )
+    .await?;
+    tokio::spawn(async move {
+        if let Err(err) = send_reset_token(&state, &request.email).await {
+            tracing::error!("Failed to send a password reset token: {:#}", err);
+        }
+    });
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Issue a token to the active user with this email, and email it to them
+async fn send_reset_token(state: &AppState, email: &str) -> anyhow::Result<()> {
+    let user = UserRepository::new(state.write_pool().clone())
+        .find_by_email(email)
+        .await?;
+    let Some(user) = user.filter(|user| user.status == UserStatus::Active) else {
+        tracing::info!("Password reset asked for an unknown or inactive account");
+        return Ok(());
+    };
+    if user.auth_source != AuthSource::Local {
+        tracing::info!(
+            "Password reset asked for user {}, whose password is managed by the directory",
+            user.id
+        );
+        return Ok(());
+    }
+
+    let config = &state.config.password_reset;
+    let token = ResetTokens::new(state.write_pool().clone())
+        .issue(user.id, config.ttl)
+        .await?;
+    state.mailer.enqueue(reset_email(&user, &token, config));
+    tracing::info!("Queued a password reset token for user {}", user.id);
+    Ok(())
+}
+
+/// The email carrying a reset token
+fn reset_email(user: &User, token: &str, config: &PasswordResetConfig) -> Email {
+    let (action, target) = token::instructions(config.url.as_ref(), token);
+    templates::PASSWORD_RESET.render(
+        &user.email,
+        &[
+            ("name", &user.display_name),
+            ("action", action),
+            ("target", &target),
+            ("minutes", &(config.ttl.as_secs() / 60).max(1).to_string()),
+        ],
+    )
+}
+
+/// `POST /auth/password/reset`: set a new password with a reset token, revoking the sessions of
+/// the user
+///
+/// Answers `403` (`password_managed_externally`) for a user of the LDAP directory.
+pub async fn reset_password(
+    State(state): State<AppState>,
+    Negotiated(_, request): Negotiated<ResetPasswordRequest>,
+) -> Result<StatusCode, AppError> {
+    let tokens = ResetTokens::new(state.write_pool().clone());
+    let users = UserRepository::new(state.write_pool().clone());
+    let user = match tokens.user_of(&request.token).await? {
+        Some(user_id) => users.find_by_id(user_id).await?,
+        None => None,
+    };
+    // The directory keeps checking the password of its users, whatever is set here
+    if user
+        .as_ref()
+        .is_some_and(|user| user.auth_source != AuthSource::Local)
+    {
+        return Err(AppError::ExternalPassword);
+    }
+    // Checked before redeeming, so that a rejected password doesn't consume the token
+    let email = user.as_ref().map_or("", |user| user.email.as_str());
+    state
+        .config
+        .password_policy
+        .enforce(&request.new_password, &UserContext { email })
+        .await?;
+    let password_hash = password::hash(&request.new_password, &state.config.password).await?;
+
+    let user_id = tokens
+        .redeem(&request.token, &password_hash)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("The token is invalid or expired".to_string()))?;
+
+    let revoked = state.sessions.revoke_user(user_id).await?;
+    if revoked > 0 {
+        state
+            .events
+            .publish(AdminEvent::SessionsRevoked { user_id, revoked });
+    }
+    if let Some(user) = users.find_by_id(user_id).await? {
+        LoginAttempts::new(state.write_pool().clone())
+            .unlock(&user)
+            .await?;
+    }
+    tracing::info!(
+        "Reset the password of user {}, revoking {} session(s)",
+        user_id,
+        revoked
+    );
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Forget the expired tokens, forever at the given interval
+pub async fn continuously_purge(pool: SqlxPool, period: Duration) {
+    let tokens = ResetTokens::new(pool);
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        if let Err(err) = tokens.purge_before(now()).await {
+            tracing::warn!("Failed to purge expired password reset tokens: {}", err);
+        }
+    }
+}
+
+/// The current time, in unix seconds
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
@@ -0,0 +1,288 @@
+//! The profile of the logged in user
+//! Users read their profile through `GET /users/me`, with their roles, the state of their 2FA and
+//! their number of active sessions, and change their display name and preferences through
+//! `PATCH /users/me`. The preferences are a free-form JSON object kept for the clients (e.g. the
+//! theme of the interface). The email isn't changed here, but through its own confirmed flow.
+//!
+//! `PUT /users/me/password` changes the password given the current one, revoking every other
+//! session of the user: whoever may have learnt the old password is logged out, while the user
+//! stays logged in, under a new session ID. It clears `must_change_password` (see
+//! [`password_change`](crate::auth::password_change)). The change is recorded in the audit log.
+
+use axum::{extract::State, http::StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use time::OffsetDateTime;
+
+use crate::{
+    audit::AuditOperation,
+    auth::{
+        current_user::CurrentUser,
+        email_change::{EmailChanges, PendingEmailChange},
+        password,
+        password_policy::UserContext,
+        two_factor::TwoFactorRepository,
+    },
+    database::{with_pool, SqlxPool},
+    error::AppError,
+    negotiate::{Format, Negotiated},
+    roles::Role,
+    session_data::AppSession,
+    state::AppState,
+    users::{is_valid_display_name, AuthSource, User, UserRepository, MAX_DISPLAY_NAME_LENGTH},
+};
+
+/// The maximum size of the preferences of a user, serialized
+pub const MAX_PREFERENCES_BYTES: usize = 4096;
+
+/// The preferences of the users, stored in the database
+#[derive(Clone, Debug)]
+pub struct PreferencesRepository {
+    pool: SqlxPool,
+}
+
+impl PreferencesRepository {
+    pub fn new(pool: SqlxPool) -> Self {
+        Self { pool }
+    }
+
+    /// The preferences of a user, empty if none were set
+    pub async fn get(&self, user_id: i64) -> Result<Map<String, Value>, sqlx::Error> {
+        let sql = self
+            .pool
+            .sql("SELECT preferences FROM user_preferences WHERE user_id = ?");
+        let preferences: Option<String> = with_pool!(&self.pool, |p| sqlx::query_scalar(&sql)
+            .bind(user_id)
+            .fetch_optional(p)
+            .await)?;
+        Ok(preferences
+            .and_then(|preferences| serde_json::from_str(&preferences).ok())
+            .unwrap_or_default())
+    }
+
+    /// Replace the preferences of a user
+    pub async fn set(
+        &self,
+        user_id: i64,
+        preferences: &Map<String, Value>,
+    ) -> Result<(), sqlx::Error> {
+        let clear = self
+            .pool
+            .sql("DELETE FROM user_preferences WHERE user_id = ?");
+        let insert = self.pool.sql(
+            "INSERT INTO user_preferences (user_id, preferences, updated_at) VALUES (?, ?, ?)",
+        );
+        let preferences = Value::Object(preferences.clone()).to_string();
+
+        with_pool!(&self.pool, |p| {
+            let mut tx = p.begin().await?;
+            sqlx::query(&clear).bind(user_id).execute(&mut *tx).await?;
+            sqlx::query(&insert)
+                .bind(user_id)
+                .bind(&preferences)
+                .bind(OffsetDateTime::now_utc().unix_timestamp())
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+        });
+        Ok(())
+    }
+}
+
+/// The profile of the logged in user
+#[derive(Serialize)]
+pub struct UserProfile {
+    #[serde(flatten)]
+    pub user: User,
+    pub roles: Vec<Role>,
+    pub two_factor_enabled: bool,
+    /// The number of unexpired sessions of the user, including the current one
+    pub active_sessions: u64,
+    pub preferences: Map<String, Value>,
+    /// The change of email waiting for its confirmation, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending_email: Option<PendingEmailChange>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateProfileRequest {
+    pub display_name: Option<String>,
+    /// Merged into the stored preferences, a `null` value removing its key
+    pub preferences: Option<Map<String, Value>>,
+    /// Rejected: the email is changed through its own confirmed flow
+    pub email: Option<Value>,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+/// `GET /users/me`: the profile of the logged in user
+pub async fn get_profile(
+    State(state): State<AppState>,
+    session: AppSession,
+    format: Format,
+    user: CurrentUser,
+) -> Result<Negotiated<UserProfile>, AppError> {
+    Ok(Negotiated(format, profile(&state, &session, user).await?))
+}
+
+/// `PATCH /users/me`: change the display name and preferences of the logged in user
+pub async fn update_profile(
+    State(state): State<AppState>,
+    session: AppSession,
+    user: CurrentUser,
+    Negotiated(format, request): Negotiated<UpdateProfileRequest>,
+) -> Result<Negotiated<UserProfile>, AppError> {
+    if request.email.is_some() {
+        return Err(AppError::BadRequest(
+            "The email can't be changed through the profile".to_string(),
+        ));
+    }
+
+    let repository = PreferencesRepository::new(state.write_pool().clone());
+    let preferences = match request.preferences {
+        Some(changes) => {
+            let mut preferences = repository.get(user.0.id).await?;
+            for (key, value) in changes {
+                match value {
+                    Value::Null => preferences.remove(&key),
+                    value => preferences.insert(key, value),
+                };
+            }
+            Some(preferences)
+        }
+        None => None,
+    };
+
+    let mut violations = Vec::new();
+    if let Some(display_name) = &request.display_name {
+        if !is_valid_display_name(display_name) {
+            violations.push(format!(
+                "the display name must have between 1 and {} characters",
+                MAX_DISPLAY_NAME_LENGTH
+            ));
+        }
+    }
+    if let Some(preferences) = &preferences {
+        if Value::Object(preferences.clone()).to_string().len() > MAX_PREFERENCES_BYTES {
+            violations.push(format!(
+                "the preferences must take at most {} bytes",
+                MAX_PREFERENCES_BYTES
+            ));
+        }
+    }
+    if !violations.is_empty() {
+        return Err(AppError::BadRequest(violations.join("; ")));
+    }
+
+    let user = match request.display_name {
+        Some(display_name) => CurrentUser(
+            UserRepository::new(state.write_pool().clone())
+                .update(&User {
+                    display_name: display_name.trim().to_string(),
+                    ..user.0
+                })
+                .await?,
+        ),
+        None => user,
+    };
+    if let Some(preferences) = &preferences {
+        repository.set(user.0.id, preferences).await?;
+    }
+
+    Ok(Negotiated(format, profile(&state, &session, user).await?))
+}
+
+/// `PUT /users/me/password`: change the password of the logged in user, revoking their other
+/// sessions
+///
+/// Answers `403` when the current password is wrong, and `403` (`password_managed_externally`)
+/// for a user of the LDAP directory.
+pub async fn change_password(
+    State(state): State<AppState>,
+    session: AppSession,
+    CurrentUser(user): CurrentUser,
+    Negotiated(_, request): Negotiated<ChangePasswordRequest>,
+) -> Result<(AuditOperation, StatusCode), AppError> {
+    if user.auth_source != AuthSource::Local {
+        return Err(AppError::ExternalPassword);
+    }
+    let valid = password::verify(
+        &request.current_password,
+        &user.password_hash,
+        &state.config.password,
+    )
+    .await
+    .is_ok_and(|outcome| outcome.is_valid());
+    if !valid {
+        return Err(AppError::Forbidden);
+    }
+    state
+        .config
+        .password_policy
+        .enforce(&request.new_password, &UserContext { email: &user.email })
+        .await?;
+
+    let password_hash = password::hash(&request.new_password, &state.config.password).await?;
+    let user = UserRepository::new(state.write_pool().clone())
+        .update(&User {
+            password_hash,
+            must_change_password: false,
+            ..user
+        })
+        .await?;
+
+    // Without a session (e.g. with an API key), every session is revoked
+    // The current session is kept under a new ID, like on login
+    let revoked = match session.0.id() {
+        Some(id) => {
+            let revoked = state.sessions.revoke_user_except(user.id, id).await?;
+            session.0.cycle_id().await?;
+            revoked
+        }
+        None => state.sessions.revoke_user(user.id).await?,
+    };
+    tracing::info!(
+        "User {} changed their password, revoking {} other session(s)",
+        user.id,
+        revoked
+    );
+
+    Ok((
+        AuditOperation::new("users.change_password", revoked),
+        StatusCode::NO_CONTENT,
+    ))
+}
+
+/// The profile of a user
+async fn profile(
+    state: &AppState,
+    session: &AppSession,
+    user: CurrentUser,
+) -> Result<UserProfile, AppError> {
+    let roles = user.roles(session, state).await?;
+    let CurrentUser(user) = user;
+    let two_factor_enabled = TwoFactorRepository::new(state.write_pool().clone())
+        .is_enabled(user.id)
+        .await?;
+    let active_sessions = state.sessions.count_user(user.id).await?;
+    let preferences = PreferencesRepository::new(state.write_pool().clone())
+        .get(user.id)
+        .await?;
+    let pending_email = EmailChanges::new(state.write_pool().clone())
+        .pending(&user)
+        .await?;
+
+    Ok(UserProfile {
+        user,
+        roles,
+        two_factor_enabled,
+        active_sessions,
+        preferences,
+        pending_email,
+    })
+}
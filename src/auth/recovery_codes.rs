@@ -0,0 +1,205 @@
+//! Recovery codes of the two-factor authentication
+//! Users enabling 2FA get [`CODE_COUNT`] single-use codes, shown once, to log in when they lose
+//! their authenticator app: a recovery code is accepted wherever a TOTP code is. Only the SHA-256
+//! of the codes is stored, and consuming one is a single conditional update, so that a code used
+//! concurrently is only accepted once. Users regenerate them with their password, which
+//! invalidates the remaining ones. Running low on codes publishes an
+//! [`AdminEvent::RecoveryCodesLow`].
+
+use axum::extract::State;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{
+    auth::{current_user::CurrentUser, password, token, two_factor::TwoFactorRepository},
+    database::{with_pool, SqlxPool},
+    error::AppError,
+    events::AdminEvent,
+    idempotency::NoStore,
+    negotiate::Negotiated,
+    state::AppState,
+    users::AuthSource,
+};
+
+/// The number of codes issued at once
+pub const CODE_COUNT: usize = 10;
+
+/// The number of remaining codes below which the user is warned
+pub const LOW_THRESHOLD: i64 = 3;
+
+/// The characters of the codes, without the ones easily confused (`0`/`o`, `1`/`l`/`i`)
+const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/// The number of characters of a code, shown in two groups
+const CODE_LEN: usize = 10;
+
+/// Generate a code, such as `k3x9p-7mqra`
+fn generate() -> String {
+    let mut rng = rand::thread_rng();
+    let mut code = String::with_capacity(CODE_LEN + 1);
+    for i in 0..CODE_LEN {
+        if i == CODE_LEN / 2 {
+            code.push('-');
+        }
+        code.push(ALPHABET[rng.gen_range(0..ALPHABET.len())] as char);
+    }
+    code
+}
+
+/// The form of a code that is hashed: lowercase, without separators nor spaces
+fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// The recovery codes of the users, stored in the database
+#[derive(Clone, Debug)]
+pub struct RecoveryCodes {
+    pool: SqlxPool,
+}
+
+impl RecoveryCodes {
+    pub fn new(pool: SqlxPool) -> Self {
+        Self { pool }
+    }
+
+    /// Issue a new set of codes to a user, invalidating the previous ones
+    pub async fn replace(&self, user_id: i64) -> Result<Vec<String>, sqlx::Error> {
+        let codes: Vec<String> = (0..CODE_COUNT).map(|_| generate()).collect();
+
+        let clear = self
+            .pool
+            .sql("DELETE FROM recovery_codes WHERE user_id = ?");
+        let insert = self
+            .pool
+            .sql("INSERT INTO recovery_codes (code_hash, user_id, created_at) VALUES (?, ?, ?)");
+        let now = now();
+
+        with_pool!(&self.pool, |p| {
+            let mut tx = p.begin().await?;
+            sqlx::query(&clear).bind(user_id).execute(&mut *tx).await?;
+            for code in &codes {
+                sqlx::query(&insert)
+                    .bind(token::hash(&normalize(code)))
+                    .bind(user_id)
+                    .bind(now)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+        });
+        Ok(codes)
+    }
+
+    /// Consume an unused code of a user, returning whether it was one
+    pub async fn consume(&self, user_id: i64, code: &str) -> Result<bool, sqlx::Error> {
+        let sql = self.pool.sql(
+            "UPDATE recovery_codes SET used_at = ? \
+             WHERE code_hash = ? AND user_id = ? AND used_at IS NULL",
+        );
+        let consumed = with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(now())
+            .bind(token::hash(&normalize(code)))
+            .bind(user_id)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))?;
+        Ok(consumed > 0)
+    }
+
+    /// The number of unused codes of a user
+    pub async fn remaining(&self, user_id: i64) -> Result<i64, sqlx::Error> {
+        let sql = self
+            .pool
+            .sql("SELECT COUNT(*) FROM recovery_codes WHERE user_id = ? AND used_at IS NULL");
+        let remaining = with_pool!(&self.pool, |p| sqlx::query_scalar(&sql)
+            .bind(user_id)
+            .fetch_one(p)
+            .await)?;
+        Ok(remaining)
+    }
+}
+
+/// Consume a recovery code of a user, warning when they run low on codes
+pub async fn redeem(state: &AppState, user_id: i64, code: &str) -> Result<bool, AppError> {
+    let codes = RecoveryCodes::new(state.write_pool().clone());
+    if !codes.consume(user_id, code).await? {
+        return Ok(false);
+    }
+
+    let remaining = codes.remaining(user_id).await?;
+    tracing::info!(
+        "User {} used a recovery code, {} remaining",
+        user_id,
+        remaining
+    );
+    if remaining < LOW_THRESHOLD {
+        tracing::warn!("User {} has {} recovery codes left", user_id, remaining);
+        state
+            .events
+            .publish(AdminEvent::RecoveryCodesLow { user_id, remaining });
+    }
+    Ok(true)
+}
+
+/// A new set of recovery codes, only ever shown once
+#[derive(Serialize)]
+pub struct RecoveryCodeSet {
+    pub recovery_codes: Vec<String>,
+}
+
+/// The password of the user, to regenerate their codes
+#[derive(Deserialize)]
+pub struct RegenerateRequest {
+    pub password: String,
+}
+
+/// `POST /auth/2fa/recovery/regenerate`: replace the recovery codes of the logged in user, given
+/// their password
+///
+/// Answers `403` (`password_managed_externally`) for a user of the LDAP directory, whose password
+/// isn't known here.
+pub async fn regenerate(
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    Negotiated(format, request): Negotiated<RegenerateRequest>,
+) -> Result<(NoStore, Negotiated<RecoveryCodeSet>), AppError> {
+    if user.auth_source != AuthSource::Local {
+        return Err(AppError::ExternalPassword);
+    }
+    let valid = password::verify(
+        &request.password,
+        &user.password_hash,
+        &state.config.password,
+    )
+    .await
+    .is_ok_and(|outcome| outcome.is_valid());
+    if !valid {
+        return Err(AppError::Forbidden);
+    }
+    if !TwoFactorRepository::new(state.write_pool().clone())
+        .is_enabled(user.id)
+        .await?
+    {
+        return Err(AppError::Conflict(
+            "Two-factor authentication is not enabled".to_string(),
+        ));
+    }
+
+    let recovery_codes = RecoveryCodes::new(state.write_pool().clone())
+        .replace(user.id)
+        .await?;
+    tracing::info!("Regenerated the recovery codes of user {}", user.id);
+    Ok((
+        NoStore,
+        Negotiated(format, RecoveryCodeSet { recovery_codes }),
+    ))
+}
+
+/// The current time, in unix seconds
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
@@ -0,0 +1,124 @@
+//! Self-service registration of users
+//! Registration is disabled unless `REGISTRATION_ENABLED` is set, deployments being invite-only
+//! otherwise. New users are active, or pending the verification of their email when
+//! `REGISTRATION_EMAIL_VERIFICATION` is set (see [`email_verification`]). With `REGISTRATION_HIDE_CONFLICTS`, registering a
+//! taken email is answered like a success, so that registration doesn't reveal who has an
+//! account.
+
+use axum::{extract::State, http::StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::{email_verification, password, password_policy::UserContext},
+    error::AppError,
+    events::AdminEvent,
+    negotiate::Negotiated,
+    settings,
+    state::AppState,
+    users::{
+        is_valid_display_name, is_valid_email, AuthSource, NewUser, UserError, UserRepository,
+        UserStatus, MAX_DISPLAY_NAME_LENGTH,
+    },
+};
+
+/// A registration
+#[derive(Deserialize)]
+pub struct RegisterRequest {
+    pub email: String,
+    pub display_name: String,
+    pub password: String,
+}
+
+/// The outcome of a registration, the same whether the email was taken or not when conflicts
+/// are hidden
+#[derive(Serialize, Deserialize)]
+pub struct Registration {
+    pub email: String,
+    /// The status of the account: `active`, or `pending_verification`
+    pub status: UserStatus,
+}
+
+/// `POST /auth/register`: create an account
+pub async fn register(
+    State(state): State<AppState>,
+    Negotiated(format, registration): Negotiated<RegisterRequest>,
+) -> Result<(StatusCode, Negotiated<Registration>), AppError> {
+    let config = &state.config.registration;
+    if !state
+        .settings
+        .get_or(settings::REGISTRATION_ENABLED, config.enabled)
+    {
+        return Err(AppError::RegistrationDisabled);
+    }
+
+    let email = registration.email.trim();
+    let display_name = registration.display_name.trim();
+    let mut violations = Vec::new();
+    if !is_valid_email(email) {
+        violations.push("the email is invalid".to_string());
+    }
+    if !is_valid_display_name(display_name) {
+        violations.push(format!(
+            "the display name must have between 1 and {} characters",
+            MAX_DISPLAY_NAME_LENGTH
+        ));
+    }
+    if !violations.is_empty() {
+        return Err(AppError::BadRequest(violations.join("; ")));
+    }
+    state
+        .config
+        .password_policy
+        .enforce(&registration.password, &UserContext { email })
+        .await?;
+
+    let status = if config.email_verification {
+        UserStatus::PendingVerification
+    } else {
+        UserStatus::Active
+    };
+    // Hashed before looking for a conflict, so that a taken email isn't answered faster
+    let password_hash = password::hash(&registration.password, &state.config.password).await?;
+
+    let created = UserRepository::new(state.write_pool().clone())
+        .create(NewUser {
+            email: email.to_string(),
+            display_name: display_name.to_string(),
+            password_hash,
+            status,
+            must_change_password: false,
+            auth_source: AuthSource::Local,
+        })
+        .await;
+    if let Ok(user) = &created {
+        state
+            .events
+            .publish(AdminEvent::UserCreated { user_id: user.id });
+    }
+    match created {
+        Ok(user) if status == UserStatus::PendingVerification => {
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Err(err) = email_verification::send_verification(&state, &user).await {
+                    tracing::error!("Failed to send an email verification token: {:#}", err);
+                }
+            });
+        }
+        Ok(_) => {}
+        Err(UserError::EmailTaken) if config.hide_conflicts => {
+            tracing::info!("Registration of a taken email hidden from the client");
+        }
+        Err(err) => return Err(err.into()),
+    }
+
+    Ok((
+        StatusCode::CREATED,
+        Negotiated(
+            format,
+            Registration {
+                email: email.to_string(),
+                status,
+            },
+        ),
+    ))
+}
@@ -0,0 +1,311 @@
+//! Login and logout of users
+//! A successful login rotates the session ID, so that an ID known before the login (e.g. fixed
+//! by an attacker) doesn't grant access, and stores the ID of the user in the session. Failed
+//! logins are answered the same way whether the email exists or not, or the account is locked
+//! (see [`lockout`]). For users with 2FA enabled, the login is only complete once the second
+//! factor is verified (see [`two_factor`]). With the `ldap` feature, the directory checks the
+//! password of its users instead (see `ldap`). The logins to an email from an address are rate
+//! limited (see [`rate_limit`]), a successful one resetting the counter.
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::{
+        current_user::CurrentUser,
+        impersonation::Impersonation,
+        lockout::{self, LoginAttempts},
+        login_history::{self, LoginClient, LoginMethod, LoginResult},
+        password::{self, VerifyOutcome},
+        recovery_codes::RecoveryCodes,
+        two_factor::{self, MfaChallenge, TwoFactorRepository},
+    },
+    error::AppError,
+    negotiate::{Format, Negotiated},
+    rate_limit,
+    session_data::AppSession,
+    settings,
+    state::AppState,
+    users::{User, UserRepository, UserStatus},
+};
+
+/// The credentials of a login
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    /// The email, or the username in the LDAP directory
+    #[serde(alias = "username")]
+    pub email: String,
+    pub password: String,
+}
+
+/// `POST /auth/login`: log a user in, returning their profile
+///
+/// Answers `403` when the password login is disabled, users logging in through OpenID Connect.
+/// When the user has 2FA enabled, answers `202` with `{"mfa_required": true}` instead, the
+/// session waiting for the second factor.
+pub async fn login(
+    State(state): State<AppState>,
+    session: AppSession,
+    client: LoginClient,
+    Negotiated(format, credentials): Negotiated<LoginRequest>,
+) -> Result<Response, AppError> {
+    if !state.config.password_login_enabled {
+        return Err(AppError::PasswordLoginDisabled);
+    }
+    let rate_limit_key = rate_limit::login_key(&client.ip, &credentials.email);
+    rate_limit::enforce(
+        &state,
+        state.config.rate_limit.login.as_ref(),
+        &rate_limit_key,
+        &client.ip,
+    )
+    .await?;
+    let config = &state.config.password;
+    let users = UserRepository::new(state.write_pool().clone());
+    let attempts = LoginAttempts::new(state.write_pool().clone());
+
+    // Spend the time of a verification, not to reveal that the address is blocked, the email
+    // unknown or the account locked
+    if attempts
+        .is_blocked(&client.ip, &state.config.lockout)
+        .await?
+    {
+        password::verify_dummy(&credentials.password, config).await?;
+        return Err(AppError::Unauthorized);
+    }
+    let user = users.find_by_email(&credentials.email).await?;
+    if let Some(ldap) = &state.config.ldap {
+        // The directory checks the password of its users, and of those unknown here
+        if user
+            .as_ref()
+            .is_none_or(|user| user.auth_source == crate::users::AuthSource::Ldap)
+        {
+            let user = crate::auth::ldap::login(
+                &state,
+                ldap,
+                &credentials.email,
+                &credentials.password,
+                user,
+                &client,
+            )
+            .await?;
+            lockout::succeed(&state, &user).await?;
+            state.rate_limiter.reset(&rate_limit_key).await?;
+            let method = LoginMethod::password_of(&user);
+            let completion = complete(&session, &state, user, method, &client).await?;
+            return Ok(completion.respond(format));
+        }
+    }
+    let Some(user) = user else {
+        password::verify_dummy(&credentials.password, config).await?;
+        attempts
+            .record_failure(&credentials.email, &client.ip)
+            .await?;
+        return Err(AppError::Unauthorized);
+    };
+    let method = LoginMethod::password_of(&user);
+    if lockout::is_locked(&user) {
+        password::verify_dummy(&credentials.password, config).await?;
+        lockout::fail(&state, &user, &client.ip).await?;
+        login_history::record(&state, &user, method, LoginResult::Locked, &client).await;
+        return Err(AppError::Unauthorized);
+    }
+
+    let outcome = match password::verify(&credentials.password, &user.password_hash, config).await {
+        Ok(outcome) => outcome,
+        Err(err) => {
+            tracing::warn!("Can't verify the password of user {}: {:#}", user.id, err);
+            lockout::fail(&state, &user, &client.ip).await?;
+            login_history::record(&state, &user, method, LoginResult::Failure, &client).await;
+            return Err(AppError::Unauthorized);
+        }
+    };
+    if !outcome.is_valid() {
+        lockout::fail(&state, &user, &client.ip).await?;
+        login_history::record(&state, &user, method, LoginResult::Failure, &client).await;
+        return Err(AppError::Unauthorized);
+    }
+    lockout::succeed(&state, &user).await?;
+    state.rate_limiter.reset(&rate_limit_key).await?;
+
+    let user = if outcome == VerifyOutcome::ValidNeedsRehash {
+        rehash(&users, user, &credentials.password, &state).await
+    } else {
+        user
+    };
+    let completion = complete(&session, &state, user, method, &client).await?;
+    Ok(completion.respond(format))
+}
+
+/// Where a login stands once its user is authenticated
+pub(crate) enum Completion {
+    /// The user is logged in the session
+    LoggedIn(User),
+    /// The session waits for the second factor of the user
+    MfaRequired,
+}
+
+impl Completion {
+    /// The answer to the login: the profile of the user, or a `202` asking for the second factor
+    pub(crate) fn respond(self, format: Format) -> Response {
+        match self {
+            Completion::LoggedIn(user) => Negotiated(format, user).into_response(),
+            Completion::MfaRequired => {
+                let challenge = MfaChallenge { mfa_required: true };
+                (StatusCode::ACCEPTED, Negotiated(format, challenge)).into_response()
+            }
+        }
+    }
+}
+
+/// Complete the login of an authenticated user (by password, the directory or the OpenID
+/// Connect provider): refuse the accounts that can't be used, then wait for the second factor or
+/// log them in
+///
+/// A login waiting for the second factor is recorded in the history once it is verified.
+pub(crate) async fn complete(
+    session: &AppSession,
+    state: &AppState,
+    user: User,
+    method: LoginMethod,
+    client: &LoginClient,
+) -> Result<Completion, AppError> {
+    // Only tell that the account can't be used to whoever authenticated as its user
+    let refusal = match user.status {
+        UserStatus::Active => None,
+        UserStatus::PendingVerification => Some(AppError::EmailNotVerified),
+        UserStatus::Disabled => Some(AppError::Forbidden),
+    };
+    if let Some(refusal) = refusal {
+        login_history::record(state, &user, method, LoginResult::Failure, client).await;
+        return Err(refusal);
+    }
+
+    if TwoFactorRepository::new(state.write_pool().clone())
+        .is_enabled(user.id)
+        .await?
+    {
+        two_factor::challenge(session, state, &user, method).await?;
+        return Ok(Completion::MfaRequired);
+    }
+
+    establish(session, state, &user).await?;
+    login_history::record(state, &user, method, LoginResult::Success, client).await;
+    Ok(Completion::LoggedIn(user))
+}
+
+/// Log the user in the session, rotating its ID
+///
+/// With `MAX_SESSIONS_PER_USER`, the oldest other sessions of the user are evicted to make room
+/// for this one, when the store inserts it under its new ID once the response is sent.
+pub async fn establish(
+    session: &AppSession,
+    state: &AppState,
+    user: &User,
+) -> Result<(), AppError> {
+    session.0.cycle_id().await?;
+    let session_limit = state
+        .settings
+        .get(settings::MAX_SESSIONS_PER_USER)
+        .or(state.config.max_sessions_per_user);
+    // The roles cached for a previous user of the session don't apply
+    session
+        .update(|data| {
+            data.user_id = Some(user.id.to_string());
+            data.impersonator_id = None;
+            data.roles.clear();
+            data.roles_version = None;
+            data.mfa_pending = None;
+            data.oidc_pending = None;
+            data.session_limit = session_limit;
+        })
+        .await?;
+    CurrentUser(user.clone()).roles(session, state).await?;
+    Ok(())
+}
+
+/// Replace the hash of the password of a user by one made with the current cost
+///
+/// A failure is logged without failing the login: the old hash is still valid.
+async fn rehash(users: &UserRepository, user: User, plain: &str, state: &AppState) -> User {
+    let hash = match password::hash(plain, &state.config.password).await {
+        Ok(hash) => hash,
+        Err(err) => {
+            tracing::warn!(
+                "Failed to rehash the password of user {}: {:#}",
+                user.id,
+                err
+            );
+            return user;
+        }
+    };
+
+    match users
+        .update(&User {
+            password_hash: hash,
+            ..user.clone()
+        })
+        .await
+    {
+        Ok(updated) => updated,
+        Err(err) => {
+            tracing::warn!("Failed to rehash the password of user {}: {}", user.id, err);
+            user
+        }
+    }
+}
+
+/// `POST /auth/logout`: log the user out, deleting the session and its cookie
+pub async fn logout(session: AppSession) -> Result<StatusCode, AppError> {
+    session.0.flush().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The profile of a user, with the state of their two-factor authentication
+#[derive(Serialize)]
+pub struct Profile {
+    #[serde(flatten)]
+    pub user: User,
+    pub two_factor_enabled: bool,
+    /// The number of unused recovery codes, when 2FA is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recovery_codes_remaining: Option<i64>,
+    /// The ID of the admin impersonating the user, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub impersonated_by: Option<i64>,
+}
+
+/// `GET /auth/me`: the profile of the logged in user
+pub async fn me(
+    State(state): State<AppState>,
+    format: Format,
+    CurrentUser(user): CurrentUser,
+    Impersonation(impersonator): Impersonation,
+) -> Result<Negotiated<Profile>, AppError> {
+    let two_factor_enabled = TwoFactorRepository::new(state.write_pool().clone())
+        .is_enabled(user.id)
+        .await?;
+    let recovery_codes_remaining = if two_factor_enabled {
+        Some(
+            RecoveryCodes::new(state.write_pool().clone())
+                .remaining(user.id)
+                .await?,
+        )
+    } else {
+        None
+    };
+
+    Ok(Negotiated(
+        format,
+        Profile {
+            user,
+            two_factor_enabled,
+            recovery_codes_remaining,
+            impersonated_by: impersonator.map(|impersonator| impersonator.id),
+        },
+    ))
+}
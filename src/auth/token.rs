@@ -0,0 +1,36 @@
+//! Single-use tokens sent by email
+//! Tokens are random and URL-safe. Only their SHA-256 is stored, so that a leak of the database
+//! doesn't leak usable tokens.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+/// The number of random bytes of a token
+const TOKEN_LEN: usize = 32;
+
+/// Generate a random token
+pub fn generate() -> String {
+    let mut bytes = [0u8; TOKEN_LEN];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// The hex SHA-256 of a token, as stored
+pub fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// What an email tells the user to do with a token, and where: follow a link to the page of the
+/// admin UI taking it in its `token` query parameter, or use the bare token when there is none
+pub fn instructions(url: Option<&Url>, token: &str) -> (&'static str, String) {
+    match url {
+        Some(url) => {
+            let mut url = url.clone();
+            url.query_pairs_mut().append_pair("token", token);
+            ("follow this link", url.to_string())
+        }
+        None => ("use this token", token.to_string()),
+    }
+}
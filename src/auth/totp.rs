@@ -0,0 +1,104 @@
+//! Time-based one-time passwords (RFC 6238), as computed by the authenticator apps
+//! The codes have 6 digits, are derived with HMAC-SHA1 from a 160-bit secret and change every 30
+//! seconds. The secrets are shared with the apps in base32 (RFC 4648), through an `otpauth://`
+//! URI.
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha1::Sha1;
+use subtle::ConstantTimeEq;
+use url::Url;
+
+/// The length of the secrets, in bytes
+pub const SECRET_LEN: usize = 20;
+
+/// The number of digits of a code
+pub const DIGITS: u32 = 6;
+
+/// The number of seconds a code is valid for
+pub const PERIOD: i64 = 30;
+
+/// The number of periods a code is accepted before or after its own, for clocks drifting apart
+pub const WINDOW: i64 = 1;
+
+/// The base32 alphabet of RFC 4648
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Generate a random secret
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0; SECRET_LEN];
+    rand::thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+/// The time step of a time, in unix seconds
+pub fn step_at(unix_time: i64) -> i64 {
+    unix_time.div_euclid(PERIOD)
+}
+
+/// The code of a time step
+pub fn code_at(secret: &[u8], step: i64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    // Dynamic truncation: 31 bits read at the offset given by the last nibble
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    format!(
+        "{:0width$}",
+        value % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+/// The time step whose code is the given one, within the window around the given time
+///
+/// Spaces in the code are ignored, as apps show them to ease reading.
+pub fn matching_step(secret: &[u8], code: &str, unix_time: i64) -> Option<i64> {
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let current = step_at(unix_time);
+    (current - WINDOW..=current + WINDOW)
+        .find(|step| bool::from(code_at(secret, *step).as_bytes().ct_eq(code.as_bytes())))
+}
+
+/// Encode bytes in base32, without padding
+pub fn base32(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity((bytes.len() * 8).div_ceil(5));
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    encoded
+}
+
+/// The `otpauth://` URI registering the secret in an authenticator app, usually as a QR code
+pub fn provisioning_uri(secret: &[u8], issuer: &str, account: &str) -> String {
+    let mut uri = Url::parse("otpauth://totp/").expect("the base URI is valid");
+    uri.set_path(&format!("{}:{}", issuer, account));
+    uri.query_pairs_mut()
+        .append_pair("secret", &base32(secret))
+        .append_pair("issuer", issuer)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &PERIOD.to_string());
+    uri.to_string()
+}
@@ -0,0 +1,454 @@
+//! Two-factor authentication of users with TOTP
+//! Users enable 2FA in two steps: `setup` generates a secret, to register in an authenticator
+//! app, and `confirm` enables it once a first code proves the app has it, returning the
+//! [`recovery_codes`] of the user. The secrets are stored
+//! encrypted with AES-256-GCM under `TOTP_ENCRYPTION_KEY`: users can't enable 2FA when it's unset.
+//!
+//! Once enabled, a correct password only leaves the session waiting for the second factor, for
+//! `TOTP_PENDING_TTL_SECS`: the user is logged in once `verify` accepts a code. Codes are accepted
+//! one period before or after the current one, and only once: the time step of the last accepted
+//! code is stored, and older or equal ones are rejected. A recovery code is accepted instead of a
+//! TOTP code, except to confirm the setup. Wrong codes count as failed logins (see
+//! [`lockout`]). Users disable 2FA with a code, and admins can disable it for a user who lost
+//! their app.
+
+use anyhow::{anyhow, Context};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use openssl::symm::{self, Cipher};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{
+    auth::{
+        current_user::CurrentUser,
+        lockout,
+        login_history::{self, LoginClient, LoginMethod, LoginResult},
+        recovery_codes::{self, RecoveryCodeSet, RecoveryCodes},
+        session, totp,
+    },
+    database::{with_pool, SqlxPool},
+    error::AppError,
+    idempotency::NoStore,
+    negotiate::{Format, Negotiated},
+    session_data::{AppSession, MfaPending},
+    state::AppState,
+    users::{User, UserRepository, UserStatus},
+};
+
+/// The length of the nonces of AES-GCM, in bytes
+const NONCE_LEN: usize = 12;
+
+/// The length of the authentication tags of AES-GCM, in bytes
+const TAG_LEN: usize = 16;
+
+/// The TOTP secret of a user, as stored
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct StoredSecret {
+    /// The encrypted secret
+    pub secret: String,
+    /// When 2FA was enabled, in unix seconds, if it is
+    pub enabled_at: Option<i64>,
+    /// The time step of the last accepted code
+    pub last_used_step: Option<i64>,
+}
+
+impl StoredSecret {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled_at.is_some()
+    }
+}
+
+/// The TOTP secrets of the users, stored in the database
+#[derive(Clone, Debug)]
+pub struct TwoFactorRepository {
+    pool: SqlxPool,
+}
+
+impl TwoFactorRepository {
+    pub fn new(pool: SqlxPool) -> Self {
+        Self { pool }
+    }
+
+    /// The secret of a user, enabled or waiting for confirmation
+    pub async fn find(&self, user_id: i64) -> Result<Option<StoredSecret>, sqlx::Error> {
+        let sql = self.pool.sql(
+            "SELECT secret, enabled_at, last_used_step FROM two_factor_secrets WHERE user_id = ?",
+        );
+        with_pool!(&self.pool, |p| sqlx::query_as(&sql)
+            .bind(user_id)
+            .fetch_optional(p)
+            .await)
+    }
+
+    /// Whether a user has 2FA enabled
+    pub async fn is_enabled(&self, user_id: i64) -> Result<bool, sqlx::Error> {
+        Ok(self
+            .find(user_id)
+            .await?
+            .is_some_and(|secret| secret.is_enabled()))
+    }
+
+    /// Store a secret waiting for confirmation, replacing the previous unconfirmed one
+    pub async fn replace_pending(&self, user_id: i64, secret: &str) -> Result<(), sqlx::Error> {
+        let clear = self
+            .pool
+            .sql("DELETE FROM two_factor_secrets WHERE user_id = ? AND enabled_at IS NULL");
+        let insert = self
+            .pool
+            .sql("INSERT INTO two_factor_secrets (user_id, secret, created_at) VALUES (?, ?, ?)");
+
+        with_pool!(&self.pool, |p| {
+            let mut tx = p.begin().await?;
+            sqlx::query(&clear).bind(user_id).execute(&mut *tx).await?;
+            sqlx::query(&insert)
+                .bind(user_id)
+                .bind(secret)
+                .bind(now())
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+        });
+        Ok(())
+    }
+
+    /// Enable the pending secret of a user with the time step of its first code
+    ///
+    /// Returns `false` when no secret is pending, or the step was already used.
+    pub async fn enable(&self, user_id: i64, step: i64) -> Result<bool, sqlx::Error> {
+        let sql = self.pool.sql(
+            "UPDATE two_factor_secrets SET enabled_at = ?, last_used_step = ? \
+             WHERE user_id = ? AND enabled_at IS NULL \
+             AND (last_used_step IS NULL OR last_used_step < ?)",
+        );
+        let enabled = with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(now())
+            .bind(step)
+            .bind(user_id)
+            .bind(step)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))?;
+        Ok(enabled > 0)
+    }
+
+    /// Record that a code of the enabled secret of a user was accepted
+    ///
+    /// Returns `false`, recording nothing, when 2FA is disabled or a code of the same or a later
+    /// step was already accepted: the code is replayed.
+    pub async fn consume_step(&self, user_id: i64, step: i64) -> Result<bool, sqlx::Error> {
+        let sql = self.pool.sql(
+            "UPDATE two_factor_secrets SET last_used_step = ? \
+             WHERE user_id = ? AND enabled_at IS NOT NULL \
+             AND (last_used_step IS NULL OR last_used_step < ?)",
+        );
+        let consumed = with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(step)
+            .bind(user_id)
+            .bind(step)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))?;
+        Ok(consumed > 0)
+    }
+
+    /// Forget the secret and the recovery codes of a user, disabling 2FA, returning whether
+    /// there was a secret
+    pub async fn delete(&self, user_id: i64) -> Result<bool, sqlx::Error> {
+        let delete_secret = self
+            .pool
+            .sql("DELETE FROM two_factor_secrets WHERE user_id = ?");
+        let delete_codes = self
+            .pool
+            .sql("DELETE FROM recovery_codes WHERE user_id = ?");
+        let deleted = with_pool!(&self.pool, |p| {
+            let mut tx = p.begin().await?;
+            let deleted = sqlx::query(&delete_secret)
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+            sqlx::query(&delete_codes)
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            deleted
+        });
+        Ok(deleted > 0)
+    }
+}
+
+/// Encrypt the secret of a user, bound to their ID
+fn seal(key: &[u8; 32], user_id: i64, secret: &[u8]) -> anyhow::Result<String> {
+    let mut nonce = [0; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let mut tag = [0; TAG_LEN];
+    let ciphertext = symm::encrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(&nonce),
+        &user_id.to_be_bytes(),
+        secret,
+        &mut tag,
+    )?;
+    Ok(STANDARD.encode([&nonce[..], &tag[..], &ciphertext[..]].concat()))
+}
+
+/// Decrypt the secret of a user
+fn open(key: &[u8; 32], user_id: i64, sealed: &str) -> anyhow::Result<Vec<u8>> {
+    let sealed = STANDARD.decode(sealed)?;
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return Err(anyhow!("the sealed secret is truncated"));
+    }
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    let (tag, ciphertext) = rest.split_at(TAG_LEN);
+    symm::decrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(nonce),
+        &user_id.to_be_bytes(),
+        ciphertext,
+        tag,
+    )
+    .context("the secret can't be decrypted with TOTP_ENCRYPTION_KEY")
+}
+
+/// The key encrypting the secrets, when configured
+fn key(state: &AppState) -> Result<&[u8; 32], AppError> {
+    state.config.two_factor.key.as_ref().ok_or_else(|| {
+        tracing::warn!("Two-factor authentication needs TOTP_ENCRYPTION_KEY");
+        AppError::Unavailable
+    })
+}
+
+/// The time step of the code, if it's one of the stored secret around now
+fn matching_step(
+    state: &AppState,
+    user_id: i64,
+    stored: &StoredSecret,
+    code: &str,
+) -> Result<Option<i64>, AppError> {
+    let secret = open(key(state)?, user_id, &stored.secret)?;
+    Ok(totp::matching_step(&secret, code, now()))
+}
+
+/// Check a code of the enabled secret of a user, or one of their recovery codes, and consume it so
+/// that it can't be replayed
+async fn check_code(state: &AppState, user_id: i64, code: &str) -> Result<bool, AppError> {
+    let secrets = TwoFactorRepository::new(state.write_pool().clone());
+    let Some(stored) = secrets.find(user_id).await? else {
+        return Ok(false);
+    };
+    if !stored.is_enabled() {
+        return Ok(false);
+    }
+    match matching_step(state, user_id, &stored, code)? {
+        Some(step) => Ok(secrets.consume_step(user_id, step).await?),
+        None => recovery_codes::redeem(state, user_id, code).await,
+    }
+}
+
+/// Leave the session waiting for the second factor of the user
+///
+/// The session ID is rotated, and whoever was logged in the session is logged out.
+pub async fn challenge(
+    session: &AppSession,
+    state: &AppState,
+    user: &User,
+    method: LoginMethod,
+) -> Result<(), AppError> {
+    let expires_at = now() + state.config.two_factor.pending_ttl.as_secs() as i64;
+    session.0.cycle_id().await?;
+    session
+        .update(|data| {
+            data.user_id = None;
+            data.roles.clear();
+            data.roles_version = None;
+            data.mfa_pending = Some(MfaPending {
+                user_id: user.id,
+                method: Some(method),
+                expires_at,
+            });
+        })
+        .await?;
+    Ok(())
+}
+
+/// The answer to a login waiting for the second factor
+#[derive(Serialize)]
+pub struct MfaChallenge {
+    pub mfa_required: bool,
+}
+
+/// A secret to register in an authenticator app
+#[derive(Serialize)]
+pub struct TwoFactorSetup {
+    /// The secret in base32, to type in the app
+    pub secret: String,
+    /// The `otpauth://` URI carrying the secret, to show as a QR code
+    pub otpauth_uri: String,
+}
+
+/// A code of an authenticator app
+#[derive(Deserialize)]
+pub struct CodeRequest {
+    pub code: String,
+}
+
+/// `POST /auth/2fa/setup`: generate a secret for the logged in user, enabled once confirmed
+///
+/// Replaces the secret waiting for confirmation, if any.
+pub async fn setup(
+    State(state): State<AppState>,
+    format: Format,
+    CurrentUser(user): CurrentUser,
+) -> Result<(NoStore, Negotiated<TwoFactorSetup>), AppError> {
+    let key = key(&state)?;
+    let secrets = TwoFactorRepository::new(state.write_pool().clone());
+    if secrets.is_enabled(user.id).await? {
+        return Err(AppError::Conflict(
+            "Two-factor authentication is already enabled".to_string(),
+        ));
+    }
+
+    let secret = totp::generate_secret();
+    secrets
+        .replace_pending(user.id, &seal(key, user.id, &secret)?)
+        .await?;
+
+    Ok((
+        NoStore,
+        Negotiated(
+            format,
+            TwoFactorSetup {
+                secret: totp::base32(&secret),
+                otpauth_uri: totp::provisioning_uri(
+                    &secret,
+                    &state.config.two_factor.issuer,
+                    &user.email,
+                ),
+            },
+        ),
+    ))
+}
+
+/// `POST /auth/2fa/confirm`: enable 2FA with a first code of the secret set up, returning the
+/// recovery codes of the user
+pub async fn confirm(
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    Negotiated(format, request): Negotiated<CodeRequest>,
+) -> Result<(NoStore, Negotiated<RecoveryCodeSet>), AppError> {
+    let secrets = TwoFactorRepository::new(state.write_pool().clone());
+    let stored = secrets
+        .find(user.id)
+        .await?
+        .filter(|stored| !stored.is_enabled())
+        .ok_or_else(|| AppError::Conflict("No two-factor setup is pending".to_string()))?;
+
+    let enabled = match matching_step(&state, user.id, &stored, &request.code)? {
+        Some(step) => secrets.enable(user.id, step).await?,
+        None => false,
+    };
+    if !enabled {
+        return Err(AppError::BadRequest("The code is invalid".to_string()));
+    }
+    let recovery_codes = RecoveryCodes::new(state.write_pool().clone())
+        .replace(user.id)
+        .await?;
+    tracing::info!("Enabled two-factor authentication for user {}", user.id);
+    Ok((
+        NoStore,
+        Negotiated(format, RecoveryCodeSet { recovery_codes }),
+    ))
+}
+
+/// `POST /auth/2fa/verify`: complete a login waiting for the second factor, returning the profile
+/// of the user
+pub async fn verify(
+    State(state): State<AppState>,
+    session: AppSession,
+    client: LoginClient,
+    Negotiated(format, request): Negotiated<CodeRequest>,
+) -> Result<Negotiated<User>, AppError> {
+    let Some(pending) = session.data().await?.mfa_pending else {
+        return Err(AppError::Unauthorized);
+    };
+    if pending.expires_at <= now() {
+        session.update(|data| data.mfa_pending = None).await?;
+        return Err(AppError::Unauthorized);
+    }
+
+    let user = UserRepository::new(state.write_pool().clone())
+        .find_by_id(pending.user_id)
+        .await?
+        .filter(|user| user.status == UserStatus::Active)
+        .ok_or(AppError::Unauthorized)?;
+    let method = pending
+        .method
+        .unwrap_or_else(|| LoginMethod::password_of(&user));
+    let result = if lockout::is_locked(&user) {
+        LoginResult::Locked
+    } else if !check_code(&state, user.id, &request.code).await? {
+        LoginResult::Failure
+    } else {
+        LoginResult::Success
+    };
+    if result != LoginResult::Success {
+        lockout::fail(&state, &user, &client.ip).await?;
+        login_history::record(&state, &user, method, result, &client).await;
+        return Err(AppError::Unauthorized);
+    }
+    lockout::succeed(&state, &user).await?;
+
+    session::establish(&session, &state, &user).await?;
+    login_history::record(&state, &user, method, LoginResult::Success, &client).await;
+    Ok(Negotiated(format, user))
+}
+
+/// `DELETE /auth/2fa`: disable 2FA, given a code
+pub async fn disable(
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    Negotiated(_, request): Negotiated<CodeRequest>,
+) -> Result<StatusCode, AppError> {
+    if !check_code(&state, user.id, &request.code).await? {
+        return Err(AppError::BadRequest("The code is invalid".to_string()));
+    }
+    TwoFactorRepository::new(state.write_pool().clone())
+        .delete(user.id)
+        .await?;
+    tracing::info!("Disabled two-factor authentication for user {}", user.id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `DELETE /admin/users/:id/2fa`: disable 2FA for a user, e.g. who lost their app
+pub async fn admin_disable(
+    State(state): State<AppState>,
+    Path(user_id): Path<i64>,
+) -> Result<StatusCode, AppError> {
+    UserRepository::new(state.write_pool().clone())
+        .find_by_id(user_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let disabled = TwoFactorRepository::new(state.write_pool().clone())
+        .delete(user_id)
+        .await?;
+    if disabled {
+        tracing::info!(
+            "Disabled two-factor authentication for user {} (admin)",
+            user_id
+        );
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The current time, in unix seconds
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
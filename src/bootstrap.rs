@@ -0,0 +1,71 @@
+//! The first admin of a fresh deployment
+//! Without any user, nobody could log in to create the others. On startup, while the `users`
+//! table is empty, an `admin` is created from `ADMIN_EMAIL` and `ADMIN_PASSWORD` (or
+//! `ADMIN_PASSWORD_FILE`). Without a password, one is generated and logged once. Either way the
+//! admin must change it on their first login. Once any user exists, nothing is created again,
+//! and of several instances starting together only one creates the admin.
+
+use anyhow::{Context, Result};
+
+use crate::{
+    auth::password,
+    config::Config,
+    database::SqlxPool,
+    roles::{Role, RoleRepository},
+    users::{AuthSource, NewUser, UserRepository, UserStatus},
+};
+
+/// The display name of the first admin
+const DISPLAY_NAME: &str = "Administrator";
+
+/// Create the first admin if there is no user yet
+pub async fn admin(config: &Config, pool: &SqlxPool) -> Result<()> {
+    let users = UserRepository::new(pool.clone());
+    // Checked first, not to hash a password on every start
+    if users.any().await? {
+        return Ok(());
+    }
+
+    let generated = match &config.bootstrap.password {
+        Some(_) => None,
+        None => Some(password::generate(&config.password_policy)),
+    };
+    let plain = config
+        .bootstrap
+        .password
+        .as_deref()
+        .or(generated.as_deref())
+        .unwrap_or_default();
+    let password_hash = password::hash(plain, &config.password)
+        .await
+        .with_context(|| "Failed to hash the password of the first admin")?;
+
+    let Some(user) = users
+        .create_first(NewUser {
+            email: config.bootstrap.email.clone(),
+            display_name: DISPLAY_NAME.to_string(),
+            password_hash,
+            status: UserStatus::Active,
+            must_change_password: true,
+            auth_source: AuthSource::Local,
+        })
+        .await?
+    else {
+        tracing::info!("Another instance created the first admin");
+        return Ok(());
+    };
+    RoleRepository::new(pool.clone())
+        .set_roles(user.id, &[Role::Admin])
+        .await?;
+
+    tracing::info!("Created the first admin, {}", user.email);
+    if let Some(generated) = generated {
+        tracing::warn!(
+            "\n\n    First admin: {}\n    Password:    {}\n\n    It is only shown this once, and \
+             must be changed on the first login.\n",
+            user.email,
+            generated
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,51 @@
+//! Metadata of the build, embedded by the build script
+
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// Which build is running
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BuildInfo {
+    /// The version of the crate
+    pub version: String,
+    /// The git commit the backend was built from (`unknown` if it couldn't be determined)
+    pub git_sha: String,
+    /// When the backend was built (RFC 3339)
+    pub build_timestamp: String,
+    /// The version of the compiler the backend was built with
+    pub rustc_version: String,
+    /// The cargo profile the backend was built with (`debug` or `release`)
+    pub profile: String,
+}
+
+impl BuildInfo {
+    /// The metadata of the running build
+    pub fn current() -> Self {
+        let build_timestamp = env!("BUILD_TIMESTAMP")
+            .parse::<i64>()
+            .ok()
+            .and_then(|timestamp| OffsetDateTime::from_unix_timestamp(timestamp).ok())
+            .and_then(|timestamp| timestamp.format(&Rfc3339).ok())
+            .unwrap_or("unknown".to_string());
+
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_sha: env!("BUILD_GIT_COMMIT").to_string(),
+            build_timestamp,
+            rustc_version: env!("BUILD_RUSTC_VERSION").to_string(),
+            profile: env!("BUILD_PROFILE").to_string(),
+        }
+    }
+
+    /// A one-line summary, as printed by `--version`
+    pub fn summary(&self) -> String {
+        format!(
+            "{} {} ({}, built {}, {})",
+            env!("CARGO_PKG_NAME"),
+            self.version,
+            self.git_sha,
+            self.build_timestamp,
+            self.profile
+        )
+    }
+}
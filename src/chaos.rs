@@ -0,0 +1,208 @@
+//! Fault injection, for resilience testing
+//! When `CHAOS_ENABLED` is set, requests matching a rule may be delayed, answered with an error
+//! or have their connection dropped, according to the probabilities of the rule. The rules start
+//! from `CHAOS_RULES` and can be replaced at runtime through `PUT /admin/chaos`.
+//!
+//! The health endpoints are only affected by rules that explicitly target them, and the control
+//! endpoint never is, so that the rules can always be cleared.
+
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::AppError,
+    negotiate::{Format, Negotiated},
+    state::AppState,
+};
+
+/// The path of the control endpoint, never affected by the rules
+pub const CONTROL_PATH: &str = "/api/v1/admin/chaos";
+
+/// The endpoints only affected by the rules that explicitly target them
+const HEALTH_PATHS: [&str; 2] = ["/health", "/readyz"];
+
+/// The faults to inject in the requests under a path prefix
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChaosRule {
+    /// The prefix of the paths the rule applies to, relative to the base path
+    pub path_prefix: String,
+    /// The latency to add, in milliseconds
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// The probability of adding the latency
+    #[serde(default)]
+    pub latency_probability: f64,
+    /// The status of the injected errors (500 or 503)
+    #[serde(default = "default_error_status")]
+    pub error_status: u16,
+    /// The probability of answering with an error
+    #[serde(default)]
+    pub error_probability: f64,
+    /// The probability of dropping the connection without answering
+    #[serde(default)]
+    pub drop_probability: f64,
+}
+
+fn default_error_status() -> u16 {
+    500
+}
+
+impl ChaosRule {
+    /// Check that the probabilities and the status make sense
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.path_prefix.starts_with('/') {
+            return Err(format!(
+                "the path prefix '{}' must start with '/'",
+                self.path_prefix
+            ));
+        }
+        if ![500, 503].contains(&self.error_status) {
+            return Err(format!(
+                "the error status must be 500 or 503, not {}",
+                self.error_status
+            ));
+        }
+        for probability in [
+            self.latency_probability,
+            self.error_probability,
+            self.drop_probability,
+        ] {
+            if !(0.0..=1.0).contains(&probability) {
+                return Err(format!(
+                    "the probabilities must be between 0 and 1, not {}",
+                    probability
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the rule applies to the given path
+    fn matches(&self, path: &str) -> bool {
+        if path == CONTROL_PATH || !path.starts_with(&self.path_prefix) {
+            return false;
+        }
+        // A health endpoint must be covered by the prefix itself, not by a broader one
+        HEALTH_PATHS
+            .iter()
+            .all(|health| !path.starts_with(health) || self.path_prefix.starts_with(health))
+    }
+}
+
+/// The rules currently applied, shared with the control endpoint
+#[derive(Clone, Default)]
+pub struct ChaosRules(Arc<RwLock<Vec<ChaosRule>>>);
+
+impl ChaosRules {
+    /// Apply the given rules
+    pub fn new(rules: Vec<ChaosRule>) -> Self {
+        Self(Arc::new(RwLock::new(rules)))
+    }
+
+    /// The rules currently applied
+    pub fn get(&self) -> Vec<ChaosRule> {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Replace the rules
+    pub fn set(&self, rules: Vec<ChaosRule>) {
+        *self.0.write().unwrap() = rules;
+    }
+
+    /// The first rule applying to the given path
+    fn find(&self, path: &str) -> Option<ChaosRule> {
+        self.0
+            .read()
+            .unwrap()
+            .iter()
+            .find(|rule| rule.matches(path))
+            .cloned()
+    }
+}
+
+/// Marks a response whose connection must be dropped instead of answered (see `server`)
+#[derive(Clone, Copy, Debug)]
+pub struct DropConnection;
+
+/// Inject the faults of the first rule matching the request
+pub async fn inject_faults(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let Some(rule) = state.chaos.find(req.uri().path()) else {
+        return next.run(req).await;
+    };
+
+    // Decide every fault up front, the generator can't be held across the sleep
+    let (delay, drop, fail) = {
+        let mut rng = rand::thread_rng();
+        (
+            rng.gen_bool(rule.latency_probability),
+            rng.gen_bool(rule.drop_probability),
+            rng.gen_bool(rule.error_probability),
+        )
+    };
+
+    if delay {
+        tokio::time::sleep(Duration::from_millis(rule.latency_ms)).await;
+    }
+    if drop {
+        tracing::debug!("Dropping the connection of {} (chaos)", req.uri().path());
+        let mut response = StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        response.extensions_mut().insert(DropConnection);
+        return response;
+    }
+    if fail {
+        return match rule.error_status {
+            503 => AppError::Unavailable.into_response(),
+            _ => AppError::Internal(anyhow!("Injected fault")).into_response(),
+        };
+    }
+
+    next.run(req).await
+}
+
+/// The rules of the control endpoint
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChaosRuleSet {
+    pub rules: Vec<ChaosRule>,
+}
+
+/// `GET /admin/chaos`: list the rules currently applied
+pub async fn get_rules(State(state): State<AppState>, format: Format) -> Negotiated<ChaosRuleSet> {
+    Negotiated(
+        format,
+        ChaosRuleSet {
+            rules: state.chaos.get(),
+        },
+    )
+}
+
+/// `PUT /admin/chaos`: replace the rules, an empty list clears them
+pub async fn put_rules(
+    State(state): State<AppState>,
+    Negotiated(format, rule_set): Negotiated<ChaosRuleSet>,
+) -> Result<Negotiated<ChaosRuleSet>, AppError> {
+    for rule in &rule_set.rules {
+        rule.validate().map_err(AppError::BadRequest)?;
+    }
+
+    tracing::warn!("Applying {} chaos rule(s)", rule_set.rules.len());
+    state.chaos.set(rule_set.rules);
+    Ok(Negotiated(
+        format,
+        ChaosRuleSet {
+            rules: state.chaos.get(),
+        },
+    ))
+}
@@ -0,0 +1,87 @@
+//! Cross-origin resource sharing
+//! When `CORS_ALLOWED_ORIGINS` is set, browsers on these origins can call the API: preflight
+//! requests (`OPTIONS` with `Access-Control-Request-Method`) from them are answered right away
+//! with the allowed methods and headers, and the responses to their other requests carry the
+//! `Access-Control-Allow-*` headers. Requests from other origins get no CORS header, so browsers
+//! don't expose the responses to them.
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{config::CorsConfig, state::AppState};
+
+/// The response headers readable by the scripts of the allowed origins
+const EXPOSED_HEADERS: &str = "x-request-id, x-csrf-token, etag, retry-after";
+
+/// Answer the preflight requests, and allow the allowed origins to read the responses
+pub async fn cors(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let Some(config) = &state.config.cors else {
+        return next.run(req).await;
+    };
+    let Some(origin) = allowed_origin(config, req.headers()) else {
+        return next.run(req).await;
+    };
+
+    let preflight = req.method() == Method::OPTIONS
+        && req
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+    if preflight {
+        let mut response = StatusCode::NO_CONTENT.into_response();
+        let headers = response.headers_mut();
+        allow_origin(config, headers, origin);
+        if let Ok(methods) = HeaderValue::from_str(&config.allowed_methods.join(", ")) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        if let Ok(allowed_headers) = HeaderValue::from_str(&config.allowed_headers.join(", ")) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers);
+        }
+        headers.insert(header::ACCESS_CONTROL_MAX_AGE, config.max_age_secs.into());
+        return response;
+    }
+
+    let mut response = next.run(req).await;
+    let headers = response.headers_mut();
+    allow_origin(config, headers, origin);
+    headers.insert(
+        header::ACCESS_CONTROL_EXPOSE_HEADERS,
+        HeaderValue::from_static(EXPOSED_HEADERS),
+    );
+    response
+}
+
+/// The `Origin` of the request, if it is allowed
+fn allowed_origin(config: &CorsConfig, headers: &HeaderMap) -> Option<HeaderValue> {
+    let origin = headers.get(header::ORIGIN)?;
+    let allowed = config.allowed_origins.iter().any(|allowed| {
+        allowed == "*"
+            || origin
+                .to_str()
+                .is_ok_and(|origin| origin.eq_ignore_ascii_case(allowed))
+    });
+    allowed.then(|| origin.clone())
+}
+
+/// Let the origin read the response
+fn allow_origin(config: &CorsConfig, headers: &mut HeaderMap, origin: HeaderValue) {
+    if config.allowed_origins.iter().any(|allowed| allowed == "*") {
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            HeaderValue::from_static("*"),
+        );
+        return;
+    }
+
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.append(header::VARY, HeaderValue::from_static("origin"));
+    if config.allow_credentials {
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    }
+}
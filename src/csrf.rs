@@ -0,0 +1,127 @@
+//! Protection against cross-site request forgery
+//! Safe requests get a CSRF token stored in their session, and echoed in the `X-CSRF-Token`
+//! response header (also returned by `GET /csrf`). Unsafe requests must send it back in the
+//! `X-CSRF-Token` header, otherwise they are rejected with a `403`. The paths of
+//! `CSRF_EXEMPT_PATHS`, and the requests authenticated with an API key, a token or the
+//! `ADMIN_TOKEN`, are neither checked nor given a token: browsers never attach these credentials
+//! on their own, so such a request can't be forged. Merely carrying an `Authorization` header
+//! isn't enough, the credentials must be valid: runs within the authentication layers.
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderValue, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tower_sessions::Session;
+
+use crate::{
+    admin,
+    api_keys::AuthenticatedKey,
+    auth::jwt::AuthenticatedToken,
+    error::AppError,
+    negotiate::{Format, Negotiated},
+    session_data::AppSession,
+    state::AppState,
+};
+
+/// The header carrying the token, in both directions
+pub const X_CSRF_TOKEN: &str = "x-csrf-token";
+
+/// Give safe requests a token, and check the token of unsafe ones
+pub async fn protect(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let path = req.uri().path();
+    if state
+        .config
+        .csrf
+        .exempt_paths
+        .iter()
+        .any(|exempt| is_under(path, exempt))
+    {
+        return next.run(req).await;
+    }
+    // API clients have no use of a session, which the CSRF token would create
+    if is_api_client(&state, &req) {
+        return next.run(req).await;
+    }
+    let Some(session) = req.extensions().get::<Session>().cloned() else {
+        return next.run(req).await;
+    };
+    let session = AppSession(session);
+
+    if matches!(
+        *req.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    ) {
+        let token = match issue_token(&session).await {
+            Ok(token) => token,
+            Err(err) => return err.into_response(),
+        };
+        let mut response = next.run(req).await;
+        if let Ok(value) = HeaderValue::from_str(&token) {
+            response.headers_mut().insert(X_CSRF_TOKEN, value);
+        }
+        return response;
+    }
+
+    let expected = match session.csrf_token().await {
+        Ok(expected) => expected,
+        Err(err) => return AppError::from(err).into_response(),
+    };
+    let provided = req
+        .headers()
+        .get(X_CSRF_TOKEN)
+        .and_then(|value| value.to_str().ok());
+    match (expected, provided) {
+        (Some(expected), Some(provided))
+            if bool::from(provided.as_bytes().ct_eq(expected.as_bytes())) =>
+        {
+            next.run(req).await
+        }
+        _ => AppError::InvalidCsrfToken.into_response(),
+    }
+}
+
+/// Whether the request was authenticated with an API key, a token or the admin token
+fn is_api_client(state: &AppState, req: &Request) -> bool {
+    req.extensions().get::<AuthenticatedKey>().is_some()
+        || req.extensions().get::<AuthenticatedToken>().is_some()
+        || admin::has_admin_token(state, req.headers())
+}
+
+/// Whether the path is the given one or below it
+fn is_under(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// The token of the session, created if it has none
+async fn issue_token(session: &AppSession) -> Result<String, AppError> {
+    if let Some(token) = session.csrf_token().await? {
+        return Ok(token);
+    }
+
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token = hex::encode(bytes);
+    session.set_csrf_token(Some(token.clone())).await?;
+    Ok(token)
+}
+
+/// The CSRF token of the session
+#[derive(Serialize, Deserialize)]
+pub struct CsrfToken {
+    pub token: String,
+}
+
+/// `GET /csrf`: the CSRF token of the session
+pub async fn get_token(
+    format: Format,
+    session: AppSession,
+) -> Result<Negotiated<CsrfToken>, AppError> {
+    let token = issue_token(&session).await?;
+    Ok(Negotiated(format, CsrfToken { token }))
+}
@@ -0,0 +1,371 @@
+//! Database connection handling
+//! The backend supports SQLite, Postgres and MySQL. Every operation touching the database
+//! dispatches on the [`SqlxPool`] variant, so that each backend can use its own driver.
+//!
+//! Queries shared by every backend are written with `?` placeholders and passed through
+//! [`SqlxPool::sql`], then run with [`with_pool!`](crate::database::with_pool). Timestamps are
+//! stored as unix seconds (`BIGINT`) so that they behave the same on every backend.
+
+use std::{borrow::Cow, future::Future, path::Path, str::FromStr};
+
+use anyhow::{Context, Result};
+use log::LevelFilter;
+use sqlx::{
+    migrate::MigrateError, mysql::MySqlConnectOptions, postgres::PgConnectOptions,
+    sqlite::SqliteConnectOptions, ConnectOptions, MySqlPool, PgPool, SqlitePool,
+};
+
+use crate::config::{Config, DatabaseUri};
+
+/// Evaluate an expression with the inner pool of a [`SqlxPool`], whatever its backend
+///
+/// The expression is expanded once per backend, so it can rely on backend-specific inference:
+///
+/// ```ignore
+/// let sql = pool.sql("SELECT name FROM users WHERE id = ?");
+/// let name: String = with_pool!(&pool, |p| sqlx::query_scalar(&sql).bind(id).fetch_one(p).await)?;
+/// ```
+macro_rules! with_pool {
+    ($pool:expr, |$p:ident| $body:expr) => {
+        match $pool {
+            $crate::database::SqlxPool::Sqlite($p) => $body,
+            $crate::database::SqlxPool::Postgres($p) => $body,
+            $crate::database::SqlxPool::MySql($p) => $body,
+        }
+    };
+}
+pub(crate) use with_pool;
+
+/// The migrations applied to a database
+#[derive(Clone, Debug)]
+pub struct MigrationReport {
+    /// The version of the latest applied migration, if any
+    pub latest_version: Option<i64>,
+    /// The number of applied migrations
+    pub applied: usize,
+    /// The versions of the migrations that failed midway
+    pub dirty: Vec<i64>,
+}
+
+/// A connection pool to one of the supported databases
+#[derive(Clone, Debug)]
+pub enum SqlxPool {
+    Sqlite(SqlitePool),
+    Postgres(PgPool),
+    MySql(MySqlPool),
+}
+
+impl SqlxPool {
+    /// Connect to the database described by the configuration
+    ///
+    /// Every statement is logged at the debug level, and statements slower than the configured
+    /// threshold at the warn level. Only the SQL text is logged, never the bound values.
+    pub async fn connect(config: &Config) -> Result<SqlxPool> {
+        let uri = config.database_uri.get_connection_string();
+        tracing::info!(
+            "Connecting to the database {}",
+            config.database_uri.redacted_string()
+        );
+
+        let pool = match &config.database_uri {
+            DatabaseUri::Sqlite(path) => {
+                if config.database_tls.is_some() {
+                    tracing::warn!(
+                        "The DB_SSL_* certificates don't apply to SQLite, ignoring them"
+                    );
+                }
+                let mut options = SqliteConnectOptions::from_str(&uri)?;
+                if config.sqlite_create && !is_in_memory(path) {
+                    create_parent_dir(path)?;
+                    options = options.create_if_missing(true);
+                }
+                SqlxPool::Sqlite(SqlitePool::connect_with(with_logging(options, config)).await?)
+            }
+            DatabaseUri::Postgres(_) => {
+                let options = with_pg_tls(PgConnectOptions::from_str(&uri)?, config);
+                SqlxPool::Postgres(PgPool::connect_with(with_logging(options, config)).await?)
+            }
+            DatabaseUri::Mysql(_) => {
+                let options = with_mysql_tls(MySqlConnectOptions::from_str(&uri)?, config);
+                SqlxPool::MySql(MySqlPool::connect_with(with_logging(options, config)).await?)
+            }
+        };
+
+        Ok(pool)
+    }
+
+    /// Create the pool to the read replica, if any
+    ///
+    /// The connections are opened lazily, so that an unreachable replica doesn't prevent the
+    /// backend from starting.
+    pub fn connect_replica(config: &Config) -> Result<Option<SqlxPool>> {
+        let Some(replica_uri) = &config.database_replica_uri else {
+            return Ok(None);
+        };
+        let uri = replica_uri.get_connection_string();
+        tracing::info!("Using the read replica {}", replica_uri.redacted_string());
+
+        let pool = match replica_uri {
+            DatabaseUri::Sqlite(_) => {
+                let options = SqliteConnectOptions::from_str(&uri)?;
+                SqlxPool::Sqlite(SqlitePool::connect_lazy_with(with_logging(options, config)))
+            }
+            DatabaseUri::Postgres(_) => {
+                let options = with_pg_tls(PgConnectOptions::from_str(&uri)?, config);
+                SqlxPool::Postgres(PgPool::connect_lazy_with(with_logging(options, config)))
+            }
+            DatabaseUri::Mysql(_) => {
+                let options = with_mysql_tls(MySqlConnectOptions::from_str(&uri)?, config);
+                SqlxPool::MySql(MySqlPool::connect_lazy_with(with_logging(options, config)))
+            }
+        };
+
+        Ok(Some(pool))
+    }
+
+    /// The kind of database behind the pool
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SqlxPool::Sqlite(_) => "sqlite",
+            SqlxPool::Postgres(_) => "postgresql",
+            SqlxPool::MySql(_) => "mysql",
+        }
+    }
+
+    /// Adapt a query written with `?` placeholders to the backend of this pool
+    ///
+    /// Postgres expects numbered placeholders (`$1`, `$2`, ...). The query must not contain any
+    /// literal `?`.
+    pub fn sql<'a>(&self, query: &'a str) -> Cow<'a, str> {
+        match self {
+            SqlxPool::Postgres(_) => {
+                let mut sql = String::with_capacity(query.len() + 8);
+                let mut index = 0;
+                for c in query.chars() {
+                    if c == '?' {
+                        index += 1;
+                        sql.push_str(&format!("${}", index));
+                    } else {
+                        sql.push(c);
+                    }
+                }
+                Cow::Owned(sql)
+            }
+            _ => Cow::Borrowed(query),
+        }
+    }
+
+    /// Run the embedded migrations for the backend of this pool
+    ///
+    /// When `allow_dirty` is set, a partially applied migration is only warned about, leaving
+    /// the pending migrations unapplied; otherwise it fails. A migration the database can't parse
+    /// is reported as likely written for another backend.
+    pub async fn migrate(&self, allow_dirty: bool) -> Result<()> {
+        let result = match self {
+            SqlxPool::Sqlite(pool) => sqlx::migrate!("migrations/sqlite").run(pool).await,
+            SqlxPool::Postgres(pool) => sqlx::migrate!("migrations/postgres").run(pool).await,
+            SqlxPool::MySql(pool) => sqlx::migrate!("migrations/mysql").run(pool).await,
+        };
+        match result {
+            Err(MigrateError::Dirty(version)) if allow_dirty => {
+                tracing::warn!(
+                    "Migration {} is dirty (partially applied), the pending migrations were not \
+                     applied",
+                    version
+                );
+                Ok(())
+            }
+            Err(MigrateError::Execute(err)) if self.is_syntax_error(&err) => Err(self
+                .mismatch_error(anyhow::Error::new(err))
+                .context("Failed to run database migrations")),
+            result => result.with_context(|| "Failed to run database migrations"),
+        }
+    }
+
+    /// Annotate an error of a migration with the likely cause when the database couldn't parse
+    /// it, e.g. for the migrations of a module
+    pub fn annotate_migration_error(&self, err: anyhow::Error) -> anyhow::Error {
+        let is_syntax_error = err.chain().any(|cause| {
+            let err = match cause.downcast_ref::<MigrateError>() {
+                Some(MigrateError::Execute(err)) => Some(err),
+                _ => cause.downcast_ref::<sqlx::Error>(),
+            };
+            err.is_some_and(|err| self.is_syntax_error(err))
+        });
+        if is_syntax_error {
+            self.mismatch_error(err)
+        } else {
+            err
+        }
+    }
+
+    /// Whether the database refused a statement as invalid SQL for it
+    fn is_syntax_error(&self, err: &sqlx::Error) -> bool {
+        let sqlx::Error::Database(err) = err else {
+            return false;
+        };
+        match self {
+            // SQLite only has a generic error code, the parse errors are told by their message
+            SqlxPool::Sqlite(_) => ["syntax error", "unrecognized token", "incomplete input"]
+                .iter()
+                .any(|message| err.message().contains(message)),
+            // `syntax_error`, and `undefined_object` for unknown types (e.g. `DATETIME`)
+            SqlxPool::Postgres(_) => matches!(err.code().as_deref(), Some("42601" | "42704")),
+            // `ER_PARSE_ERROR`, its SQLSTATE being shared with unrelated errors
+            SqlxPool::MySql(_) => err
+                .try_downcast_ref::<sqlx::mysql::MySqlDatabaseError>()
+                .is_some_and(|err| err.number() == 1064),
+        }
+    }
+
+    /// Add the backend/migration mismatch guidance to an error
+    fn mismatch_error(&self, err: anyhow::Error) -> anyhow::Error {
+        err.context(format!(
+            "This looks like a backend/migration mismatch for {}: a migration uses SQL this \
+             database doesn't understand, check that it was written for {}",
+            self.kind(),
+            self.kind()
+        ))
+    }
+
+    /// Describe the migrations applied to the database
+    pub async fn migration_report(&self) -> Result<MigrationReport> {
+        let sql = "SELECT version, success FROM _sqlx_migrations ORDER BY version";
+        let migrations: Vec<(i64, bool)> =
+            with_pool!(self, |p| sqlx::query_as(sql).fetch_all(p).await)
+                .with_context(|| "Failed to read the applied migrations")?;
+
+        Ok(MigrationReport {
+            latest_version: migrations.last().map(|(version, _)| *version),
+            applied: migrations.len(),
+            dirty: migrations
+                .iter()
+                .filter(|(_, success)| !success)
+                .map(|(version, _)| *version)
+                .collect(),
+        })
+    }
+
+    /// Make sure the pool holds at least one working connection
+    pub async fn warm_up(&self) -> Result<()> {
+        self.ping()
+            .await
+            .with_context(|| "Failed to warm up the database pool")
+    }
+
+    /// Close the pool, waiting for the connections in use to be returned
+    ///
+    /// The connections are closed cleanly rather than dropped, so that the database doesn't keep
+    /// them until they time out. Queries made afterwards fail with [`sqlx::Error::PoolClosed`].
+    pub async fn close(&self) {
+        match self {
+            SqlxPool::Sqlite(pool) => pool.close().await,
+            SqlxPool::Postgres(pool) => pool.close().await,
+            SqlxPool::MySql(pool) => pool.close().await,
+        }
+    }
+
+    /// Run a trivial statement to check that the database is reachable
+    pub async fn ping(&self) -> Result<(), sqlx::Error> {
+        with_pool!(self, |p| sqlx::query("SELECT 1")
+            .execute(p)
+            .await
+            .map(|_| ()))
+    }
+}
+
+/// Whether the SQLite database path designates an in-memory database
+fn is_in_memory(path: &str) -> bool {
+    path.starts_with(":memory:") || path.contains("mode=memory")
+}
+
+/// Create the directory containing the SQLite database file, if missing
+fn create_parent_dir(path: &str) -> Result<()> {
+    let file = path.split_once('?').map_or(path, |(file, _)| file);
+    match Path::new(file).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create the directory {}", dir.display())),
+        _ => Ok(()),
+    }
+}
+
+/// Apply the TLS certificates of the configuration to the given Postgres connect options
+///
+/// Whether TLS is used, and how the server is verified, is still up to the `sslmode` of the URI.
+fn with_pg_tls(mut options: PgConnectOptions, config: &Config) -> PgConnectOptions {
+    let Some(tls) = &config.database_tls else {
+        return options;
+    };
+    if let Some(client) = &tls.client {
+        options = options
+            .ssl_client_cert_from_pem(&client.cert)
+            .ssl_client_key_from_pem(&client.key);
+    }
+    if let Some(root_cert) = &tls.root_cert {
+        options = options.ssl_root_cert_from_pem(root_cert.clone());
+    }
+    options
+}
+
+/// Apply the TLS certificates of the configuration to the given MySQL connect options
+///
+/// Whether TLS is used, and how the server is verified, is still up to the `ssl-mode` of the URI.
+fn with_mysql_tls(mut options: MySqlConnectOptions, config: &Config) -> MySqlConnectOptions {
+    let Some(tls) = &config.database_tls else {
+        return options;
+    };
+    if let Some(client) = &tls.client {
+        options = options
+            .ssl_client_cert_from_pem(&client.cert)
+            .ssl_client_key_from_pem(&client.key);
+    }
+    if let Some(root_cert) = &tls.root_cert {
+        options = options.ssl_ca_from_pem(root_cert.clone());
+    }
+    options
+}
+
+/// Configure the statement logging of the given connect options
+fn with_logging<O: ConnectOptions>(options: O, config: &Config) -> O {
+    options
+        .log_statements(LevelFilter::Debug)
+        .log_slow_statements(LevelFilter::Warn, config.slow_query_threshold)
+}
+
+/// Whether the error is a violation of a unique constraint
+pub fn is_unique_violation(err: &sqlx::Error) -> bool {
+    err.as_database_error()
+        .is_some_and(|err| err.is_unique_violation())
+}
+
+/// Whether the error comes from the connection (e.g. closed by a network blip), rather than from
+/// the query
+///
+/// A query the database refused, or a pool that timed out or was closed, isn't a connection
+/// error: running the query again wouldn't help.
+pub fn is_connection_error(err: &sqlx::Error) -> bool {
+    matches!(
+        err,
+        sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::Protocol(_)
+            | sqlx::Error::WorkerCrashed
+    )
+}
+
+/// Run an idempotent read, running it again once if its connection failed
+///
+/// The pool discards the failed connection, so the second attempt runs on another one.
+pub async fn retry_read<T, F, Fut>(mut read: F) -> Result<T, sqlx::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+{
+    match read().await {
+        Err(err) if is_connection_error(&err) => {
+            tracing::warn!("Retrying a read after a connection error: {}", err);
+            read().await
+        }
+        result => result,
+    }
+}
@@ -0,0 +1,245 @@
+//! Errors returned by the handlers
+//! Every error is rendered as a JSON envelope `{"error": {"code": ..., "message": ...}}`. The
+//! `code` is machine-readable and stable, while the `message` is meant for humans and resolved
+//! through the message catalog (see [`crate::i18n`]).
+
+use std::time::Duration;
+
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{auth::password_policy::PolicyViolation, i18n};
+
+/// An error returned by a handler
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    /// None of the formats accepted by the client can be produced
+    #[error("None of the accepted formats can be produced")]
+    NotAcceptable,
+    /// The request body is in a format we don't understand
+    #[error("Unsupported content type")]
+    UnsupportedMediaType,
+    /// The request is malformed
+    #[error("{0}")]
+    BadRequest(String),
+    /// The request lacks valid credentials
+    #[error("Authentication required")]
+    Unauthorized,
+    /// The credentials are valid, but don't grant access
+    #[error("Access denied")]
+    Forbidden,
+    /// Users can't register themselves
+    #[error("Registration is disabled")]
+    RegistrationDisabled,
+    /// Users only log in through OpenID Connect
+    #[error("The password login is disabled")]
+    PasswordLoginDisabled,
+    /// The password of the account is managed by the directory, not locally
+    #[error("The password of this account is managed by the directory")]
+    ExternalPassword,
+    /// The user must verify their email before logging in
+    #[error("The email must be verified first")]
+    EmailNotVerified,
+    /// The invitation expired before being accepted
+    #[error("The invitation expired")]
+    InvitationExpired,
+    /// The invitation was revoked, or superseded by a newer one
+    #[error("The invitation was revoked")]
+    InvitationRevoked,
+    /// The session impersonates a user, who alone may do this
+    #[error("Not allowed while impersonating a user")]
+    ImpersonationForbidden,
+    /// The request is well-formed, but one of its values is out of range
+    #[error("{0}")]
+    Unprocessable(String),
+    /// The new password breaks the policy
+    #[error("The password doesn't meet the policy")]
+    PasswordPolicy(Vec<PolicyViolation>),
+    /// The user must change their password before anything else
+    #[error("The password must be changed first")]
+    PasswordChangeRequired,
+    /// The CSRF token of the request is missing or doesn't match the one of the session
+    #[error("Missing or invalid CSRF token")]
+    InvalidCsrfToken,
+    /// The resource doesn't exist
+    #[error("Not found")]
+    NotFound,
+    /// No route matches the path
+    #[error("Not found")]
+    NoRoute(String),
+    /// The resource doesn't support the method, but supports the listed ones
+    #[error("Method not allowed")]
+    MethodNotAllowed(Vec<String>),
+    /// The request conflicts with the current state of the resource
+    #[error("{0}")]
+    Conflict(String),
+    /// The client sent too many requests, and may retry after the delay
+    #[error("Too many requests")]
+    TooManyRequests(Duration),
+    /// The service can't handle the request for now
+    #[error("Service temporarily unavailable")]
+    Unavailable,
+    /// An unexpected failure
+    #[error("Internal server error")]
+    Internal(#[from] anyhow::Error),
+}
+
+impl AppError {
+    /// The status code of the response
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::NotAcceptable => StatusCode::NOT_ACCEPTABLE,
+            AppError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unprocessable(_) | AppError::PasswordPolicy(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden
+            | AppError::RegistrationDisabled
+            | AppError::PasswordLoginDisabled
+            | AppError::ExternalPassword
+            | AppError::EmailNotVerified
+            | AppError::PasswordChangeRequired
+            | AppError::ImpersonationForbidden
+            | AppError::InvalidCsrfToken => StatusCode::FORBIDDEN,
+            AppError::InvitationExpired | AppError::InvitationRevoked => StatusCode::GONE,
+            AppError::NotFound | AppError::NoRoute(_) => StatusCode::NOT_FOUND,
+            AppError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The machine-readable code of the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::NotAcceptable => "not_acceptable",
+            AppError::UnsupportedMediaType => "unsupported_media_type",
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Unprocessable(_) => "unprocessable",
+            AppError::PasswordPolicy(_) => "password_policy",
+            AppError::Unauthorized => "unauthorized",
+            AppError::Forbidden => "forbidden",
+            AppError::RegistrationDisabled => "registration_disabled",
+            AppError::PasswordLoginDisabled => "password_login_disabled",
+            AppError::ExternalPassword => "password_managed_externally",
+            AppError::EmailNotVerified => "email_not_verified",
+            AppError::InvitationExpired => "invitation_expired",
+            AppError::InvitationRevoked => "invitation_revoked",
+            AppError::ImpersonationForbidden => "impersonation_forbidden",
+            AppError::PasswordChangeRequired => "password_change_required",
+            AppError::InvalidCsrfToken => "invalid_csrf_token",
+            AppError::NotFound | AppError::NoRoute(_) => "not_found",
+            AppError::MethodNotAllowed(_) => "method_not_allowed",
+            AppError::Conflict(_) => "conflict",
+            AppError::TooManyRequests(_) => "too_many_requests",
+            AppError::Unavailable => "unavailable",
+            AppError::Internal(_) => "internal",
+        }
+    }
+
+    /// The arguments substituted in the message of the error
+    pub fn args(&self) -> Vec<String> {
+        match self {
+            AppError::BadRequest(detail)
+            | AppError::Unprocessable(detail)
+            | AppError::Conflict(detail) => vec![detail.clone()],
+            AppError::MethodNotAllowed(allowed) => vec![allowed.join(", ")],
+            AppError::PasswordPolicy(violations) => vec![violations
+                .iter()
+                .map(|violation| violation.message.as_str())
+                .collect::<Vec<_>>()
+                .join("; ")],
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(err: sqlx::Error) -> Self {
+        AppError::Internal(err.into())
+    }
+}
+
+impl From<tower_sessions::session::Error> for AppError {
+    fn from(err: tower_sessions::session::Error) -> Self {
+        AppError::Internal(err.into())
+    }
+}
+
+/// The cause of an internal error, kept in the extensions of the response to report it
+#[derive(Clone, Debug)]
+pub struct InternalErrorDetail(pub String);
+
+/// The body of an error response
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ErrorEnvelope {
+    pub error: ErrorBody,
+}
+
+/// The details of an error
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+    /// The arguments of the message, to render it in another locale
+    #[serde(skip)]
+    pub args: Vec<String>,
+    /// The path no route matches, for the `not_found` errors of the router
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// The rules broken by a password, for the `password_policy` errors
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<PolicyViolation>,
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let code = self.code();
+        let args = self.args();
+        let envelope = ErrorEnvelope {
+            error: ErrorBody {
+                code: code.to_string(),
+                message: i18n::message(i18n::DEFAULT_LOCALE, code, &args),
+                args,
+                path: match &self {
+                    AppError::NoRoute(path) => Some(path.clone()),
+                    _ => None,
+                },
+                violations: match &self {
+                    AppError::PasswordPolicy(violations) => violations.clone(),
+                    _ => Vec::new(),
+                },
+            },
+        };
+
+        // The envelope is kept in the extensions so that it can be localized and re-encoded in
+        // the negotiated format (see `i18n::localize` and `negotiate::encode_errors`)
+        let mut response = (self.status(), Json(envelope.clone())).into_response();
+        response.extensions_mut().insert(envelope);
+        if let AppError::MethodNotAllowed(allowed) = &self {
+            if let Ok(allow) = HeaderValue::from_str(&allowed.join(",")) {
+                response.headers_mut().insert(header::ALLOW, allow);
+            }
+        }
+        if let AppError::TooManyRequests(retry_after) = &self {
+            response.headers_mut().insert(
+                header::RETRY_AFTER,
+                HeaderValue::from(retry_after.as_secs().max(1)),
+            );
+        }
+        if let AppError::Internal(err) = &self {
+            response
+                .extensions_mut()
+                .insert(InternalErrorDetail(format!("{:#}", err)));
+        }
+        response
+    }
+}
@@ -0,0 +1,70 @@
+//! Conditional GET
+//! Routes opting in get a strong `ETag` computed from their response body, and answer
+//! `304 Not Modified` when the client already has that representation. The body is hashed as
+//! sent, after content negotiation, so each format gets its own tag.
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+
+/// Tag the successful responses of GET requests and honor `If-None-Match`
+pub async fn conditional(req: Request, next: Next) -> Response {
+    if !matches!(*req.method(), Method::GET | Method::HEAD) {
+        return next.run(req).await;
+    }
+
+    let if_none_match = req.headers().get(header::IF_NONE_MATCH).cloned();
+    let response = next.run(req).await;
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = to_bytes(body, usize::MAX).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+
+    let etag = format!("\"{}\"", &hex::encode(Sha256::digest(&bytes))[..32]);
+    let headers = &mut parts.headers;
+    headers.insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("private, no-cache"),
+    );
+    headers.append(header::VARY, HeaderValue::from_static("accept"));
+
+    if if_none_match.is_some_and(|value| matches_any(&value, &etag)) {
+        let mut not_modified = StatusCode::NOT_MODIFIED.into_response();
+        copy_validators(headers, not_modified.headers_mut());
+        return not_modified;
+    }
+
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+/// Whether an `If-None-Match` value matches the tag
+///
+/// `If-None-Match` uses the weak comparison: `W/"x"` matches `"x"`.
+fn matches_any(if_none_match: &HeaderValue, etag: &str) -> bool {
+    let Ok(value) = if_none_match.to_str() else {
+        return false;
+    };
+
+    value.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    })
+}
+
+/// Copy the headers a `304` must carry
+fn copy_validators(from: &HeaderMap, to: &mut HeaderMap) {
+    for name in [header::ETAG, header::CACHE_CONTROL, header::VARY] {
+        for value in from.get_all(&name) {
+            to.append(name.clone(), value.clone());
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Events of the backend
+//! Noteworthy changes, such as an account being locked, are published on the [`EventBus`] of the
+//! state, for embedders and background tasks to react to them. Publishing never waits: a
+//! subscriber falling too far behind misses the oldest events.
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+/// The number of events kept for the slowest subscriber
+const CAPACITY: usize = 256;
+
+/// An event of interest to the administrators
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AdminEvent {
+    /// An account was locked after repeated failed logins
+    AccountLocked {
+        user_id: i64,
+        /// When the lock expires, in unix seconds
+        locked_until: i64,
+    },
+    /// A user with two-factor authentication is running out of recovery codes
+    RecoveryCodesLow { user_id: i64, remaining: i64 },
+    /// A setting was changed or unset by an admin
+    SettingChanged { key: String },
+    /// A user was created, by an admin, an invitation, a registration or on their first login
+    UserCreated { user_id: i64 },
+    /// The sessions of a user were revoked, after their deactivation or a password reset
+    SessionsRevoked { user_id: i64, revoked: u64 },
+}
+
+impl AdminEvent {
+    /// The type of every event, as serialized
+    pub const TYPES: &'static [&'static str] = &[
+        "account_locked",
+        "recovery_codes_low",
+        "setting_changed",
+        "user_created",
+        "sessions_revoked",
+    ];
+
+    /// The type of the event, as serialized
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            AdminEvent::AccountLocked { .. } => "account_locked",
+            AdminEvent::RecoveryCodesLow { .. } => "recovery_codes_low",
+            AdminEvent::SettingChanged { .. } => "setting_changed",
+            AdminEvent::UserCreated { .. } => "user_created",
+            AdminEvent::SessionsRevoked { .. } => "sessions_revoked",
+        }
+    }
+}
+
+/// Where events are published
+#[derive(Clone, Debug)]
+pub struct EventBus {
+    sender: broadcast::Sender<AdminEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Self { sender }
+    }
+}
+
+impl EventBus {
+    /// Publish an event to the current subscribers
+    pub fn publish(&self, event: AdminEvent) {
+        tracing::debug!("Publishing {:?}", event);
+        // Having no subscriber is fine
+        let _ = self.sender.send(event);
+    }
+
+    /// Receive the events published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<AdminEvent> {
+        self.sender.subscribe()
+    }
+}
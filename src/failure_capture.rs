@@ -0,0 +1,140 @@
+//! Capture of the bodies of failed requests, to reproduce client reports
+//! When `DEBUG_CAPTURE_FAILED_BODIES` is set, request bodies announced as small enough are
+//! buffered. If the response is an error (`>= 400`), the body is redacted like in the audit log,
+//! logged at debug level with the request ID, and kept in a ring buffer listed by
+//! `GET /admin/debug/failures`. Nothing is buffered when disabled.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{OriginalUri, Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{
+    error::AppError,
+    negotiate::{Format, Negotiated},
+    redact::redact_json,
+    request_id::RequestId,
+    state::AppState,
+};
+
+/// A request answered with an error
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FailedRequest {
+    /// When the request completed, in unix seconds
+    pub created_at: i64,
+    pub request_id: Option<String>,
+    pub method: String,
+    pub route: String,
+    /// The status of the response
+    pub status: u16,
+    /// The redacted JSON body, if the request had a JSON body small enough to be captured
+    pub body: Option<String>,
+}
+
+/// The last failed requests, most recent last
+#[derive(Clone, Default)]
+pub struct FailureLog(Arc<Mutex<VecDeque<FailedRequest>>>);
+
+impl FailureLog {
+    /// Keep a failed request, dropping the oldest one beyond `retained`
+    fn push(&self, failure: FailedRequest, retained: usize) {
+        let mut failures = self.0.lock().unwrap();
+        while failures.len() >= retained.max(1) {
+            failures.pop_front();
+        }
+        failures.push_back(failure);
+    }
+
+    /// The failed requests kept, most recent first
+    pub fn list(&self) -> Vec<FailedRequest> {
+        self.0.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+/// Capture the body of requests answered with an error
+pub async fn capture_failed_bodies(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let request_id = req.extensions().get::<RequestId>().map(|id| id.0.clone());
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<OriginalUri>()
+        .map_or(req.uri().path(), |uri| uri.0.path())
+        .to_string();
+
+    // Only buffer bodies that are announced as small enough
+    let max_body_size = state.config.debug.max_body_bytes;
+    let announced_size = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+
+    let (req, bytes) = match announced_size {
+        Some(size) if size > 0 && size <= max_body_size => {
+            let (parts, body) = req.into_parts();
+            let bytes = match to_bytes(body, max_body_size).await {
+                Ok(bytes) => bytes,
+                Err(_) => {
+                    return AppError::BadRequest("Invalid request body".to_string()).into_response()
+                }
+            };
+            (
+                Request::from_parts(parts, Body::from(bytes.clone())),
+                Some(bytes),
+            )
+        }
+        _ => (req, None),
+    };
+
+    let response = next.run(req).await;
+    if !(response.status().is_client_error() || response.status().is_server_error()) {
+        return response;
+    }
+
+    let body = bytes
+        .and_then(|bytes| redact_json(&bytes, &state.config.audit.redact_fields))
+        .map(|value| value.to_string());
+    tracing::debug!(
+        "{} {} failed with {} (request {}): {}",
+        method,
+        route,
+        response.status().as_u16(),
+        request_id.as_deref().unwrap_or("-"),
+        body.as_deref().unwrap_or("<no captured body>")
+    );
+    state.failures.push(
+        FailedRequest {
+            created_at: OffsetDateTime::now_utc().unix_timestamp(),
+            request_id,
+            method,
+            route,
+            status: response.status().as_u16(),
+            body,
+        },
+        state.config.debug.retained,
+    );
+
+    response
+}
+
+/// `GET /admin/debug/failures`: list the last failed requests
+pub async fn list_failures(
+    State(state): State<AppState>,
+    format: Format,
+) -> Negotiated<Vec<FailedRequest>> {
+    Negotiated(format, state.failures.list())
+}
@@ -0,0 +1,359 @@
+//! Feature flags
+//! Risky features can be shipped dark and turned on progressively. The flags are declared in
+//! [`FLAGS`] with their default, and their state is stored with the
+//! [settings](crate::settings) under the `flags.<name>` key, so that evaluating a flag only reads
+//! the in-memory copy of the settings. A flag is evaluated for a user by, in order:
+//!
+//! 1. the override of the user, if any;
+//! 2. the overrides of their groups, if any, a disabling override winning;
+//! 3. the switch of the flag, a disabled flag being off for everyone else;
+//! 4. the rollout: the flag is on for the given percentage of the users, picked by a stable hash
+//!    of the name of the flag and the identifier of the user. The same users keep the flag while
+//!    the percentage grows, and each flag picks different users.
+//!
+//! Users fetch their evaluated flags from `/users/me/flags`, and the admins change them through
+//! `/admin/flags`.
+
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    audit::AuditActor,
+    auth::current_user::CurrentUser,
+    error::AppError,
+    events::AdminEvent,
+    groups::GroupRepository,
+    negotiate::{Format, Negotiated},
+    settings::Settings,
+    state::AppState,
+    users::User,
+};
+
+/// The new dashboard of the SPA
+pub const NEW_DASHBOARD: &str = "new_dashboard";
+
+/// Every feature flag known to the backend
+pub const FLAGS: &[FlagDefinition] = &[FlagDefinition {
+    name: NEW_DASHBOARD,
+    description: "The new dashboard of the SPA",
+    default: false,
+}];
+
+/// A flag registered in [`FLAGS`]
+#[derive(Clone, Copy, Debug)]
+pub struct FlagDefinition {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Whether the flag is on for everyone while its state is unset
+    pub default: bool,
+}
+
+impl FlagDefinition {
+    /// The definition of the flag with this name, if it is known
+    pub fn find(name: &str) -> Option<&'static FlagDefinition> {
+        FLAGS.iter().find(|definition| definition.name == name)
+    }
+
+    /// The state of the flag while it is unset
+    fn default_state(&self) -> FlagState {
+        FlagState {
+            enabled: self.default,
+            rollout_percent: 100,
+            users: BTreeMap::new(),
+            groups: BTreeMap::new(),
+        }
+    }
+}
+
+/// The state of a flag, as stored
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlagState {
+    /// Whether the flag is on, for the users without an override
+    pub enabled: bool,
+    /// The percentage of the users the flag is on for, when enabled
+    #[serde(default = "full_rollout")]
+    pub rollout_percent: u8,
+    /// Whether the flag is on for some users, by identifier
+    #[serde(default)]
+    pub users: BTreeMap<i64, bool>,
+    /// Whether the flag is on for the members of some groups, by identifier
+    #[serde(default)]
+    pub groups: BTreeMap<i64, bool>,
+}
+
+fn full_rollout() -> u8 {
+    100
+}
+
+impl FlagState {
+    /// Whether the flag is on for the subject
+    pub fn evaluate(&self, name: &str, subject: &FlagSubject) -> bool {
+        if let Some(enabled) = self.users.get(&subject.user_id) {
+            return *enabled;
+        }
+        let mut overrides = subject
+            .group_ids
+            .iter()
+            .filter_map(|group_id| self.groups.get(group_id));
+        if let Some(first) = overrides.next() {
+            return *first && overrides.all(|enabled| *enabled);
+        }
+        self.enabled && rollout_bucket(name, subject.user_id) < self.rollout_percent as u64
+    }
+}
+
+/// The bucket of a user for a flag, between 0 and 99, stable across restarts and replicas
+pub fn rollout_bucket(name: &str, user_id: i64) -> u64 {
+    let digest = Sha256::digest(format!("{}:{}", name, user_id).as_bytes());
+    let mut prefix = [0; 8];
+    prefix.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(prefix) % 100
+}
+
+/// Who a flag is evaluated for
+#[derive(Clone, Debug, Default)]
+pub struct FlagSubject {
+    pub user_id: i64,
+    /// The identifiers of the groups of the user
+    pub group_ids: Vec<i64>,
+}
+
+impl FlagSubject {
+    /// A user, with their groups read from the database
+    ///
+    /// Load it once and evaluate as many flags as needed against it.
+    pub async fn load(state: &AppState, user: &User) -> Result<Self, AppError> {
+        let group_ids = GroupRepository::new(state.read_pool().clone())
+            .groups_of(user.id)
+            .await?;
+        Ok(Self {
+            user_id: user.id,
+            group_ids,
+        })
+    }
+}
+
+/// The settings key of the state of a flag
+fn key(name: &str) -> String {
+    format!("flags.{}", name)
+}
+
+/// The feature flags, evaluated against the in-memory copy of the settings
+#[derive(Clone, Debug)]
+pub struct FeatureFlags {
+    settings: Settings,
+}
+
+impl FeatureFlags {
+    pub fn new(settings: Settings) -> Self {
+        Self { settings }
+    }
+
+    /// The state of a registered flag, its default when unset
+    pub fn state(&self, definition: &FlagDefinition) -> FlagState {
+        self.settings
+            .get(&key(definition.name))
+            .unwrap_or_else(|| definition.default_state())
+    }
+
+    /// Whether a flag is on for the subject, an unknown flag being off
+    pub fn enabled(&self, name: &str, subject: &FlagSubject) -> bool {
+        match FlagDefinition::find(name) {
+            Some(definition) => self.state(definition).evaluate(name, subject),
+            None => {
+                tracing::warn!("Evaluating the unknown feature flag {}", name);
+                false
+            }
+        }
+    }
+
+    /// Whether every registered flag is on for the subject
+    pub fn evaluate_all(&self, subject: &FlagSubject) -> BTreeMap<String, bool> {
+        FLAGS
+            .iter()
+            .map(|definition| {
+                let enabled = self.state(definition).evaluate(definition.name, subject);
+                (definition.name.to_string(), enabled)
+            })
+            .collect()
+    }
+}
+
+/// A flag, as listed to the admins
+#[derive(Serialize, Deserialize)]
+pub struct FlagView {
+    pub name: String,
+    pub description: String,
+    /// Whether the flag is on for everyone while its state is unset
+    pub default: bool,
+    /// The current state of the flag
+    pub state: FlagState,
+    /// When the flag was last changed, in unix seconds, unless it is unset
+    pub updated_at: Option<i64>,
+    /// Who last changed the flag, if known
+    pub updated_by: Option<String>,
+}
+
+fn view(state: &AppState, definition: &FlagDefinition) -> FlagView {
+    let stored = state.settings.stored(&key(definition.name));
+    FlagView {
+        name: definition.name.to_string(),
+        description: definition.description.to_string(),
+        default: definition.default,
+        state: state.flags.state(definition),
+        updated_at: stored.as_ref().map(|stored| stored.updated_at),
+        updated_by: stored.and_then(|stored| stored.updated_by),
+    }
+}
+
+/// `GET /admin/flags`: list the flags with their current state
+pub async fn list_flags(
+    State(state): State<AppState>,
+    format: Format,
+) -> Negotiated<Vec<FlagView>> {
+    let views = FLAGS
+        .iter()
+        .map(|definition| view(&state, definition))
+        .collect();
+    Negotiated(format, views)
+}
+
+/// `PUT /admin/flags/:name`: replace the state of a flag
+pub async fn update_flag(
+    State(state): State<AppState>,
+    AuditActor(audit_actor): AuditActor,
+    Path(name): Path<String>,
+    Negotiated(format, flag): Negotiated<FlagState>,
+) -> Result<Negotiated<FlagView>, AppError> {
+    let definition = FlagDefinition::find(&name).ok_or(AppError::NotFound)?;
+    if flag.rollout_percent > 100 {
+        return Err(AppError::BadRequest(
+            "rollout_percent: expected a percentage between 0 and 100".to_string(),
+        ));
+    }
+
+    let previous = state.flags.state(definition);
+    state
+        .settings
+        .set(&key(&name), Some(&json!(flag)), audit_actor.as_deref())
+        .await?;
+    changed(&state, audit_actor, &name, &previous, Some(&flag));
+    Ok(Negotiated(format, view(&state, definition)))
+}
+
+/// `DELETE /admin/flags/:name`: reset a flag to its default
+pub async fn reset_flag(
+    State(state): State<AppState>,
+    AuditActor(audit_actor): AuditActor,
+    Path(name): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let definition = FlagDefinition::find(&name).ok_or(AppError::NotFound)?;
+    let previous = state.flags.state(definition);
+    state
+        .settings
+        .set(&key(&name), None, audit_actor.as_deref())
+        .await?;
+    changed(&state, audit_actor, &name, &previous, None);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Record the change of a flag in the audit log and publish it
+fn changed(
+    state: &AppState,
+    audit_actor: Option<String>,
+    name: &str,
+    previous: &FlagState,
+    flag: Option<&FlagState>,
+) {
+    state.audit.record(
+        audit_actor,
+        "flag.changed",
+        name,
+        json!({ "previous": previous, "state": flag }),
+    );
+    state
+        .events
+        .publish(AdminEvent::SettingChanged { key: key(name) });
+    tracing::info!("Changed the feature flag {}", name);
+}
+
+/// `GET /users/me/flags`: whether every flag is on for the logged in user
+pub async fn my_flags(
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+    format: Format,
+) -> Result<Negotiated<BTreeMap<String, bool>>, AppError> {
+    let subject = FlagSubject::load(&state, &user).await?;
+    Ok(Negotiated(format, state.flags.evaluate_all(&subject)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flag(enabled: bool, rollout_percent: u8) -> FlagState {
+        FlagState {
+            enabled,
+            rollout_percent,
+            users: BTreeMap::new(),
+            groups: BTreeMap::new(),
+        }
+    }
+
+    fn subject(user_id: i64, group_ids: &[i64]) -> FlagSubject {
+        FlagSubject {
+            user_id,
+            group_ids: group_ids.to_vec(),
+        }
+    }
+
+    /// The users out of the first thousand the flag is on for
+    fn enabled_users(state: &FlagState, name: &str) -> Vec<i64> {
+        (0..1000)
+            .filter(|user_id| state.evaluate(name, &subject(*user_id, &[])))
+            .collect()
+    }
+
+    #[test]
+    fn rolls_out_to_a_stable_share_of_the_users() {
+        let ten = enabled_users(&flag(true, 10), NEW_DASHBOARD);
+        assert!((50..150).contains(&ten.len()), "{}", ten.len());
+        assert_eq!(enabled_users(&flag(true, 10), NEW_DASHBOARD), ten);
+
+        // The users keep the flag as the rollout grows
+        let fifty = enabled_users(&flag(true, 50), NEW_DASHBOARD);
+        assert!(ten.iter().all(|user_id| fifty.contains(user_id)));
+        assert_eq!(enabled_users(&flag(true, 100), NEW_DASHBOARD).len(), 1000);
+        assert!(enabled_users(&flag(true, 0), NEW_DASHBOARD).is_empty());
+        assert!(enabled_users(&flag(false, 100), NEW_DASHBOARD).is_empty());
+
+        // Each flag picks other users
+        assert_ne!(enabled_users(&flag(true, 10), "other_flag"), ten);
+    }
+
+    #[test]
+    fn applies_the_overrides_in_order() {
+        let mut state = flag(false, 100);
+        state.groups = BTreeMap::from([(1, true), (2, false)]);
+        state.users = BTreeMap::from([(10, false), (11, true)]);
+
+        // The user override comes first, even against a group
+        assert!(!state.evaluate(NEW_DASHBOARD, &subject(10, &[1])));
+        assert!(state.evaluate(NEW_DASHBOARD, &subject(11, &[2])));
+        // Then the groups, a disabling override winning
+        assert!(state.evaluate(NEW_DASHBOARD, &subject(12, &[1, 3])));
+        assert!(!state.evaluate(NEW_DASHBOARD, &subject(12, &[1, 2])));
+        // Then the switch
+        assert!(!state.evaluate(NEW_DASHBOARD, &subject(12, &[3])));
+        state.enabled = true;
+        assert!(state.evaluate(NEW_DASHBOARD, &subject(12, &[3])));
+        assert!(!state.evaluate(NEW_DASHBOARD, &subject(12, &[2])));
+    }
+}
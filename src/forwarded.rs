@@ -0,0 +1,171 @@
+//! Requests forwarded by a TLS-terminating proxy
+//! Behind such a proxy, requests reach the backend over plain HTTP while the clients use HTTPS.
+//! With `TRUST_FORWARDED_PROTO`, the `X-Forwarded-Proto` header tells whether a request is
+//! effectively secure, but only when the connection comes from one of the `TRUSTED_PROXIES`
+//! (anyone else could forge it). The cookies of secure requests are marked `Secure`, and their
+//! responses carry `Strict-Transport-Security` when `HSTS_MAX_AGE_SECS` is set.
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{config::Config, state::AppState};
+
+/// The header set by the proxies to the scheme used by the client
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+/// The address of the peer of the connection, the proxy when there is one
+///
+/// Unlike [`ConnectInfo`](axum::extract::ConnectInfo), it is never replaced by the client address
+/// relayed through the PROXY protocol.
+#[derive(Clone, Copy, Debug)]
+pub struct PeerAddr(pub SocketAddr);
+
+/// Whether the request is effectively made over HTTPS, set in the request extensions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Secure(pub bool);
+
+/// A range of IP addresses, such as `10.0.0.0/8` or a single address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    /// Whether the address is in the range (IPv4-mapped IPv6 addresses included)
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+            IpAddr::V4(_) => addr,
+        };
+        match (self.addr, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("'{}' is not an IP address", addr))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("'{}' is not a valid prefix length", prefix_len))?,
+            None => max_len,
+        };
+        Ok(IpNetwork { addr, prefix_len })
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Whether the request is effectively made over HTTPS, according to a trusted proxy
+pub fn is_secure(req: &Request, config: &Config) -> bool {
+    if !config.forwarded.trust_proto {
+        return false;
+    }
+
+    let trusted = req.extensions().get::<PeerAddr>().is_some_and(|peer| {
+        config
+            .forwarded
+            .trusted_proxies
+            .iter()
+            .any(|network| network.contains(peer.0.ip()))
+    });
+    if !trusted {
+        return false;
+    }
+
+    // Each proxy appends its own value, the first ones may come from the client: only the last
+    // one, set by the trusted proxy, can be relied upon
+    req.headers()
+        .get_all(X_FORWARDED_PROTO)
+        .iter()
+        .next_back()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"))
+}
+
+/// Mark the cookies of secure requests as `Secure` and add the HSTS header to their responses
+pub async fn secure(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let secure = is_secure(&req, &state.config);
+    req.extensions_mut().insert(Secure(secure));
+
+    let mut response = next.run(req).await;
+    if !secure {
+        return response;
+    }
+
+    let headers = response.headers_mut();
+    let cookies: Vec<HeaderValue> = headers
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .cloned()
+        .collect();
+    if !cookies.is_empty() {
+        headers.remove(header::SET_COOKIE);
+        for cookie in cookies {
+            let is_secure = cookie.to_str().is_ok_and(|cookie| {
+                cookie
+                    .split(';')
+                    .any(|attribute| attribute.trim().eq_ignore_ascii_case("secure"))
+            });
+            let cookie = if is_secure {
+                cookie
+            } else {
+                let mut bytes = cookie.as_bytes().to_vec();
+                bytes.extend_from_slice(b"; Secure");
+                HeaderValue::from_bytes(&bytes).unwrap_or(cookie)
+            };
+            headers.append(header::SET_COOKIE, cookie);
+        }
+    }
+
+    if let Some(max_age) = state.config.forwarded.hsts_max_age {
+        headers.insert(
+            header::STRICT_TRANSPORT_SECURITY,
+            HeaderValue::from_str(&format!("max-age={}", max_age.as_secs()))
+                .expect("A number is a valid header value"),
+        );
+    }
+
+    response
+}
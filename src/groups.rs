@@ -0,0 +1,576 @@
+//! Groups of users
+//! A group grants its roles to its members, on top of their own: the effective roles of a user
+//! (see [`crate::roles::RoleRepository::effective_roles_of`]) are the union of both. Changing the
+//! members or the roles of a group, or deleting it, bumps the `roles_version` of the users
+//! concerned, so that the roles cached in their sessions are refreshed on their next request. Admins manage the groups
+//! through `/admin/groups`, and list their members through `/admin/users?group=...`.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use time::OffsetDateTime;
+
+use crate::{
+    admin::Pagination,
+    audit::AuditActor,
+    database::{with_pool, SqlxPool},
+    error::AppError,
+    negotiate::{Format, Negotiated},
+    roles::Role,
+    state::AppState,
+    users::UserRepository,
+};
+
+/// The maximum number of characters of the name of a group
+const MAX_NAME_LENGTH: usize = 100;
+
+/// The maximum number of characters of the description of a group
+const MAX_DESCRIPTION_LENGTH: usize = 500;
+
+/// The columns of the `user_groups` table, in the order of [`Group`]
+const COLUMNS: &str = "id, name, description, created_at, updated_at";
+
+/// The statement bumping the `roles_version` of the members of a group
+const BUMP_MEMBERS: &str = "UPDATE users SET roles_version = roles_version + 1, updated_at = ? \
+                            WHERE id IN (SELECT user_id FROM group_members WHERE group_id = ?)";
+
+/// A group of users
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Group {
+    pub id: i64,
+    pub name: String,
+    pub description: Option<String>,
+    /// When the group was created, in unix seconds
+    pub created_at: i64,
+    /// When the group was last changed, in unix seconds
+    pub updated_at: i64,
+}
+
+/// A group to create
+#[derive(Clone, Debug)]
+pub struct NewGroup {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+/// An error of the group repository
+#[derive(Debug, thiserror::Error)]
+pub enum GroupError {
+    /// Another group has the same name
+    #[error("A group with this name already exists")]
+    NameTaken,
+    /// The group doesn't exist
+    #[error("No such group")]
+    NotFound,
+    #[error(transparent)]
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for GroupError {
+    fn from(err: sqlx::Error) -> Self {
+        match &err {
+            sqlx::Error::Database(db_err) if db_err.is_unique_violation() => GroupError::NameTaken,
+            _ => GroupError::Database(err),
+        }
+    }
+}
+
+impl From<GroupError> for AppError {
+    fn from(err: GroupError) -> Self {
+        match err {
+            GroupError::NameTaken => AppError::Conflict(err.to_string()),
+            GroupError::NotFound => AppError::NotFound,
+            GroupError::Database(err) => err.into(),
+        }
+    }
+}
+
+/// The groups, stored in the database
+#[derive(Clone, Debug)]
+pub struct GroupRepository {
+    pool: SqlxPool,
+}
+
+impl GroupRepository {
+    pub fn new(pool: SqlxPool) -> Self {
+        Self { pool }
+    }
+
+    /// Create a group, without members nor roles
+    pub async fn create(&self, group: NewGroup) -> Result<Group, GroupError> {
+        let insert = self.pool.sql(
+            "INSERT INTO user_groups (name, description, created_at, updated_at) \
+             VALUES (?, ?, ?, ?)",
+        );
+        let select = self
+            .pool
+            .sql(&format!("SELECT {COLUMNS} FROM user_groups WHERE name = ?"))
+            .into_owned();
+        let now = now();
+
+        Ok(with_pool!(&self.pool, |p| {
+            sqlx::query(&insert)
+                .bind(&group.name)
+                .bind(&group.description)
+                .bind(now)
+                .bind(now)
+                .execute(p)
+                .await?;
+            sqlx::query_as(&select)
+                .bind(&group.name)
+                .fetch_one(p)
+                .await?
+        }))
+    }
+
+    /// The groups, by ID
+    pub async fn list(&self, limit: i64, offset: i64) -> Result<Vec<Group>, GroupError> {
+        let sql = self
+            .pool
+            .sql(&format!(
+                "SELECT {COLUMNS} FROM user_groups ORDER BY id LIMIT ? OFFSET ?"
+            ))
+            .into_owned();
+        Ok(with_pool!(&self.pool, |p| sqlx::query_as(&sql)
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(p)
+            .await)?)
+    }
+
+    pub async fn find(&self, id: i64) -> Result<Option<Group>, GroupError> {
+        let sql = self
+            .pool
+            .sql(&format!("SELECT {COLUMNS} FROM user_groups WHERE id = ?"))
+            .into_owned();
+        Ok(with_pool!(&self.pool, |p| sqlx::query_as(&sql)
+            .bind(id)
+            .fetch_optional(p)
+            .await)?)
+    }
+
+    /// Save the name and description of a group
+    pub async fn update(&self, group: &Group) -> Result<Group, GroupError> {
+        let update = self
+            .pool
+            .sql("UPDATE user_groups SET name = ?, description = ?, updated_at = ? WHERE id = ?");
+        let updated_at = now();
+        let affected = with_pool!(&self.pool, |p| sqlx::query(&update)
+            .bind(&group.name)
+            .bind(&group.description)
+            .bind(updated_at)
+            .bind(group.id)
+            .execute(p)
+            .await
+            .map(|result| result.rows_affected()))?;
+        if affected == 0 {
+            return Err(GroupError::NotFound);
+        }
+        Ok(Group {
+            updated_at,
+            ..group.clone()
+        })
+    }
+
+    /// Delete a group, invalidating the roles cached in the sessions of its members
+    ///
+    /// Returns whether the group existed.
+    pub async fn delete(&self, id: i64) -> Result<bool, GroupError> {
+        let bump = self.pool.sql(BUMP_MEMBERS);
+        let delete_members = self
+            .pool
+            .sql("DELETE FROM group_members WHERE group_id = ?");
+        let delete_roles = self.pool.sql("DELETE FROM group_roles WHERE group_id = ?");
+        let delete = self.pool.sql("DELETE FROM user_groups WHERE id = ?");
+        let now = now();
+
+        Ok(with_pool!(&self.pool, |p| {
+            let mut tx = p.begin().await?;
+            sqlx::query(&bump)
+                .bind(now)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query(&delete_members)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query(&delete_roles)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            let deleted = sqlx::query(&delete)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+            tx.commit().await?;
+            deleted > 0
+        }))
+    }
+
+    /// The roles granted by a group, lowest first
+    pub async fn roles_of(&self, id: i64) -> Result<Vec<Role>, GroupError> {
+        let sql = self
+            .pool
+            .sql("SELECT role FROM group_roles WHERE group_id = ?")
+            .into_owned();
+        let names: Vec<String> = with_pool!(&self.pool, |p| sqlx::query_scalar(&sql)
+            .bind(id)
+            .fetch_all(p)
+            .await)?;
+
+        // Roles unknown to this version are ignored
+        let mut roles: Vec<Role> = names.iter().filter_map(|name| Role::parse(name)).collect();
+        roles.sort();
+        Ok(roles)
+    }
+
+    /// Replace the roles granted by a group, invalidating the roles cached in the sessions of its
+    /// members
+    pub async fn set_roles(&self, id: i64, roles: &[Role]) -> Result<(), GroupError> {
+        let touch = self
+            .pool
+            .sql("UPDATE user_groups SET updated_at = ? WHERE id = ?");
+        let bump = self.pool.sql(BUMP_MEMBERS);
+        let clear = self.pool.sql("DELETE FROM group_roles WHERE group_id = ?");
+        let insert = self
+            .pool
+            .sql("INSERT INTO group_roles (group_id, role) VALUES (?, ?)");
+        let now = now();
+
+        let mut roles = roles.to_vec();
+        roles.sort();
+        roles.dedup();
+
+        let found = with_pool!(&self.pool, |p| {
+            let mut tx = p.begin().await?;
+            let touched = sqlx::query(&touch)
+                .bind(now)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+            if touched > 0 {
+                sqlx::query(&bump)
+                    .bind(now)
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query(&clear).bind(id).execute(&mut *tx).await?;
+                for role in &roles {
+                    sqlx::query(&insert)
+                        .bind(id)
+                        .bind(role.as_str())
+                        .execute(&mut *tx)
+                        .await?;
+                }
+            }
+            tx.commit().await?;
+            touched > 0
+        });
+
+        if found {
+            Ok(())
+        } else {
+            Err(GroupError::NotFound)
+        }
+    }
+
+    /// Add a user to a group, invalidating the roles cached in their sessions
+    ///
+    /// Returns whether the user wasn't already a member.
+    pub async fn add_member(&self, id: i64, user_id: i64) -> Result<bool, GroupError> {
+        let exists = self
+            .pool
+            .sql("SELECT COUNT(*) FROM group_members WHERE group_id = ? AND user_id = ?");
+        let insert = self
+            .pool
+            .sql("INSERT INTO group_members (group_id, user_id) VALUES (?, ?)");
+        let bump = self
+            .pool
+            .sql("UPDATE users SET roles_version = roles_version + 1, updated_at = ? WHERE id = ?");
+        let now = now();
+
+        let added = with_pool!(&self.pool, |p| {
+            let mut tx = p.begin().await?;
+            let count: i64 = sqlx::query_scalar(&exists)
+                .bind(id)
+                .bind(user_id)
+                .fetch_one(&mut *tx)
+                .await?;
+            if count == 0 {
+                sqlx::query(&insert)
+                    .bind(id)
+                    .bind(user_id)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query(&bump)
+                    .bind(now)
+                    .bind(user_id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+            count == 0
+        });
+        Ok(added)
+    }
+
+    /// Remove a user from a group, invalidating the roles cached in their sessions
+    ///
+    /// Returns whether the user was a member.
+    pub async fn remove_member(&self, id: i64, user_id: i64) -> Result<bool, GroupError> {
+        let delete = self
+            .pool
+            .sql("DELETE FROM group_members WHERE group_id = ? AND user_id = ?");
+        let bump = self
+            .pool
+            .sql("UPDATE users SET roles_version = roles_version + 1, updated_at = ? WHERE id = ?");
+        let now = now();
+
+        let removed = with_pool!(&self.pool, |p| {
+            let mut tx = p.begin().await?;
+            let removed = sqlx::query(&delete)
+                .bind(id)
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+            if removed > 0 {
+                sqlx::query(&bump)
+                    .bind(now)
+                    .bind(user_id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+            removed > 0
+        });
+        Ok(removed)
+    }
+
+    /// The identifiers of the groups of a user
+    pub async fn groups_of(&self, user_id: i64) -> Result<Vec<i64>, GroupError> {
+        let sql = self
+            .pool
+            .sql("SELECT group_id FROM group_members WHERE user_id = ? ORDER BY group_id")
+            .into_owned();
+        Ok(with_pool!(&self.pool, |p| sqlx::query_scalar(&sql)
+            .bind(user_id)
+            .fetch_all(p)
+            .await)?)
+    }
+}
+
+/// A group and the roles it grants
+#[derive(Serialize, Deserialize)]
+pub struct GroupDetails {
+    #[serde(flatten)]
+    pub group: Group,
+    pub roles: Vec<Role>,
+}
+
+/// A group to create
+#[derive(Deserialize)]
+pub struct CreateGroupRequest {
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub roles: Vec<Role>,
+}
+
+/// The changes to a group, the missing fields being kept
+#[derive(Deserialize)]
+pub struct UpdateGroupRequest {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub roles: Option<Vec<Role>>,
+}
+
+/// The violations of the name and description of a group
+fn validate(name: Option<&str>, description: Option<&str>) -> Result<(), AppError> {
+    let mut violations = Vec::new();
+    if name.is_some_and(|name| {
+        let length = name.trim().chars().count();
+        length == 0 || length > MAX_NAME_LENGTH
+    }) {
+        violations.push(format!(
+            "the name must have between 1 and {} characters",
+            MAX_NAME_LENGTH
+        ));
+    }
+    if description.is_some_and(|description| description.chars().count() > MAX_DESCRIPTION_LENGTH) {
+        violations.push(format!(
+            "the description must have at most {} characters",
+            MAX_DESCRIPTION_LENGTH
+        ));
+    }
+    if !violations.is_empty() {
+        return Err(AppError::BadRequest(violations.join("; ")));
+    }
+    Ok(())
+}
+
+/// `GET /admin/groups`: list the groups
+pub async fn list_groups(
+    State(state): State<AppState>,
+    format: Format,
+    Query(pagination): Query<Pagination>,
+) -> Result<Negotiated<Vec<Group>>, AppError> {
+    let groups = GroupRepository::new(state.read_pool().clone())
+        .list(pagination.limit(), pagination.offset())
+        .await?;
+    Ok(Negotiated(format, groups))
+}
+
+/// `GET /admin/groups/:id`: a group and its roles
+pub async fn get_group(
+    State(state): State<AppState>,
+    format: Format,
+    Path(id): Path<i64>,
+) -> Result<Negotiated<GroupDetails>, AppError> {
+    let repository = GroupRepository::new(state.read_pool().clone());
+    let group = repository.find(id).await?.ok_or(AppError::NotFound)?;
+    let roles = repository.roles_of(id).await?;
+    Ok(Negotiated(format, GroupDetails { group, roles }))
+}
+
+/// `POST /admin/groups`: create a group, without members
+pub async fn create_group(
+    State(state): State<AppState>,
+    AuditActor(audit_actor): AuditActor,
+    Negotiated(format, request): Negotiated<CreateGroupRequest>,
+) -> Result<(StatusCode, Negotiated<GroupDetails>), AppError> {
+    validate(Some(&request.name), request.description.as_deref())?;
+
+    let repository = GroupRepository::new(state.write_pool().clone());
+    let group = repository
+        .create(NewGroup {
+            name: request.name.trim().to_string(),
+            description: request
+                .description
+                .filter(|description| !description.is_empty()),
+        })
+        .await?;
+    if !request.roles.is_empty() {
+        repository.set_roles(group.id, &request.roles).await?;
+    }
+    let roles = repository.roles_of(group.id).await?;
+    state.audit.record(
+        audit_actor,
+        "group.created",
+        &group.id.to_string(),
+        json!({ "name": group.name, "roles": roles }),
+    );
+
+    Ok((
+        StatusCode::CREATED,
+        Negotiated(format, GroupDetails { group, roles }),
+    ))
+}
+
+/// `PATCH /admin/groups/:id`: change the name, description or roles of a group
+pub async fn update_group(
+    State(state): State<AppState>,
+    AuditActor(audit_actor): AuditActor,
+    Path(id): Path<i64>,
+    Negotiated(format, request): Negotiated<UpdateGroupRequest>,
+) -> Result<Negotiated<GroupDetails>, AppError> {
+    validate(request.name.as_deref(), request.description.as_deref())?;
+
+    let repository = GroupRepository::new(state.write_pool().clone());
+    let mut group = repository.find(id).await?.ok_or(AppError::NotFound)?;
+    if let Some(name) = request.name {
+        group.name = name.trim().to_string();
+    }
+    if let Some(description) = request.description {
+        group.description = Some(description).filter(|description| !description.is_empty());
+    }
+    let group = repository.update(&group).await?;
+
+    let previous = repository.roles_of(id).await?;
+    if let Some(roles) = &request.roles {
+        repository.set_roles(id, roles).await?;
+    }
+    let roles = repository.roles_of(id).await?;
+    if roles != previous {
+        state.audit.record(
+            audit_actor,
+            "group.roles_changed",
+            &id.to_string(),
+            json!({ "previous": previous, "roles": roles }),
+        );
+    }
+    Ok(Negotiated(format, GroupDetails { group, roles }))
+}
+
+/// `DELETE /admin/groups/:id`: delete a group, its members losing its roles
+pub async fn delete_group(
+    State(state): State<AppState>,
+    AuditActor(audit_actor): AuditActor,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, AppError> {
+    if !GroupRepository::new(state.write_pool().clone())
+        .delete(id)
+        .await?
+    {
+        return Err(AppError::NotFound);
+    }
+    state
+        .audit
+        .record(audit_actor, "group.deleted", &id.to_string(), json!({}));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `PUT /admin/groups/:id/members/:user_id`: add a user to a group
+pub async fn add_group_member(
+    State(state): State<AppState>,
+    AuditActor(audit_actor): AuditActor,
+    Path((id, user_id)): Path<(i64, i64)>,
+) -> Result<StatusCode, AppError> {
+    let repository = GroupRepository::new(state.write_pool().clone());
+    repository.find(id).await?.ok_or(AppError::NotFound)?;
+    UserRepository::new(state.write_pool().clone())
+        .find_by_id(user_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    if repository.add_member(id, user_id).await? {
+        state.audit.record(
+            audit_actor,
+            "group.member_added",
+            &id.to_string(),
+            json!({ "user_id": user_id }),
+        );
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `DELETE /admin/groups/:id/members/:user_id`: remove a user from a group
+pub async fn remove_group_member(
+    State(state): State<AppState>,
+    AuditActor(audit_actor): AuditActor,
+    Path((id, user_id)): Path<(i64, i64)>,
+) -> Result<StatusCode, AppError> {
+    if !GroupRepository::new(state.write_pool().clone())
+        .remove_member(id, user_id)
+        .await?
+    {
+        return Err(AppError::NotFound);
+    }
+    state.audit.record(
+        audit_actor,
+        "group.member_removed",
+        &id.to_string(),
+        json!({ "user_id": user_id }),
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The current time, in unix seconds
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
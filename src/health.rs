@@ -0,0 +1,205 @@
+//! Liveness and readiness probes
+//! Besides the databases, the probes report whether the background maintenance keeps up: the
+//! tasks beat a [`Heartbeat`] after every successful run, and are considered stalled once they
+//! missed [`STALE_PERIODS`] runs.
+
+use std::{
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::{
+    database::{retry_read, SqlxPool},
+    state::AppState,
+};
+
+/// How long a database probe may take before the database is considered unreachable
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The number of periods without a successful run after which a background task is stalled
+pub const STALE_PERIODS: u32 = 3;
+
+/// When a periodic background task last completed a run
+#[derive(Clone, Debug)]
+pub struct Heartbeat {
+    /// The last successful run, in unix seconds (the creation of the heartbeat until then)
+    last: Arc<AtomicI64>,
+    /// The interval between the runs
+    period: Duration,
+}
+
+impl Heartbeat {
+    pub fn new(period: Duration) -> Self {
+        Self {
+            last: Arc::new(AtomicI64::new(now())),
+            period,
+        }
+    }
+
+    /// The interval between the runs
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Record a successful run
+    pub fn beat(&self) {
+        self.last.store(now(), Ordering::Relaxed);
+    }
+
+    /// When the task last completed a run, in unix seconds
+    pub fn last_at(&self) -> i64 {
+        self.last.load(Ordering::Relaxed)
+    }
+
+    /// Whether the task missed [`STALE_PERIODS`] runs at the given time
+    pub fn is_stale_at(&self, now: i64) -> bool {
+        let threshold = (self.period * STALE_PERIODS).as_secs() as i64;
+        now - self.last_at() > threshold
+    }
+
+    /// Whether the task missed [`STALE_PERIODS`] runs
+    pub fn is_stale(&self) -> bool {
+        self.is_stale_at(now())
+    }
+}
+
+/// The overall health of the backend
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    /// Every pool is reachable
+    Ok,
+    /// The read replica is unreachable, so reads fall short while writes still work, or the
+    /// background maintenance stalled
+    Degraded,
+    /// The primary database is unreachable
+    Down,
+}
+
+/// The reachability of a pool
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PoolStatus {
+    Up,
+    Down,
+}
+
+/// The reachability of the write and read pools
+#[derive(Debug, Serialize)]
+pub struct PoolsHealth {
+    pub write: PoolStatus,
+    pub read: PoolStatus,
+}
+
+/// Whether a background task keeps up
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskStatus {
+    Up,
+    /// No successful run for [`STALE_PERIODS`] periods
+    Stalled,
+}
+
+/// The health of a periodic background task
+#[derive(Debug, Serialize)]
+pub struct TaskHealth {
+    pub status: TaskStatus,
+    /// When the task last completed a run, in unix seconds
+    pub last_run_at: i64,
+}
+
+impl From<&Heartbeat> for TaskHealth {
+    fn from(heartbeat: &Heartbeat) -> Self {
+        TaskHealth {
+            status: if heartbeat.is_stale() {
+                TaskStatus::Stalled
+            } else {
+                TaskStatus::Up
+            },
+            last_run_at: heartbeat.last_at(),
+        }
+    }
+}
+
+/// The health of the background maintenance
+#[derive(Debug, Serialize)]
+pub struct BackgroundHealth {
+    /// The deletion of the expired sessions
+    pub session_deletion: TaskHealth,
+}
+
+/// The health summary
+#[derive(Debug, Serialize)]
+pub struct HealthSummary {
+    pub status: HealthStatus,
+    pub pools: PoolsHealth,
+    pub background: BackgroundHealth,
+}
+
+/// Health probe: reports whether the primary and the replica databases are reachable, and whether
+/// the background maintenance keeps up
+///
+/// Answers 503 when the primary database is down, and 200 otherwise (even when degraded).
+pub async fn health(State(state): State<AppState>) -> impl IntoResponse {
+    let (write, read) = tokio::join!(probe(state.write_pool()), probe(state.read_pool()));
+    let background = BackgroundHealth {
+        session_deletion: TaskHealth::from(&state.session_sweep),
+    };
+
+    let status = match (write, read, background.session_deletion.status) {
+        (PoolStatus::Down, _, _) => HealthStatus::Down,
+        (PoolStatus::Up, PoolStatus::Down, _) | (PoolStatus::Up, _, TaskStatus::Stalled) => {
+            HealthStatus::Degraded
+        }
+        (PoolStatus::Up, PoolStatus::Up, TaskStatus::Up) => HealthStatus::Ok,
+    };
+    let code = match status {
+        HealthStatus::Down => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::OK,
+    };
+
+    (
+        code,
+        Json(HealthSummary {
+            status,
+            pools: PoolsHealth { write, read },
+            background,
+        }),
+    )
+}
+
+/// Check whether the pool can reach its database
+async fn probe(pool: &SqlxPool) -> PoolStatus {
+    match tokio::time::timeout(PROBE_TIMEOUT, retry_read(|| pool.ping())).await {
+        Ok(Ok(())) => PoolStatus::Up,
+        _ => PoolStatus::Down,
+    }
+}
+
+/// Readiness probe: answers 503 until the startup phases are done
+///
+/// A stalled background maintenance is reported, without taking the backend out of rotation.
+pub async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    if !state.is_ready() {
+        (StatusCode::SERVICE_UNAVAILABLE, "Initializing")
+    } else if state.session_sweep.is_stale() {
+        (
+            StatusCode::OK,
+            "Degraded: the expired sessions are no longer deleted",
+        )
+    } else {
+        (StatusCode::OK, "Ready")
+    }
+}
+
+/// The current time, in unix seconds
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
@@ -0,0 +1,219 @@
+//! The HTTP client, for the few requests the backend makes to other services
+//! Requests are sent with `reqwest`, secured with native TLS for `https` URLs, with a total
+//! timeout covering the connection, the request and the whole response. Only what the identity
+//! providers, the breach check and the webhooks need is exposed: `GET`, form-encoded and JSON
+//! `POST` requests, answered with a body of at most 1 MiB. Redirects are not followed.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    header, redirect, Client, RequestBuilder,
+};
+use serde::de::DeserializeOwned;
+use url::{Host, Url};
+
+/// The maximum size of the body of a response
+const MAX_RESPONSE_BYTES: usize = 1024 * 1024;
+
+/// A response to a request
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// Parse the JSON body of the response
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        serde_json::from_slice(&self.body).context("Invalid JSON response")
+    }
+}
+
+/// Whether the client may connect to an address
+pub type AddressFilter = Arc<dyn Fn(IpAddr) -> bool + Send + Sync>;
+
+/// The client shared by the requests to any address
+fn shared_client() -> Result<&'static Client> {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    if let Some(client) = CLIENT.get() {
+        return Ok(client);
+    }
+    let client = builder()
+        .build()
+        .context("Failed to build the HTTP client")?;
+    Ok(CLIENT.get_or_init(|| client))
+}
+
+/// The configuration common to the clients
+fn builder() -> reqwest::ClientBuilder {
+    Client::builder()
+        .redirect(redirect::Policy::none())
+        .default_headers(header::HeaderMap::from_iter([(
+            header::ACCEPT,
+            header::HeaderValue::from_static("application/json"),
+        )]))
+}
+
+/// Send a `GET` request
+pub async fn get(url: &Url, timeout: Duration) -> Result<HttpResponse> {
+    send(shared_client()?.get(url.clone()), url, timeout).await
+}
+
+/// Send a `POST` request with a form-encoded body, authenticated with HTTP Basic credentials if
+/// given
+pub async fn post_form(
+    url: &Url,
+    form: &[(&str, &str)],
+    basic_auth: Option<(&str, &str)>,
+    timeout: Duration,
+) -> Result<HttpResponse> {
+    let mut request = shared_client()?.post(url.clone()).form(form);
+    if let Some((user, password)) = basic_auth {
+        // The credentials are form-encoded first (RFC 6749, section 2.3.1)
+        let encode = |value: &str| {
+            url::form_urlencoded::byte_serialize(value.as_bytes()).collect::<String>()
+        };
+        request = request.basic_auth(encode(user), Some(encode(password)));
+    }
+    send(request, url, timeout).await
+}
+
+/// Send a `POST` request with a JSON body and extra headers, only connecting to the addresses
+/// the filter allows
+///
+/// The addresses are checked once resolved, so that a name can't point to a denied address.
+pub async fn post_json(
+    url: &Url,
+    body: String,
+    headers: Vec<(&'static str, String)>,
+    allow: AddressFilter,
+    timeout: Duration,
+) -> Result<HttpResponse> {
+    // Literal addresses aren't resolved, so they are checked here
+    let literal = match url.host() {
+        Some(Host::Ipv4(addr)) => Some(IpAddr::V4(addr)),
+        Some(Host::Ipv6(addr)) => Some(IpAddr::V6(addr)),
+        _ => None,
+    };
+    if let Some(addr) = literal.filter(|addr| !allow(*addr)) {
+        bail!("Failed to connect to {}: the address is denied", addr);
+    }
+
+    let client = builder()
+        .dns_resolver(Arc::new(FilteringResolver { allow }))
+        .build()
+        .context("Failed to build the HTTP client")?;
+    let mut request = client
+        .post(url.clone())
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body);
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    send(request, url, timeout).await
+}
+
+/// Send a request and read its response, within the timeout
+async fn send(request: RequestBuilder, url: &Url, timeout: Duration) -> Result<HttpResponse> {
+    let host = url.host_str().unwrap_or_default();
+    let mut response = request
+        .timeout(timeout)
+        .send()
+        .await
+        .with_context(|| format!("The request to {} failed", host))?;
+    let status = response.status().as_u16();
+    if response
+        .content_length()
+        .is_some_and(|length| length > MAX_RESPONSE_BYTES as u64)
+    {
+        bail!("The response of {} is too large", host);
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .with_context(|| format!("Failed to read the response of {}", host))?
+    {
+        if body.len() + chunk.len() > MAX_RESPONSE_BYTES {
+            bail!("The response of {} is too large", host);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(HttpResponse { status, body })
+}
+
+/// Resolves the names with the system resolver, keeping only the allowed addresses
+struct FilteringResolver {
+    allow: AddressFilter,
+}
+
+impl Resolve for FilteringResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allow = self.allow.clone();
+        Box::pin(async move {
+            let resolved: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await
+                .with_context(|| format!("Failed to resolve '{}'", name.as_str()))?
+                .collect();
+            let allowed: Vec<SocketAddr> = resolved
+                .iter()
+                .copied()
+                .filter(|addr| allow(addr.ip()))
+                .collect();
+            if allowed.is_empty() {
+                return Err(match resolved.first() {
+                    Some(addr) => anyhow!("the address {} is denied", addr.ip()),
+                    None => anyhow!("no address"),
+                }
+                .into());
+            }
+            Ok(Box::new(allowed.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deny_loopback() -> AddressFilter {
+        Arc::new(|addr: IpAddr| !addr.is_loopback())
+    }
+
+    async fn post(url: &str) -> Result<HttpResponse> {
+        let url = Url::parse(url).unwrap();
+        post_json(
+            &url,
+            "{}".to_string(),
+            Vec::new(),
+            deny_loopback(),
+            Duration::from_secs(5),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn refuses_denied_literal_addresses() {
+        let err = post("http://127.0.0.1:9/").await.unwrap_err();
+        assert!(format!("{:#}", err).contains("denied"));
+        let err = post("http://[::1]:9/").await.unwrap_err();
+        assert!(format!("{:#}", err).contains("denied"));
+    }
+
+    #[tokio::test]
+    async fn refuses_names_resolving_to_denied_addresses() {
+        let err = post("http://localhost:9/").await.unwrap_err();
+        assert!(format!("{:#}", err).contains("denied"));
+    }
+}
@@ -0,0 +1,63 @@
+//! Redirection of plain HTTP requests to HTTPS
+//! When `HTTP_REDIRECT_PORT` is set, a second listener answers every request with a `301` to the
+//! same path and query on `https://EXTERNAL_HOST`. The `Host` header is never trusted. ACME
+//! HTTP-01 challenges can be answered from `ACME_CHALLENGE_DIR` instead, for certificate
+//! automation.
+
+use std::path::PathBuf;
+
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+
+use crate::config::HttpRedirectConfig;
+
+/// Build the router of the redirect listener
+pub fn router(config: &HttpRedirectConfig) -> Router {
+    let router = Router::new().fallback(redirect);
+
+    let router = match &config.acme_challenge_dir {
+        Some(dir) => router.route(
+            "/.well-known/acme-challenge/:token",
+            get(challenge).with_state(dir.clone()),
+        ),
+        None => router,
+    };
+
+    router.with_state(config.external_host.clone())
+}
+
+/// Redirect to the HTTPS equivalent of the request
+async fn redirect(State(external_host): State<String>, uri: Uri) -> Response {
+    let path_and_query = uri
+        .path_and_query()
+        .map(|path_and_query| path_and_query.as_str())
+        .unwrap_or("/");
+    let location = format!("https://{}{}", external_host, path_and_query);
+    (
+        StatusCode::MOVED_PERMANENTLY,
+        [(header::LOCATION, location)],
+    )
+        .into_response()
+}
+
+/// Serve the key authorization of an ACME HTTP-01 challenge
+async fn challenge(State(dir): State<PathBuf>, Path(token): Path<String>) -> Response {
+    // Tokens are base64url: anything else can't be a challenge, nor escape the directory
+    if token.is_empty()
+        || !token
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    match tokio::fs::read(dir.join(&token)).await {
+        Ok(contents) => ([(header::CONTENT_TYPE, "text/plain")], contents).into_response(),
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
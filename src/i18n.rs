@@ -0,0 +1,194 @@
+//! Localization of the messages meant for humans
+//! The locale of a request is negotiated from its `Accept-Language` header against the
+//! configured locales, and echoed in the `Content-Language` header of the response. Messages
+//! are looked up in a catalog compiled in the binary, falling back to English.
+
+use axum::{
+    async_trait,
+    body::Body,
+    extract::{FromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{error::ErrorEnvelope, state::AppState};
+
+/// The locale of the catalog used when no translation is available
+pub const DEFAULT_LOCALE: &str = "en";
+
+/// The negotiated locale of the current request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Locale(pub String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Locale {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<Locale>()
+            .cloned()
+            .unwrap_or_else(|| Locale(DEFAULT_LOCALE.to_string())))
+    }
+}
+
+impl Locale {
+    /// Pick the preferred supported locale from an `Accept-Language` header
+    ///
+    /// A language range matches a supported locale either exactly or by its primary subtag
+    /// (`fr-CH` matches `fr`). The first supported locale is used when nothing matches.
+    pub fn negotiate(headers: &HeaderMap, supported: &[String]) -> Locale {
+        let default = supported
+            .first()
+            .cloned()
+            .unwrap_or_else(|| DEFAULT_LOCALE.to_string());
+
+        let Some(accept) = headers
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return Locale(default);
+        };
+
+        let mut best: Option<(&String, f32)> = None;
+        for item in accept.split(',') {
+            let mut params = item.split(';');
+            let range = params.next().unwrap_or_default().trim();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            if quality <= 0.0 || range.is_empty() {
+                continue;
+            }
+
+            let primary = range.split('-').next().unwrap_or(range);
+            let matched = if range == "*" {
+                supported.first()
+            } else {
+                supported
+                    .iter()
+                    .find(|locale| locale.eq_ignore_ascii_case(range))
+                    .or_else(|| {
+                        supported
+                            .iter()
+                            .find(|locale| locale.eq_ignore_ascii_case(primary))
+                    })
+            };
+
+            if let Some(locale) = matched {
+                if best.is_none_or(|(_, best_quality)| quality > best_quality) {
+                    best = Some((locale, quality));
+                }
+            }
+        }
+
+        Locale(best.map_or(default, |(locale, _)| locale.clone()))
+    }
+}
+
+/// Negotiate the locale of the request, localize error messages and set `Content-Language`
+pub async fn localize(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let locale = Locale::negotiate(req.headers(), &state.config.locales);
+    req.extensions_mut().insert(locale.clone());
+
+    let mut response = next.run(req).await;
+
+    if let Ok(value) = HeaderValue::from_str(&locale.0) {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_LANGUAGE, value);
+    }
+
+    let Some(mut envelope) = response.extensions().get::<ErrorEnvelope>().cloned() else {
+        return response;
+    };
+    if locale.0 == DEFAULT_LOCALE {
+        return response;
+    }
+
+    envelope.error.message = message(&locale.0, &envelope.error.code, &envelope.error.args);
+    let Ok(body) = serde_json::to_vec(&envelope) else {
+        return response;
+    };
+
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.extensions.insert(envelope);
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// Render the message of the given key in the given locale
+///
+/// `{0}`, `{1}`, ... are replaced by the arguments.
+pub fn message(locale: &str, key: &str, args: &[String]) -> String {
+    let template = lookup(locale, key)
+        .or_else(|| lookup(DEFAULT_LOCALE, key))
+        .unwrap_or(key);
+
+    let mut message = template.to_string();
+    for (index, arg) in args.iter().enumerate() {
+        message = message.replace(&format!("{{{}}}", index), arg);
+    }
+    message
+}
+
+/// The message catalog
+fn lookup(locale: &str, key: &str) -> Option<&'static str> {
+    Some(match (locale, key) {
+        ("en", "not_acceptable") => "None of the accepted formats can be produced",
+        ("en", "unsupported_media_type") => "Unsupported content type",
+        ("en", "bad_request") => "{0}",
+        ("en", "unprocessable") => "{0}",
+        ("en", "password_policy") => "The password doesn't meet the policy: {0}",
+        ("en", "unauthorized") => "Authentication required",
+        ("en", "forbidden") => "Access denied",
+        ("en", "registration_disabled") => "Registration is disabled",
+        ("en", "password_login_disabled") => "The password login is disabled",
+        ("en", "password_managed_externally") => {
+            "The password of this account is managed by the directory"
+        }
+        ("en", "email_not_verified") => "The email must be verified first",
+        ("en", "invitation_expired") => "The invitation expired",
+        ("en", "invitation_revoked") => "The invitation was revoked",
+        ("en", "impersonation_forbidden") => "Not allowed while impersonating a user",
+        ("en", "password_change_required") => "The password must be changed first",
+        ("en", "invalid_csrf_token") => "Missing or invalid CSRF token",
+        ("en", "not_found") => "Not found",
+        ("en", "method_not_allowed") => "Method not allowed, the allowed methods are: {0}",
+        ("en", "conflict") => "{0}",
+        ("en", "too_many_requests") => "Too many requests, retry later",
+        ("en", "unavailable") => "Service temporarily unavailable",
+        ("en", "internal") => "Internal server error",
+
+        ("fr", "not_acceptable") => "Aucun des formats acceptés ne peut être produit",
+        ("fr", "unsupported_media_type") => "Type de contenu non pris en charge",
+        ("fr", "bad_request") => "Requête invalide : {0}",
+        ("fr", "unprocessable") => "Valeur invalide : {0}",
+        ("fr", "password_policy") => "Le mot de passe ne respecte pas la politique : {0}",
+        ("fr", "unauthorized") => "Authentification requise",
+        ("fr", "forbidden") => "Accès refusé",
+        ("fr", "registration_disabled") => "Les inscriptions sont désactivées",
+        ("fr", "password_login_disabled") => "La connexion par mot de passe est désactivée",
+        ("fr", "password_managed_externally") => {
+            "Le mot de passe de ce compte est géré par l'annuaire"
+        }
+        ("fr", "email_not_verified") => "L'adresse email doit d'abord être vérifiée",
+        ("fr", "invitation_expired") => "L'invitation a expiré",
+        ("fr", "invitation_revoked") => "L'invitation a été révoquée",
+        ("fr", "impersonation_forbidden") => "Interdit en se faisant passer pour un utilisateur",
+        ("fr", "password_change_required") => "Le mot de passe doit d'abord être changé",
+        ("fr", "invalid_csrf_token") => "Jeton CSRF manquant ou invalide",
+        ("fr", "not_found") => "Introuvable",
+        ("fr", "method_not_allowed") => "Méthode non autorisée, les méthodes autorisées sont : {0}",
+        ("fr", "conflict") => "Conflit : {0}",
+        ("fr", "too_many_requests") => "Trop de requêtes, réessayez plus tard",
+        ("fr", "unavailable") => "Service temporairement indisponible",
+        ("fr", "internal") => "Erreur interne du serveur",
+
+        _ => return None,
+    })
+}
@@ -0,0 +1,373 @@
+//! Idempotency keys for mutating requests
+//! Authenticated clients retrying a `POST`, `PATCH` or `DELETE` under `/api/v1` can send an
+//! `Idempotency-Key` header. The first response for a key is stored along with a fingerprint of
+//! the request, and replayed for any retry of the same request until the key expires. Reusing a
+//! key for a different request is a conflict.
+//!
+//! The keys belong to the principal of the request (its user, or its API key when the key has no
+//! user): the same key sent by someone else is another key. Anonymous requests are never
+//! replayed, and neither are the responses carrying a secret, marked with [`NoStore`].
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::FromRequestParts,
+    extract::{Request, State},
+    http::{header, request::Parts, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, IntoResponseParts, Response, ResponseParts},
+};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+use crate::{
+    api_keys::AuthenticatedKey,
+    auth::current_user::OptionalUser,
+    database::{is_unique_violation, with_pool, SqlxPool},
+    error::AppError,
+    state::AppState,
+};
+
+/// The header carrying the key
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+/// Set on responses replayed from a previous request
+pub const IDEMPOTENT_REPLAYED: &str = "idempotent-replayed";
+
+/// The maximum length of a key
+const MAX_KEY_LENGTH: usize = 255;
+/// The maximum size of the request and response bodies of an idempotent request
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+/// The routes whose requests can be replayed
+const API_PREFIX: &str = "/api/v1/";
+
+/// Marks a response carrying a secret (e.g. a new API key): it is never stored, so a retry runs
+/// the request again, and `Cache-Control: no-store` keeps it out of the HTTP caches too
+///
+/// ```ignore
+/// Ok((StatusCode::CREATED, NoStore, Negotiated(format, created)))
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct NoStore;
+
+impl IntoResponseParts for NoStore {
+    type Error = std::convert::Infallible;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        res.headers_mut()
+            .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        Ok(res)
+    }
+}
+
+/// A stored response
+struct StoredResponse {
+    fingerprint: String,
+    status: i64,
+    content_type: Option<String>,
+    body: Vec<u8>,
+}
+
+/// Serializes the requests sharing a key within this process
+#[derive(Clone, Default)]
+pub struct KeyLocks(Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>);
+
+impl KeyLocks {
+    /// Get the lock of the given key
+    fn get(&self, key: &str) -> Arc<tokio::sync::Mutex<()>> {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Forget the lock of the given key if nobody else holds it
+    fn release(&self, key: &str, lock: Arc<tokio::sync::Mutex<()>>) {
+        let mut locks = self.0.lock().unwrap();
+        drop(lock);
+        if locks
+            .get(key)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(key);
+        }
+    }
+}
+
+/// Replay the stored response of requests carrying a known `Idempotency-Key`
+///
+/// Runs once the request is authenticated, to scope the key to its principal.
+pub async fn idempotency(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    if !matches!(*req.method(), Method::POST | Method::PATCH | Method::DELETE)
+        || !req.uri().path().starts_with(API_PREFIX)
+    {
+        return Ok(next.run(req).await);
+    }
+
+    let Some(key) = req.headers().get(IDEMPOTENCY_KEY) else {
+        return Ok(next.run(req).await);
+    };
+    let key = key
+        .to_str()
+        .ok()
+        .filter(|key| !key.is_empty() && key.len() <= MAX_KEY_LENGTH)
+        .ok_or(AppError::BadRequest(format!(
+            "The {} header must be between 1 and {} visible characters",
+            IDEMPOTENCY_KEY, MAX_KEY_LENGTH
+        )))?
+        .to_string();
+
+    let (mut parts, body) = req.into_parts();
+    let Some(principal) = principal(&mut parts, &state).await? else {
+        return Ok(next.run(Request::from_parts(parts, body)).await);
+    };
+
+    // Buffer the body to fingerprint the request
+    let body = to_bytes(body, MAX_BODY_SIZE)
+        .await
+        .map_err(|_| AppError::BadRequest("The request body is too large".to_string()))?;
+    let fingerprint = fingerprint(
+        &principal,
+        &parts.method,
+        parts.uri.path_and_query().map_or("", |p| p.as_str()),
+        &body,
+    );
+    let req = Request::from_parts(parts, Body::from(body));
+
+    let lock_key = format!("{}\n{}", principal, key);
+    let lock = state.idempotency_locks.get(&lock_key);
+    let response = {
+        let _guard = lock.lock().await;
+        process(&state, &principal, &key, &fingerprint, req, next).await
+    };
+    state.idempotency_locks.release(&lock_key, lock);
+
+    response
+}
+
+/// Who the keys of the request belong to: the API key when it has no user, the user otherwise,
+/// if the request is authenticated
+async fn principal(parts: &mut Parts, state: &AppState) -> Result<Option<String>, AppError> {
+    if let Some(key) = parts.extensions.get::<AuthenticatedKey>() {
+        if key.user.is_none() {
+            return Ok(Some(format!("api-key:{}", key.key.id)));
+        }
+    }
+    let OptionalUser(user) = OptionalUser::from_request_parts(parts, state).await?;
+    Ok(user.map(|user| format!("user:{}", user.id)))
+}
+
+/// Replay the stored response for the key, or run the request and store its response
+async fn process(
+    state: &AppState,
+    principal: &str,
+    key: &str,
+    fingerprint: &str,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let pool = &state.pool;
+
+    if let Some(stored) = find(pool, principal, key).await? {
+        if stored.fingerprint != fingerprint {
+            return Err(AppError::Conflict(format!(
+                "The {} was already used for a different request",
+                IDEMPOTENCY_KEY
+            )));
+        }
+        return Ok(replay(stored));
+    }
+
+    let response = next.run(req).await;
+
+    // Server errors are not stored so that retries get a chance to succeed, and secrets are
+    // never stored
+    if response.status().is_server_error() || is_no_store(&response) {
+        return Ok(response);
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = match to_bytes(body, MAX_BODY_SIZE).await {
+        Ok(body) => body,
+        Err(err) => {
+            tracing::warn!("Idempotent response body could not be buffered: {}", err);
+            return Err(AppError::Internal(anyhow::anyhow!(
+                "Idempotent response body too large"
+            )));
+        }
+    };
+
+    let stored = StoredResponse {
+        fingerprint: fingerprint.to_string(),
+        status: parts.status.as_u16() as i64,
+        content_type: parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string()),
+        body: body.to_vec(),
+    };
+    let ttl_secs = state.config.idempotency_ttl.as_secs();
+    if let Err(err) = insert(pool, principal, key, &stored, ttl_secs).await {
+        // Another replica stored a response for the same key first: keep ours
+        if !is_unique_violation(&err) {
+            tracing::warn!("Failed to store idempotent response: {}", err);
+        }
+    }
+
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+/// Whether a response must not be stored, e.g. as it carries a secret (see [`NoStore`])
+fn is_no_store(response: &Response) -> bool {
+    response
+        .headers()
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-store"))
+}
+
+/// Compute the fingerprint of a request from its principal, method, path and body
+fn fingerprint(principal: &str, method: &Method, path: &str, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(principal.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(method.as_str().as_bytes());
+    hasher.update(b"\n");
+    hasher.update(path.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+/// Rebuild a stored response
+fn replay(stored: StoredResponse) -> Response {
+    let status = u16::try_from(stored.status)
+        .ok()
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .unwrap_or(StatusCode::OK);
+
+    let mut response = (status, stored.body).into_response();
+    let headers = response.headers_mut();
+    headers.remove(header::CONTENT_TYPE);
+    if let Some(content_type) = stored
+        .content_type
+        .and_then(|value| HeaderValue::from_str(&value).ok())
+    {
+        headers.insert(header::CONTENT_TYPE, content_type);
+    }
+    headers.insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+    response
+}
+
+/// Find the unexpired stored response of a key of the principal
+async fn find(
+    pool: &SqlxPool,
+    principal: &str,
+    key: &str,
+) -> Result<Option<StoredResponse>, sqlx::Error> {
+    let sql = pool.sql(
+        "SELECT fingerprint, status, content_type, body FROM idempotency_keys \
+         WHERE principal = ? AND idempotency_key = ? AND expires_at > ?",
+    );
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+
+    let row: Option<(String, i64, Option<String>, Vec<u8>)> = with_pool!(pool, |p| {
+        sqlx::query_as(&sql)
+            .bind(principal)
+            .bind(key)
+            .bind(now)
+            .fetch_optional(p)
+            .await
+    })?;
+
+    Ok(
+        row.map(|(fingerprint, status, content_type, body)| StoredResponse {
+            fingerprint,
+            status,
+            content_type,
+            body,
+        }),
+    )
+}
+
+/// Store the response of a key of the principal, replacing an expired one
+async fn insert(
+    pool: &SqlxPool,
+    principal: &str,
+    key: &str,
+    stored: &StoredResponse,
+    ttl_secs: u64,
+) -> Result<(), sqlx::Error> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let expires_at = now.saturating_add(ttl_secs as i64);
+
+    let delete = pool.sql(
+        "DELETE FROM idempotency_keys \
+         WHERE principal = ? AND idempotency_key = ? AND expires_at <= ?",
+    );
+    with_pool!(pool, |p| sqlx::query(&delete)
+        .bind(principal)
+        .bind(key)
+        .bind(now)
+        .execute(p)
+        .await
+        .map(|r| r.rows_affected()))?;
+
+    let insert = pool.sql(
+        "INSERT INTO idempotency_keys \
+         (principal, idempotency_key, fingerprint, status, content_type, body, created_at, \
+         expires_at) \
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+    );
+    with_pool!(pool, |p| {
+        sqlx::query(&insert)
+            .bind(principal)
+            .bind(key)
+            .bind(&stored.fingerprint)
+            .bind(stored.status)
+            .bind(&stored.content_type)
+            .bind(&stored.body)
+            .bind(now)
+            .bind(expires_at)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected())
+    })?;
+
+    Ok(())
+}
+
+/// Delete every expired key
+pub async fn purge_expired(pool: &SqlxPool) -> Result<u64, sqlx::Error> {
+    let sql = pool.sql("DELETE FROM idempotency_keys WHERE expires_at <= ?");
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let result = with_pool!(pool, |p| sqlx::query(&sql)
+        .bind(now)
+        .execute(p)
+        .await
+        .map(|r| r.rows_affected()))?;
+    Ok(result)
+}
+
+/// Purge the expired keys forever at the given interval
+pub async fn continuously_purge_expired(pool: SqlxPool, period: std::time::Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        if let Err(err) = purge_expired(&pool).await {
+            tracing::warn!("Failed to purge expired idempotency keys: {}", err);
+        }
+    }
+}
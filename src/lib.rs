@@ -0,0 +1,691 @@
+//! The administration center API
+//! The server can either be started through the binary, or embedded in a larger one by calling
+//! [`run`], or [`start`] to learn the address it listens on (e.g. with `PORT=0`). [`build_app`]
+//! builds the router alone, so that it can be driven in-process.
+
+use std::{fmt, net::SocketAddr, sync::Arc};
+
+use anyhow::{Context, Result};
+use axum::{http::Method, middleware, routing::get, Router};
+use futures_util::FutureExt;
+use tokio::signal;
+use tower_sessions::{cookie::time::Duration, SessionManagerLayer};
+
+use auth::oidc::OidcProvider;
+use config::Config;
+use database::SqlxPool;
+use mailer::MailerHandle;
+use modules::ModuleRegistry;
+use reporting::ReporterHandle;
+use session_activity::LastSeenStore;
+use session_backend::{DynSessionStore, SessionStoreRegistry};
+use session_store::SqlxSessionStore;
+use state::AppState;
+use supervisor::Supervisor;
+
+mod access_log;
+mod admin;
+pub mod api_keys;
+mod audit;
+pub mod auth;
+mod bootstrap;
+pub mod build_info;
+pub mod chaos;
+pub mod config;
+mod cors;
+mod csrf;
+pub mod database;
+pub mod error;
+mod etag;
+pub mod events;
+mod failure_capture;
+pub mod feature_flags;
+mod forwarded;
+pub mod groups;
+mod health;
+mod http_client;
+mod https_redirect;
+mod i18n;
+mod idempotency;
+pub mod logging;
+pub mod mailer;
+mod method_not_allowed;
+pub mod modules;
+mod negotiate;
+mod normalize_path;
+mod not_found;
+mod partitioned_cookies;
+pub mod permissions;
+mod proxy_protocol;
+pub mod rate_limit;
+mod redact;
+pub mod reporting;
+mod request_id;
+pub mod roles;
+mod seed;
+mod server;
+mod server_header;
+pub mod session_activity;
+pub mod session_backend;
+pub mod session_data;
+mod session_store;
+pub mod settings;
+pub mod state;
+mod static_files;
+pub mod supervisor;
+pub mod systemd;
+pub mod tenant;
+pub mod transaction;
+pub mod user_admin;
+pub mod users;
+pub mod webhooks;
+
+// Configuration for the session layer
+const SESSION_STORE_EXPIRATION: Duration = Duration::minutes(20);
+
+/// The phases the server goes through before accepting traffic
+#[derive(Clone, Copy, Debug)]
+enum StartupPhase {
+    Connecting,
+    Migrating,
+    WarmingPool,
+}
+
+impl fmt::Display for StartupPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartupPhase::Connecting => write!(f, "connecting to the database"),
+            StartupPhase::Migrating => write!(f, "running migrations"),
+            StartupPhase::WarmingPool => write!(f, "warming up the connection pool"),
+        }
+    }
+}
+
+/// Connect to the database and run the migrations, without serving requests
+pub async fn migrate(config: &Config) -> Result<()> {
+    initialize(config, &ModuleRegistry::default(), false).await?;
+    Ok(())
+}
+
+/// Connect to the database, run the migrations and create `count` synthetic sessions, returning
+/// their IDs
+pub async fn seed_sessions(config: &Config, count: usize) -> Result<Vec<String>> {
+    let pool = initialize(config, &ModuleRegistry::default(), false).await?;
+    let store = build_session_store(config, &pool, &SessionStoreRegistry::default())?;
+    let ids = seed::sessions(&store, count, SESSION_STORE_EXPIRATION)
+        .await
+        .with_context(|| "Failed to create the sessions")?;
+    pool.close().await;
+    Ok(ids.iter().map(|id| id.to_string()).collect())
+}
+
+/// Go through the startup phases, aborting if they take longer than the configured timeout
+///
+/// When `warm_up` is false, the pool is returned right after the migrations.
+async fn initialize(config: &Config, modules: &ModuleRegistry, warm_up: bool) -> Result<SqlxPool> {
+    let mut phase = StartupPhase::Connecting;
+    let startup = startup_phases(config, modules, warm_up, &mut phase);
+
+    match config.startup_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, startup).await.map_err(|_| {
+            anyhow::anyhow!(
+                "Startup did not complete within {:?} (stuck while {})",
+                timeout,
+                phase
+            )
+        })?,
+        None => startup.await,
+    }
+}
+
+/// Connect to the database, run the migrations (including the ones of the enabled modules) and
+/// warm up the pool
+async fn startup_phases(
+    config: &Config,
+    modules: &ModuleRegistry,
+    warm_up: bool,
+    phase: &mut StartupPhase,
+) -> Result<SqlxPool> {
+    *phase = StartupPhase::Connecting;
+    let pool = SqlxPool::connect(config).await.with_context(|| {
+        format!(
+            "Failed to connect to the database {}",
+            config.database_uri.redacted_string()
+        )
+    })?;
+
+    *phase = StartupPhase::Migrating;
+    pool.migrate(config.allow_dirty_migrations).await?;
+    report_migrations(&pool, config.allow_dirty_migrations).await?;
+    let session_store = SqlxSessionStore::new(pool.clone());
+    session_store
+        .migrate()
+        .await
+        .with_context(|| "Failed to migrate session store")?;
+    session_store.check_schema().await?;
+    for module in modules.enabled(&config.disabled_modules)? {
+        module
+            .migrate(&pool)
+            .await
+            .map_err(|err| pool.annotate_migration_error(err))
+            .with_context(|| format!("Failed to migrate module '{}'", module.name()))?;
+    }
+
+    if warm_up {
+        *phase = StartupPhase::WarmingPool;
+        pool.warm_up().await?;
+    }
+
+    Ok(pool)
+}
+
+/// Log the version of the schema, failing on a dirty migration unless allowed
+async fn report_migrations(pool: &SqlxPool, allow_dirty: bool) -> Result<()> {
+    let report = pool.migration_report().await?;
+    match report.latest_version {
+        Some(version) => tracing::info!(
+            "Database schema at version {} ({} migrations applied)",
+            version,
+            report.applied
+        ),
+        None => tracing::info!("Database schema has no migration applied"),
+    }
+
+    if report.dirty.is_empty() {
+        return Ok(());
+    }
+    tracing::warn!(
+        "Dirty (partially applied) migrations: {:?}, the schema may be inconsistent",
+        report.dirty
+    );
+    if !allow_dirty {
+        anyhow::bail!(
+            "Dirty migrations {:?}: repair the schema and delete their rows from \
+             _sqlx_migrations, or set ALLOW_DIRTY_MIGRATIONS=1",
+            report.dirty
+        );
+    }
+    Ok(())
+}
+
+/// Build the application over an already migrated pool, with the built-in session stores and
+/// modules
+///
+/// No background task is started and the application is not marked as ready, which is left to
+/// [`run`].
+pub async fn build_app(config: &Config, pool: SqlxPool) -> Result<Router> {
+    let reporter = ReporterHandle::from_config(&config.error_reporting);
+    build_app_with(
+        config,
+        pool,
+        &ModuleRegistry::default(),
+        reporter,
+        std::convert::identity,
+    )
+    .await
+}
+
+/// Same as [`build_app`], with the given modules and error reporter, the router being passed
+/// through `transform` once every layer of the crate is applied (e.g. to add the layers of the
+/// embedder)
+pub async fn build_app_with(
+    config: &Config,
+    pool: SqlxPool,
+    modules: &ModuleRegistry,
+    reporter: ReporterHandle,
+    transform: impl FnOnce(Router) -> Router,
+) -> Result<Router> {
+    let config = Arc::new(config.clone());
+    let store = build_session_store(&config, &pool, &SessionStoreRegistry::default())?;
+    let replica = SqlxPool::connect_replica(&config)?;
+    let oidc = discover_oidc(&config).await?;
+    let state = AppState::new(config, pool, reporter)
+        .with_replica(replica)
+        .with_oidc(oidc);
+    state
+        .settings
+        .reload()
+        .await
+        .with_context(|| "Failed to load the settings")?;
+    Ok(transform(app(state, store, modules)?))
+}
+
+/// Discover the OpenID Connect provider, if configured
+async fn discover_oidc(config: &Config) -> Result<Option<Arc<OidcProvider>>> {
+    let Some(oidc) = &config.oidc else {
+        return Ok(None);
+    };
+    let provider = OidcProvider::discover(oidc).await.with_context(|| {
+        format!(
+            "Failed to discover the OpenID Connect provider {}",
+            oidc.issuer
+        )
+    })?;
+    Ok(Some(Arc::new(provider)))
+}
+
+/// A started server, serving requests in the background until a shutdown signal is received
+pub struct Server {
+    local_addr: SocketAddr,
+    task: tokio::task::JoinHandle<Result<()>>,
+}
+
+impl Server {
+    /// The address the server listens on, with the port picked by the system when `PORT=0`
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Wait for the server to shut down
+    pub async fn wait(self) -> Result<()> {
+        self.task
+            .await
+            .with_context(|| "The server task panicked")?
+    }
+}
+
+/// Initialize the backend and serve requests until a shutdown signal is received
+///
+/// The requests are accepted on `listener` if given (e.g. the socket passed by systemd, see
+/// [`systemd::take_listener`]), on `HOST:PORT` otherwise.
+pub async fn run(config: Config, listener: Option<std::net::TcpListener>) -> Result<()> {
+    start(config, listener).await?.wait().await
+}
+
+/// Same as [`run`], picking the session store and the modules from the given registries,
+/// reporting errors to the given reporter, and passing the router through `transform` (see
+/// [`build_app_with`])
+pub async fn run_with(
+    config: Config,
+    listener: Option<std::net::TcpListener>,
+    session_stores: SessionStoreRegistry,
+    modules: ModuleRegistry,
+    reporter: ReporterHandle,
+    transform: impl FnOnce(Router) -> Router,
+) -> Result<()> {
+    start_with(
+        config,
+        listener,
+        session_stores,
+        modules,
+        reporter,
+        transform,
+    )
+    .await?
+    .wait()
+    .await
+}
+
+/// Initialize the backend and start serving requests, returning once the listener is bound
+///
+/// See [`run`] for `listener`.
+pub async fn start(config: Config, listener: Option<std::net::TcpListener>) -> Result<Server> {
+    let reporter = ReporterHandle::from_config(&config.error_reporting);
+    start_with(
+        config,
+        listener,
+        SessionStoreRegistry::default(),
+        ModuleRegistry::default(),
+        reporter,
+        std::convert::identity,
+    )
+    .await
+}
+
+/// Same as [`start`], picking the session store and the modules from the given registries,
+/// reporting errors to the given reporter, and passing the router through `transform` (see
+/// [`build_app_with`])
+pub async fn start_with(
+    config: Config,
+    listener: Option<std::net::TcpListener>,
+    session_stores: SessionStoreRegistry,
+    modules: ModuleRegistry,
+    reporter: ReporterHandle,
+    transform: impl FnOnce(Router) -> Router,
+) -> Result<Server> {
+    let pool = initialize(&config, &modules, true).await?;
+    bootstrap::admin(&config, &pool)
+        .await
+        .with_context(|| "Failed to create the first admin")?;
+    let config = Arc::new(config);
+
+    let store = build_session_store(&config, &pool, &session_stores)?;
+    let replica = SqlxPool::connect_replica(&config)?;
+    let oidc = discover_oidc(&config).await?;
+    let (access_log, access_log_task) = match &config.access_log {
+        Some(access_log_config) => {
+            let (access_log, task) = access_log::AccessLog::spawn(access_log_config)
+                .await
+                .with_context(|| "Failed to open the access log")?;
+            (Some(access_log), Some(task))
+        }
+        None => (None, None),
+    };
+    let state = AppState::new(config.clone(), pool.clone(), reporter)
+        .with_replica(replica)
+        .with_access_log(access_log.clone())
+        .with_mailer(
+            MailerHandle::from_config(config.mail.as_ref())
+                .with_context(|| "Failed to set up the SMTP mailer")?,
+        )
+        .with_oidc(oidc);
+    state
+        .settings
+        .reload()
+        .await
+        .with_context(|| "Failed to load the settings")?;
+    let app = transform(app(state.clone(), store.clone(), &modules)?);
+
+    let mut supervisor = Supervisor::default();
+    supervisor.spawn(
+        "session-deletion",
+        store
+            .clone()
+            .continuously_sweep(state.session_sweep.clone()),
+    );
+    if let Some(period) = config.session_write_behind {
+        supervisor.spawn("session-flush", store.clone().continuously_flush(period));
+    }
+    supervisor.spawn("mail-delivery", state.mailer.clone().continuously_deliver());
+    supervisor.spawn("audit-write", state.audit.clone().continuously_write());
+    supervisor.spawn(
+        "idempotency-purge",
+        idempotency::continuously_purge_expired(pool, tokio::time::Duration::from_secs(60)),
+    );
+    for module in modules.enabled(&config.disabled_modules)? {
+        module.tasks(&state, &mut supervisor);
+    }
+
+    // Start the server, on the given socket if any
+    let listener = match listener {
+        Some(listener) => tokio::net::TcpListener::from_std(listener)
+            .with_context(|| "Failed to register the listener")?,
+        None => server::bind(&config.host, config.port, config.bind_ipv6_only)
+            .await
+            .with_context(|| "Failed to bind the listener")?,
+    };
+    let local_addr = listener
+        .local_addr()
+        .with_context(|| "Failed to get the address of the listener")?;
+    tracing::info!("Listening on {}", local_addr);
+
+    let redirect_listener = match &config.http_redirect {
+        Some(http_redirect) => Some(
+            server::bind(&config.host, http_redirect.port, config.bind_ipv6_only)
+                .await
+                .with_context(|| "Failed to bind the HTTP redirect listener")?,
+        ),
+        None => None,
+    };
+    if let Some(redirect_listener) = &redirect_listener {
+        let redirect_addr = redirect_listener
+            .local_addr()
+            .with_context(|| "Failed to get the address of the HTTP redirect listener")?;
+        tracing::info!("Redirecting HTTP requests on {}", redirect_addr);
+    }
+
+    // The listener is bound and every startup phase is done: accept traffic
+    state.mark_ready();
+    systemd::notify(systemd::NotifyState::Ready);
+
+    if let Some(interval) = systemd::watchdog_interval() {
+        supervisor.spawn("systemd-watchdog", systemd::run_watchdog(interval));
+    }
+
+    let task = tokio::spawn(async move {
+        // Both listeners stop on the same signal
+        let shutdown = shutdown_signal().shared();
+        let redirect_server = redirect_listener.zip(config.http_redirect.as_ref()).map(
+            |(redirect_listener, http_redirect)| {
+                tokio::spawn(server::serve(
+                    redirect_listener,
+                    https_redirect::router(http_redirect),
+                    config.proxy_protocol,
+                    config.max_header_bytes,
+                    shutdown.clone(),
+                ))
+            },
+        );
+
+        server::serve(
+            listener,
+            app,
+            config.proxy_protocol,
+            config.max_header_bytes,
+            shutdown,
+        )
+        .await;
+        if let Some(redirect_server) = redirect_server {
+            let _ = redirect_server.await;
+        }
+
+        supervisor.shutdown().await;
+
+        // Write the buffered session saves once every request is done
+        if let Err(err) = store.flush().await {
+            tracing::error!("Failed to flush session saves: {}", err);
+        }
+
+        // Write the queued audit log entries once every request is done
+        state.audit.flush().await;
+
+        // Send the pending error reports
+        state
+            .reporter
+            .flush(std::time::Duration::from_secs(5))
+            .await;
+
+        // Flush the access log once every request is done
+        if let (Some(access_log), Some(task)) = (access_log, access_log_task) {
+            access_log.close().await;
+            let _ = task.await;
+        }
+
+        // Return the connections once nothing uses them anymore
+        state.write_pool().close().await;
+        if let Some(replica) = &state.replica {
+            replica.close().await;
+        }
+        tracing::info!("Closed the database connections");
+
+        Ok(())
+    });
+
+    Ok(Server { local_addr, task })
+}
+
+/// Create the session store from the session store URI (defaults to the database)
+fn build_session_store(
+    config: &Config,
+    pool: &SqlxPool,
+    session_stores: &SessionStoreRegistry,
+) -> Result<DynSessionStore> {
+    let store_uri = config
+        .session_store_uri
+        .clone()
+        .unwrap_or_else(|| config.database_uri.get_connection_string());
+    let mut store = session_stores
+        .build(&store_uri, pool)
+        .with_context(|| "Failed to create the session store")?;
+    if let Some(interval) = config.session_last_seen {
+        store = DynSessionStore::new(LastSeenStore::new(
+            store,
+            pool.clone(),
+            interval,
+            SESSION_STORE_EXPIRATION.unsigned_abs(),
+        ));
+    }
+    Ok(store
+        .with_absolute_max(config.session_absolute_max)
+        .with_write_behind(config.session_write_behind.is_some()))
+}
+
+/// Describe the application
+///
+/// Fails when the routes of the enabled modules conflict.
+fn app(state: AppState, store: DynSessionStore, modules: &ModuleRegistry) -> Result<Router> {
+    let session_layer = SessionManagerLayer::new(store)
+        .with_path(state.config.base_path.clone())
+        .with_secure(state.config.session_cookie.secure)
+        .with_same_site(state.config.session_cookie.same_site)
+        .with_expiry(tower_sessions::Expiry::OnInactivity(
+            SESSION_STORE_EXPIRATION,
+        ));
+
+    let app = modules
+        .router(&state.config.disabled_modules, state.clone())?
+        .layer(middleware::from_fn(transaction::commit))
+        // Within the authentication, so that the keys are scoped to the principal
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            idempotency::idempotency,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            reporting::report_errors,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::password_change::enforce,
+        ))
+        .layer(middleware::from_fn(auth::impersonation::guard));
+
+    // The CSRF tokens are kept in the session, and the requests authenticated with an API key or a
+    // token are not checked
+    let app = if state.config.csrf.enabled {
+        app.layer(middleware::from_fn_with_state(state.clone(), csrf::protect))
+    } else {
+        app
+    }
+    .layer(middleware::from_fn_with_state(
+        state.clone(),
+        api_keys::authenticate,
+    ))
+    .layer(middleware::from_fn_with_state(
+        state.clone(),
+        auth::jwt::authenticate,
+    ))
+    .layer(session_layer);
+    let app = if state.config.session_cookie.partitioned {
+        app.layer(middleware::from_fn(partitioned_cookies::partition))
+    } else {
+        app
+    };
+
+    // The static assets bypass the session layer
+    let app = match static_files::router(&state) {
+        Some(static_files) => app.merge(static_files),
+        None => app,
+    };
+
+    let app = app
+        .route("/health", get(health::health))
+        .route("/readyz", get(health::readyz));
+    state.routes.add(Method::GET, "/health");
+    state.routes.add(Method::GET, "/readyz");
+
+    // Set once the session layer is applied, so that it doesn't wrap the fallback
+    let app = app.fallback(not_found::fallback);
+
+    // The faults are injected within the access log, so that they are visible there
+    let app = if state.config.chaos.enabled {
+        tracing::warn!("Fault injection is enabled");
+        app.layer(middleware::from_fn_with_state(
+            state.clone(),
+            chaos::inject_faults,
+        ))
+    } else {
+        app
+    };
+
+    // Nothing is buffered unless the capture is enabled
+    let app = if state.config.debug.capture_failed_bodies {
+        app.layer(middleware::from_fn_with_state(
+            state.clone(),
+            failure_capture::capture_failed_bodies,
+        ))
+    } else {
+        app
+    };
+
+    let app = app
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            method_not_allowed::explain,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            i18n::localize,
+        ))
+        .layer(middleware::from_fn(negotiate::encode_errors))
+        // Around everything setting cookies, including the errors
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            forwarded::secure,
+        ))
+        // Preflight requests are answered before anything else, and errors are shared too
+        .layer(middleware::from_fn_with_state(state.clone(), cors::cors))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            access_log::log_requests,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            tenant::resolve,
+        ))
+        .layer(middleware::from_fn(request_id::request_id))
+        .with_state(state.clone());
+
+    // Serve under the base path, if any (axum doesn't support nesting at the root)
+    let app = if state.config.base_path == "/" {
+        app
+    } else {
+        // Outside of the base path, the errors are left in JSON
+        Router::new()
+            .nest(&state.config.base_path, app)
+            .fallback(not_found::fallback)
+    };
+
+    let static_prefix = state.config.static_files.dir.as_ref().map(|_| {
+        format!(
+            "{}{}",
+            state.config.base_path.trim_end_matches('/'),
+            state.config.static_files.prefix
+        )
+    });
+    let app = normalize_path::wrap(
+        app,
+        state.config.path_normalization,
+        state.config.trailing_slash,
+        static_prefix,
+    );
+    // Outermost, so that the redirects of the normalization get the header too
+    Ok(server_header::wrap(app, &state.config.server_header))
+}
+
+// Resolves when the server should shut down
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to register signal handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    systemd::notify(systemd::NotifyState::Stopping);
+}
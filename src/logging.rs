@@ -0,0 +1,183 @@
+//! Logging, with a filter adjustable at runtime
+//! [`init`] installs the global subscriber, filtered through `RUST_LOG`. The filter can then be
+//! replaced without a restart through `PUT /admin/logging`, optionally reverting to `RUST_LOG`
+//! after a while. Embedders installing their own subscriber don't get the endpoint.
+
+use std::{
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::task::JoinHandle;
+use tracing_subscriber::{
+    filter::EnvFilter, layer::SubscriberExt, reload, util::SubscriberInitExt, Registry,
+};
+
+use crate::{
+    error::AppError,
+    negotiate::{Format, Negotiated},
+};
+
+/// The longest delay before the filter reverts to the default
+pub const MAX_REVERT_AFTER: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The control of the filter, set once the subscriber is installed by [`init`]
+static CONTROL: OnceLock<LogControl> = OnceLock::new();
+
+/// Install the global subscriber, logging to the standard output
+///
+/// # Panics
+///
+/// Panics when a global subscriber is already installed.
+pub fn init() {
+    let default = std::env::var(EnvFilter::DEFAULT_ENV).unwrap_or_default();
+    let (filter, handle) = reload::Layer::new(EnvFilter::builder().parse_lossy(&default));
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    let _ = CONTROL.set(LogControl {
+        handle,
+        default: default.clone(),
+        current: Mutex::new(Current {
+            filter: default,
+            generation: 0,
+            revert: None,
+        }),
+    });
+}
+
+/// The control of the filter, if the subscriber was installed by [`init`]
+pub fn control() -> Option<&'static LogControl> {
+    CONTROL.get()
+}
+
+/// The reloadable filter of the global subscriber
+pub struct LogControl {
+    handle: reload::Handle<EnvFilter, Registry>,
+    /// The filter given through `RUST_LOG`
+    default: String,
+    current: Mutex<Current>,
+}
+
+struct Current {
+    filter: String,
+    /// Incremented on every change, so that a late revert can tell it is outdated
+    generation: u64,
+    /// When the filter reverts to the default, and the task doing so
+    revert: Option<(OffsetDateTime, JoinHandle<()>)>,
+}
+
+impl LogControl {
+    /// Describe the current filter
+    pub fn status(&self) -> LoggingStatus {
+        let current = self.current.lock().unwrap();
+        LoggingStatus {
+            filter: current.filter.clone(),
+            default_filter: self.default.clone(),
+            revert_at: current.revert.as_ref().map(|(at, _)| *at),
+        }
+    }
+
+    /// Replace the filter, reverting to the default after the given duration if any
+    ///
+    /// Any pending revert is cancelled. The duration can't exceed [`MAX_REVERT_AFTER`].
+    pub fn set(&'static self, filter: &str, revert_after: Option<Duration>) -> Result<(), String> {
+        if revert_after.is_some_and(|revert_after| revert_after > MAX_REVERT_AFTER) {
+            return Err(revert_too_late());
+        }
+        let parsed = EnvFilter::builder()
+            .parse(filter)
+            .map_err(|e| format!("invalid filter '{}': {}", filter, e))?;
+
+        let mut current = self.current.lock().unwrap();
+        self.handle.reload(parsed).map_err(|e| e.to_string())?;
+        current.filter = filter.to_string();
+        current.generation += 1;
+        if let Some((_, task)) = current.revert.take() {
+            task.abort();
+        }
+        if let Some(revert_after) = revert_after {
+            let generation = current.generation;
+            let task = tokio::spawn(async move {
+                tokio::time::sleep(revert_after).await;
+                self.revert(generation);
+            });
+            current.revert = Some((OffsetDateTime::now_utc() + revert_after, task));
+        }
+
+        tracing::warn!("Log filter set to '{}'", filter);
+        Ok(())
+    }
+
+    /// Go back to the default filter, unless it changed since the given generation
+    fn revert(&self, generation: u64) {
+        let mut current = self.current.lock().unwrap();
+        if current.generation != generation {
+            return;
+        }
+        if let Err(err) = self
+            .handle
+            .reload(EnvFilter::builder().parse_lossy(&self.default))
+        {
+            tracing::error!("Failed to revert the log filter: {}", err);
+            return;
+        }
+        current.filter = self.default.clone();
+        current.generation += 1;
+        current.revert = None;
+        tracing::warn!("Log filter reverted to '{}'", self.default);
+    }
+}
+
+/// The current filter
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoggingStatus {
+    /// The filter currently applied, in the `RUST_LOG` syntax
+    pub filter: String,
+    /// The filter given through `RUST_LOG`
+    pub default_filter: String,
+    /// When the filter reverts to the default, if it does
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub revert_at: Option<OffsetDateTime>,
+}
+
+/// A new filter
+#[derive(Debug, Deserialize)]
+pub struct LoggingUpdate {
+    /// The filter to apply, in the `RUST_LOG` syntax
+    pub filter: String,
+    /// Revert to the default filter after this many seconds
+    pub revert_after_secs: Option<u64>,
+}
+
+/// `GET /admin/logging`: describe the current filter
+pub async fn get_filter(format: Format) -> Result<Negotiated<LoggingStatus>, AppError> {
+    let control = control().ok_or(AppError::Unavailable)?;
+    Ok(Negotiated(format, control.status()))
+}
+
+/// `PUT /admin/logging`: replace the filter
+pub async fn put_filter(
+    Negotiated(format, update): Negotiated<LoggingUpdate>,
+) -> Result<Negotiated<LoggingStatus>, AppError> {
+    let control = control().ok_or(AppError::Unavailable)?;
+    let revert_after = update.revert_after_secs.map(Duration::from_secs);
+    if revert_after.is_some_and(|revert_after| revert_after > MAX_REVERT_AFTER) {
+        return Err(AppError::Unprocessable(revert_too_late()));
+    }
+    control
+        .set(&update.filter, revert_after)
+        .map_err(AppError::BadRequest)?;
+    Ok(Negotiated(format, control.status()))
+}
+
+fn revert_too_late() -> String {
+    format!(
+        "revert_after_secs can't exceed {}",
+        MAX_REVERT_AFTER.as_secs()
+    )
+}
@@ -0,0 +1,263 @@
+//! Emails sent to the users
+//! Emails, such as the password reset ones, are rendered from [`templates`] and queued on the
+//! [`MailerHandle`] of the state. A background task delivers them through the [`Mailer`],
+//! retrying failed deliveries with a backoff, so that a failing mail server never fails the
+//! request that sent the email. When the queue is full, emails are dropped.
+//!
+//! The mailer is an SMTP client when `MAIL_SMTP_HOST` is set, and otherwise only writes the
+//! emails to the logs, which suits development. Embedders can plug another one (e.g. an HTTP
+//! API) by implementing [`Mailer`].
+
+pub mod smtp;
+pub mod templates;
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use axum::async_trait;
+use tokio::sync::mpsc;
+
+use crate::config::MailConfig;
+
+/// The number of emails queued before emails get dropped
+const QUEUE_CAPACITY: usize = 1024;
+
+/// The number of delivery attempts of an email before it is dropped
+const MAX_ATTEMPTS: u32 = 5;
+
+/// The delay before the first retry, doubled by each further one
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// An email, in plain text and optionally in HTML
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+    pub html: Option<String>,
+}
+
+/// A way to deliver emails
+#[async_trait]
+pub trait Mailer: Send + Sync + 'static {
+    async fn send(&self, email: Email) -> Result<()>;
+}
+
+/// Writes the emails to the logs, instead of delivering them
+///
+/// The bodies may carry secrets, such as password reset tokens: not to be used in production.
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: Email) -> Result<()> {
+        tracing::info!(
+            to = %email.to,
+            subject = %email.subject,
+            "Email not delivered (no mailer configured):\n{}",
+            email.text
+        );
+        Ok(())
+    }
+}
+
+/// Keeps the emails in memory instead of delivering them, to inspect them
+#[derive(Clone, Default)]
+pub struct NullMailer {
+    sent: Arc<Mutex<Vec<Email>>>,
+}
+
+impl NullMailer {
+    /// The emails sent so far
+    pub fn sent(&self) -> Vec<Email> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Mailer for NullMailer {
+    async fn send(&self, email: Email) -> Result<()> {
+        self.sent.lock().unwrap().push(email);
+        Ok(())
+    }
+}
+
+/// A shared handle to the mailer, queuing the emails for the delivery task
+#[derive(Clone)]
+pub struct MailerHandle {
+    mailer: Arc<dyn Mailer>,
+    sender: mpsc::Sender<Email>,
+    /// Taken by the delivery task when it starts
+    receiver: Arc<Mutex<Option<mpsc::Receiver<Email>>>>,
+}
+
+impl Default for MailerHandle {
+    fn default() -> Self {
+        Self::new(LogMailer)
+    }
+}
+
+impl MailerHandle {
+    /// Send the emails through the given mailer
+    pub fn new(mailer: impl Mailer) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        Self {
+            mailer: Arc::new(mailer),
+            sender,
+            receiver: Arc::new(Mutex::new(Some(receiver))),
+        }
+    }
+
+    /// Send the emails through SMTP when configured, or to the logs otherwise
+    pub fn from_config(config: Option<&MailConfig>) -> Result<Self> {
+        Ok(match config {
+            Some(config) => Self::new(smtp::SmtpMailer::new(config.clone())?),
+            None => Self::default(),
+        })
+    }
+
+    /// Queue an email for delivery
+    ///
+    /// Never waits: the email is dropped when the queue is full.
+    pub fn enqueue(&self, email: Email) {
+        if let Err(err) = self.sender.try_send(email) {
+            tracing::error!(
+                "Dropped an email, the mail queue is full or closed: {}",
+                err
+            );
+        }
+    }
+
+    /// Deliver the queued emails, forever
+    ///
+    /// Only one delivery task runs per handle: the next ones return immediately.
+    pub async fn continuously_deliver(self) {
+        let Some(mut receiver) = self.receiver.lock().unwrap().take() else {
+            tracing::warn!("The mail delivery task is already running");
+            return;
+        };
+
+        while let Some(email) = receiver.recv().await {
+            self.deliver(email).await;
+        }
+    }
+
+    /// Deliver an email, retrying with a backoff when it fails
+    async fn deliver(&self, email: Email) {
+        let mut delay = RETRY_DELAY;
+        for attempt in 1..=MAX_ATTEMPTS {
+            match self.mailer.send(email.clone()).await {
+                Ok(()) => return,
+                Err(err) if attempt < MAX_ATTEMPTS => {
+                    tracing::warn!(
+                        "Failed to send '{}' (attempt {}/{}), retrying in {:?}: {:#}",
+                        email.subject,
+                        attempt,
+                        MAX_ATTEMPTS,
+                        delay,
+                        err
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(err) => {
+                    tracing::error!(
+                        "Failed to send '{}', giving up after {} attempts: {:#}",
+                        email.subject,
+                        MAX_ATTEMPTS,
+                        err
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use anyhow::bail;
+
+    use super::*;
+
+    fn email() -> Email {
+        Email {
+            to: "alice@example.com".to_string(),
+            subject: "Hello".to_string(),
+            text: "Hello Alice".to_string(),
+            html: None,
+        }
+    }
+
+    /// Fails the first deliveries, then keeps the emails as the [`NullMailer`]
+    struct FlakyMailer {
+        failures: AtomicU32,
+        mailer: NullMailer,
+    }
+
+    #[async_trait]
+    impl Mailer for FlakyMailer {
+        async fn send(&self, email: Email) -> Result<()> {
+            if self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                bail!("connection refused");
+            }
+            self.mailer.send(email).await
+        }
+    }
+
+    /// Wait for the mailer to have sent the given number of emails
+    async fn wait_for(mailer: &NullMailer, count: usize) {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while mailer.sent().len() < count {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the emails were not delivered");
+    }
+
+    #[tokio::test]
+    async fn delivers_the_queued_emails() {
+        let mailer = NullMailer::default();
+        let handle = MailerHandle::new(mailer.clone());
+        handle.enqueue(email());
+        handle.enqueue(email());
+        tokio::spawn(handle.clone().continuously_deliver());
+
+        wait_for(&mailer, 2).await;
+        assert_eq!(mailer.sent(), [email(), email()]);
+    }
+
+    #[tokio::test]
+    async fn retries_a_failed_delivery() {
+        let mailer = NullMailer::default();
+        let handle = MailerHandle::new(FlakyMailer {
+            failures: AtomicU32::new(1),
+            mailer: mailer.clone(),
+        });
+        handle.enqueue(email());
+        tokio::spawn(handle.clone().continuously_deliver());
+
+        wait_for(&mailer, 1).await;
+        assert_eq!(mailer.sent(), [email()]);
+    }
+
+    #[tokio::test]
+    async fn runs_a_single_delivery_task() {
+        let handle = MailerHandle::new(NullMailer::default());
+        tokio::spawn(handle.clone().continuously_deliver());
+        tokio::task::yield_now().await;
+        // Returns at once, the receiver being taken
+        tokio::time::timeout(Duration::from_secs(1), handle.continuously_deliver())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+//! Delivery of the emails through an SMTP server, with `lettre`
+//! Each email is delivered over its own connection, secured with `STARTTLS` or implicit TLS
+//! (`MAIL_SMTP_TLS`) and authenticated with `AUTH PLAIN` when credentials are configured. The
+//! message is sent as a `multipart/alternative` one when there is an HTML body.
+
+use anyhow::{Context, Result};
+use axum::async_trait;
+use lettre::{
+    message::{header::ContentType, Mailbox, MultiPart},
+    transport::smtp::{
+        authentication::{Credentials, Mechanism},
+        extension::ClientId,
+    },
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+
+use super::{Email, Mailer};
+use crate::config::{MailConfig, SmtpTls};
+
+/// The name the client gives itself in `EHLO`
+const CLIENT_NAME: &str = "localhost";
+
+/// Delivers the emails to an SMTP server
+pub struct SmtpMailer {
+    config: MailConfig,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpMailer {
+    pub fn new(config: MailConfig) -> Result<Self> {
+        let builder = match config.tls {
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+            SmtpTls::StartTls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?
+            }
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?,
+        };
+        let mut builder = builder
+            .port(config.port)
+            .hello_name(ClientId::Domain(CLIENT_NAME.to_string()))
+            .timeout(Some(config.timeout));
+        if let Some((username, password)) = &config.credentials {
+            builder = builder
+                .credentials(Credentials::new(username.clone(), password.clone()))
+                .authentication(vec![Mechanism::Plain]);
+        }
+        Ok(Self {
+            transport: builder.build(),
+            config,
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<()> {
+        let message = format_message(&self.config, &email)?;
+        self.transport
+            .send(message)
+            .await
+            .with_context(|| format!("Failed to deliver the email to {}", email.to))?;
+        Ok(())
+    }
+}
+
+/// Build the message of an email, refusing the addresses that don't parse
+fn format_message(config: &MailConfig, email: &Email) -> Result<Message> {
+    let from: Mailbox = config.from.parse().context("Invalid sender address")?;
+    let to: Mailbox = email.to.parse().context("Invalid recipient address")?;
+    let builder = Message::builder()
+        .from(from)
+        .to(to)
+        .subject(email.subject.as_str());
+    let message = match &email.html {
+        None => builder
+            .header(ContentType::TEXT_PLAIN)
+            .body(email.text.clone())?,
+        Some(html) => builder.multipart(MultiPart::alternative_plain_html(
+            email.text.clone(),
+            html.clone(),
+        ))?,
+    };
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    fn config(port: u16) -> MailConfig {
+        MailConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            tls: SmtpTls::None,
+            credentials: None,
+            from: "admin@example.com".to_string(),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    fn email(html: Option<&str>) -> Email {
+        Email {
+            to: "alice@example.com".to_string(),
+            subject: "Réinitialisation du mot de passe".to_string(),
+            text: "Hello Alice".to_string(),
+            html: html.map(str::to_string),
+        }
+    }
+
+    /// Accept one SMTP session, answering every command, and return its commands and message
+    async fn smtp_server() -> (u16, tokio::task::JoinHandle<(Vec<String>, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let task = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+            let mut commands = Vec::new();
+            let mut message = String::new();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                commands.push(line.clone());
+                let reply: &[u8] = match line.split(' ').next().unwrap() {
+                    "EHLO" => b"250-localhost\r\n250 8BITMIME\r\n",
+                    "DATA" => {
+                        writer.write_all(b"354 go ahead\r\n").await.unwrap();
+                        while let Some(line) = lines.next_line().await.unwrap() {
+                            if line == "." {
+                                break;
+                            }
+                            message.push_str(&line);
+                            message.push('\n');
+                        }
+                        b"250 queued\r\n"
+                    }
+                    "QUIT" => {
+                        writer.write_all(b"221 bye\r\n").await.unwrap();
+                        break;
+                    }
+                    _ => b"250 ok\r\n",
+                };
+                writer.write_all(reply).await.unwrap();
+            }
+            (commands, message)
+        });
+        (port, task)
+    }
+
+    #[tokio::test]
+    async fn delivers_an_email() {
+        let (port, server) = smtp_server().await;
+        let mailer = SmtpMailer::new(config(port)).unwrap();
+        mailer.send(email(None)).await.unwrap();
+
+        let (commands, message) = server.await.unwrap();
+        assert_eq!(commands[0], "EHLO localhost");
+        assert!(commands.contains(&"MAIL FROM:<admin@example.com>".to_string()));
+        assert!(commands.contains(&"RCPT TO:<alice@example.com>".to_string()));
+        assert!(message.contains("To: alice@example.com"));
+        // Not ASCII, hence encoded
+        assert!(message.contains("Subject: =?utf-8?"));
+        assert!(message.contains("Hello Alice"));
+    }
+
+    #[test]
+    fn sends_both_bodies_as_alternatives() {
+        let message = format_message(&config(25), &email(Some("<p>Hello Alice</p>"))).unwrap();
+        let formatted = String::from_utf8(message.formatted()).unwrap();
+        assert!(formatted.contains("multipart/alternative"));
+        assert!(formatted.contains("text/plain"));
+        assert!(formatted.contains("text/html"));
+        assert!(formatted.contains("<p>Hello Alice</p>"));
+    }
+
+    #[test]
+    fn refuses_header_injections() {
+        let email = Email {
+            to: "alice@example.com\r\nBcc: mallory@example.com".to_string(),
+            ..email(None)
+        };
+        assert!(format_message(&config(25), &email).is_err());
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_server_is_unreachable() {
+        // Bound then released, so that nothing listens on it
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mailer = SmtpMailer::new(config(port)).unwrap();
+        assert!(mailer.send(email(None)).await.is_err());
+    }
+}
@@ -0,0 +1,178 @@
+//! Templates of the emails
+//! A template has a subject, a plain text body and an HTML body, in which `{{name}}` is replaced
+//! by the value of the variable `name`. Values are escaped in the HTML body. Placeholders without
+//! a value are left as they are.
+
+use super::Email;
+
+/// An email template
+#[derive(Clone, Copy, Debug)]
+pub struct Template {
+    pub subject: &'static str,
+    pub text: &'static str,
+    pub html: &'static str,
+}
+
+/// The email carrying a password reset token
+///
+/// Variables: `name`, `action` (what to do with `target`), `target` (a link or the bare token),
+/// and `minutes` (before the token expires).
+pub const PASSWORD_RESET: Template = Template {
+    subject: "Reset your password",
+    text: "Hello {{name}},\n\n\
+           Someone asked to reset the password of your account. If it was you, {{action}}:\n\n\
+           {{target}}\n\n\
+           It expires in {{minutes}} minutes. Otherwise, you can ignore this email.\n",
+    html: "<p>Hello {{name}},</p>\n\
+           <p>Someone asked to reset the password of your account. If it was you, {{action}}:</p>\n\
+           <p><code>{{target}}</code></p>\n\
+           <p>It expires in {{minutes}} minutes. Otherwise, you can ignore this email.</p>\n",
+};
+
+/// The email carrying an email verification token
+///
+/// Variables: `name`, `action` (what to do with `target`), `target` (a link or the bare token),
+/// and `hours` (before the token expires).
+pub const EMAIL_VERIFICATION: Template = Template {
+    subject: "Verify your email",
+    text: "Hello {{name}},\n\n\
+           To activate your account, {{action}}:\n\n\
+           {{target}}\n\n\
+           It expires in {{hours}} hours. If you didn't create an account, you can ignore this \
+           email.\n",
+    html: "<p>Hello {{name}},</p>\n\
+           <p>To activate your account, {{action}}:</p>\n\
+           <p><code>{{target}}</code></p>\n\
+           <p>It expires in {{hours}} hours. If you didn't create an account, you can ignore \
+           this email.</p>\n",
+};
+
+/// The email carrying the token confirming a change of email, sent to the new email
+///
+/// Variables: `name`, `action` (what to do with `target`), `target` (a link or the bare token),
+/// and `hours` (before the token expires).
+pub const EMAIL_CHANGE: Template = Template {
+    subject: "Confirm your new email",
+    text: "Hello {{name}},\n\n\
+           To use this email for your account, {{action}}:\n\n\
+           {{target}}\n\n\
+           It expires in {{hours}} hours. If you didn't ask for it, you can ignore this email.\n",
+    html: "<p>Hello {{name}},</p>\n\
+           <p>To use this email for your account, {{action}}:</p>\n\
+           <p><code>{{target}}</code></p>\n\
+           <p>It expires in {{hours}} hours. If you didn't ask for it, you can ignore this \
+           email.</p>\n",
+};
+
+/// The notice sent to the previous email of a user once they changed it
+///
+/// Variables: `name` and `email` (the new one).
+pub const EMAIL_CHANGED: Template = Template {
+    subject: "Your email was changed",
+    text: "Hello {{name}},\n\n\
+           The email of your account was changed to {{email}}, which you now log in with.\n\n\
+           If you didn't do it, contact your administrator right away.\n",
+    html: "<p>Hello {{name}},</p>\n\
+           <p>The email of your account was changed to {{email}}, which you now log in with.</p>\n\
+           <p>If you didn't do it, contact your administrator right away.</p>\n",
+};
+
+/// The notice sent to a user who logged in from a new device
+///
+/// Variables: `name`, `time` (RFC 3339), `ip` and `user_agent`.
+pub const NEW_DEVICE_LOGIN: Template = Template {
+    subject: "New login to your account",
+    text: "Hello {{name}},\n\n\
+           Your account was logged in to from a new device:\n\n\
+           Time: {{time}}\n\
+           Address: {{ip}}\n\
+           Browser: {{user_agent}}\n\n\
+           If it wasn't you, change your password and contact your administrator right away.\n",
+    html: "<p>Hello {{name}},</p>\n\
+           <p>Your account was logged in to from a new device:</p>\n\
+           <ul>\n\
+           <li>Time: {{time}}</li>\n\
+           <li>Address: {{ip}}</li>\n\
+           <li>Browser: {{user_agent}}</li>\n\
+           </ul>\n\
+           <p>If it wasn't you, change your password and contact your administrator right \
+           away.</p>\n",
+};
+
+/// The email inviting someone to create their account
+///
+/// Variables: `inviter` (who sent the invitation), `action` (what to do with `target`), `target`
+/// (a link or the bare token), and `days` (before the invitation expires).
+pub const INVITATION: Template = Template {
+    subject: "You're invited to create your account",
+    text: "Hello,\n\n\
+           {{inviter}} invited you to create your account. To accept, {{action}}:\n\n\
+           {{target}}\n\n\
+           It expires in {{days}} days. If you weren't expecting it, you can ignore this email.\n",
+    html: "<p>Hello,</p>\n\
+           <p>{{inviter}} invited you to create your account. To accept, {{action}}:</p>\n\
+           <p><code>{{target}}</code></p>\n\
+           <p>It expires in {{days}} days. If you weren't expecting it, you can ignore this \
+           email.</p>\n",
+};
+
+impl Template {
+    /// Render the email to the given address
+    pub fn render(&self, to: &str, vars: &[(&str, &str)]) -> Email {
+        Email {
+            to: to.to_string(),
+            // A line break would end the header
+            subject: substitute(self.subject, vars, false).replace(['\r', '\n'], " "),
+            text: substitute(self.text, vars, false),
+            html: Some(substitute(self.html, vars, true)),
+        }
+    }
+}
+
+/// Replace the placeholders of the variables by their values, escaped for HTML if asked
+fn substitute(template: &str, vars: &[(&str, &str)], html: bool) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let value = after.find("}}").and_then(|end| {
+            let name = after[..end].trim();
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| (end, *value))
+        });
+        match value {
+            Some((end, value)) => {
+                if html {
+                    rendered.push_str(&escape_html(value));
+                } else {
+                    rendered.push_str(value);
+                }
+                rest = &after[end + 2..];
+            }
+            None => {
+                rendered.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Escape the characters with a meaning in HTML
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -1,115 +1,291 @@
-use anyhow::{Context, Result};
-use axum::{response::IntoResponse, routing::get};
-use serde::{Deserialize, Serialize};
-use session_store::{SqlxPool, SqlxSessionStore};
-use sqlx::{MySqlPool, PgPool, SqlitePool};
-use tokio::{signal, task::AbortHandle};
-use tower_sessions::{
-    cookie::time::Duration, session_store::ExpiredDeletion, Session, SessionManagerLayer,
-};
+use std::process::ExitCode;
 
-mod config;
-mod session_store;
-
-// States
-#[derive(Serialize, Deserialize, Default)]
-struct Counter(usize);
-
-// Handlers
-async fn index(session: Session) -> impl IntoResponse {
-    let counter: Counter = session.get("counter").await.unwrap().unwrap_or_default();
-    session
-        .insert("counter", Counter(counter.0 + 1))
-        .await
-        .unwrap();
-    format!("Hello {}!", counter.0)
-}
+use administration_center_api::{
+    build_info::BuildInfo,
+    config::{Config, ConfigError, RuntimeConfig},
+    roles::Role,
+    systemd,
+    user_admin::UserAdmin,
+    users::{CreatedUser, UserDetails, UserError},
+};
+use anyhow::{bail, Context, Result};
+use clap::{Args, Parser, Subcommand};
 
-// Configuration for the session layer
-const SESSION_LAYER_SECURE: bool = false;
-const SESSION_STORE_EXPIRATION: Duration = Duration::minutes(20);
+// Process exit codes, following sysexits.h so that supervisors can tell failures apart
+const EXIT_SOFTWARE: u8 = 1;
+const EXIT_NO_USER: u8 = 67;
+const EXIT_UNAVAILABLE: u8 = 69;
+const EXIT_CONFIG: u8 = 78;
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Load config based on the environment
+fn main() -> ExitCode {
+    // Load config based on the environment, the runtime is sized from it
     dotenv::dotenv().ok();
-    let config = config::Config::from_env()?;
-
-    // Connect to the database
-    let pool = match config.database_uri {
-        config::DatabaseUri::Sqlite(_) => SqlxPool::Sqlite(
-            SqlitePool::connect(&config.database_uri.get_connection_string()).await?,
-        ),
-        config::DatabaseUri::Postgres(_) => {
-            SqlxPool::Postgres(PgPool::connect(&config.database_uri.get_connection_string()).await?)
-        }
-        config::DatabaseUri::Mysql(_) => {
-            SqlxPool::MySql(MySqlPool::connect(&config.database_uri.get_connection_string()).await?)
+
+    // Read and cleared while the process has a single thread
+    let result = systemd::take_listener()
+        .with_context(|| "Failed to take the socket passed by systemd")
+        .and_then(|listener| build_runtime().and_then(|runtime| runtime.block_on(start(listener))));
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            ExitCode::from(exit_code(&err))
         }
-    };
+    }
+}
 
-    // Create the session store
-    let store = SqlxSessionStore::new(pool.clone());
+/// Build the async runtime, sized by `WORKER_THREADS` and `MAX_BLOCKING_THREADS`
+fn build_runtime() -> Result<tokio::runtime::Runtime> {
+    RuntimeConfig::from_env()?
+        .build()
+        .with_context(|| "Failed to start the async runtime")
+}
 
-    store
-        .migrate()
-        .await
-        .with_context(|| "Failed to migrate session store")?;
+/// The administration center API, or one of its maintenance commands
+#[derive(Parser)]
+#[command(disable_version_flag = true)]
+struct Cli {
+    /// Print the version, git commit and build time, and exit
+    #[arg(long)]
+    version: bool,
+    /// Run the migrations and exit
+    #[arg(long)]
+    migrate_only: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-    let deletion_task = tokio::task::spawn(
-        store
-            .clone()
-            .continuously_delete_expired(tokio::time::Duration::from_secs(60)),
-    );
+#[derive(Subcommand)]
+enum Command {
+    /// Run the migrations, create anonymous sessions with random data, print their IDs and exit
+    Seed {
+        /// The number of sessions to create
+        #[arg(long)]
+        sessions: usize,
+    },
+    /// Administer the users, e.g. when nobody can log in anymore
+    User(UserArgs),
+}
 
-    let session_layer = SessionManagerLayer::new(store)
-        .with_secure(SESSION_LAYER_SECURE)
-        .with_expiry(tower_sessions::Expiry::OnInactivity(
-            SESSION_STORE_EXPIRATION,
-        ));
+#[derive(Args)]
+struct UserArgs {
+    /// Print the result as JSON rather than as a table
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    command: UserCommand,
+}
+
+#[derive(Subcommand)]
+enum UserCommand {
+    /// Create an active user, with a generated temporary password unless one is given
+    Create {
+        #[arg(long)]
+        email: String,
+        /// Defaults to the email
+        #[arg(long)]
+        display_name: Option<String>,
+        /// A role of the user (viewer, operator or admin), repeatable
+        #[arg(long = "role", value_parser = parse_role)]
+        roles: Vec<Role>,
+        /// Read the password from the first line of the standard input
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// Replace the password of a user, revoking their sessions and lifting their lock, with a
+    /// generated temporary one unless one is given
+    SetPassword {
+        email: String,
+        /// Read the password from the first line of the standard input
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// Unlock a user locked after failed logins
+    Unlock { email: String },
+    /// Disable a user, revoking their sessions
+    Disable { email: String },
+    /// List the users with their roles
+    List,
+}
+
+async fn start(listener: Option<std::net::TcpListener>) -> Result<()> {
+    let cli = Cli::parse();
+    if cli.version {
+        println!("{}", BuildInfo::current().summary());
+        return Ok(());
+    }
 
-    // Describe the application
-    let app = axum::Router::new()
-        .route("/", get(index))
-        .layer(session_layer);
+    administration_center_api::logging::init();
+    let config = Config::from_env()?;
 
-    // Start the server
-    let listener = tokio::net::TcpListener::bind(format!("{}:{}", config.host, config.port))
-        .await
-        .unwrap();
+    if cli.migrate_only {
+        return administration_center_api::migrate(&config).await;
+    }
 
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal(deletion_task.abort_handle()))
-        .await
-        .unwrap();
+    match cli.command {
+        Some(Command::Seed { sessions }) => {
+            for id in administration_center_api::seed_sessions(&config, sessions).await? {
+                println!("{}", id);
+            }
+            Ok(())
+        }
+        Some(Command::User(args)) => administer_users(&config, args).await,
+        None => administration_center_api::run(config, listener).await,
+    }
+}
 
-    // Wait for the deletion task to finish
-    deletion_task.await??;
+/// Run a `user` subcommand and print its result
+async fn administer_users(config: &Config, args: UserArgs) -> Result<()> {
+    let admin = UserAdmin::open(config).await?;
+    let result = run_user_command(&admin, args).await;
+    admin.close().await;
+    result
+}
 
+async fn run_user_command(admin: &UserAdmin, args: UserArgs) -> Result<()> {
+    match args.command {
+        UserCommand::Create {
+            email,
+            display_name,
+            roles,
+            password_stdin,
+        } => {
+            let password = password_stdin.then(read_password).transpose()?;
+            let created = admin
+                .create(&email, display_name.as_deref(), &roles, password)
+                .await?;
+            print_created(&created, args.json)
+        }
+        UserCommand::SetPassword {
+            email,
+            password_stdin,
+        } => {
+            let password = password_stdin.then(read_password).transpose()?;
+            let changed = admin.set_password(&email, password).await?;
+            print_created(&changed, args.json)
+        }
+        UserCommand::Unlock { email } => print_users(&[admin.unlock(&email).await?], args.json),
+        UserCommand::Disable { email } => print_users(&[admin.disable(&email).await?], args.json),
+        UserCommand::List => print_users(&admin.list().await?, args.json),
+    }
+}
+
+/// Parse the name of a role
+fn parse_role(role: &str) -> Result<Role, String> {
+    Role::parse(role).ok_or_else(|| format!("unknown role '{}'", role))
+}
+
+/// Read a password from the first line of the standard input
+fn read_password() -> Result<String> {
+    let mut line = String::new();
+    std::io::stdin()
+        .read_line(&mut line)
+        .with_context(|| "Failed to read the password from the standard input")?;
+    let password = line.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        bail!("No password on the standard input");
+    }
+    Ok(password.to_string())
+}
+
+/// Print a user, followed by their temporary password if one was generated
+fn print_created(created: &CreatedUser, json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(created)?);
+        return Ok(());
+    }
+    print_users(std::slice::from_ref(&created.user), false)?;
+    if let Some(password) = &created.temporary_password {
+        println!(
+            "\nTemporary password (to change on the next login): {}",
+            password
+        );
+    }
     Ok(())
 }
 
-// Aborts the deletion task when the server is shut down
-async fn shutdown_signal(abort_handle: AbortHandle) {
-    let ctrl_c = async {
-        signal::ctrl_c()
-            .await
-            .expect("failed to install Ctrl+C handler");
-    };
+/// Print users as a table, or as JSON
+fn print_users(users: &[UserDetails], json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(users)?);
+        return Ok(());
+    }
 
-    #[cfg(unix)]
-    let terminate = async {
-        signal::unix::signal(signal::unix::SignalKind::terminate())
-            .expect("failed to register signal handler")
-            .recv()
-            .await;
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+    let header = ["ID", "EMAIL", "DISPLAY NAME", "STATUS", "ROLES", "LOCKED"];
+    let rows: Vec<[String; 6]> = users
+        .iter()
+        .map(|details| {
+            let user = &details.user;
+            [
+                user.id.to_string(),
+                user.email.clone(),
+                user.display_name.clone(),
+                user.status.as_str().to_string(),
+                details
+                    .roles
+                    .iter()
+                    .map(|role| role.as_str())
+                    .collect::<Vec<_>>()
+                    .join(","),
+                match user.locked_until {
+                    Some(until) if until > now => "yes",
+                    _ => "no",
+                }
+                .to_string(),
+            ]
+        })
+        .collect();
+    let widths: Vec<usize> = (0..header.len())
+        .map(|column| {
+            rows.iter()
+                .map(|row| row[column].chars().count())
+                .chain([header[column].len()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    let print_row = |cells: Vec<&str>| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
     };
+    print_row(header.to_vec());
+    for row in &rows {
+        print_row(row.iter().map(String::as_str).collect());
+    }
+    Ok(())
+}
 
-    #[cfg(not(unix))]
-    let terminate = std::future::pending::<()>();
+/// Get the process exit code for the given failure
+fn exit_code(err: &anyhow::Error) -> u8 {
+    if err.downcast_ref::<ConfigError>().is_some() {
+        return EXIT_CONFIG;
+    }
+    let not_found = err
+        .chain()
+        .any(|cause| matches!(cause.downcast_ref::<UserError>(), Some(UserError::NotFound)));
+    if not_found {
+        return EXIT_NO_USER;
+    }
 
-    tokio::select! {
-        _ = ctrl_c => { abort_handle.abort() },
-        _ = terminate => { abort_handle.abort() },
+    let database_unavailable = err.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<sqlx::Error>(),
+            Some(
+                sqlx::Error::Io(_)
+                    | sqlx::Error::Tls(_)
+                    | sqlx::Error::PoolTimedOut
+                    | sqlx::Error::PoolClosed
+            )
+        )
+    });
+    if database_unavailable {
+        return EXIT_UNAVAILABLE;
     }
+
+    EXIT_SOFTWARE
 }
@@ -0,0 +1,45 @@
+//! Explanation of the `405 Method Not Allowed` responses
+//! The router answers a request whose method a route doesn't support with an empty `405`. Such
+//! responses are replaced by an error envelope listing the methods of the route, also given in
+//! the `Allow` header, so that every error has a body.
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    error::{AppError, ErrorEnvelope},
+    state::AppState,
+};
+
+/// Give a body to the `405` responses of the router
+pub async fn explain(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let matched_path = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string());
+
+    let response = next.run(req).await;
+    if response.status() != StatusCode::METHOD_NOT_ALLOWED
+        || response.extensions().get::<ErrorEnvelope>().is_some()
+    {
+        return response;
+    }
+
+    // The routes are recorded relative to the base path
+    let allowed = matched_path
+        .map(|path| {
+            let base_path = state.config.base_path.as_str();
+            let path = match path.strip_prefix(base_path) {
+                Some("") => "/",
+                Some(path) if base_path != "/" => path,
+                _ => &path,
+            };
+            state.routes.methods(path)
+        })
+        .unwrap_or_default();
+    AppError::MethodNotAllowed(allowed).into_response()
+}
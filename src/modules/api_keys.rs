@@ -0,0 +1,20 @@
+//! Administration of the API keys
+
+use super::{Module, Routes};
+use crate::{admin, api_keys, permissions::Permission, state::AppState};
+
+pub struct ApiKeysModule;
+
+impl Module for ApiKeysModule {
+    fn name(&self) -> &str {
+        "api_keys"
+    }
+
+    fn routes(&self, state: AppState) -> Routes {
+        Routes::new()
+            .get("/api/v1/admin/api-keys", api_keys::list_api_keys)
+            .post("/api/v1/admin/api-keys", api_keys::create_api_key)
+            .delete("/api/v1/admin/api-keys/:id", api_keys::revoke_api_key)
+            .map(|router| admin::protect(router, state, Permission::API_KEYS_MANAGE))
+    }
+}
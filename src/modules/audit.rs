@@ -0,0 +1,33 @@
+//! Consultation of the audit log, and the pruning of its old entries
+
+use std::time::Duration;
+
+use super::{Module, Routes};
+use crate::{admin, audit, permissions::Permission, state::AppState, supervisor::Supervisor};
+
+pub struct AuditModule;
+
+impl Module for AuditModule {
+    fn name(&self) -> &str {
+        "audit"
+    }
+
+    fn routes(&self, state: AppState) -> Routes {
+        Routes::new()
+            .get("/api/v1/admin/audit", audit::list_entries)
+            .map(|router| admin::protect(router, state, Permission::AUDIT_READ))
+    }
+
+    fn tasks(&self, state: &AppState, supervisor: &mut Supervisor) {
+        if let Some(retention) = state.config.audit.retention {
+            supervisor.spawn(
+                "audit-prune",
+                audit::continuously_prune(
+                    state.write_pool().clone(),
+                    retention,
+                    Duration::from_secs(60 * 60),
+                ),
+            );
+        }
+    }
+}
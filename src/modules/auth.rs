@@ -0,0 +1,126 @@
+//! Registration, invitation, login and logout of users, their profile and login history, the verification and
+//! change of their email, the reset of their password, their two-factor authentication, their login
+//! through OpenID Connect and the tokens minted for service-to-service calls
+
+use std::time::Duration;
+
+use axum::middleware;
+
+use super::{Module, Routes};
+use crate::{
+    audit,
+    auth::{
+        email_change, email_verification, impersonation, invitations, jwt, lockout, login_history,
+        oidc, password_reset, profile, recovery_codes, registration, session, two_factor,
+    },
+    rate_limit,
+    state::AppState,
+    supervisor::Supervisor,
+};
+
+pub struct AuthModule;
+
+impl Module for AuthModule {
+    fn name(&self) -> &str {
+        "auth"
+    }
+
+    fn routes(&self, state: AppState) -> Routes {
+        Routes::new()
+            .post("/api/v1/auth/register", registration::register)
+            .post("/api/v1/auth/login", session::login)
+            .post("/api/v1/auth/logout", session::logout)
+            .get("/api/v1/auth/me", session::me)
+            .post("/api/v1/auth/token", jwt::issue)
+            .post("/api/v1/auth/impersonate/stop", impersonation::stop)
+            .post(
+                "/api/v1/auth/password/forgot",
+                password_reset::forgot_password,
+            )
+            .post(
+                "/api/v1/auth/password/reset",
+                password_reset::reset_password,
+            )
+            .post(
+                "/api/v1/auth/email/verify",
+                email_verification::verify_email,
+            )
+            .post(
+                "/api/v1/auth/email/resend",
+                email_verification::resend_verification,
+            )
+            .post(
+                "/api/v1/auth/invitations/accept",
+                invitations::accept_invitation,
+            )
+            .post(
+                "/api/v1/auth/email/confirm-change",
+                email_change::confirm_change,
+            )
+            .post("/api/v1/auth/2fa/setup", two_factor::setup)
+            .post("/api/v1/auth/2fa/confirm", two_factor::confirm)
+            .post("/api/v1/auth/2fa/verify", two_factor::verify)
+            .delete("/api/v1/auth/2fa", two_factor::disable)
+            .post(
+                "/api/v1/auth/2fa/recovery/regenerate",
+                recovery_codes::regenerate,
+            )
+            .get("/api/v1/auth/oidc/login", oidc::login)
+            .get("/api/v1/auth/oidc/callback", oidc::callback)
+            .get("/api/v1/users/me", profile::get_profile)
+            .patch("/api/v1/users/me", profile::update_profile)
+            .post("/api/v1/users/me/email", email_change::request_change)
+            .get("/api/v1/users/me/logins", login_history::my_logins)
+            .merge(
+                Routes::new()
+                    .put("/api/v1/users/me/password", profile::change_password)
+                    .map(|router| {
+                        router.route_layer(middleware::from_fn_with_state(state, audit::capture))
+                    }),
+            )
+    }
+
+    fn tasks(&self, state: &AppState, supervisor: &mut Supervisor) {
+        supervisor.spawn(
+            "login-attempts-purge",
+            lockout::continuously_purge(
+                state.write_pool().clone(),
+                state.config.lockout.clone(),
+                Duration::from_secs(60 * 60),
+            ),
+        );
+        supervisor.spawn(
+            "rate-limits-purge",
+            rate_limit::continuously_purge(state.rate_limiter.clone(), Duration::from_secs(60)),
+        );
+        supervisor.spawn(
+            "login-history-purge",
+            login_history::continuously_purge(
+                state.write_pool().clone(),
+                state.config.login_history.retention,
+                Duration::from_secs(60 * 60),
+            ),
+        );
+        supervisor.spawn(
+            "password-reset-purge",
+            password_reset::continuously_purge(
+                state.write_pool().clone(),
+                Duration::from_secs(60 * 60),
+            ),
+        );
+        supervisor.spawn(
+            "email-verification-purge",
+            email_verification::continuously_purge(
+                state.write_pool().clone(),
+                Duration::from_secs(60 * 60),
+            ),
+        );
+        supervisor.spawn(
+            "email-change-purge",
+            email_change::continuously_purge(
+                state.write_pool().clone(),
+                Duration::from_secs(60 * 60),
+            ),
+        );
+    }
+}
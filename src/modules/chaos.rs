@@ -0,0 +1,24 @@
+//! Runtime control of the fault injection
+
+use super::{Module, Routes};
+use crate::{admin, chaos, permissions::Permission, state::AppState};
+
+pub struct ChaosModule;
+
+impl Module for ChaosModule {
+    fn name(&self) -> &str {
+        "chaos"
+    }
+
+    fn routes(&self, state: AppState) -> Routes {
+        // The control endpoint only exists when the faults are injected
+        if !state.config.chaos.enabled {
+            return Routes::new();
+        }
+
+        Routes::new()
+            .get(chaos::CONTROL_PATH, chaos::get_rules)
+            .put(chaos::CONTROL_PATH, chaos::put_rules)
+            .map(|router| admin::protect(router, state, Permission::CHAOS_MANAGE))
+    }
+}
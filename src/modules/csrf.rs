@@ -0,0 +1,20 @@
+//! The CSRF token of the session
+
+use super::{Module, Routes};
+use crate::{csrf, state::AppState};
+
+pub struct CsrfModule;
+
+impl Module for CsrfModule {
+    fn name(&self) -> &str {
+        "csrf"
+    }
+
+    fn routes(&self, state: AppState) -> Routes {
+        if !state.config.csrf.enabled {
+            return Routes::new();
+        }
+
+        Routes::new().get("/api/v1/csrf", csrf::get_token)
+    }
+}
@@ -0,0 +1,25 @@
+//! Debugging aids for operators
+
+use super::{Module, Routes};
+use crate::{admin, failure_capture, permissions::Permission, state::AppState};
+
+pub struct DebugModule;
+
+impl Module for DebugModule {
+    fn name(&self) -> &str {
+        "debug"
+    }
+
+    fn routes(&self, state: AppState) -> Routes {
+        if !state.config.debug.capture_failed_bodies {
+            return Routes::new();
+        }
+
+        Routes::new()
+            .get(
+                "/api/v1/admin/debug/failures",
+                failure_capture::list_failures,
+            )
+            .map(|router| admin::protect(router, state, Permission::DEBUG_READ))
+    }
+}
@@ -0,0 +1,24 @@
+//! The feature flags
+
+use super::{Module, Routes};
+use crate::{admin, feature_flags, permissions::Permission, state::AppState};
+
+pub struct FeatureFlagsModule;
+
+impl Module for FeatureFlagsModule {
+    fn name(&self) -> &str {
+        "feature_flags"
+    }
+
+    fn routes(&self, state: AppState) -> Routes {
+        Routes::new()
+            .get("/api/v1/users/me/flags", feature_flags::my_flags)
+            .merge(
+                Routes::new()
+                    .get("/api/v1/admin/flags", feature_flags::list_flags)
+                    .put("/api/v1/admin/flags/:name", feature_flags::update_flag)
+                    .delete("/api/v1/admin/flags/:name", feature_flags::reset_flag)
+                    .map(|router| admin::protect(router, state, Permission::SETTINGS_MANAGE)),
+            )
+    }
+}
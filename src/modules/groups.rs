@@ -0,0 +1,30 @@
+//! Administration of the groups of users
+
+use super::{Module, Routes};
+use crate::{admin, groups, permissions::Permission, state::AppState};
+
+pub struct GroupsModule;
+
+impl Module for GroupsModule {
+    fn name(&self) -> &str {
+        "groups"
+    }
+
+    fn routes(&self, state: AppState) -> Routes {
+        Routes::new()
+            .get("/api/v1/admin/groups", groups::list_groups)
+            .post("/api/v1/admin/groups", groups::create_group)
+            .get("/api/v1/admin/groups/:id", groups::get_group)
+            .patch("/api/v1/admin/groups/:id", groups::update_group)
+            .delete("/api/v1/admin/groups/:id", groups::delete_group)
+            .put(
+                "/api/v1/admin/groups/:id/members/:user_id",
+                groups::add_group_member,
+            )
+            .delete(
+                "/api/v1/admin/groups/:id/members/:user_id",
+                groups::remove_group_member,
+            )
+            .map(|router| admin::protect(router, state, Permission::GROUPS_MANAGE))
+    }
+}
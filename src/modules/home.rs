@@ -0,0 +1,32 @@
+//! The landing page, counting the visits of the session
+
+use super::{Module, Routes};
+use crate::{session_data::AppSession, state::AppState};
+use axum::response::IntoResponse;
+use serde::{Deserialize, Serialize};
+
+pub struct HomeModule;
+
+impl Module for HomeModule {
+    fn name(&self) -> &str {
+        "home"
+    }
+
+    fn routes(&self, _state: AppState) -> Routes {
+        Routes::new().get("/", index)
+    }
+}
+
+// States
+#[derive(Serialize, Deserialize, Default)]
+struct Counter(usize);
+
+// Handlers
+async fn index(AppSession(session): AppSession) -> impl IntoResponse {
+    let counter: Counter = session.get("counter").await.unwrap().unwrap_or_default();
+    session
+        .insert("counter", Counter(counter.0 + 1))
+        .await
+        .unwrap();
+    format!("Hello {}!", counter.0)
+}
@@ -0,0 +1,24 @@
+//! Runtime adjustment of the log filter
+
+use super::{Module, Routes};
+use crate::{admin, logging, permissions::Permission, state::AppState};
+
+pub struct LoggingModule;
+
+impl Module for LoggingModule {
+    fn name(&self) -> &str {
+        "logging"
+    }
+
+    fn routes(&self, state: AppState) -> Routes {
+        // The filter can only be adjusted when the subscriber was installed by the backend
+        if logging::control().is_none() {
+            return Routes::new();
+        }
+
+        Routes::new()
+            .get("/api/v1/admin/logging", logging::get_filter)
+            .put("/api/v1/admin/logging", logging::put_filter)
+            .map(|router| admin::protect(router, state, Permission::LOGGING_MANAGE))
+    }
+}
@@ -0,0 +1,285 @@
+//! Feature modules
+//! Each feature lives in its own module, which contributes routes and optionally migrations and
+//! background tasks. The enabled modules are picked from a [`ModuleRegistry`], so that embedders
+//! can add their own and operators can disable some (`MODULES_DISABLED`).
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Result;
+use axum::{
+    async_trait,
+    handler::Handler,
+    http::Method,
+    routing::{self, MethodFilter},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{database::SqlxPool, state::AppState, supervisor::Supervisor};
+
+mod api_keys;
+mod audit;
+mod auth;
+mod chaos;
+mod csrf;
+mod debug;
+mod feature_flags;
+mod groups;
+mod home;
+mod logging;
+mod permissions;
+mod roles;
+mod routes;
+mod sessions;
+mod settings;
+mod stats;
+mod system;
+mod users;
+mod version;
+mod webhooks;
+
+/// A feature of the backend
+#[async_trait]
+pub trait Module: Send + Sync + 'static {
+    /// The unique name of the module, used to disable it
+    fn name(&self) -> &str;
+
+    /// The routes of the module, with their full path
+    fn routes(&self, state: AppState) -> Routes;
+
+    /// Run the migrations of the module
+    async fn migrate(&self, _pool: &SqlxPool) -> Result<()> {
+        Ok(())
+    }
+
+    /// Spawn the background tasks of the module
+    fn tasks(&self, _state: &AppState, _supervisor: &mut Supervisor) {}
+}
+
+/// The routes of a module
+///
+/// Wraps a [`Router`], keeping track of the methods and paths so that conflicts between modules
+/// can be reported and the routes listed (axum doesn't expose its route table).
+#[derive(Default)]
+pub struct Routes {
+    router: Router<AppState>,
+    routes: Vec<(Method, String)>,
+}
+
+impl Routes {
+    /// No routes
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a route answering the given method, see [`Router::route`]
+    ///
+    /// # Panics
+    ///
+    /// Panics when the method is not supported by axum, or when the route already answers it.
+    pub fn on<H, T>(mut self, method: Method, path: &str, handler: H) -> Self
+    where
+        H: Handler<T, AppState>,
+        T: 'static,
+    {
+        let filter = MethodFilter::try_from(method.clone()).expect("Unsupported method");
+        self.router = self.router.route(path, routing::on(filter, handler));
+        self.routes.push((method, path.to_string()));
+        self
+    }
+
+    /// Add a `GET` route
+    pub fn get<H: Handler<T, AppState>, T: 'static>(self, path: &str, handler: H) -> Self {
+        self.on(Method::GET, path, handler)
+    }
+
+    /// Add a `POST` route
+    pub fn post<H: Handler<T, AppState>, T: 'static>(self, path: &str, handler: H) -> Self {
+        self.on(Method::POST, path, handler)
+    }
+
+    /// Add a `PUT` route
+    pub fn put<H: Handler<T, AppState>, T: 'static>(self, path: &str, handler: H) -> Self {
+        self.on(Method::PUT, path, handler)
+    }
+
+    /// Add a `PATCH` route
+    pub fn patch<H: Handler<T, AppState>, T: 'static>(self, path: &str, handler: H) -> Self {
+        self.on(Method::PATCH, path, handler)
+    }
+
+    /// Add a `DELETE` route
+    pub fn delete<H: Handler<T, AppState>, T: 'static>(self, path: &str, handler: H) -> Self {
+        self.on(Method::DELETE, path, handler)
+    }
+
+    /// Add the routes of another set, e.g. guarded by different route layers
+    pub fn merge(mut self, other: Routes) -> Self {
+        self.router = self.router.merge(other.router);
+        self.routes.extend(other.routes);
+        self
+    }
+
+    /// Transform the router, e.g. to add route layers to every route of the module
+    pub fn map(mut self, f: impl FnOnce(Router<AppState>) -> Router<AppState>) -> Self {
+        self.router = f(self.router);
+        self
+    }
+}
+
+/// A route served by the backend
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteEntry {
+    pub method: String,
+    /// The path of the route, relative to the base path
+    pub path: String,
+}
+
+/// The routes served by the backend, filled as the application is built
+#[derive(Clone, Default)]
+pub struct RouteTable(Arc<RwLock<Vec<RouteEntry>>>);
+
+impl RouteTable {
+    /// Record a route
+    pub fn add(&self, method: Method, path: &str) {
+        self.0.write().unwrap().push(RouteEntry {
+            method: method.to_string(),
+            path: path.to_string(),
+        });
+    }
+
+    /// The methods of the routes recorded at the given path, `HEAD` included along `GET`
+    pub fn methods(&self, path: &str) -> Vec<String> {
+        let mut methods: Vec<String> = self
+            .0
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|route| route.path == path)
+            .map(|route| route.method.clone())
+            .collect();
+        if methods.iter().any(|method| method == "GET") {
+            methods.push("HEAD".to_string());
+        }
+        methods.sort();
+        methods.dedup();
+        methods
+    }
+
+    /// The recorded routes, sorted by path then method
+    pub fn list(&self) -> Vec<RouteEntry> {
+        let mut routes = self.0.read().unwrap().clone();
+        routes.sort_by(|a, b| (&a.path, &a.method).cmp(&(&b.path, &b.method)));
+        routes
+    }
+}
+
+/// The modules the backend can be built with
+pub struct ModuleRegistry {
+    modules: Vec<Box<dyn Module>>,
+}
+
+impl Default for ModuleRegistry {
+    /// A registry with the built-in modules (`home`, `sessions`, `auth`, `csrf`, `users`, `roles`,
+    /// `groups`, `settings`, `permissions`, `api_keys`, `stats`, `audit`, `system`, `version`,
+    /// `chaos`, `routes`, `logging` and `debug`)
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register(home::HomeModule);
+        registry.register(sessions::SessionsModule);
+        registry.register(auth::AuthModule);
+        registry.register(csrf::CsrfModule);
+        registry.register(users::UsersModule);
+        registry.register(roles::RolesModule);
+        registry.register(groups::GroupsModule);
+        registry.register(settings::SettingsModule);
+        registry.register(feature_flags::FeatureFlagsModule);
+        registry.register(permissions::PermissionsModule);
+        registry.register(api_keys::ApiKeysModule);
+        registry.register(webhooks::WebhooksModule);
+        registry.register(stats::StatsModule);
+        registry.register(audit::AuditModule);
+        registry.register(system::SystemModule);
+        registry.register(version::VersionModule);
+        registry.register(chaos::ChaosModule);
+        registry.register(routes::RoutesModule);
+        registry.register(logging::LoggingModule);
+        registry.register(debug::DebugModule);
+        registry
+    }
+}
+
+impl ModuleRegistry {
+    /// A registry without any module
+    pub fn empty() -> Self {
+        Self {
+            modules: Vec::new(),
+        }
+    }
+
+    /// Register a module, replacing any previous one with the same name
+    pub fn register(&mut self, module: impl Module) {
+        self.modules
+            .retain(|existing| existing.name() != module.name());
+        self.modules.push(Box::new(module));
+    }
+
+    /// The registered modules that aren't disabled
+    ///
+    /// Fails when a disabled module isn't registered, which most likely is a typo.
+    pub fn enabled(&self, disabled: &[String]) -> Result<Vec<&dyn Module>> {
+        if let Some(unknown) = disabled
+            .iter()
+            .find(|name| !self.modules.iter().any(|module| module.name() == *name))
+        {
+            return Err(anyhow::anyhow!(
+                "Cannot disable unknown module '{}'",
+                unknown
+            ));
+        }
+
+        Ok(self
+            .modules
+            .iter()
+            .filter(|module| !disabled.iter().any(|name| name == module.name()))
+            .map(|module| module.as_ref())
+            .collect())
+    }
+
+    /// Merge the routes of the enabled modules, recording them in the route table of the state
+    ///
+    /// Fails when two modules register the same path.
+    pub fn router(&self, disabled: &[String], state: AppState) -> Result<Router<AppState>> {
+        let mut owners: HashMap<String, String> = HashMap::new();
+        let mut router = Router::new();
+
+        for module in self.enabled(disabled)? {
+            let routes = module.routes(state.clone());
+
+            for (method, path) in routes.routes {
+                match owners.get(&path) {
+                    Some(owner) if owner != module.name() => {
+                        return Err(anyhow::anyhow!(
+                            "Route '{}' of module '{}' conflicts with module '{}'",
+                            path,
+                            module.name(),
+                            owner
+                        ));
+                    }
+                    Some(_) => {}
+                    None => {
+                        owners.insert(path.clone(), module.name().to_string());
+                    }
+                }
+                state.routes.add(method, &path);
+            }
+
+            router = router.merge(routes.router);
+        }
+
+        Ok(router)
+    }
+}
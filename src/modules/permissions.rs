@@ -0,0 +1,30 @@
+//! Administration of the permissions granted to the roles
+
+use super::{Module, Routes};
+use crate::{
+    admin,
+    permissions::{self, Permission},
+    state::AppState,
+};
+
+pub struct PermissionsModule;
+
+impl Module for PermissionsModule {
+    fn name(&self) -> &str {
+        "permissions"
+    }
+
+    fn routes(&self, state: AppState) -> Routes {
+        Routes::new()
+            .get("/api/v1/admin/permissions", permissions::list_permissions)
+            .get(
+                "/api/v1/admin/roles/:id/permissions",
+                permissions::get_role_permissions,
+            )
+            .put(
+                "/api/v1/admin/roles/:id/permissions",
+                permissions::put_role_permissions,
+            )
+            .map(|router| admin::protect(router, state, Permission::ROLES_MANAGE))
+    }
+}
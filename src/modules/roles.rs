@@ -0,0 +1,19 @@
+//! Administration of the roles of the users
+
+use super::{Module, Routes};
+use crate::{admin, permissions::Permission, roles, state::AppState};
+
+pub struct RolesModule;
+
+impl Module for RolesModule {
+    fn name(&self) -> &str {
+        "roles"
+    }
+
+    fn routes(&self, state: AppState) -> Routes {
+        Routes::new()
+            .get("/api/v1/admin/users/:id/roles", roles::get_user_roles)
+            .put("/api/v1/admin/users/:id/roles", roles::put_user_roles)
+            .map(|router| admin::protect(router, state, Permission::USERS_MANAGE))
+    }
+}
@@ -0,0 +1,44 @@
+//! Discovery of the routes served by the backend
+
+use axum::extract::State;
+
+use super::{Module, RouteEntry, Routes};
+use crate::{
+    admin,
+    negotiate::{Format, Negotiated},
+    permissions::Permission,
+    state::AppState,
+};
+
+pub struct RoutesModule;
+
+impl Module for RoutesModule {
+    fn name(&self) -> &str {
+        "routes"
+    }
+
+    fn routes(&self, state: AppState) -> Routes {
+        Routes::new()
+            .get("/api/v1/admin/routes", list_routes)
+            .map(|router| admin::protect(router, state, Permission::ROUTES_READ))
+    }
+}
+
+/// `GET /admin/routes`: list the method and path of every route, under the base path
+async fn list_routes(State(state): State<AppState>, format: Format) -> Negotiated<Vec<RouteEntry>> {
+    let base_path = state.config.base_path.trim_end_matches('/');
+    let routes = state
+        .routes
+        .list()
+        .into_iter()
+        .map(|route| RouteEntry {
+            path: match route.path.as_str() {
+                // The root of a nested router is served at the base path itself
+                "/" if !base_path.is_empty() => base_path.to_string(),
+                path => format!("{}{}", base_path, path),
+            },
+            ..route
+        })
+        .collect();
+    Negotiated(format, routes)
+}
@@ -0,0 +1,119 @@
+//! Administration of the sessions
+
+use axum::{
+    extract::{Query, State},
+    middleware,
+};
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use super::{Module, Routes};
+use crate::{
+    admin::{self, Pagination},
+    audit::AuditOperation,
+    error::AppError,
+    etag,
+    negotiate::{Format, Negotiated},
+    permissions::Permission,
+    session_store::{SessionSummary, StorageStats},
+    state::AppState,
+};
+
+pub struct SessionsModule;
+
+impl Module for SessionsModule {
+    fn name(&self) -> &str {
+        "sessions"
+    }
+
+    fn routes(&self, state: AppState) -> Routes {
+        Routes::new()
+            .get("/api/v1/admin/sessions", list_sessions)
+            .get("/api/v1/admin/sessions/storage", storage_stats)
+            .map(|router| router.route_layer(middleware::from_fn(etag::conditional)))
+            .map(|router| admin::protect(router, state.clone(), Permission::SESSIONS_READ))
+            .merge(
+                Routes::new()
+                    .post("/api/v1/admin/sessions/prune", prune_sessions)
+                    .post("/api/v1/admin/sessions/sweep", sweep_sessions)
+                    .map(|router| admin::protect(router, state, Permission::SESSIONS_REVOKE)),
+            )
+    }
+}
+
+/// `GET /admin/sessions`: list the active sessions
+async fn list_sessions(
+    State(state): State<AppState>,
+    format: Format,
+    Query(pagination): Query<Pagination>,
+) -> Result<Negotiated<Vec<SessionSummary>>, AppError> {
+    let sessions = state
+        .sessions
+        .list(pagination.limit(), pagination.offset())
+        .await?;
+    Ok(Negotiated(format, sessions))
+}
+
+/// `GET /admin/sessions/storage`: estimate the space taken by the sessions
+async fn storage_stats(
+    State(state): State<AppState>,
+    format: Format,
+) -> Result<Negotiated<StorageStats>, AppError> {
+    Ok(Negotiated(format, state.sessions.storage_stats().await?))
+}
+
+/// The sessions to prune
+#[derive(Deserialize)]
+struct PruneQuery {
+    /// Prune the sessions created before this time
+    #[serde(with = "time::serde::rfc3339")]
+    before: OffsetDateTime,
+}
+
+/// The outcome of a pruning
+#[derive(Serialize, Deserialize)]
+struct PruneOutcome {
+    /// The number of sessions deleted
+    pruned: u64,
+}
+
+/// `POST /admin/sessions/prune?before=...`: delete the sessions created before a date, even if
+/// they haven't expired
+async fn prune_sessions(
+    State(state): State<AppState>,
+    format: Format,
+    Query(query): Query<PruneQuery>,
+) -> Result<(AuditOperation, Negotiated<PruneOutcome>), AppError> {
+    let pruned = state.sessions.prune_before(query.before).await?;
+    tracing::info!(
+        "Pruned {} session(s) created before {}",
+        pruned,
+        query.before
+    );
+    Ok((
+        AuditOperation::new("sessions.prune", pruned),
+        Negotiated(format, PruneOutcome { pruned }),
+    ))
+}
+
+/// The outcome of a sweep
+#[derive(Serialize, Deserialize)]
+struct SweepOutcome {
+    /// The number of expired sessions deleted
+    swept: u64,
+}
+
+/// `POST /admin/sessions/sweep`: delete the expired sessions now, without waiting for the
+/// background sweep
+async fn sweep_sessions(
+    State(state): State<AppState>,
+    format: Format,
+) -> Result<(AuditOperation, Negotiated<SweepOutcome>), AppError> {
+    let swept = state.sessions.sweep_expired().await?;
+    tracing::info!("Swept {} expired session(s)", swept);
+    Ok((
+        AuditOperation::new("sessions.sweep", swept),
+        Negotiated(format, SweepOutcome { swept }),
+    ))
+}
@@ -0,0 +1,34 @@
+//! The settings adjustable at runtime
+
+use super::{Module, Routes};
+use crate::{admin, permissions::Permission, settings, state::AppState, supervisor::Supervisor};
+
+pub struct SettingsModule;
+
+impl Module for SettingsModule {
+    fn name(&self) -> &str {
+        "settings"
+    }
+
+    fn routes(&self, state: AppState) -> Routes {
+        Routes::new()
+            .get("/api/v1/maintenance", settings::maintenance)
+            .merge(
+                Routes::new()
+                    .get("/api/v1/admin/settings", settings::list_settings)
+                    .put("/api/v1/admin/settings", settings::update_settings)
+                    .map(|router| admin::protect(router, state, Permission::SETTINGS_MANAGE)),
+            )
+    }
+
+    fn tasks(&self, state: &AppState, supervisor: &mut Supervisor) {
+        supervisor.spawn(
+            "settings-refresh",
+            settings::continuously_refresh(
+                state.settings.clone(),
+                state.events.clone(),
+                state.config.settings_refresh,
+            ),
+        );
+    }
+}
@@ -0,0 +1,54 @@
+//! Statistics about the backend
+
+use axum::{extract::State, middleware};
+
+use super::{Module, Routes};
+use crate::{
+    admin,
+    audit::AuditStats,
+    error::AppError,
+    etag,
+    negotiate::{Format, Negotiated},
+    permissions::Permission,
+    rate_limit::RateLimitStats,
+    session_store::SessionStats,
+    state::AppState,
+};
+
+pub struct StatsModule;
+
+impl Module for StatsModule {
+    fn name(&self) -> &str {
+        "stats"
+    }
+
+    fn routes(&self, state: AppState) -> Routes {
+        Routes::new()
+            .get("/api/v1/admin/stats/sessions", session_stats)
+            .get("/api/v1/admin/stats/audit", audit_stats)
+            .get("/api/v1/admin/stats/rate-limits", rate_limit_stats)
+            .map(|router| router.route_layer(middleware::from_fn(etag::conditional)))
+            .map(|router| admin::protect(router, state, Permission::STATS_READ))
+    }
+}
+
+/// `GET /admin/stats/sessions`: count the sessions in the store
+async fn session_stats(
+    State(state): State<AppState>,
+    format: Format,
+) -> Result<Negotiated<SessionStats>, AppError> {
+    Ok(Negotiated(format, state.sessions.stats().await?))
+}
+
+/// `GET /admin/stats/audit`: count the audit log entries queued and dropped
+async fn audit_stats(State(state): State<AppState>, format: Format) -> Negotiated<AuditStats> {
+    Negotiated(format, state.audit.stats())
+}
+
+/// `GET /admin/stats/rate-limits`: count the requests rejected by the rate limiter
+async fn rate_limit_stats(
+    State(state): State<AppState>,
+    format: Format,
+) -> Negotiated<RateLimitStats> {
+    Negotiated(format, state.rate_limiter.stats())
+}
@@ -0,0 +1,63 @@
+//! Information about the running backend
+
+use std::time::Instant;
+
+use axum::extract::State;
+use serde::{Deserialize, Serialize};
+
+use super::{Module, Routes};
+use crate::{
+    admin,
+    build_info::BuildInfo,
+    error::AppError,
+    negotiate::{Format, Negotiated},
+    permissions::Permission,
+    state::AppState,
+};
+
+pub struct SystemModule;
+
+impl Module for SystemModule {
+    fn name(&self) -> &str {
+        "system"
+    }
+
+    fn routes(&self, state: AppState) -> Routes {
+        Routes::new()
+            .get("/api/v1/system/info", system_info)
+            .map(|router| admin::protect(router, state, Permission::SYSTEM_READ))
+    }
+}
+
+/// The build of the backend, and how long it has been running
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SystemInfo {
+    #[serde(flatten)]
+    pub build: BuildInfo,
+    /// The number of seconds since the backend started
+    pub uptime_secs: u64,
+    /// The kind of database (`sqlite`, `postgresql` or `mysql`)
+    pub database: String,
+}
+
+impl SystemInfo {
+    /// Describe the backend started at the given instant
+    pub fn new(started_at: Instant, database: &str) -> Self {
+        Self {
+            build: BuildInfo::current(),
+            uptime_secs: started_at.elapsed().as_secs(),
+            database: database.to_string(),
+        }
+    }
+}
+
+/// `GET /system/info`: describe the running backend
+async fn system_info(
+    State(state): State<AppState>,
+    format: Format,
+) -> Result<Negotiated<SystemInfo>, AppError> {
+    Ok(Negotiated(
+        format,
+        SystemInfo::new(state.started_at, state.pool.kind()),
+    ))
+}
@@ -0,0 +1,46 @@
+//! Administration of the users, their invitations and impersonation
+
+use super::{Module, Routes};
+use crate::{
+    admin,
+    auth::{impersonation, invitations, lockout, login_history, password_change, two_factor},
+    permissions::Permission,
+    state::AppState,
+    users,
+};
+
+pub struct UsersModule;
+
+impl Module for UsersModule {
+    fn name(&self) -> &str {
+        "users"
+    }
+
+    fn routes(&self, state: AppState) -> Routes {
+        Routes::new()
+            .get("/api/v1/admin/users", users::list_users)
+            .post("/api/v1/admin/users", users::create_user)
+            .get("/api/v1/admin/users/:id", users::get_user)
+            .patch("/api/v1/admin/users/:id", users::update_user)
+            .delete("/api/v1/admin/users/:id", users::deactivate_user)
+            .get("/api/v1/admin/users/:id/logins", login_history::user_logins)
+            .post("/api/v1/admin/users/:id/unlock", lockout::unlock_user)
+            .post(
+                "/api/v1/admin/users/:id/require-password-change",
+                password_change::require_password_change,
+            )
+            .delete("/api/v1/admin/users/:id/2fa", two_factor::admin_disable)
+            .get("/api/v1/admin/invitations", invitations::list_invitations)
+            .post("/api/v1/admin/invitations", invitations::create_invitation)
+            .delete(
+                "/api/v1/admin/invitations/:id",
+                invitations::revoke_invitation,
+            )
+            .map(|router| admin::protect(router, state.clone(), Permission::USERS_MANAGE))
+            .merge(
+                Routes::new()
+                    .post("/api/v1/admin/users/:id/impersonate", impersonation::start)
+                    .map(|router| admin::protect(router, state, Permission::USERS_IMPERSONATE)),
+            )
+    }
+}
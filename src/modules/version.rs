@@ -0,0 +1,26 @@
+//! The version of the running build, for operators and deployment checks
+
+use super::{Module, Routes};
+use crate::{
+    build_info::BuildInfo,
+    error::AppError,
+    negotiate::{Format, Negotiated},
+    state::AppState,
+};
+
+pub struct VersionModule;
+
+impl Module for VersionModule {
+    fn name(&self) -> &str {
+        "version"
+    }
+
+    fn routes(&self, _state: AppState) -> Routes {
+        Routes::new().get("/version", version)
+    }
+}
+
+/// `GET /version`: describe the running build
+async fn version(format: Format) -> Result<Negotiated<BuildInfo>, AppError> {
+    Ok(Negotiated(format, BuildInfo::current()))
+}
@@ -0,0 +1,47 @@
+//! The webhooks notified of the events
+
+use std::time::Duration;
+
+use super::{Module, Routes};
+use crate::{admin, permissions::Permission, state::AppState, supervisor::Supervisor, webhooks};
+
+pub struct WebhooksModule;
+
+impl Module for WebhooksModule {
+    fn name(&self) -> &str {
+        "webhooks"
+    }
+
+    fn routes(&self, state: AppState) -> Routes {
+        Routes::new()
+            .get("/api/v1/admin/webhooks", webhooks::list_webhooks)
+            .post("/api/v1/admin/webhooks", webhooks::create_webhook)
+            .get("/api/v1/admin/webhooks/:id", webhooks::get_webhook)
+            .patch("/api/v1/admin/webhooks/:id", webhooks::update_webhook)
+            .delete("/api/v1/admin/webhooks/:id", webhooks::delete_webhook)
+            .get(
+                "/api/v1/admin/webhooks/:id/deliveries",
+                webhooks::list_deliveries,
+            )
+            .post(
+                "/api/v1/admin/webhooks/:id/deliveries/:delivery_id/redeliver",
+                webhooks::redeliver,
+            )
+            .map(|router| admin::protect(router, state, Permission::WEBHOOKS_MANAGE))
+    }
+
+    fn tasks(&self, state: &AppState, supervisor: &mut Supervisor) {
+        supervisor.spawn(
+            "webhooks-delivery",
+            webhooks::continuously_deliver(state.clone()),
+        );
+        supervisor.spawn(
+            "webhook-deliveries-purge",
+            webhooks::continuously_purge(
+                state.write_pool().clone(),
+                state.config.webhook.delivery_retention,
+                Duration::from_secs(60 * 60),
+            ),
+        );
+    }
+}
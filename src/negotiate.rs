@@ -0,0 +1,201 @@
+//! Content negotiation
+//! Responses are encoded as JSON by default, or as MessagePack when the client asks for it
+//! through the `Accept` header. Request bodies are decoded according to their `Content-Type`.
+
+use axum::{
+    async_trait,
+    body::{Body, Bytes},
+    extract::{FromRequest, FromRequestParts, Request},
+    http::{header, request::Parts, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::error::{AppError, ErrorEnvelope};
+
+const JSON: &str = "application/json";
+const MSGPACK: &str = "application/msgpack";
+const MSGPACK_ALIASES: [&str; 3] = [MSGPACK, "application/x-msgpack", "application/vnd.msgpack"];
+
+/// A format in which bodies can be encoded
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+impl Format {
+    /// The media type of this format
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => JSON,
+            Format::MessagePack => MSGPACK,
+        }
+    }
+
+    /// Get the format of the given media type (parameters are ignored), if supported
+    pub fn from_media_type(media_type: &str) -> Option<Format> {
+        let media_type = media_type.split(';').next().unwrap_or_default().trim();
+
+        if media_type.eq_ignore_ascii_case(JSON) {
+            Some(Format::Json)
+        } else if MSGPACK_ALIASES
+            .iter()
+            .any(|alias| media_type.eq_ignore_ascii_case(alias))
+        {
+            Some(Format::MessagePack)
+        } else {
+            None
+        }
+    }
+
+    /// Pick the preferred format from an `Accept` header
+    ///
+    /// A missing header accepts anything, in which case JSON is used. Ties between formats with
+    /// the same quality are resolved in favor of the first listed one.
+    pub fn from_accept(headers: &HeaderMap) -> Result<Format, AppError> {
+        let Some(accept) = headers.get(header::ACCEPT) else {
+            return Ok(Format::Json);
+        };
+        let accept = accept.to_str().map_err(|_| AppError::NotAcceptable)?;
+
+        let mut best: Option<(Format, f32)> = None;
+        for item in accept.split(',') {
+            let mut params = item.split(';');
+            let media_type = params.next().unwrap_or_default().trim();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            if quality <= 0.0 {
+                continue;
+            }
+
+            let format = match media_type {
+                "*/*" | "application/*" => Format::Json,
+                _ => match Format::from_media_type(media_type) {
+                    Some(format) => format,
+                    None => continue,
+                },
+            };
+
+            if best.is_none_or(|(_, best_quality)| quality > best_quality) {
+                best = Some((format, quality));
+            }
+        }
+
+        best.map(|(format, _)| format)
+            .ok_or(AppError::NotAcceptable)
+    }
+
+    /// Encode a value in this format
+    pub fn encode<T: Serialize>(&self, value: &T) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            Format::Json => serde_json::to_vec(value)?,
+            Format::MessagePack => rmp_serde::to_vec_named(value)?,
+        })
+    }
+
+    /// Decode a value from this format
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, AppError> {
+        match self {
+            Format::Json => {
+                serde_json::from_slice(bytes).map_err(|e| AppError::BadRequest(e.to_string()))
+            }
+            Format::MessagePack => {
+                rmp_serde::from_slice(bytes).map_err(|e| AppError::BadRequest(e.to_string()))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Format {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Format::from_accept(&parts.headers)
+    }
+}
+
+/// A body encoded in the negotiated format
+///
+/// As an extractor, the body is decoded according to its `Content-Type` and the format of the
+/// response is picked from the `Accept` header. As a response, the value is encoded in the
+/// carried format.
+pub struct Negotiated<T>(pub Format, pub T);
+
+impl<T: Serialize> IntoResponse for Negotiated<T> {
+    fn into_response(self) -> Response {
+        let Negotiated(format, value) = self;
+        match format.encode(&value) {
+            Ok(body) => (
+                [(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static(format.content_type()),
+                )],
+                body,
+            )
+                .into_response(),
+            Err(err) => AppError::Internal(err).into_response(),
+        }
+    }
+}
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Negotiated<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let response_format = Format::from_accept(req.headers())?;
+        let body_format = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .and_then(Format::from_media_type)
+            .ok_or(AppError::UnsupportedMediaType)?;
+
+        let bytes = Bytes::from_request(req, state)
+            .await
+            .map_err(|e| AppError::BadRequest(e.body_text()))?;
+
+        Ok(Negotiated(response_format, body_format.decode(&bytes)?))
+    }
+}
+
+/// Re-encode error envelopes in the format accepted by the client
+///
+/// Errors are rendered as JSON by [`AppError`], which doesn't know about the request. Clients
+/// that don't accept any supported format still get JSON.
+pub async fn encode_errors(req: Request, next: Next) -> Response {
+    let format = Format::from_accept(req.headers()).unwrap_or_default();
+    let response = next.run(req).await;
+
+    if format == Format::Json {
+        return response;
+    }
+
+    let Some(envelope) = response.extensions().get::<ErrorEnvelope>().cloned() else {
+        return response;
+    };
+
+    let (mut parts, _) = response.into_parts();
+    match format.encode(&envelope) {
+        Ok(body) => {
+            parts.headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(format.content_type()),
+            );
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Response::from_parts(parts, Body::from(body))
+        }
+        Err(err) => AppError::Internal(err).into_response(),
+    }
+}
@@ -0,0 +1,118 @@
+//! Path normalization
+//! Duplicate slashes and trailing slashes are normalized before routing, so that
+//! `//api//v1/admin/sessions/` reaches `/api/v1/admin/sessions`. Depending on the configured
+//! mode, non-canonical paths are redirected (`308`, preserving the method), rewritten in place,
+//! or left alone (and most likely answered with a `404`). The static assets are never
+//! normalized, as trailing slashes are meaningful there.
+//!
+//! `TRAILING_SLASH` picks the canonical form: without a trailing slash (`trim`), with one
+//! (`append`, the routes still matching the path without it), or as requested (`off`, only the
+//! duplicate slashes being collapsed). The base path is part of the normalized path, so
+//! `/admin//health/` reaches `/health` under `BASE_PATH=/admin`.
+
+use axum::{
+    extract::{Request, State},
+    http::{header, uri::PathAndQuery, HeaderValue, StatusCode, Uri},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+use tower::Layer;
+
+use crate::config::{PathNormalization, TrailingSlash};
+
+/// How paths are normalized
+#[derive(Clone)]
+struct Normalizer {
+    mode: PathNormalization,
+    trailing_slash: TrailingSlash,
+    /// The paths under this prefix are left untouched
+    exempt_prefix: Option<String>,
+}
+
+/// Normalize the paths of the requests before they are routed by the given router
+///
+/// Middlewares added with [`Router::layer`] run after routing, so the router is wrapped as the
+/// fallback of an empty one.
+pub fn wrap(
+    app: Router,
+    mode: PathNormalization,
+    trailing_slash: TrailingSlash,
+    exempt_prefix: Option<String>,
+) -> Router {
+    if mode == PathNormalization::Strict {
+        return app;
+    }
+
+    let normalizer = Normalizer {
+        mode,
+        trailing_slash,
+        exempt_prefix,
+    };
+    Router::new().fallback_service(middleware::from_fn_with_state(normalizer, normalize).layer(app))
+}
+
+async fn normalize(State(normalizer): State<Normalizer>, mut req: Request, next: Next) -> Response {
+    let path = req.uri().path();
+    let exempt = normalizer
+        .exempt_prefix
+        .as_deref()
+        .is_some_and(|prefix| path == prefix || path.starts_with(&format!("{}/", prefix)));
+    if exempt {
+        return next.run(req).await;
+    }
+
+    let canonical = canonical_path(path, normalizer.trailing_slash);
+    if canonical != path && normalizer.mode == PathNormalization::Redirect {
+        let location = match req.uri().query() {
+            Some(query) => format!("{}?{}", canonical, query),
+            None => canonical,
+        };
+        return match HeaderValue::from_str(&location) {
+            Ok(location) => (
+                StatusCode::PERMANENT_REDIRECT,
+                [(header::LOCATION, location)],
+            )
+                .into_response(),
+            Err(_) => next.run(req).await,
+        };
+    }
+
+    // The routes are declared without a trailing slash
+    let routed = match normalizer.trailing_slash {
+        TrailingSlash::Append => canonical_path(&canonical, TrailingSlash::Trim),
+        TrailingSlash::Trim | TrailingSlash::Off => canonical,
+    };
+    if routed == path {
+        return next.run(req).await;
+    }
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{}?{}", routed, query),
+        None => routed,
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = PathAndQuery::try_from(path_and_query).ok();
+    if let Ok(uri) = Uri::from_parts(parts) {
+        *req.uri_mut() = uri;
+    }
+    next.run(req).await
+}
+
+/// Collapse duplicate slashes, and add or drop the trailing slash (except for the root)
+fn canonical_path(path: &str, trailing_slash: TrailingSlash) -> String {
+    let mut canonical = String::with_capacity(path.len() + 1);
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        canonical.push('/');
+        canonical.push_str(segment);
+    }
+
+    let keep_slash = match trailing_slash {
+        TrailingSlash::Trim => false,
+        TrailingSlash::Append => true,
+        TrailingSlash::Off => path.ends_with('/'),
+    };
+    if canonical.is_empty() || keep_slash {
+        canonical.push('/');
+    }
+    canonical
+}
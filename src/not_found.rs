@@ -0,0 +1,14 @@
+//! Answer of the requests no route matches
+//! The router answers them with an empty `404`. They are answered with an error envelope instead,
+//! giving the path that matched nothing, in the format negotiated for every error. The fallback
+//! is outside the session layer, so that probing unknown paths neither loads nor creates a
+//! session.
+
+use axum::extract::OriginalUri;
+
+use crate::error::AppError;
+
+/// The fallback of the router
+pub async fn fallback(OriginalUri(uri): OriginalUri) -> AppError {
+    AppError::NoRoute(uri.path().to_string())
+}
@@ -0,0 +1,43 @@
+//! Partitioned session cookies (CHIPS)
+//! Browsers blocking third-party cookies still accept the ones marked `Partitioned`, kept apart
+//! for each top-level site embedding the backend. With `SESSION_COOKIE_PARTITIONED`, the cookies
+//! set by the session layer get the attribute, which tower-sessions can't set itself.
+
+use axum::{
+    extract::Request,
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+
+/// Mark the cookies of the response as `Partitioned`
+pub async fn partition(req: Request, next: Next) -> Response {
+    let mut response = next.run(req).await;
+
+    let headers = response.headers_mut();
+    let cookies: Vec<HeaderValue> = headers
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .cloned()
+        .collect();
+    if cookies.is_empty() {
+        return response;
+    }
+    headers.remove(header::SET_COOKIE);
+    for cookie in cookies {
+        let is_partitioned = cookie.to_str().is_ok_and(|cookie| {
+            cookie
+                .split(';')
+                .any(|attribute| attribute.trim().eq_ignore_ascii_case("partitioned"))
+        });
+        let cookie = if is_partitioned {
+            cookie
+        } else {
+            let mut bytes = cookie.as_bytes().to_vec();
+            bytes.extend_from_slice(b"; Partitioned");
+            HeaderValue::from_bytes(&bytes).unwrap_or(cookie)
+        };
+        headers.append(header::SET_COOKIE, cookie);
+    }
+    response
+}
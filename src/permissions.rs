@@ -0,0 +1,279 @@
+//! Permissions granted to the roles
+//! Each admin endpoint requires a permission, such as `sessions.revoke`. The permissions known to
+//! the backend are listed in [`Permission::ALL`], and granted to roles through the
+//! `role_permissions` table: a user holds a permission when one of their roles is granted it.
+//! Nothing is granted by default. The grants are checked with a single query on the primary key
+//! of `role_permissions`, so that changing them applies on the next request.
+
+use std::fmt;
+
+use axum::extract::{Path, State};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    database::{with_pool, SqlxPool},
+    error::AppError,
+    negotiate::{Format, Negotiated},
+    roles::Role,
+    state::AppState,
+};
+
+/// A permission, granting access to some admin endpoints
+///
+/// Only the permissions of [`Permission::ALL`] can be built, so that a typo is caught at compile
+/// time, and unknown ones are rejected when deserializing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Permission(&'static str);
+
+impl Permission {
+    /// Read the statistics
+    pub const STATS_READ: Permission = Permission("stats.read");
+    /// Read the system information
+    pub const SYSTEM_READ: Permission = Permission("system.read");
+    /// List the routes
+    pub const ROUTES_READ: Permission = Permission("routes.read");
+    /// Read and change the log filter
+    pub const LOGGING_MANAGE: Permission = Permission("logging.manage");
+    /// Read and change the fault injection rules
+    pub const CHAOS_MANAGE: Permission = Permission("chaos.manage");
+    /// List the sessions
+    pub const SESSIONS_READ: Permission = Permission("sessions.read");
+    /// Prune the sessions
+    pub const SESSIONS_REVOKE: Permission = Permission("sessions.revoke");
+    /// Read the audit log
+    pub const AUDIT_READ: Permission = Permission("audit.read");
+    /// Read the failed requests
+    pub const DEBUG_READ: Permission = Permission("debug.read");
+    /// Read and change the roles of the users
+    pub const USERS_MANAGE: Permission = Permission("users.manage");
+    /// Read and change the permissions granted to the roles
+    pub const ROLES_MANAGE: Permission = Permission("roles.manage");
+    /// Create, list and revoke the API keys
+    pub const API_KEYS_MANAGE: Permission = Permission("api_keys.manage");
+    /// Read and change the groups of users, their members and roles
+    pub const GROUPS_MANAGE: Permission = Permission("groups.manage");
+    /// Act as another user, to see the backend as they do
+    pub const USERS_IMPERSONATE: Permission = Permission("users.impersonate");
+    /// Read and change the settings adjustable at runtime
+    pub const SETTINGS_MANAGE: Permission = Permission("settings.manage");
+    /// Read and change the webhooks notified of the events, and their deliveries
+    pub const WEBHOOKS_MANAGE: Permission = Permission("webhooks.manage");
+
+    /// Every permission known to the backend, seeded in the `permissions` table
+    pub const ALL: &'static [Permission] = &[
+        Permission::STATS_READ,
+        Permission::SYSTEM_READ,
+        Permission::ROUTES_READ,
+        Permission::LOGGING_MANAGE,
+        Permission::CHAOS_MANAGE,
+        Permission::SESSIONS_READ,
+        Permission::SESSIONS_REVOKE,
+        Permission::AUDIT_READ,
+        Permission::DEBUG_READ,
+        Permission::USERS_MANAGE,
+        Permission::ROLES_MANAGE,
+        Permission::API_KEYS_MANAGE,
+        Permission::GROUPS_MANAGE,
+        Permission::USERS_IMPERSONATE,
+        Permission::SETTINGS_MANAGE,
+        Permission::WEBHOOKS_MANAGE,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+
+    /// The known permission with this name
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .find(|permission| permission.0 == name)
+            .copied()
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl Serialize for Permission {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Permission {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Permission::parse(&name)
+            .ok_or_else(|| serde::de::Error::custom(format!("unknown permission `{}`", name)))
+    }
+}
+
+/// The permissions granted to the roles, stored in the database
+#[derive(Clone, Debug)]
+pub struct PermissionRepository {
+    pool: SqlxPool,
+}
+
+impl PermissionRepository {
+    pub fn new(pool: SqlxPool) -> Self {
+        Self { pool }
+    }
+
+    /// Whether one of the roles is granted the permission
+    pub async fn granted(&self, roles: &[Role], permission: Permission) -> Result<bool, AppError> {
+        if roles.is_empty() {
+            return Ok(false);
+        }
+
+        let placeholders = vec!["?"; roles.len()].join(", ");
+        let sql = self
+            .pool
+            .sql(&format!(
+                "SELECT role FROM role_permissions WHERE permission = ? AND role IN ({}) LIMIT 1",
+                placeholders
+            ))
+            .into_owned();
+        let found: Option<String> = with_pool!(&self.pool, |p| {
+            let mut query = sqlx::query_scalar(&sql).bind(permission.as_str());
+            for role in roles {
+                query = query.bind(role.as_str());
+            }
+            query.fetch_optional(p).await
+        })?;
+        Ok(found.is_some())
+    }
+
+    /// Every grant, as `(role, permission)` pairs
+    pub async fn grants(&self) -> Result<Vec<(Role, Permission)>, AppError> {
+        let rows: Vec<(String, String)> = with_pool!(&self.pool, |p| sqlx::query_as(
+            "SELECT role, permission FROM role_permissions"
+        )
+        .fetch_all(p)
+        .await)?;
+
+        // Roles and permissions unknown to this version are ignored
+        let mut grants: Vec<(Role, Permission)> = rows
+            .iter()
+            .filter_map(|(role, permission)| {
+                Some((Role::parse(role)?, Permission::parse(permission)?))
+            })
+            .collect();
+        grants.sort();
+        Ok(grants)
+    }
+
+    /// The permissions granted to a role
+    pub async fn of_role(&self, role: Role) -> Result<Vec<Permission>, AppError> {
+        let sql = self
+            .pool
+            .sql("SELECT permission FROM role_permissions WHERE role = ?")
+            .into_owned();
+        let names: Vec<String> = with_pool!(&self.pool, |p| sqlx::query_scalar(&sql)
+            .bind(role.as_str())
+            .fetch_all(p)
+            .await)?;
+
+        let mut permissions: Vec<Permission> = names
+            .iter()
+            .filter_map(|name| Permission::parse(name))
+            .collect();
+        permissions.sort();
+        Ok(permissions)
+    }
+
+    /// Replace the permissions granted to a role
+    pub async fn set_grants(&self, role: Role, permissions: &[Permission]) -> Result<(), AppError> {
+        let clear = self.pool.sql("DELETE FROM role_permissions WHERE role = ?");
+        let insert = self
+            .pool
+            .sql("INSERT INTO role_permissions (role, permission) VALUES (?, ?)");
+
+        let mut permissions = permissions.to_vec();
+        permissions.sort();
+        permissions.dedup();
+
+        with_pool!(&self.pool, |p| {
+            let mut tx = p.begin().await?;
+            sqlx::query(&clear)
+                .bind(role.as_str())
+                .execute(&mut *tx)
+                .await?;
+            for permission in &permissions {
+                sqlx::query(&insert)
+                    .bind(role.as_str())
+                    .bind(permission.as_str())
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+        });
+        Ok(())
+    }
+}
+
+/// A known permission, and the roles it is granted to
+#[derive(Serialize, Deserialize)]
+pub struct PermissionInfo {
+    pub name: Permission,
+    pub roles: Vec<Role>,
+}
+
+/// The permissions granted to a role
+#[derive(Serialize, Deserialize)]
+pub struct PermissionSet {
+    pub permissions: Vec<Permission>,
+}
+
+/// `GET /admin/permissions`: list the known permissions
+pub async fn list_permissions(
+    State(state): State<AppState>,
+    format: Format,
+) -> Result<Negotiated<Vec<PermissionInfo>>, AppError> {
+    let grants = PermissionRepository::new(state.read_pool().clone())
+        .grants()
+        .await?;
+    let permissions = Permission::ALL
+        .iter()
+        .map(|&name| PermissionInfo {
+            name,
+            roles: grants
+                .iter()
+                .filter(|(_, permission)| *permission == name)
+                .map(|(role, _)| *role)
+                .collect(),
+        })
+        .collect();
+    Ok(Negotiated(format, permissions))
+}
+
+/// `GET /admin/roles/:id/permissions`: the permissions granted to a role
+pub async fn get_role_permissions(
+    State(state): State<AppState>,
+    format: Format,
+    Path(role): Path<String>,
+) -> Result<Negotiated<PermissionSet>, AppError> {
+    let role = Role::parse(&role).ok_or(AppError::NotFound)?;
+    let permissions = PermissionRepository::new(state.read_pool().clone())
+        .of_role(role)
+        .await?;
+    Ok(Negotiated(format, PermissionSet { permissions }))
+}
+
+/// `PUT /admin/roles/:id/permissions`: replace the permissions granted to a role
+pub async fn put_role_permissions(
+    State(state): State<AppState>,
+    Path(role): Path<String>,
+    Negotiated(format, permission_set): Negotiated<PermissionSet>,
+) -> Result<Negotiated<PermissionSet>, AppError> {
+    let role = Role::parse(&role).ok_or(AppError::NotFound)?;
+    let repository = PermissionRepository::new(state.write_pool().clone());
+    repository
+        .set_grants(role, &permission_set.permissions)
+        .await?;
+    let permissions = repository.of_role(role).await?;
+    Ok(Negotiated(format, PermissionSet { permissions }))
+}
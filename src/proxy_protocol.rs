@@ -0,0 +1,190 @@
+//! PROXY protocol (v1 and v2) decoding
+//! A load balancer forwarding TCP connections with the PROXY protocol sends a header before the
+//! HTTP traffic, carrying the address of the real client. See
+//! <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The start of a v1 header
+const V1_PREFIX: &[u8; 6] = b"PROXY ";
+
+/// The maximum length of a v1 header, line break included
+const V1_MAX_LENGTH: usize = 107;
+
+/// The signature of a v2 header
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Read the PROXY protocol header at the start of a connection
+///
+/// Returns the source address it carries, or `None` when the sender doesn't relay a client
+/// (v1 `UNKNOWN`, v2 `LOCAL` or non-TCP families). Exactly the header is consumed, so the
+/// stream can then be handed to the HTTP server. Fails when the header is missing or malformed.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut prefix = [0u8; 6];
+    stream.read_exact(&mut prefix).await?;
+
+    if &prefix == V1_PREFIX {
+        read_v1(stream).await
+    } else if prefix == V2_SIGNATURE[..6] {
+        read_v2(stream, &prefix).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+/// Read the rest of a v1 header: `PROXY TCP4 <src> <dst> <sport> <dport>\r\n`
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    // Read byte by byte so that nothing after the header is consumed
+    let mut line = Vec::with_capacity(V1_MAX_LENGTH);
+    while !line.ends_with(b"\r\n") {
+        if line.len() + V1_PREFIX.len() >= V1_MAX_LENGTH {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    parse_v1(line)
+}
+
+/// Parse the fields of a v1 header (after `PROXY `)
+fn parse_v1(line: &str) -> io::Result<Option<SocketAddr>> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["UNKNOWN", ..] => Ok(None),
+        [protocol @ ("TCP4" | "TCP6"), source, _destination, source_port, _destination_port] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| invalid("invalid PROXY v1 source address"))?;
+            if ip.is_ipv4() != (*protocol == "TCP4") {
+                return Err(invalid("PROXY v1 address doesn't match the protocol"));
+            }
+            let port: u16 = source_port
+                .parse()
+                .map_err(|_| invalid("invalid PROXY v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY v1 header")),
+    }
+}
+
+/// Read the rest of a v2 header
+async fn read_v2<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    prefix: &[u8; 6],
+) -> io::Result<Option<SocketAddr>> {
+    let mut header = [0u8; 16];
+    header[..6].copy_from_slice(prefix);
+    stream.read_exact(&mut header[6..]).await?;
+
+    if &header[..12] != V2_SIGNATURE {
+        return Err(invalid("invalid PROXY v2 signature"));
+    }
+
+    let version_command = header[12];
+    let family = header[13];
+    let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+
+    let mut addresses = vec![0u8; length];
+    stream.read_exact(&mut addresses).await?;
+
+    match version_command & 0x0F {
+        // LOCAL: the connection was opened by the proxy itself (e.g. health checks)
+        0x0 => Ok(None),
+        0x1 => parse_v2_addresses(family, &addresses),
+        _ => Err(invalid("unsupported PROXY v2 command")),
+    }
+}
+
+/// Extract the source address of a v2 header
+fn parse_v2_addresses(family: u8, addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    match family {
+        // TCP over IPv4: source (4), destination (4), source port (2), destination port (2)
+        0x11 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // TCP over IPv6: source (16), destination (16), source port (2), destination port (2)
+        0x21 if addresses.len() >= 36 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        0x11 | 0x21 => Err(invalid("truncated PROXY v2 addresses")),
+        // Unspecified, UDP or unix sockets: no client address to relay
+        _ => Ok(None),
+    }
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read the header at the start of `bytes`, returning what follows it
+    async fn read(bytes: &[u8]) -> io::Result<(Option<SocketAddr>, Vec<u8>)> {
+        let mut stream = bytes;
+        let addr = read_header(&mut stream).await?;
+        Ok((addr, stream.to_vec()))
+    }
+
+    #[tokio::test]
+    async fn reads_a_v1_header() {
+        let (addr, rest) =
+            read(b"PROXY TCP4 203.0.113.7 192.0.2.1 56324 443\r\nGET / HTTP/1.1\r\n")
+                .await
+                .unwrap();
+        assert_eq!(addr, Some("203.0.113.7:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+
+        let (addr, _) = read(b"PROXY TCP6 2001:db8::7 2001:db8::1 56324 443\r\n")
+            .await
+            .unwrap();
+        assert_eq!(addr, Some("[2001:db8::7]:56324".parse().unwrap()));
+
+        let (addr, _) = read(b"PROXY UNKNOWN\r\n").await.unwrap();
+        assert_eq!(addr, None);
+    }
+
+    #[tokio::test]
+    async fn reads_a_v2_header() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        header.extend_from_slice(&[203, 0, 113, 7, 192, 0, 2, 1]);
+        header.extend_from_slice(&56324u16.to_be_bytes());
+        header.extend_from_slice(&443u16.to_be_bytes());
+        header.extend_from_slice(b"GET /");
+
+        let (addr, rest) = read(&header).await.unwrap();
+        assert_eq!(addr, Some("203.0.113.7:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn refuses_a_missing_or_malformed_header() {
+        for bytes in [
+            &b"GET / HTTP/1.1\r\n\r\n"[..],
+            b"PROXY TCP4 203.0.113.7\r\n",
+            b"PROXY TCP6 203.0.113.7 192.0.2.1 56324 443\r\n",
+            b"PROXY TCP4 203.0.113.7 192.0.2.1 port 443\r\n",
+        ] {
+            let err = read(bytes).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
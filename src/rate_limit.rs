@@ -0,0 +1,341 @@
+//! Rate limiting of the authentication routes
+//! On top of the account lockout, the logins are limited per address and email
+//! (`RATE_LIMIT_LOGIN`) and the password reset requests per email
+//! (`RATE_LIMIT_PASSWORD_FORGOT`). A rule allows a number of hits to a key within a fixed window
+//! starting with the first hit. Requests over the limit are answered with a `429` and a
+//! `Retry-After` header, and recorded in the audit log. A successful login resets the counter of
+//! its address and email.
+//!
+//! The counters are kept in memory, or in the `rate_limits` table with `RATE_LIMIT_BACKEND=db` so
+//! that the limits hold across replicas and restarts. The table only stores the SHA-256 of the
+//! keys, not the emails.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+use crate::{
+    config::RateLimitBackend,
+    database::{with_pool, SqlxPool},
+    error::AppError,
+    state::AppState,
+    users::normalize_email,
+};
+
+/// The hits allowed to a key within a window
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimitRule {
+    /// The hits allowed within a window
+    pub max: u32,
+    /// How long a window lasts, from its first hit
+    pub window: Duration,
+}
+
+impl FromStr for RateLimitRule {
+    type Err = String;
+
+    /// Parse `<max>/<window>`, the window being in seconds or suffixed with `s`, `m` or `h`
+    /// (e.g. `5/1m`)
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected <max>/<window>, such as 5/1m, not '{}'", rule);
+        let (max, window) = rule.trim().split_once('/').ok_or_else(invalid)?;
+        let max: u32 = max.trim().parse().map_err(|_| invalid())?;
+        let window = window.trim();
+        let (amount, unit) = match window.char_indices().last() {
+            Some((index, 's')) => (&window[..index], 1),
+            Some((index, 'm')) => (&window[..index], 60),
+            Some((index, 'h')) => (&window[..index], 60 * 60),
+            _ => (window, 1),
+        };
+        let seconds: u64 = amount.parse().map_err(|_| invalid())?;
+        if max == 0 || seconds == 0 {
+            return Err("the max and the window must be positive".to_string());
+        }
+        Ok(Self {
+            max,
+            window: Duration::from_secs(seconds.saturating_mul(unit)),
+        })
+    }
+}
+
+impl fmt::Display for RateLimitRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}s", self.max, self.window.as_secs())
+    }
+}
+
+/// Whether a hit is allowed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// Within the limit, with the hits left in the window
+    Allowed { remaining: u32 },
+    /// Over the limit until the window ends
+    Limited { retry_after: Duration },
+}
+
+/// Counters about the rate limiter
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RateLimitStats {
+    /// The requests rejected since the start
+    pub rejected: u64,
+}
+
+/// The hits of a key in its current window
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    hits: i64,
+    /// When the window ends, in unix seconds
+    reset_at: i64,
+}
+
+/// Where the counters are kept
+#[derive(Clone, Debug)]
+enum Backend {
+    Memory(Arc<Mutex<HashMap<String, Bucket>>>),
+    Sql(SqlxPool),
+}
+
+/// Counts the hits of keys against rules
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    backend: Backend,
+    /// The number of hits refused since the start
+    rejected: Arc<AtomicU64>,
+}
+
+impl RateLimiter {
+    /// A limiter keeping its counters in memory, for this process alone
+    pub fn memory() -> Self {
+        Self::with_backend(Backend::Memory(Default::default()))
+    }
+
+    /// A limiter keeping its counters in the `rate_limits` table, shared by the replicas
+    pub fn sql(pool: SqlxPool) -> Self {
+        Self::with_backend(Backend::Sql(pool))
+    }
+
+    /// A limiter with the configured backend
+    pub fn new(backend: RateLimitBackend, pool: SqlxPool) -> Self {
+        match backend {
+            RateLimitBackend::Memory => Self::memory(),
+            RateLimitBackend::Db => Self::sql(pool),
+        }
+    }
+
+    fn with_backend(backend: Backend) -> Self {
+        Self {
+            backend,
+            rejected: Default::default(),
+        }
+    }
+
+    /// Count a hit of the key, telling whether the rule allows it
+    pub async fn check(&self, key: &str, rule: &RateLimitRule) -> Result<Decision, sqlx::Error> {
+        let now = now();
+        let reset_at = now + rule.window.as_secs() as i64;
+        let bucket = match &self.backend {
+            Backend::Memory(buckets) => {
+                let mut buckets = buckets.lock().unwrap();
+                let bucket = buckets
+                    .entry(key.to_string())
+                    .or_insert(Bucket { hits: 0, reset_at });
+                if bucket.reset_at <= now {
+                    *bucket = Bucket { hits: 0, reset_at };
+                }
+                bucket.hits += 1;
+                *bucket
+            }
+            Backend::Sql(pool) => hit(pool, &bucket_id(key), now, reset_at).await?,
+        };
+
+        if bucket.hits <= rule.max as i64 {
+            return Ok(Decision::Allowed {
+                remaining: (rule.max as i64 - bucket.hits) as u32,
+            });
+        }
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        Ok(Decision::Limited {
+            retry_after: Duration::from_secs((bucket.reset_at - now).max(1) as u64),
+        })
+    }
+
+    /// Forget the hits of the key
+    pub async fn reset(&self, key: &str) -> Result<(), sqlx::Error> {
+        match &self.backend {
+            Backend::Memory(buckets) => {
+                buckets.lock().unwrap().remove(key);
+            }
+            Backend::Sql(pool) => {
+                let sql = pool.sql("DELETE FROM rate_limits WHERE bucket = ?");
+                with_pool!(pool, |p| sqlx::query(&sql)
+                    .bind(bucket_id(key))
+                    .execute(p)
+                    .await
+                    .map(|r| r.rows_affected()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget the windows that ended, returning how many were
+    pub async fn purge_expired(&self) -> Result<u64, sqlx::Error> {
+        let now = now();
+        match &self.backend {
+            Backend::Memory(buckets) => {
+                let mut buckets = buckets.lock().unwrap();
+                let before = buckets.len();
+                buckets.retain(|_, bucket| bucket.reset_at > now);
+                Ok((before - buckets.len()) as u64)
+            }
+            Backend::Sql(pool) => {
+                let sql = pool.sql("DELETE FROM rate_limits WHERE reset_at <= ?");
+                with_pool!(pool, |p| sqlx::query(&sql)
+                    .bind(now)
+                    .execute(p)
+                    .await
+                    .map(|r| r.rows_affected()))
+            }
+        }
+    }
+
+    pub fn stats(&self) -> RateLimitStats {
+        RateLimitStats {
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Count a hit of the bucket in the table, starting a new window when the previous one ended
+async fn hit(
+    pool: &SqlxPool,
+    bucket: &str,
+    now: i64,
+    reset_at: i64,
+) -> Result<Bucket, sqlx::Error> {
+    // `hits` is assigned first, MySQL evaluating the assignments in order
+    let update = pool.sql(
+        "UPDATE rate_limits \
+         SET hits = CASE WHEN reset_at <= ? THEN 1 ELSE hits + 1 END, \
+         reset_at = CASE WHEN reset_at <= ? THEN ? ELSE reset_at END \
+         WHERE bucket = ?",
+    );
+    let insert = pool.sql("INSERT INTO rate_limits (bucket, hits, reset_at) VALUES (?, 1, ?)");
+    let select = pool.sql("SELECT hits, reset_at FROM rate_limits WHERE bucket = ?");
+    let updated = with_pool!(pool, |p| sqlx::query(&update)
+        .bind(now)
+        .bind(now)
+        .bind(reset_at)
+        .bind(bucket)
+        .execute(p)
+        .await
+        .map(|r| r.rows_affected()))?;
+    if updated == 0 {
+        let inserted = with_pool!(pool, |p| sqlx::query(&insert)
+            .bind(bucket)
+            .bind(reset_at)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()));
+        match inserted {
+            Ok(_) => {}
+            // A concurrent hit inserted the row first
+            Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+                with_pool!(pool, |p| sqlx::query(&update)
+                    .bind(now)
+                    .bind(now)
+                    .bind(reset_at)
+                    .bind(bucket)
+                    .execute(p)
+                    .await
+                    .map(|r| r.rows_affected()))?;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    let (hits, reset_at): (i64, i64) = with_pool!(pool, |p| sqlx::query_as(&select)
+        .bind(bucket)
+        .fetch_one(p)
+        .await)?;
+    Ok(Bucket { hits, reset_at })
+}
+
+/// The identifier of the bucket of a key in the table
+fn bucket_id(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// The key of the logins to an email from an address
+pub fn login_key(ip: &str, email: &str) -> String {
+    format!("login:{}:{}", ip, normalize_email(email))
+}
+
+/// The key of the password reset requests for an email
+pub fn password_forgot_key(email: &str) -> String {
+    format!("password_forgot:{}", normalize_email(email))
+}
+
+/// Count a hit of the key, refusing it with a `429` once over the rule, if any
+///
+/// A refusal is recorded in the audit log, with the address of the client.
+pub async fn enforce(
+    state: &AppState,
+    rule: Option<&RateLimitRule>,
+    key: &str,
+    ip: &str,
+) -> Result<(), AppError> {
+    let Some(rule) = rule else {
+        return Ok(());
+    };
+    let Decision::Limited { retry_after } = state.rate_limiter.check(key, rule).await? else {
+        return Ok(());
+    };
+
+    // The name of the rule, without the email
+    let name = key.split(':').next().unwrap_or_default();
+    tracing::warn!(
+        "Rate limited a request from {} ({}), retry after {:?}",
+        ip,
+        name,
+        retry_after
+    );
+    state.audit.record(
+        None,
+        "rate_limit.rejected",
+        name,
+        json!({
+            "ip": ip,
+            "rule": rule.to_string(),
+            "retry_after": retry_after.as_secs(),
+        }),
+    );
+    Err(AppError::TooManyRequests(retry_after))
+}
+
+/// Forget the windows that ended, forever at the given interval
+pub async fn continuously_purge(limiter: RateLimiter, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        if let Err(err) = limiter.purge_expired().await {
+            tracing::warn!("Failed to purge the rate limits: {}", err);
+        }
+    }
+}
+
+/// The current time, in unix seconds
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
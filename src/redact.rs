@@ -0,0 +1,39 @@
+//! Redaction of secrets in captured request bodies
+
+use serde_json::Value;
+
+/// The placeholder replacing redacted values
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Redact a JSON body, returning `None` if the body isn't JSON
+///
+/// Values of fields whose name contains one of the denylisted words (case-insensitive) are
+/// replaced, at any depth. Bodies in other formats can't be redacted reliably, so they must not
+/// be recorded.
+pub fn redact_json(body: &[u8], denylist: &[String]) -> Option<Value> {
+    let mut value: Value = serde_json::from_slice(body).ok()?;
+    redact_value(&mut value, denylist);
+    Some(value)
+}
+
+/// Redact a JSON value in place
+pub fn redact_value(value: &mut Value, denylist: &[String]) {
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                let name = name.to_ascii_lowercase();
+                if denylist.iter().any(|word| name.contains(word.as_str())) {
+                    *field = Value::String(REDACTED.to_string());
+                } else {
+                    redact_value(field, denylist);
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                redact_value(item, denylist);
+            }
+        }
+        _ => {}
+    }
+}
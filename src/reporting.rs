@@ -0,0 +1,257 @@
+//! Error reporting
+//! Panics in handlers and internal errors are reported through a [`ReporterHandle`], with the
+//! request ID, the route and the user as context. Events never carry the headers (cookies
+//! included) nor the bodies of the requests.
+//!
+//! The default reporter writes the events to the logs, or sends them to Sentry when `SENTRY_DSN`
+//! is set (with the `sentry` feature). Embedders can plug another one by implementing
+//! [`Reporter`].
+
+use std::{
+    any::Any,
+    panic::AssertUnwindSafe,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::{MatchedPath, OriginalUri, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures_util::FutureExt;
+use rand::Rng;
+use tower_sessions::Session;
+
+use crate::{
+    config::ErrorReportingConfig,
+    error::{AppError, InternalErrorDetail},
+    request_id::RequestId,
+    session_data::AppSession,
+    state::AppState,
+};
+
+/// What went wrong
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    /// A handler panicked
+    Panic,
+    /// A handler returned an internal error
+    InternalError,
+}
+
+/// A reported error, with the context of the request that caused it
+#[derive(Clone, Debug)]
+pub struct ErrorEvent {
+    pub kind: EventKind,
+    pub message: String,
+    pub environment: String,
+    pub request_id: Option<String>,
+    pub method: String,
+    /// The matched route (e.g. `/api/v1/admin/sessions`), or the path when unmatched
+    pub route: String,
+    /// The user who made the request, if known
+    pub user_id: Option<String>,
+}
+
+/// A destination for the error events
+pub trait Reporter: Send + Sync + 'static {
+    fn capture(&self, event: &ErrorEvent);
+
+    /// Wait for the events captured so far to be delivered, at most for `timeout`
+    fn flush(&self, _timeout: Duration) {}
+}
+
+/// Writes the events to the logs
+pub struct LogReporter;
+
+impl Reporter for LogReporter {
+    fn capture(&self, event: &ErrorEvent) {
+        tracing::error!(
+            kind = ?event.kind,
+            environment = %event.environment,
+            request_id = event.request_id.as_deref(),
+            method = %event.method,
+            route = %event.route,
+            user_id = event.user_id.as_deref(),
+            "{}",
+            event.message
+        );
+    }
+}
+
+/// Keeps the events in memory, to inspect them
+#[derive(Clone, Default)]
+pub struct InMemoryReporter {
+    events: Arc<Mutex<Vec<ErrorEvent>>>,
+}
+
+impl InMemoryReporter {
+    /// The events captured so far
+    pub fn events(&self) -> Vec<ErrorEvent> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+impl Reporter for InMemoryReporter {
+    fn capture(&self, event: &ErrorEvent) {
+        self.events.lock().unwrap().push(event.clone());
+    }
+}
+
+/// Sends the events to Sentry, in the background
+#[cfg(feature = "sentry")]
+pub struct SentryReporter {
+    client: sentry::Client,
+}
+
+#[cfg(feature = "sentry")]
+impl SentryReporter {
+    /// Report to the project of the given DSN
+    pub fn new(dsn: sentry::types::Dsn) -> Self {
+        let options = sentry::ClientOptions {
+            dsn: Some(dsn),
+            release: sentry::release_name!(),
+            transport: Some(Arc::new(sentry::transports::DefaultTransportFactory)),
+            ..Default::default()
+        };
+        Self {
+            client: sentry::Client::from(options),
+        }
+    }
+}
+
+#[cfg(feature = "sentry")]
+impl Reporter for SentryReporter {
+    fn capture(&self, event: &ErrorEvent) {
+        use sentry::protocol::{Event, Level, User};
+
+        let kind = match event.kind {
+            EventKind::Panic => "panic",
+            EventKind::InternalError => "internal_error",
+        };
+        let mut tags = std::collections::BTreeMap::from([
+            ("kind".to_string(), kind.to_string()),
+            ("method".to_string(), event.method.clone()),
+        ]);
+        if let Some(request_id) = &event.request_id {
+            tags.insert("request_id".to_string(), request_id.clone());
+        }
+        let sentry_event = Event {
+            level: Level::Error,
+            message: Some(event.message.clone()),
+            environment: Some(event.environment.clone().into()),
+            transaction: Some(event.route.clone()),
+            tags,
+            user: event.user_id.clone().map(|id| User {
+                id: Some(id),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        self.client.capture_event(sentry_event, None);
+    }
+
+    fn flush(&self, timeout: Duration) {
+        self.client.flush(Some(timeout));
+    }
+}
+
+/// A shared handle to the reporter, sampling the events
+#[derive(Clone)]
+pub struct ReporterHandle {
+    reporter: Arc<dyn Reporter>,
+    environment: String,
+    sample_rate: f64,
+}
+
+impl ReporterHandle {
+    /// Report to the given reporter, with the environment and sample rate of the configuration
+    pub fn new(reporter: impl Reporter, config: &ErrorReportingConfig) -> Self {
+        Self {
+            reporter: Arc::new(reporter),
+            environment: config.environment.clone(),
+            sample_rate: config.sample_rate,
+        }
+    }
+
+    /// Report to Sentry if a DSN is configured, to the logs otherwise
+    pub fn from_config(config: &ErrorReportingConfig) -> Self {
+        #[cfg(feature = "sentry")]
+        if let Some(dsn) = &config.sentry_dsn {
+            return Self::new(SentryReporter::new(dsn.clone()), config);
+        }
+        Self::new(LogReporter, config)
+    }
+
+    /// Wait for the reported events to be delivered, at most for `timeout`
+    pub async fn flush(&self, timeout: Duration) {
+        let reporter = self.reporter.clone();
+        let _ = tokio::task::spawn_blocking(move || reporter.flush(timeout)).await;
+    }
+
+    fn capture(&self, mut event: ErrorEvent) {
+        if self.sample_rate < 1.0 && !rand::thread_rng().gen_bool(self.sample_rate.max(0.0)) {
+            return;
+        }
+
+        event.environment = self.environment.clone();
+        self.reporter.capture(&event);
+    }
+}
+
+/// Catch the panics of the handlers and report them along with the internal errors
+///
+/// A panic is answered with an internal error instead of dropping the connection.
+pub async fn report_errors(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let session = req.extensions().get::<Session>().cloned();
+    let mut event = ErrorEvent {
+        kind: EventKind::InternalError,
+        message: String::new(),
+        environment: String::new(),
+        request_id: req.extensions().get::<RequestId>().map(|id| id.0.clone()),
+        method: req.method().to_string(),
+        route: match req.extensions().get::<MatchedPath>() {
+            Some(path) => path.as_str().to_string(),
+            None => req
+                .extensions()
+                .get::<OriginalUri>()
+                .map_or(req.uri().path(), |uri| uri.0.path())
+                .to_string(),
+        },
+        user_id: None,
+    };
+
+    let response = match AssertUnwindSafe(next.run(req)).catch_unwind().await {
+        Ok(response) => match response.extensions().get::<InternalErrorDetail>() {
+            Some(detail) => {
+                event.message = detail.0.clone();
+                response
+            }
+            None => return response,
+        },
+        Err(panic) => {
+            event.kind = EventKind::Panic;
+            event.message = panic_message(panic.as_ref());
+            AppError::Internal(anyhow::anyhow!("Handler panicked")).into_response()
+        }
+    };
+
+    if let Some(session) = session {
+        event.user_id = AppSession(session).user_id().await.ok().flatten();
+    }
+    state.reporter.capture(event);
+
+    response
+}
+
+/// The message a panic was raised with
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Handler panicked".to_string()
+    }
+}
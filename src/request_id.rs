@@ -0,0 +1,67 @@
+//! Request IDs
+//! Every request gets an ID, taken from the `X-Request-Id` header when the client (or a proxy)
+//! provides a sane one, or generated otherwise. The ID is echoed in the response so that logs
+//! and client reports can be correlated.
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request},
+    http::{request::Parts, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use rand::RngCore;
+
+/// The header carrying the ID
+pub const X_REQUEST_ID: &str = "x-request-id";
+
+/// The maximum length of an ID provided by the client
+const MAX_LENGTH: usize = 128;
+
+/// The ID of the current request
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RequestId {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<RequestId>()
+            .cloned()
+            .unwrap_or_else(|| RequestId(String::new())))
+    }
+}
+
+/// Assign an ID to the request and echo it in the response
+pub async fn request_id(mut req: Request, next: Next) -> Response {
+    let id = req
+        .headers()
+        .get(X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| {
+            !value.is_empty()
+                && value.len() <= MAX_LENGTH
+                && value.bytes().all(|b| b.is_ascii_graphic())
+        })
+        .map(|value| value.to_string())
+        .unwrap_or_else(generate);
+
+    // The ID is only made of visible ASCII characters, so it's a valid header value
+    let header = HeaderValue::from_str(&id).unwrap();
+    req.headers_mut().insert(X_REQUEST_ID, header.clone());
+    req.extensions_mut().insert(RequestId(id));
+
+    let mut response = next.run(req).await;
+    response.headers_mut().insert(X_REQUEST_ID, header);
+    response
+}
+
+/// Generate a random ID
+fn generate() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
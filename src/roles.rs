@@ -0,0 +1,215 @@
+//! Roles of the users
+//! The roles grant permissions (see [`crate::permissions`]), by default `viewer` < `operator` <
+//! `admin`, each granted what the lower ones are. They are stored in the `roles` table, and given
+//! to users through `user_roles`, or through their groups (see [`crate::groups`]). Changing the
+//! roles of a user bumps their `roles_version`, so that the roles cached in their sessions are
+//! refreshed on their next request.
+
+use std::fmt;
+
+use axum::extract::{Path, State};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use time::OffsetDateTime;
+
+use crate::{
+    audit::AuditActor,
+    auth::current_user::OptionalUser,
+    database::{with_pool, SqlxPool},
+    error::AppError,
+    negotiate::{Format, Negotiated},
+    state::AppState,
+    users::{UserError, UserRepository},
+};
+
+/// A role, granted permissions on the admin endpoints
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Read the statistics and the state of the backend
+    Viewer,
+    /// Also operate the backend
+    Operator,
+    /// Also manage the users, their sessions and the audit log
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<Self> {
+        match role {
+            "viewer" => Some(Role::Viewer),
+            "operator" => Some(Role::Operator),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The roles of the users, stored in the database
+#[derive(Clone, Debug)]
+pub struct RoleRepository {
+    pool: SqlxPool,
+}
+
+impl RoleRepository {
+    pub fn new(pool: SqlxPool) -> Self {
+        Self { pool }
+    }
+
+    /// The roles of a user, lowest first (none for an unknown user)
+    pub async fn roles_of(&self, user_id: i64) -> Result<Vec<Role>, UserError> {
+        let sql = self
+            .pool
+            .sql("SELECT role FROM user_roles WHERE user_id = ?")
+            .into_owned();
+        let names: Vec<String> = with_pool!(&self.pool, |p| sqlx::query_scalar(&sql)
+            .bind(user_id)
+            .fetch_all(p)
+            .await)?;
+
+        // Roles unknown to this version are ignored
+        let mut roles: Vec<Role> = names.iter().filter_map(|name| Role::parse(name)).collect();
+        roles.sort();
+        Ok(roles)
+    }
+
+    /// The roles of a user, their own and those granted by their groups, lowest first
+    pub async fn effective_roles_of(&self, user_id: i64) -> Result<Vec<Role>, UserError> {
+        let sql = self
+            .pool
+            .sql(
+                "SELECT role FROM user_roles WHERE user_id = ? \
+                 UNION SELECT group_roles.role FROM group_roles \
+                 JOIN group_members ON group_members.group_id = group_roles.group_id \
+                 WHERE group_members.user_id = ?",
+            )
+            .into_owned();
+        let names: Vec<String> = with_pool!(&self.pool, |p| sqlx::query_scalar(&sql)
+            .bind(user_id)
+            .bind(user_id)
+            .fetch_all(p)
+            .await)?;
+
+        let mut roles: Vec<Role> = names.iter().filter_map(|name| Role::parse(name)).collect();
+        roles.sort();
+        roles.dedup();
+        Ok(roles)
+    }
+
+    /// Replace the roles of a user, invalidating the roles cached in their sessions
+    pub async fn set_roles(&self, user_id: i64, roles: &[Role]) -> Result<(), UserError> {
+        let bump = self
+            .pool
+            .sql("UPDATE users SET roles_version = roles_version + 1, updated_at = ? WHERE id = ?");
+        let clear = self.pool.sql("DELETE FROM user_roles WHERE user_id = ?");
+        let insert = self
+            .pool
+            .sql("INSERT INTO user_roles (user_id, role) VALUES (?, ?)");
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+
+        let mut roles = roles.to_vec();
+        roles.sort();
+        roles.dedup();
+
+        let found = with_pool!(&self.pool, |p| {
+            let mut tx = p.begin().await?;
+            let bumped = sqlx::query(&bump)
+                .bind(now)
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+            if bumped > 0 {
+                sqlx::query(&clear).bind(user_id).execute(&mut *tx).await?;
+                for role in &roles {
+                    sqlx::query(&insert)
+                        .bind(user_id)
+                        .bind(role.as_str())
+                        .execute(&mut *tx)
+                        .await?;
+                }
+            }
+            tx.commit().await?;
+            bumped > 0
+        });
+
+        if found {
+            Ok(())
+        } else {
+            Err(UserError::NotFound)
+        }
+    }
+}
+
+/// Refuse to replace the roles of the user making the request if it removes their `admin` role,
+/// which could lock every admin out
+pub fn refuse_self_demotion(current: &[Role], new: &[Role]) -> Result<(), AppError> {
+    if current.contains(&Role::Admin) && !new.contains(&Role::Admin) {
+        return Err(AppError::Conflict(
+            "You can't remove your own admin role".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// The roles of a user
+#[derive(Serialize, Deserialize)]
+pub struct RoleSet {
+    pub roles: Vec<Role>,
+}
+
+/// `GET /admin/users/:id/roles`: the roles of a user
+pub async fn get_user_roles(
+    State(state): State<AppState>,
+    format: Format,
+    Path(user_id): Path<i64>,
+) -> Result<Negotiated<RoleSet>, AppError> {
+    UserRepository::new(state.read_pool().clone())
+        .find_by_id(user_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let roles = RoleRepository::new(state.read_pool().clone())
+        .roles_of(user_id)
+        .await?;
+    Ok(Negotiated(format, RoleSet { roles }))
+}
+
+/// `PUT /admin/users/:id/roles`: replace the roles of a user
+pub async fn put_user_roles(
+    State(state): State<AppState>,
+    OptionalUser(actor): OptionalUser,
+    AuditActor(audit_actor): AuditActor,
+    Path(user_id): Path<i64>,
+    Negotiated(format, role_set): Negotiated<RoleSet>,
+) -> Result<Negotiated<RoleSet>, AppError> {
+    let repository = RoleRepository::new(state.write_pool().clone());
+    let previous = repository.roles_of(user_id).await?;
+    if actor.is_some_and(|actor| actor.id == user_id) {
+        refuse_self_demotion(&previous, &role_set.roles)?;
+    }
+    repository.set_roles(user_id, &role_set.roles).await?;
+    let roles = repository.roles_of(user_id).await?;
+    if roles != previous {
+        state.audit.record(
+            audit_actor,
+            "user.roles_changed",
+            &user_id.to_string(),
+            json!({ "previous": previous, "roles": roles }),
+        );
+    }
+    Ok(Negotiated(format, RoleSet { roles }))
+}
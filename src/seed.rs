@@ -0,0 +1,59 @@
+//! Synthetic data for demos and tests
+//! `seed --sessions N` fills the session store with anonymous sessions, so that the admin and
+//! metrics endpoints have something to show. The sessions carry random data, and expire at
+//! random times within the lifetime of a real session.
+
+use std::collections::HashMap;
+
+use rand::Rng;
+use serde_json::json;
+use time::{Duration, OffsetDateTime};
+use tower_sessions::{
+    session::{Id, Record},
+    session_store, SessionStore,
+};
+
+use crate::session_backend::DynSessionStore;
+
+/// The key of the record data marking a session as synthetic
+const SEEDED_KEY: &str = "__seeded";
+
+/// Create `count` synthetic sessions through the store, returning their IDs
+///
+/// The sessions expire between a minute and `lifetime` from now.
+pub async fn sessions(
+    store: &DynSessionStore,
+    count: usize,
+    lifetime: Duration,
+) -> session_store::Result<Vec<Id>> {
+    let mut ids = Vec::with_capacity(count);
+    for _ in 0..count {
+        let mut record = random_record(lifetime);
+        store.create(&mut record).await?;
+        ids.push(record.id);
+    }
+    // Written behind, the records would be lost when the command exits
+    store.flush().await?;
+    Ok(ids)
+}
+
+/// An anonymous session with random data
+fn random_record(lifetime: Duration) -> Record {
+    let mut rng = rand::thread_rng();
+    let seconds = rng.gen_range(60..=lifetime.whole_seconds().max(60));
+    let mut data = HashMap::new();
+    data.insert(SEEDED_KEY.to_string(), json!(true));
+    data.insert(
+        "payload".to_string(),
+        json!({
+            "visits": rng.gen_range(1..100),
+            "theme": if rng.gen() { "light" } else { "dark" },
+            "nonce": hex::encode(rng.gen::<[u8; 8]>()),
+        }),
+    );
+    Record {
+        id: Id::default(),
+        data,
+        expiry_date: OffsetDateTime::now_utc() + Duration::seconds(seconds),
+    }
+}
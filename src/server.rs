@@ -0,0 +1,220 @@
+//! The listeners and the accept loop
+//! Listeners bound to an IPv6 address accept IPv4 connections as well (as IPv4-mapped
+//! addresses), unless `ipv6_only` is set. Connections are served over HTTP/1.1 with hyper. The
+//! peer address (or the client address relayed through the PROXY protocol, when enabled) is
+//! exposed to the handlers as
+//! [`ConnectInfo<SocketAddr>`], and the peer address alone as [`PeerAddr`]. Requests whose line
+//! and headers exceed the configured size are answered with `431 Request Header Fields Too Large`
+//! by hyper, before reaching the router.
+//! Responses marked with [`DropConnection`] are not sent: their
+//! connection is closed instead.
+
+use std::{future::Future, io, net::SocketAddr, time::Duration};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use axum::{extract::ConnectInfo, Router};
+use hyper::{body::Incoming, server::conn::http1, service::service_fn, Request};
+use hyper_util::rt::TokioIo;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::watch,
+};
+use tower::ServiceExt;
+
+use crate::{chaos::DropConnection, forwarded::PeerAddr, proxy_protocol};
+
+/// The smallest header size limit hyper accepts
+pub const MIN_HEADER_BYTES: usize = 8192;
+
+/// How long a client may take to send the PROXY protocol header
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The number of pending connections the listeners queue, as for [`TcpListener::bind`]
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// Bind a listener to the first address the host resolves to which can be bound
+///
+/// `host` is a name or an IP address, IPv6 ones without brackets (e.g. `::`).
+pub async fn bind(host: &str, port: u16, ipv6_only: bool) -> io::Result<TcpListener> {
+    let mut last_err = None;
+    for addr in tokio::net::lookup_host((host, port)).await? {
+        match bind_addr(addr, ipv6_only) {
+            Ok(listener) => return Ok(listener),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} resolves to no address", host),
+        )
+    }))
+}
+
+fn bind_addr(addr: SocketAddr, ipv6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Serve the application until the shutdown future resolves, then wait for the connections to
+/// finish their in-flight requests
+///
+/// With `proxy_protocol`, every connection must start with a PROXY protocol header; the ones
+/// that don't are closed. `max_header_bytes` must be at least [`MIN_HEADER_BYTES`].
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    proxy_protocol: bool,
+    max_header_bytes: usize,
+    shutdown: impl Future<Output = ()>,
+) {
+    // Every connection holds a receiver: the sender is closed once they are all done
+    let (signal, watcher) = watch::channel(());
+    tokio::pin!(shutdown);
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    tracing::warn!("Failed to accept a connection: {}", err);
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let app = app.clone();
+        let watcher = watcher.clone();
+        tokio::spawn(serve_connection(
+            stream,
+            peer,
+            app,
+            proxy_protocol,
+            max_header_bytes,
+            watcher,
+        ));
+    }
+
+    drop(listener);
+    drop(watcher);
+    let _ = signal.send(());
+    signal.closed().await;
+}
+
+/// Serve the requests of a connection, until it is closed or the server shuts down
+async fn serve_connection(
+    stream: TcpStream,
+    peer: SocketAddr,
+    app: Router,
+    proxy_protocol: bool,
+    max_header_bytes: usize,
+    mut watcher: watch::Receiver<()>,
+) {
+    let Some((stream, client)) = handshake(stream, peer, proxy_protocol).await else {
+        return;
+    };
+
+    // Failing the service makes hyper close the connection without answering
+    let service = service_fn(move |mut req: Request<Incoming>| {
+        req.extensions_mut().insert(ConnectInfo(client));
+        req.extensions_mut().insert(PeerAddr(peer));
+        let app = app.clone();
+        async move {
+            let Ok(response) = app.oneshot(req).await;
+            if response.extensions().get::<DropConnection>().is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "connection dropped on purpose",
+                ));
+            }
+            Ok(response)
+        }
+    });
+    // hyper buffers the request line and headers, and answers with a 431 once they overflow
+    let connection = http1::Builder::new()
+        .max_buf_size(max_header_bytes)
+        .serve_connection(TokioIo::new(stream), service);
+    tokio::pin!(connection);
+
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = watcher.changed() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(err) = result {
+        tracing::debug!("Connection from {} failed: {}", client, err);
+    }
+}
+
+/// Learn the address of the client, reading the PROXY protocol header if enabled
+async fn handshake(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    proxy_protocol: bool,
+) -> Option<(TcpStream, SocketAddr)> {
+    if !proxy_protocol {
+        return Some((stream, peer));
+    }
+
+    let header = tokio::time::timeout(
+        PROXY_HEADER_TIMEOUT,
+        proxy_protocol::read_header(&mut stream),
+    );
+    match header.await {
+        Ok(Ok(client)) => Some((stream, client.unwrap_or(peer))),
+        Ok(Err(err)) => {
+            tracing::debug!("Rejected connection from {}: {}", peer, err);
+            None
+        }
+        Err(_) => {
+            tracing::debug!("Rejected connection from {}: no PROXY header in time", peer);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+
+    /// Connect to the listener over IPv4, returning the peer address it accepted
+    async fn connect_v4(listener: &TcpListener) -> io::Result<SocketAddr> {
+        let port = listener.local_addr()?.port();
+        let _client = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await?;
+        let (_, peer) = listener.accept().await?;
+        Ok(peer)
+    }
+
+    #[tokio::test]
+    async fn accepts_ipv4_connections_on_a_dual_stack_listener() {
+        let listener = bind("::", 0, false).await.unwrap();
+
+        let peer = connect_v4(&listener).await.unwrap();
+        let IpAddr::V6(ip) = peer.ip() else {
+            panic!("{} isn't an IPv4-mapped address", peer);
+        };
+        assert_eq!(ip.to_ipv4_mapped(), Some(Ipv4Addr::LOCALHOST));
+    }
+
+    #[tokio::test]
+    async fn refuses_ipv4_connections_on_an_ipv6_only_listener() {
+        let listener = bind("::", 0, true).await.unwrap();
+
+        let err = connect_v4(&listener).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
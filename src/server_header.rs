@@ -0,0 +1,34 @@
+//! The `Server` header of the responses
+//! Hyper doesn't send a `Server` header. Some organizations require a specific one, while others
+//! want it gone so as not to advertise the software, including when a handler sets it. With
+//! `SERVER_HEADER`, every response gets the configured value, or none when it is `none`.
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue},
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+
+use crate::config::ServerHeader;
+
+/// Wrap the application so that every response gets the configured `Server` header, if any
+pub fn wrap(app: Router, server_header: &ServerHeader) -> Router {
+    let value = match server_header {
+        ServerHeader::Default => return app,
+        ServerHeader::Value(value) => Some(value.clone()),
+        ServerHeader::Suppressed => None,
+    };
+    app.layer(middleware::from_fn_with_state(value, identify))
+}
+
+/// Set the `Server` header of the response to the value, or remove it
+async fn identify(State(value): State<Option<HeaderValue>>, req: Request, next: Next) -> Response {
+    let mut response = next.run(req).await;
+    match value {
+        Some(value) => response.headers_mut().insert(header::SERVER, value),
+        None => response.headers_mut().remove(header::SERVER),
+    };
+    response
+}
@@ -0,0 +1,224 @@
+//! When the sessions were last active
+//! With `SESSION_TRACK_LAST_SEEN` set, the session store is wrapped in a [`LastSeenStore`], which
+//! records when each session was last loaded or saved in the `session_activity` table, for
+//! analytics. The writes are throttled: a session is written at most once per
+//! `SESSION_LAST_SEEN_INTERVAL_SECS`, so `last_seen` lags behind by up to that much. With
+//! write-behind, saves reach the store when flushed, and are seen then.
+//!
+//! The rows of the sessions deleted through the store are deleted along with them. The rows of
+//! the sessions that expired are purged with the expired sessions, once older than any session can
+//! stay inactive.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::async_trait;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tower_sessions::{
+    session::{Id, Record},
+    session_store, ExpiredDeletion, SessionStore,
+};
+
+use crate::database::{is_unique_violation, with_pool, SqlxPool};
+
+/// When a session was last active
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct SessionActivity {
+    pub session_id: String,
+    /// When the session was last seen, in unix seconds
+    pub last_seen: i64,
+}
+
+/// The activity of the sessions, stored in the database
+#[derive(Clone, Debug)]
+pub struct SessionActivityRepository {
+    pool: SqlxPool,
+}
+
+impl SessionActivityRepository {
+    pub fn new(pool: SqlxPool) -> Self {
+        Self { pool }
+    }
+
+    /// Record that a session was seen at the given time (in unix seconds)
+    pub async fn touch(&self, session_id: &str, last_seen: i64) -> Result<(), sqlx::Error> {
+        let update = self
+            .pool
+            .sql("UPDATE session_activity SET last_seen = ? WHERE session_id = ?");
+        let insert = self
+            .pool
+            .sql("INSERT INTO session_activity (session_id, last_seen) VALUES (?, ?)");
+
+        let updated = with_pool!(&self.pool, |p| sqlx::query(&update)
+            .bind(last_seen)
+            .bind(session_id)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))?;
+        if updated > 0 {
+            return Ok(());
+        }
+        let inserted = with_pool!(&self.pool, |p| sqlx::query(&insert)
+            .bind(session_id)
+            .bind(last_seen)
+            .execute(p)
+            .await
+            .map(|_| ()));
+        match inserted {
+            // Inserted concurrently, by another request of the same session
+            Err(err) if is_unique_violation(&err) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Forget a session
+    pub async fn delete(&self, session_id: &str) -> Result<(), sqlx::Error> {
+        let sql = self
+            .pool
+            .sql("DELETE FROM session_activity WHERE session_id = ?");
+        with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(session_id)
+            .execute(p)
+            .await
+            .map(|_| ()))
+    }
+
+    /// The sessions last seen before the cutoff (in unix seconds), the least recently seen first
+    pub async fn inactive_since(&self, cutoff: i64) -> Result<Vec<SessionActivity>, sqlx::Error> {
+        let sql = self.pool.sql(
+            "SELECT session_id, last_seen FROM session_activity WHERE last_seen < ? \
+             ORDER BY last_seen",
+        );
+        with_pool!(&self.pool, |p| sqlx::query_as(&sql)
+            .bind(cutoff)
+            .fetch_all(p)
+            .await)
+    }
+
+    /// Forget the sessions last seen before the cutoff (in unix seconds)
+    pub async fn purge_before(&self, cutoff: i64) -> Result<u64, sqlx::Error> {
+        let sql = self
+            .pool
+            .sql("DELETE FROM session_activity WHERE last_seen < ?");
+        with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(cutoff)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))
+    }
+}
+
+/// A session store recording when the sessions of the store it wraps were last active
+#[derive(Clone, Debug)]
+pub struct LastSeenStore<S> {
+    inner: S,
+    activity: SessionActivityRepository,
+    /// The minimum time between two writes of a same session
+    interval: Duration,
+    /// How long a session stays alive without being seen
+    retention: Duration,
+    /// When each session was last written
+    written: Arc<Mutex<HashMap<Id, Instant>>>,
+}
+
+impl<S> LastSeenStore<S> {
+    /// Wrap a store, writing the activity of a session at most once per `interval`
+    ///
+    /// `retention` is the longest a session stays alive without being seen: the activity of the
+    /// sessions unseen for longer is purged.
+    pub fn new(inner: S, pool: SqlxPool, interval: Duration, retention: Duration) -> Self {
+        Self {
+            inner,
+            activity: SessionActivityRepository::new(pool),
+            interval,
+            retention,
+            written: Default::default(),
+        }
+    }
+
+    /// The sessions last seen before `since`, the least recently seen first
+    pub async fn inactive_sessions(
+        &self,
+        since: OffsetDateTime,
+    ) -> Result<Vec<SessionActivity>, sqlx::Error> {
+        self.activity.inactive_since(since.unix_timestamp()).await
+    }
+
+    /// Record that a session was seen, unless it was written less than `interval` ago
+    ///
+    /// Failures are only logged: they don't fail the request.
+    async fn touch(&self, session_id: &Id) {
+        {
+            let mut written = self.written.lock().unwrap();
+            if written
+                .get(session_id)
+                .is_some_and(|at| at.elapsed() < self.interval)
+            {
+                return;
+            }
+            written.insert(*session_id, Instant::now());
+        }
+
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        if let Err(err) = self.activity.touch(&session_id.to_string(), now).await {
+            tracing::warn!("Failed to record the activity of a session: {}", err);
+            // Retried on the next request
+            self.written.lock().unwrap().remove(session_id);
+        }
+    }
+}
+
+#[async_trait]
+impl<S: SessionStore> SessionStore for LastSeenStore<S> {
+    async fn create(&self, session_record: &mut Record) -> session_store::Result<()> {
+        self.inner.create(session_record).await?;
+        self.touch(&session_record.id).await;
+        Ok(())
+    }
+
+    async fn save(&self, session_record: &Record) -> session_store::Result<()> {
+        self.inner.save(session_record).await?;
+        self.touch(&session_record.id).await;
+        Ok(())
+    }
+
+    async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
+        let record = self.inner.load(session_id).await?;
+        if record.is_some() {
+            self.touch(session_id).await;
+        }
+        Ok(record)
+    }
+
+    async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
+        self.inner.delete(session_id).await?;
+        self.written.lock().unwrap().remove(session_id);
+        self.activity
+            .delete(&session_id.to_string())
+            .await
+            .map_err(|err| session_store::Error::Backend(err.to_string()))
+    }
+}
+
+#[async_trait]
+impl<S: ExpiredDeletion> ExpiredDeletion for LastSeenStore<S> {
+    async fn delete_expired(&self) -> session_store::Result<()> {
+        self.inner.delete_expired().await?;
+
+        // A session unseen for longer than it can stay inactive has expired
+        let cutoff = OffsetDateTime::now_utc() - self.retention - self.interval;
+        self.activity
+            .purge_before(cutoff.unix_timestamp())
+            .await
+            .map_err(|err| session_store::Error::Backend(err.to_string()))?;
+        self.written
+            .lock()
+            .unwrap()
+            .retain(|_, at| at.elapsed() < self.interval);
+        Ok(())
+    }
+}
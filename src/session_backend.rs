@@ -0,0 +1,408 @@
+//! Pluggable session stores
+//! The session layer is built over a [`DynSessionStore`], which can wrap any store. The store is
+//! picked by a [`SessionStoreRegistry`] from the scheme of the session store URI, so that other
+//! backends can be registered without touching the built-in ones.
+//!
+//! The [`DynSessionStore`] can also cap the lifetime of the sessions regardless of their
+//! activity, by storing their creation timestamp in their record, and buffer the saves so that
+//! a session updated on every request is written once per flush (write-behind).
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use axum::async_trait;
+use time::OffsetDateTime;
+use tower_sessions::{
+    session::{Id, Record},
+    session_store, ExpiredDeletion, SessionStore,
+};
+
+use crate::{database::SqlxPool, health::Heartbeat, session_store::SqlxSessionStore};
+
+/// The interval between the deletions of the expired sessions
+pub const DELETION_PERIOD: Duration = Duration::from_secs(60);
+
+/// An object-safe union of [`SessionStore`] and [`ExpiredDeletion`]
+///
+/// Implemented for every store implementing both traits.
+#[async_trait]
+pub trait SessionBackend: Debug + Send + Sync + 'static {
+    async fn create(&self, session_record: &mut Record) -> session_store::Result<()>;
+    async fn save(&self, session_record: &Record) -> session_store::Result<()>;
+    async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>>;
+    async fn delete(&self, session_id: &Id) -> session_store::Result<()>;
+    async fn delete_expired(&self) -> session_store::Result<()>;
+}
+
+#[async_trait]
+impl<T: ExpiredDeletion> SessionBackend for T {
+    async fn create(&self, session_record: &mut Record) -> session_store::Result<()> {
+        SessionStore::create(self, session_record).await
+    }
+
+    async fn save(&self, session_record: &Record) -> session_store::Result<()> {
+        SessionStore::save(self, session_record).await
+    }
+
+    async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
+        SessionStore::load(self, session_id).await
+    }
+
+    async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
+        SessionStore::delete(self, session_id).await
+    }
+
+    async fn delete_expired(&self) -> session_store::Result<()> {
+        ExpiredDeletion::delete_expired(self).await
+    }
+}
+
+/// The key of the record data holding the creation timestamp (unix seconds) of the session
+const CREATED_AT_KEY: &str = "__created_at";
+
+/// The saves waiting to be flushed, keyed by session (only the latest one is kept)
+type PendingSaves = Arc<Mutex<HashMap<Id, Record>>>;
+
+/// A type-erased session store
+#[derive(Clone, Debug)]
+pub struct DynSessionStore {
+    inner: Arc<dyn SessionBackend>,
+    /// The maximum lifetime of a session, even if active
+    absolute_max: Option<Duration>,
+    /// The buffered saves, when writing behind
+    pending: Option<PendingSaves>,
+}
+
+impl DynSessionStore {
+    /// Wrap the given store
+    pub fn new(store: impl ExpiredDeletion) -> Self {
+        Self {
+            inner: Arc::new(store),
+            absolute_max: None,
+            pending: None,
+        }
+    }
+
+    /// Force-expire the sessions older than `max`, even if they are still active
+    pub fn with_absolute_max(mut self, max: Option<Duration>) -> Self {
+        self.absolute_max = max;
+        self
+    }
+
+    /// Buffer the saves until [`DynSessionStore::flush`] is called, coalescing the saves of a
+    /// same session
+    pub fn with_write_behind(mut self, enabled: bool) -> Self {
+        self.pending = enabled.then(PendingSaves::default);
+        self
+    }
+
+    /// Write the buffered saves to the store
+    ///
+    /// Saves that fail are kept buffered, unless a newer save of the same session arrived.
+    pub async fn flush(&self) -> session_store::Result<()> {
+        let Some(pending) = &self.pending else {
+            return Ok(());
+        };
+
+        let records: Vec<Record> = pending.lock().unwrap().drain().map(|(_, r)| r).collect();
+        let mut result = Ok(());
+        for record in records {
+            if let Err(err) = self.inner.save(&record).await {
+                pending.lock().unwrap().entry(record.id).or_insert(record);
+                result = Err(err);
+            }
+        }
+        result
+    }
+
+    /// Flush the buffered saves forever at the given interval
+    pub async fn continuously_flush(self, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            if let Err(err) = self.flush().await {
+                tracing::warn!("Failed to flush session saves: {}", err);
+            }
+        }
+    }
+
+    /// Delete the expired sessions forever at the interval of the heartbeat, beating it after every
+    /// successful deletion
+    ///
+    /// A failed deletion is retried on the next tick, the heartbeat telling when it keeps failing.
+    pub async fn continuously_sweep(self, heartbeat: Heartbeat) {
+        let mut interval = tokio::time::interval(heartbeat.period());
+        loop {
+            interval.tick().await;
+            match ExpiredDeletion::delete_expired(&self).await {
+                Ok(()) => heartbeat.beat(),
+                Err(err) => tracing::warn!("Failed to delete expired sessions: {}", err),
+            }
+        }
+    }
+
+    /// Stamp the creation timestamp of a record if missing, and cap its expiry to the absolute
+    /// maximum lifetime if any
+    ///
+    /// Records are stamped even without a maximum lifetime, so that sessions can be pruned by
+    /// creation time.
+    fn stamp(&self, record: &mut Record) {
+        let created_at = match created_at(record) {
+            Some(created_at) => created_at,
+            None => {
+                let now = OffsetDateTime::now_utc().unix_timestamp();
+                record.data.insert(CREATED_AT_KEY.to_string(), now.into());
+                now
+            }
+        };
+
+        let Some(max) = self.absolute_max else {
+            return;
+        };
+
+        let deadline = OffsetDateTime::from_unix_timestamp(created_at)
+            .map(|created_at| created_at + max)
+            .ok();
+        if let Some(deadline) = deadline.filter(|deadline| *deadline < record.expiry_date) {
+            record.expiry_date = deadline;
+        }
+    }
+
+    /// Whether the record outlived the absolute maximum lifetime
+    fn is_past_absolute_max(&self, record: &Record) -> bool {
+        let (Some(max), Some(created_at)) = (self.absolute_max, created_at(record)) else {
+            return false;
+        };
+
+        OffsetDateTime::now_utc().unix_timestamp() - created_at >= max.as_secs() as i64
+    }
+}
+
+/// The creation timestamp of a record, if stamped
+pub(crate) fn created_at(record: &Record) -> Option<i64> {
+    record
+        .data
+        .get(CREATED_AT_KEY)
+        .and_then(|value| value.as_i64())
+}
+
+#[async_trait]
+impl SessionStore for DynSessionStore {
+    async fn create(&self, session_record: &mut Record) -> session_store::Result<()> {
+        self.stamp(session_record);
+        self.inner.create(session_record).await
+    }
+
+    async fn save(&self, session_record: &Record) -> session_store::Result<()> {
+        if self.absolute_max.is_none() && self.pending.is_none() {
+            return self.inner.save(session_record).await;
+        }
+
+        let mut session_record = session_record.clone();
+        self.stamp(&mut session_record);
+
+        match &self.pending {
+            Some(pending) => {
+                pending
+                    .lock()
+                    .unwrap()
+                    .insert(session_record.id, session_record);
+                Ok(())
+            }
+            None => self.inner.save(&session_record).await,
+        }
+    }
+
+    async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
+        // A buffered save is newer than what the store holds
+        let buffered = self
+            .pending
+            .as_ref()
+            .and_then(|pending| pending.lock().unwrap().get(session_id).cloned());
+        let record = match buffered {
+            Some(record) => Some(record),
+            None => self.inner.load(session_id).await?,
+        };
+
+        match record {
+            Some(record) if self.is_past_absolute_max(&record) => {
+                SessionStore::delete(self, session_id).await?;
+                Ok(None)
+            }
+            record => Ok(record),
+        }
+    }
+
+    async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
+        if let Some(pending) = &self.pending {
+            pending.lock().unwrap().remove(session_id);
+        }
+        self.inner.delete(session_id).await
+    }
+}
+
+#[async_trait]
+impl ExpiredDeletion for DynSessionStore {
+    async fn delete_expired(&self) -> session_store::Result<()> {
+        self.inner.delete_expired().await
+    }
+}
+
+/// Builds a session store from the session store URI and the database pool
+pub type SessionStoreFactory =
+    Box<dyn Fn(&str, &SqlxPool) -> Result<DynSessionStore> + Send + Sync>;
+
+/// The session store factories, keyed by URI scheme
+pub struct SessionStoreRegistry {
+    factories: HashMap<String, SessionStoreFactory>,
+}
+
+impl Default for SessionStoreRegistry {
+    /// A registry with the built-in stores (`sqlite`, `postgresql` and `mysql`), which store the
+    /// sessions in the main database
+    fn default() -> Self {
+        let mut registry = Self {
+            factories: HashMap::new(),
+        };
+
+        for scheme in ["sqlite", "postgresql", "mysql"] {
+            registry.register(scheme, |_, pool| {
+                Ok(DynSessionStore::new(SqlxSessionStore::new(pool.clone())))
+            });
+        }
+
+        registry
+    }
+}
+
+impl SessionStoreRegistry {
+    /// Register a factory for the given scheme, replacing any previous one
+    pub fn register<F>(&mut self, scheme: impl Into<String>, factory: F)
+    where
+        F: Fn(&str, &SqlxPool) -> Result<DynSessionStore> + Send + Sync + 'static,
+    {
+        self.factories.insert(scheme.into(), Box::new(factory));
+    }
+
+    /// Build the session store for the given URI
+    pub fn build(&self, uri: &str, pool: &SqlxPool) -> Result<DynSessionStore> {
+        let scheme = uri.split_once(':').map(|(scheme, _)| scheme).unwrap_or(uri);
+        let factory = self.factories.get(scheme).ok_or(anyhow::anyhow!(
+            "No session store registered for scheme '{}'",
+            scheme
+        ))?;
+        factory(uri, pool)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// An in-memory store counting its writes
+    #[derive(Debug, Default)]
+    struct CountingStore {
+        records: Mutex<HashMap<Id, Record>>,
+        writes: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl SessionStore for CountingStore {
+        async fn create(&self, session_record: &mut Record) -> session_store::Result<()> {
+            SessionStore::save(self, session_record).await
+        }
+
+        async fn save(&self, session_record: &Record) -> session_store::Result<()> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.records
+                .lock()
+                .unwrap()
+                .insert(session_record.id, session_record.clone());
+            Ok(())
+        }
+
+        async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
+            Ok(self.records.lock().unwrap().get(session_id).cloned())
+        }
+
+        async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
+            self.records.lock().unwrap().remove(session_id);
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl ExpiredDeletion for CountingStore {
+        async fn delete_expired(&self) -> session_store::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A write-behind store over a counting store, kept to inspect it
+    fn store() -> (DynSessionStore, Arc<CountingStore>) {
+        let inner = Arc::new(CountingStore::default());
+        let store = DynSessionStore {
+            inner: inner.clone(),
+            absolute_max: None,
+            pending: None,
+        };
+        (store.with_write_behind(true), inner)
+    }
+
+    fn record(counter: i64) -> Record {
+        Record {
+            id: Id::default(),
+            data: HashMap::from([("counter".to_string(), counter.into())]),
+            expiry_date: OffsetDateTime::now_utc() + time::Duration::hours(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn coalesces_the_saves_of_a_session() {
+        let (store, inner) = store();
+        let mut record = record(0);
+        SessionStore::create(&store, &mut record).await.unwrap();
+        assert_eq!(inner.writes.load(Ordering::SeqCst), 1);
+
+        for counter in 1..=10 {
+            record.data.insert("counter".to_string(), counter.into());
+            SessionStore::save(&store, &record).await.unwrap();
+        }
+        assert_eq!(inner.writes.load(Ordering::SeqCst), 1);
+        // The buffered save is loaded before being written
+        let loaded = SessionStore::load(&store, &record.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.data["counter"], 10);
+
+        store.flush().await.unwrap();
+        assert_eq!(inner.writes.load(Ordering::SeqCst), 2);
+        let written = inner.records.lock().unwrap()[&record.id].clone();
+        assert_eq!(written.data["counter"], 10);
+
+        // Nothing is left to write
+        store.flush().await.unwrap();
+        assert_eq!(inner.writes.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn drops_the_buffered_saves_of_a_deleted_session() {
+        let (store, inner) = store();
+        let mut record = record(0);
+        SessionStore::create(&store, &mut record).await.unwrap();
+        SessionStore::save(&store, &record).await.unwrap();
+
+        SessionStore::delete(&store, &record.id).await.unwrap();
+        store.flush().await.unwrap();
+        assert_eq!(inner.writes.load(Ordering::SeqCst), 1);
+        assert!(inner.records.lock().unwrap().is_empty());
+    }
+}
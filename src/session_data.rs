@@ -0,0 +1,149 @@
+//! Typed data of the sessions
+//! The identity, roles, CSRF token, flash messages and pending logins of a session are kept
+//! together as a [`SessionData`] under a single key, and read or changed through
+//! [`AppSession`], an extractor layered over [`Session`].
+
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use serde::{Deserialize, Serialize};
+use tower_sessions::{session, Session};
+
+use crate::{auth::login_history::LoginMethod, error::AppError};
+
+/// The key of the [`SessionData`] in the session
+pub const SESSION_DATA_KEY: &str = "data";
+
+/// The data of a session, empty for an anonymous one
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionData {
+    /// The ID of the logged in user, if any
+    pub user_id: Option<String>,
+    /// The ID of the admin impersonating the logged in user, if any
+    pub impersonator_id: Option<String>,
+    /// The roles of the logged in user
+    pub roles: Vec<String>,
+    /// The version of the roles of the user when they were cached, to refresh them once changed
+    pub roles_version: Option<i64>,
+    /// The token expected in state-changing requests, if one was issued
+    pub csrf_token: Option<String>,
+    /// The messages to show on the next page, oldest first
+    pub flash: Vec<String>,
+    /// The login waiting for its second factor, if any: the user isn't logged in yet
+    pub mfa_pending: Option<MfaPending>,
+    /// The login redirected to the OpenID Connect provider, if any
+    pub oidc_pending: Option<OidcPending>,
+    /// The maximum number of sessions of the logged in user as of their login, if limited: the
+    /// store evicts their oldest other sessions when it stores this one
+    pub session_limit: Option<usize>,
+}
+
+/// A login whose user is authenticated, waiting for the second factor
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MfaPending {
+    /// The ID of the user logging in
+    pub user_id: i64,
+    /// How the user authenticated, a password when missing
+    #[serde(default)]
+    pub method: Option<LoginMethod>,
+    /// Until when the second factor is awaited, in unix seconds
+    pub expires_at: i64,
+}
+
+/// A login redirected to the OpenID Connect provider, waiting for its callback
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OidcPending {
+    /// The `state` the callback must carry
+    pub state: String,
+    /// The `nonce` the ID token must carry
+    pub nonce: String,
+    /// The PKCE code verifier, sent with the code
+    pub verifier: String,
+    /// Until when the callback is awaited, in unix seconds
+    pub expires_at: i64,
+}
+
+/// The session of the request, with typed accessors to its [`SessionData`]
+#[derive(Clone, Debug)]
+pub struct AppSession(pub Session);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AppSession {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Session>()
+            .cloned()
+            .map(AppSession)
+            .ok_or_else(|| AppError::Internal(anyhow::anyhow!("The session layer is missing")))
+    }
+}
+
+impl AppSession {
+    /// The data of the session, empty if none was stored
+    pub async fn data(&self) -> Result<SessionData, session::Error> {
+        Ok(self.0.get(SESSION_DATA_KEY).await?.unwrap_or_default())
+    }
+
+    /// Replace the data of the session
+    pub async fn set_data(&self, data: &SessionData) -> Result<(), session::Error> {
+        self.0.insert(SESSION_DATA_KEY, data).await
+    }
+
+    /// Change the data of the session, returning what the change returns
+    pub async fn update<T>(
+        &self,
+        change: impl FnOnce(&mut SessionData) -> T,
+    ) -> Result<T, session::Error> {
+        let mut data = self.data().await?;
+        let result = change(&mut data);
+        self.set_data(&data).await?;
+        Ok(result)
+    }
+
+    /// The ID of the logged in user, if any
+    pub async fn user_id(&self) -> Result<Option<String>, session::Error> {
+        Ok(self.data().await?.user_id)
+    }
+
+    pub async fn set_user_id(&self, user_id: Option<String>) -> Result<(), session::Error> {
+        self.update(|data| data.user_id = user_id).await
+    }
+
+    /// The roles of the logged in user
+    pub async fn roles(&self) -> Result<Vec<String>, session::Error> {
+        Ok(self.data().await?.roles)
+    }
+
+    pub async fn set_roles(&self, roles: Vec<String>) -> Result<(), session::Error> {
+        self.update(|data| data.roles = roles).await
+    }
+
+    /// The CSRF token of the session, if one was issued
+    pub async fn csrf_token(&self) -> Result<Option<String>, session::Error> {
+        Ok(self.data().await?.csrf_token)
+    }
+
+    pub async fn set_csrf_token(&self, token: Option<String>) -> Result<(), session::Error> {
+        self.update(|data| data.csrf_token = token).await
+    }
+
+    /// Queue a message for the next page
+    pub async fn push_flash(&self, message: impl Into<String>) -> Result<(), session::Error> {
+        let message = message.into();
+        self.update(|data| data.flash.push(message)).await
+    }
+
+    /// Take the queued messages, oldest first: they are only returned once
+    pub async fn pop_flash(&self) -> Result<Vec<String>, session::Error> {
+        // Reading an empty queue doesn't need a write
+        let mut data = self.data().await?;
+        if data.flash.is_empty() {
+            return Ok(Vec::new());
+        }
+        let flash = std::mem::take(&mut data.flash);
+        self.set_data(&data).await?;
+        Ok(flash)
+    }
+}
@@ -1,24 +1,99 @@
+use std::collections::HashMap;
+
 use anyhow::Result;
 use axum::async_trait;
+use serde::{Deserialize, Serialize};
 use sqlx::{MySqlPool, PgPool, SqlitePool};
+use time::OffsetDateTime;
 use tower_sessions::{
     session::{Id, Record},
     session_store, ExpiredDeletion, SessionStore,
 };
-use tower_sessions_sqlx_store::{MySqlStore, PostgresStore, SqliteStore};
 
+use crate::{
+    database::{retry_read, SqlxPool},
+    session_backend,
+    session_data::{SessionData, SESSION_DATA_KEY},
+    transaction::Transaction,
+    with_tx,
+};
+
+// The session tables, created by `SqlxSessionStore::migrate`
+//
+// SQLite stores the expiry dates as unix timestamps, so that they compare as numbers. The
+// creation dates are unix timestamps on every backend.
+const SQLITE_TABLE: &str = "tower_sessions";
+const POSTGRES_TABLE: &str = r#""tower_sessions"."session""#;
+const MYSQL_TABLE: &str = "`tower_sessions`.`session`";
+
+/// The columns added to the session tables after their creation, all `BIGINT NULL`
+const ADDED_COLUMNS: [&str; 2] = ["created_at", "user_id"];
+
+/// The indexes of the session tables, with their columns
+const INDEXES: [(&str, &str); 2] = [
+    ("session_created_at", "created_at"),
+    ("session_user_id", "user_id, created_at"),
+];
+
+/// The number of sessions deleted per statement
+const PRUNE_BATCH_SIZE: usize = 500;
+
+/// The number of sessions loaded per statement, below the 999 parameters of older SQLite versions
+const LOAD_BATCH_SIZE: usize = 500;
+
+/// The number of IDs tried when creating a session, before giving up
+const MAX_CREATE_ATTEMPTS: usize = 8;
+
+/// A session store backed by one of the supported databases
 #[derive(Clone, Debug)]
-pub enum SqlxPool {
+pub enum SqlxSessionStore {
     Sqlite(SqlitePool),
     Postgres(PgPool),
     MySql(MySqlPool),
 }
 
-#[derive(Clone, Debug)]
-pub enum SqlxSessionStore {
-    Sqlite(SqliteStore),
-    Postgres(PostgresStore),
-    MySql(MySqlStore),
+/// The session schema can't be used
+#[derive(Debug, thiserror::Error)]
+pub enum SessionSchemaError {
+    /// The session table, or one of its columns, doesn't exist
+    #[error("session schema missing; did migrations run? ({table}: {reason})")]
+    Missing { table: &'static str, reason: String },
+    /// The schema couldn't be checked
+    #[error(transparent)]
+    Database(sqlx::Error),
+}
+
+/// A session as listed to administrators
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionSummary {
+    /// The ID of the session
+    pub id: String,
+    /// When the session expires
+    #[serde(with = "time::serde::rfc3339")]
+    pub expiry_date: OffsetDateTime,
+}
+
+/// Counters about the sessions in the store
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionStats {
+    /// Every session in the store, including expired ones not swept yet
+    pub total: i64,
+    /// The sessions that have not expired yet
+    pub active: i64,
+    /// The sessions that have expired but were not swept yet
+    pub expired: i64,
+}
+
+/// The space taken by the sessions in the store
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StorageStats {
+    /// The sessions in the store, including expired ones not swept yet
+    pub rows: i64,
+    /// The approximate size of the sessions, in bytes
+    pub bytes: i64,
+    /// Whether `bytes` is the size of the table on disk, indexes included (Postgres), rather
+    /// than the size of the session data alone
+    pub on_disk: bool,
 }
 
 impl SqlxSessionStore {
@@ -26,7 +101,7 @@ impl SqlxSessionStore {
     ///
     /// # Examples
     ///
-    /// ```rust,no_run
+    /// ```rust,ignore
     /// use sqlx::AnyPool;
     ///
     /// # tokio_test::block_on(async {
@@ -36,19 +111,834 @@ impl SqlxSessionStore {
     /// ```
     pub fn new(pool: SqlxPool) -> Self {
         match pool {
-            SqlxPool::Sqlite(pool) => Self::Sqlite(SqliteStore::new(pool)),
-            SqlxPool::Postgres(pool) => Self::Postgres(PostgresStore::new(pool)),
-            SqlxPool::MySql(pool) => Self::MySql(MySqlStore::new(pool)),
+            SqlxPool::Sqlite(pool) => Self::Sqlite(pool),
+            SqlxPool::Postgres(pool) => Self::Postgres(pool),
+            SqlxPool::MySql(pool) => Self::MySql(pool),
         }
     }
 
     /// Migrate the session schema.
+    ///
+    /// Idempotent: the schema, the table, its columns and its indexes are only created when
+    /// missing.
     pub async fn migrate(&self) -> Result<(), sqlx::Error> {
-        match &self {
-            SqlxSessionStore::Sqlite(store) => store.migrate().await,
-            SqlxSessionStore::Postgres(store) => store.migrate().await,
-            SqlxSessionStore::MySql(store) => store.migrate().await,
+        match self {
+            SqlxSessionStore::Sqlite(pool) => {
+                sqlx::query(&format!(
+                    "CREATE TABLE IF NOT EXISTS {SQLITE_TABLE} (\
+                     id TEXT PRIMARY KEY NOT NULL, \
+                     data BLOB NOT NULL, \
+                     expiry_date INTEGER NOT NULL, \
+                     created_at BIGINT, \
+                     user_id BIGINT)"
+                ))
+                .execute(pool)
+                .await?;
+            }
+            SqlxSessionStore::Postgres(pool) => {
+                let mut tx = pool.begin().await?;
+                sqlx::query(r#"CREATE SCHEMA IF NOT EXISTS "tower_sessions""#)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query(&format!(
+                    "CREATE TABLE IF NOT EXISTS {POSTGRES_TABLE} (\
+                     id TEXT PRIMARY KEY NOT NULL, \
+                     data BYTEA NOT NULL, \
+                     expiry_date TIMESTAMPTZ NOT NULL, \
+                     created_at BIGINT, \
+                     user_id BIGINT)"
+                ))
+                .execute(&mut *tx)
+                .await?;
+                tx.commit().await?;
+            }
+            SqlxSessionStore::MySql(pool) => {
+                sqlx::query("CREATE SCHEMA IF NOT EXISTS `tower_sessions`")
+                    .execute(pool)
+                    .await?;
+                sqlx::query(&format!(
+                    "CREATE TABLE IF NOT EXISTS {MYSQL_TABLE} (\
+                     id CHAR(22) PRIMARY KEY NOT NULL, \
+                     data BLOB NOT NULL, \
+                     expiry_date TIMESTAMP(6) NOT NULL, \
+                     created_at BIGINT NULL, \
+                     user_id BIGINT NULL)"
+                ))
+                .execute(pool)
+                .await?;
+            }
+        }
+        self.add_missing_columns().await?;
+        self.create_missing_indexes().await
+    }
+
+    /// Add the columns a table created by an earlier version lacks, filling them from the
+    /// records
+    ///
+    /// The columns are added and filled in a single transaction, so that a failure leaves the
+    /// table as it was, to be migrated again on the next start. MySQL commits an `ALTER TABLE`
+    /// on its own, though: there, a failed fill leaves the columns empty for the sessions stored
+    /// so far.
+    async fn add_missing_columns(&self) -> Result<(), sqlx::Error> {
+        let mut missing = Vec::new();
+        for column in ADDED_COLUMNS {
+            if !self.has_column(column).await? {
+                missing.push(column);
+            }
+        }
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        tracing::info!("Adding the columns {:?} to the session table", missing);
+        let pool = self.pool();
+        let table = self.table();
+        let mut tx = Transaction::begin(&pool).await?;
+        for column in missing {
+            let sql = format!("ALTER TABLE {table} ADD COLUMN {column} BIGINT NULL");
+            with_tx!(&mut tx, |c| sqlx::query(&sql)
+                .execute(&mut *c)
+                .await
+                .map(|_| ()))?;
+        }
+        let sql = format!("SELECT id, data FROM {table}");
+        let rows: Vec<(String, Vec<u8>)> =
+            with_tx!(&mut tx, |c| sqlx::query_as(&sql).fetch_all(&mut *c).await)?;
+        let update = format!(
+            "UPDATE {table} SET created_at = COALESCE(created_at, ?), user_id = ? WHERE id = ?"
+        );
+        let sql = pool.sql(&update);
+        for (id, data) in rows {
+            let Ok(record) = rmp_serde::from_slice::<Record>(&data) else {
+                continue;
+            };
+            let created_at = session_backend::created_at(&record);
+            with_tx!(&mut tx, |c| sqlx::query(&sql)
+                .bind(created_at)
+                .bind(user_id_of(&record))
+                .bind(&id)
+                .execute(&mut *c)
+                .await
+                .map(|_| ()))?;
+        }
+        tx.commit().await
+    }
+
+    /// Create the indexes of the session table that don't exist yet
+    async fn create_missing_indexes(&self) -> Result<(), sqlx::Error> {
+        for (name, columns) in INDEXES {
+            match self {
+                SqlxSessionStore::Sqlite(pool) => {
+                    sqlx::query(&format!(
+                        "CREATE INDEX IF NOT EXISTS {name} ON {SQLITE_TABLE} ({columns})"
+                    ))
+                    .execute(pool)
+                    .await?;
+                }
+                SqlxSessionStore::Postgres(pool) => {
+                    sqlx::query(&format!(
+                        "CREATE INDEX IF NOT EXISTS {name} ON {POSTGRES_TABLE} ({columns})"
+                    ))
+                    .execute(pool)
+                    .await?;
+                }
+                SqlxSessionStore::MySql(pool) => {
+                    // MySQL has no `CREATE INDEX IF NOT EXISTS`
+                    let exists: i64 = sqlx::query_scalar(
+                        "SELECT COUNT(*) FROM information_schema.statistics \
+                         WHERE table_schema = 'tower_sessions' AND table_name = 'session' \
+                         AND index_name = ?",
+                    )
+                    .bind(name)
+                    .fetch_one(pool)
+                    .await?;
+                    if exists == 0 {
+                        sqlx::query(&format!("CREATE INDEX {name} ON {MYSQL_TABLE} ({columns})"))
+                            .execute(pool)
+                            .await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the session table has the column
+    async fn has_column(&self, column: &str) -> Result<bool, sqlx::Error> {
+        let sql = format!("SELECT {column} FROM {} LIMIT 0", self.table());
+        let result = match self {
+            SqlxSessionStore::Sqlite(pool) => sqlx::query(&sql).fetch_all(pool).await.map(|_| ()),
+            SqlxSessionStore::Postgres(pool) => sqlx::query(&sql).fetch_all(pool).await.map(|_| ()),
+            SqlxSessionStore::MySql(pool) => sqlx::query(&sql).fetch_all(pool).await.map(|_| ()),
+        };
+        match result {
+            Ok(()) => Ok(true),
+            // The database answered: the column is missing
+            Err(sqlx::Error::Database(_)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// The pool of the store
+    fn pool(&self) -> SqlxPool {
+        match self {
+            SqlxSessionStore::Sqlite(pool) => SqlxPool::Sqlite(pool.clone()),
+            SqlxSessionStore::Postgres(pool) => SqlxPool::Postgres(pool.clone()),
+            SqlxSessionStore::MySql(pool) => SqlxPool::MySql(pool.clone()),
+        }
+    }
+
+    /// The session table, qualified with its schema
+    fn table(&self) -> &'static str {
+        match self {
+            SqlxSessionStore::Sqlite(_) => SQLITE_TABLE,
+            SqlxSessionStore::Postgres(_) => POSTGRES_TABLE,
+            SqlxSessionStore::MySql(_) => MYSQL_TABLE,
+        }
+    }
+
+    /// Check that the session table exists with the columns the store uses
+    ///
+    /// Run after [`migrate`](Self::migrate), to fail the startup with a clear error rather than
+    /// every request later.
+    pub async fn check_schema(&self) -> Result<(), SessionSchemaError> {
+        let (table, result) = match self {
+            SqlxSessionStore::Sqlite(pool) => (
+                SQLITE_TABLE,
+                sqlx::query(&format!(
+                    "SELECT id, data, expiry_date, created_at, user_id FROM {SQLITE_TABLE} LIMIT 0"
+                ))
+                .fetch_all(pool)
+                .await
+                .map(|_| ()),
+            ),
+            SqlxSessionStore::Postgres(pool) => (
+                POSTGRES_TABLE,
+                sqlx::query(&format!(
+                    "SELECT id, data, expiry_date, created_at, user_id FROM {POSTGRES_TABLE} LIMIT 0"
+                ))
+                .fetch_all(pool)
+                .await
+                .map(|_| ()),
+            ),
+            SqlxSessionStore::MySql(pool) => (
+                MYSQL_TABLE,
+                sqlx::query(&format!(
+                    "SELECT id, data, expiry_date, created_at, user_id FROM {MYSQL_TABLE} LIMIT 0"
+                ))
+                .fetch_all(pool)
+                .await
+                .map(|_| ()),
+            ),
+        };
+
+        match result {
+            Ok(()) => Ok(()),
+            // The database answered: the table or one of its columns is missing
+            Err(sqlx::Error::Database(err)) => Err(SessionSchemaError::Missing {
+                table,
+                reason: err.message().to_string(),
+            }),
+            Err(err) => Err(SessionSchemaError::Database(err)),
+        }
+    }
+
+    /// List the active sessions, the ones expiring last first
+    pub async fn list(&self, limit: i64, offset: i64) -> Result<Vec<SessionSummary>, sqlx::Error> {
+        let now = OffsetDateTime::now_utc();
+        let rows: Vec<(String, OffsetDateTime)> = match self {
+            SqlxSessionStore::Sqlite(pool) => {
+                sqlx::query_as(&format!(
+                    "SELECT id, expiry_date FROM {SQLITE_TABLE} WHERE expiry_date > ? \
+                     ORDER BY expiry_date DESC LIMIT ? OFFSET ?"
+                ))
+                .bind(now.unix_timestamp())
+                .bind(limit)
+                .bind(offset)
+                .fetch_all(pool)
+                .await?
+            }
+            SqlxSessionStore::Postgres(pool) => {
+                sqlx::query_as(&format!(
+                    "SELECT id, expiry_date FROM {POSTGRES_TABLE} WHERE expiry_date > $1 \
+                     ORDER BY expiry_date DESC LIMIT $2 OFFSET $3"
+                ))
+                .bind(now)
+                .bind(limit)
+                .bind(offset)
+                .fetch_all(pool)
+                .await?
+            }
+            SqlxSessionStore::MySql(pool) => {
+                sqlx::query_as(&format!(
+                    "SELECT id, expiry_date FROM {MYSQL_TABLE} WHERE expiry_date > ? \
+                     ORDER BY expiry_date DESC LIMIT ? OFFSET ?"
+                ))
+                .bind(now)
+                .bind(limit)
+                .bind(offset)
+                .fetch_all(pool)
+                .await?
+            }
+        };
+
+        Ok(rows
+            .into_iter()
+            .map(|(id, expiry_date)| SessionSummary { id, expiry_date })
+            .collect())
+    }
+
+    /// Count the sessions in the store
+    pub async fn stats(&self) -> Result<SessionStats, sqlx::Error> {
+        let now = OffsetDateTime::now_utc();
+        let (total, active): (i64, i64) = match self {
+            SqlxSessionStore::Sqlite(pool) => {
+                sqlx::query_as(&format!(
+                    "SELECT COUNT(*), COUNT(CASE WHEN expiry_date > ? THEN 1 END) \
+                     FROM {SQLITE_TABLE}"
+                ))
+                .bind(now.unix_timestamp())
+                .fetch_one(pool)
+                .await?
+            }
+            SqlxSessionStore::Postgres(pool) => {
+                sqlx::query_as(&format!(
+                    "SELECT COUNT(*), COUNT(CASE WHEN expiry_date > $1 THEN 1 END) \
+                     FROM {POSTGRES_TABLE}"
+                ))
+                .bind(now)
+                .fetch_one(pool)
+                .await?
+            }
+            SqlxSessionStore::MySql(pool) => {
+                sqlx::query_as(&format!(
+                    "SELECT COUNT(*), COUNT(CASE WHEN expiry_date > ? THEN 1 END) \
+                     FROM {MYSQL_TABLE}"
+                ))
+                .bind(now)
+                .fetch_one(pool)
+                .await?
+            }
+        };
+
+        Ok(SessionStats {
+            total,
+            active,
+            expired: total - active,
+        })
+    }
+
+    /// Estimate the space taken by the sessions
+    ///
+    /// Postgres reports the size of the table on disk, while SQLite and MySQL only sum the sizes
+    /// of the session data.
+    pub async fn storage_stats(&self) -> Result<StorageStats, sqlx::Error> {
+        let (rows, bytes): (i64, i64) = match self {
+            SqlxSessionStore::Sqlite(pool) => {
+                sqlx::query_as(&format!(
+                    "SELECT COUNT(*), COALESCE(SUM(LENGTH(data)), 0) FROM {SQLITE_TABLE}"
+                ))
+                .fetch_one(pool)
+                .await?
+            }
+            SqlxSessionStore::Postgres(pool) => {
+                sqlx::query_as(&format!(
+                    "SELECT COUNT(*), pg_total_relation_size('{POSTGRES_TABLE}') \
+                     FROM {POSTGRES_TABLE}"
+                ))
+                .fetch_one(pool)
+                .await?
+            }
+            SqlxSessionStore::MySql(pool) => {
+                // SUM returns a DECIMAL
+                sqlx::query_as(&format!(
+                    "SELECT COUNT(*), CAST(COALESCE(SUM(LENGTH(data)), 0) AS SIGNED) \
+                     FROM {MYSQL_TABLE}"
+                ))
+                .fetch_one(pool)
+                .await?
+            }
+        };
+
+        Ok(StorageStats {
+            rows,
+            bytes,
+            on_disk: matches!(self, SqlxSessionStore::Postgres(..)),
+        })
+    }
+
+    /// Delete the expired sessions, returning how many were deleted
+    ///
+    /// A single statement per call, so that sweeps running concurrently (e.g. the background one
+    /// and an on-demand one) never count the same session twice.
+    pub async fn sweep_expired(&self) -> Result<u64, sqlx::Error> {
+        let now = OffsetDateTime::now_utc();
+        let swept = match self {
+            SqlxSessionStore::Sqlite(pool) => {
+                sqlx::query(&format!("DELETE FROM {SQLITE_TABLE} WHERE expiry_date < ?"))
+                    .bind(now.unix_timestamp())
+                    .execute(pool)
+                    .await?
+                    .rows_affected()
+            }
+            SqlxSessionStore::Postgres(pool) => sqlx::query(&format!(
+                "DELETE FROM {POSTGRES_TABLE} WHERE expiry_date < $1"
+            ))
+            .bind(now)
+            .execute(pool)
+            .await?
+            .rows_affected(),
+            SqlxSessionStore::MySql(pool) => {
+                sqlx::query(&format!("DELETE FROM {MYSQL_TABLE} WHERE expiry_date < ?"))
+                    .bind(now)
+                    .execute(pool)
+                    .await?
+                    .rows_affected()
+            }
+        };
+        Ok(swept)
+    }
+
+    /// Delete the sessions created before the cutoff, even if they haven't expired, returning
+    /// how many were deleted
+    ///
+    /// The creation time of a session is the one stamped in its record by the session store
+    /// (see [`session_backend`]), or else when it was first stored. Sessions stored before the
+    /// creation times were, without a stamp, are pruned when they expire before the cutoff, as
+    /// they were necessarily created before. A single statement in a transaction, so that a
+    /// failure prunes nothing.
+    pub async fn prune_before(&self, cutoff: OffsetDateTime) -> Result<u64, sqlx::Error> {
+        let mut tx = Transaction::begin(&self.pool()).await?;
+        let pruned = match &mut tx {
+            Transaction::Sqlite(tx) => sqlx::query(&format!(
+                "DELETE FROM {SQLITE_TABLE} \
+                 WHERE created_at < ? OR (created_at IS NULL AND expiry_date <= ?)"
+            ))
+            .bind(cutoff.unix_timestamp())
+            .bind(cutoff.unix_timestamp())
+            .execute(&mut **tx)
+            .await?
+            .rows_affected(),
+            Transaction::Postgres(tx) => sqlx::query(&format!(
+                "DELETE FROM {POSTGRES_TABLE} \
+                 WHERE created_at < $1 OR (created_at IS NULL AND expiry_date <= $2)"
+            ))
+            .bind(cutoff.unix_timestamp())
+            .bind(cutoff)
+            .execute(&mut **tx)
+            .await?
+            .rows_affected(),
+            Transaction::MySql(tx) => sqlx::query(&format!(
+                "DELETE FROM {MYSQL_TABLE} \
+                 WHERE created_at < ? OR (created_at IS NULL AND expiry_date <= ?)"
+            ))
+            .bind(cutoff.unix_timestamp())
+            .bind(cutoff)
+            .execute(&mut **tx)
+            .await?
+            .rows_affected(),
+        };
+        tx.commit().await?;
+        Ok(pruned)
+    }
+
+    /// Delete the sessions of a user, returning how many were deleted
+    pub async fn revoke_user(&self, user_id: i64) -> Result<u64, sqlx::Error> {
+        self.revoke(user_id, None).await
+    }
+
+    /// Delete the sessions of a user but the given one, returning how many were deleted
+    pub async fn revoke_user_except(&self, user_id: i64, keep: Id) -> Result<u64, sqlx::Error> {
+        self.revoke(user_id, Some(keep)).await
+    }
+
+    /// The number of unexpired sessions of a user
+    pub async fn count_user(&self, user_id: i64) -> Result<u64, sqlx::Error> {
+        let now = OffsetDateTime::now_utc();
+        let count: i64 = match self {
+            SqlxSessionStore::Sqlite(pool) => {
+                sqlx::query_scalar(&format!(
+                    "SELECT COUNT(*) FROM {SQLITE_TABLE} WHERE user_id = ? AND expiry_date > ?"
+                ))
+                .bind(user_id)
+                .bind(now.unix_timestamp())
+                .fetch_one(pool)
+                .await?
+            }
+            SqlxSessionStore::Postgres(pool) => {
+                sqlx::query_scalar(&format!(
+                    "SELECT COUNT(*) FROM {POSTGRES_TABLE} WHERE user_id = $1 AND expiry_date > $2"
+                ))
+                .bind(user_id)
+                .bind(now)
+                .fetch_one(pool)
+                .await?
+            }
+            SqlxSessionStore::MySql(pool) => {
+                sqlx::query_scalar(&format!(
+                    "SELECT COUNT(*) FROM {MYSQL_TABLE} WHERE user_id = ? AND expiry_date > ?"
+                ))
+                .bind(user_id)
+                .bind(now)
+                .fetch_one(pool)
+                .await?
+            }
+        };
+        Ok(count as u64)
+    }
+
+    /// Load the unexpired sessions with the given IDs, in the order of the IDs
+    ///
+    /// Unknown and expired sessions are skipped, and so are the records that can't be decoded.
+    /// The sessions are read with one statement per batch of IDs rather than one per session.
+    pub async fn load_many(&self, ids: &[Id]) -> Result<Vec<(Id, Record)>, sqlx::Error> {
+        let now = OffsetDateTime::now_utc();
+        let keys: Vec<String> = ids.iter().map(Id::to_string).collect();
+        let mut found: HashMap<String, Vec<u8>> = HashMap::new();
+        for batch in keys.chunks(LOAD_BATCH_SIZE) {
+            let rows: Vec<(String, Vec<u8>)> = match self {
+                SqlxSessionStore::Sqlite(pool) => {
+                    let sql = format!(
+                        "SELECT id, data FROM {SQLITE_TABLE} WHERE expiry_date > ? AND id IN ({})",
+                        vec!["?"; batch.len()].join(", ")
+                    );
+                    let mut query = sqlx::query_as(&sql).bind(now.unix_timestamp());
+                    for id in batch {
+                        query = query.bind(id);
+                    }
+                    query.fetch_all(pool).await?
+                }
+                SqlxSessionStore::Postgres(pool) => {
+                    let placeholders: Vec<String> =
+                        (2..=batch.len() + 1).map(|i| format!("${}", i)).collect();
+                    let sql = format!(
+                        "SELECT id, data FROM {POSTGRES_TABLE} \
+                         WHERE expiry_date > $1 AND id IN ({})",
+                        placeholders.join(", ")
+                    );
+                    let mut query = sqlx::query_as(&sql).bind(now);
+                    for id in batch {
+                        query = query.bind(id);
+                    }
+                    query.fetch_all(pool).await?
+                }
+                SqlxSessionStore::MySql(pool) => {
+                    let sql = format!(
+                        "SELECT id, data FROM {MYSQL_TABLE} WHERE expiry_date > ? AND id IN ({})",
+                        vec!["?"; batch.len()].join(", ")
+                    );
+                    let mut query = sqlx::query_as(&sql).bind(now);
+                    for id in batch {
+                        query = query.bind(id);
+                    }
+                    query.fetch_all(pool).await?
+                }
+            };
+            found.extend(rows);
+        }
+
+        Ok(ids
+            .iter()
+            .zip(keys)
+            .filter_map(|(id, key)| {
+                // Removed so that a repeated ID is loaded once
+                let data = found.remove(&key)?;
+                match rmp_serde::from_slice::<Record>(&data) {
+                    Ok(record) => Some((*id, record)),
+                    Err(err) => {
+                        tracing::warn!("Skipping the session {}, not decodable: {}", key, err);
+                        None
+                    }
+                }
+            })
+            .collect())
+    }
+
+    /// Delete the sessions of a user but the kept one, if any, in one transaction, returning how
+    /// many were deleted
+    async fn revoke(&self, user_id: i64, keep: Option<Id>) -> Result<u64, sqlx::Error> {
+        let pool = self.pool();
+        let mut sql = format!("DELETE FROM {} WHERE user_id = ?", self.table());
+        if keep.is_some() {
+            sql.push_str(" AND id <> ?");
+        }
+        let sql = pool.sql(&sql);
+
+        let mut tx = Transaction::begin(&pool).await?;
+        let revoked = with_tx!(&mut tx, |c| {
+            let mut query = sqlx::query(&sql).bind(user_id);
+            if let Some(keep) = keep {
+                query = query.bind(keep.to_string());
+            }
+            query.execute(&mut *c).await.map(|r| r.rows_affected())
+        })?;
+        tx.commit().await?;
+        Ok(revoked)
+    }
+
+    /// Delete the sessions with the given IDs in one transaction, returning how many were
+    /// deleted
+    async fn delete_ids(&self, ids: &[String]) -> Result<u64, sqlx::Error> {
+        let mut tx = Transaction::begin(&self.pool()).await?;
+        let deleted = self.delete_ids_in(&mut tx, ids).await?;
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    /// Delete the sessions with the given IDs in the transaction, returning how many were
+    /// deleted
+    async fn delete_ids_in(
+        &self,
+        tx: &mut Transaction,
+        ids: &[String],
+    ) -> Result<u64, sqlx::Error> {
+        let pool = self.pool();
+        let mut deleted = 0;
+        for batch in ids.chunks(PRUNE_BATCH_SIZE) {
+            let sql = format!(
+                "DELETE FROM {} WHERE id IN ({})",
+                self.table(),
+                vec!["?"; batch.len()].join(", ")
+            );
+            let sql = pool.sql(&sql);
+            deleted += with_tx!(tx, |c| {
+                let mut query = sqlx::query(&sql);
+                for id in batch {
+                    query = query.bind(id);
+                }
+                query.execute(&mut *c).await.map(|r| r.rows_affected())
+            })?;
+        }
+        Ok(deleted)
+    }
+
+    /// The encoded data of an unexpired session, if any
+    async fn load_data(&self, session_id: &Id) -> Result<Option<Vec<u8>>, sqlx::Error> {
+        let now = OffsetDateTime::now_utc();
+        match self {
+            SqlxSessionStore::Sqlite(pool) => {
+                sqlx::query_scalar(&format!(
+                    "SELECT data FROM {SQLITE_TABLE} WHERE id = ? AND expiry_date > ?"
+                ))
+                .bind(session_id.to_string())
+                .bind(now.unix_timestamp())
+                .fetch_optional(pool)
+                .await
+            }
+            SqlxSessionStore::Postgres(pool) => {
+                sqlx::query_scalar(&format!(
+                    "SELECT data FROM {POSTGRES_TABLE} WHERE id = $1 AND expiry_date > $2"
+                ))
+                .bind(session_id.to_string())
+                .bind(now)
+                .fetch_optional(pool)
+                .await
+            }
+            SqlxSessionStore::MySql(pool) => {
+                sqlx::query_scalar(&format!(
+                    "SELECT data FROM {MYSQL_TABLE} WHERE id = ? AND expiry_date > ?"
+                ))
+                .bind(session_id.to_string())
+                .bind(now)
+                .fetch_optional(pool)
+                .await
+            }
+        }
+    }
+
+    /// Insert a new session with its encoded data, failing if its ID is taken
+    ///
+    /// A session logging a user in under a limit of sessions (see
+    /// [`SessionData::session_limit`]) evicts the oldest other unexpired sessions of the user
+    /// above the limit, in the same transaction as its insertion. The sessions are ordered by
+    /// creation time, then by expiry date, pushed back on every activity (SQLite, storing it in
+    /// seconds, then falls back to the order of insertion); sessions without a creation time are
+    /// the oldest. Postgres and MySQL first lock the row of the user, so that the concurrent
+    /// logins of a user are stored one at a time, SQLite running a single write at a time.
+    async fn insert(&self, record: &Record, data: Vec<u8>) -> Result<(), sqlx::Error> {
+        let id = record.id.to_string();
+        let created_at = created_at_of(record);
+        let user_id = user_id_of(record);
+        let limit = user_id.zip(session_data_of(record).and_then(|data| data.session_limit));
+        let now = OffsetDateTime::now_utc();
+
+        let mut tx = Transaction::begin(&self.pool()).await?;
+        let others: Vec<String> = match &mut tx {
+            Transaction::Sqlite(tx) => {
+                sqlx::query(&format!(
+                    "INSERT INTO {SQLITE_TABLE} (id, data, expiry_date, created_at, user_id) \
+                     VALUES (?, ?, ?, ?, ?)"
+                ))
+                .bind(&id)
+                .bind(data)
+                .bind(record.expiry_date.unix_timestamp())
+                .bind(created_at)
+                .bind(user_id)
+                .execute(&mut **tx)
+                .await?;
+                match limit {
+                    Some((user_id, _)) => {
+                        sqlx::query_scalar(&format!(
+                            "SELECT id FROM {SQLITE_TABLE} \
+                             WHERE user_id = ? AND id <> ? AND expiry_date > ? \
+                             ORDER BY COALESCE(created_at, 0) DESC, expiry_date DESC, rowid DESC"
+                        ))
+                        .bind(user_id)
+                        .bind(&id)
+                        .bind(now.unix_timestamp())
+                        .fetch_all(&mut **tx)
+                        .await?
+                    }
+                    None => Vec::new(),
+                }
+            }
+            Transaction::Postgres(tx) => {
+                if let Some((user_id, _)) = limit {
+                    sqlx::query("SELECT id FROM users WHERE id = $1 FOR UPDATE")
+                        .bind(user_id)
+                        .fetch_optional(&mut **tx)
+                        .await?;
+                }
+                sqlx::query(&format!(
+                    "INSERT INTO {POSTGRES_TABLE} (id, data, expiry_date, created_at, user_id) \
+                     VALUES ($1, $2, $3, $4, $5)"
+                ))
+                .bind(&id)
+                .bind(data)
+                .bind(record.expiry_date)
+                .bind(created_at)
+                .bind(user_id)
+                .execute(&mut **tx)
+                .await?;
+                match limit {
+                    Some((user_id, _)) => {
+                        sqlx::query_scalar(&format!(
+                            "SELECT id FROM {POSTGRES_TABLE} \
+                             WHERE user_id = $1 AND id <> $2 AND expiry_date > $3 \
+                             ORDER BY COALESCE(created_at, 0) DESC, expiry_date DESC"
+                        ))
+                        .bind(user_id)
+                        .bind(&id)
+                        .bind(now)
+                        .fetch_all(&mut **tx)
+                        .await?
+                    }
+                    None => Vec::new(),
+                }
+            }
+            Transaction::MySql(tx) => {
+                if let Some((user_id, _)) = limit {
+                    sqlx::query("SELECT id FROM users WHERE id = ? FOR UPDATE")
+                        .bind(user_id)
+                        .fetch_optional(&mut **tx)
+                        .await?;
+                }
+                sqlx::query(&format!(
+                    "INSERT INTO {MYSQL_TABLE} (id, data, expiry_date, created_at, user_id) \
+                     VALUES (?, ?, ?, ?, ?)"
+                ))
+                .bind(&id)
+                .bind(data)
+                .bind(record.expiry_date)
+                .bind(created_at)
+                .bind(user_id)
+                .execute(&mut **tx)
+                .await?;
+                match limit {
+                    Some((user_id, _)) => {
+                        sqlx::query_scalar(&format!(
+                            "SELECT id FROM {MYSQL_TABLE} \
+                             WHERE user_id = ? AND id <> ? AND expiry_date > ? \
+                             ORDER BY COALESCE(created_at, 0) DESC, expiry_date DESC"
+                        ))
+                        .bind(user_id)
+                        .bind(&id)
+                        .bind(now)
+                        .fetch_all(&mut **tx)
+                        .await?
+                    }
+                    None => Vec::new(),
+                }
+            }
+        };
+        let evicted = match limit {
+            Some((_, max)) => others.into_iter().skip(max.saturating_sub(1)).collect(),
+            None => Vec::new(),
+        };
+        self.delete_ids_in(&mut tx, &evicted).await?;
+        tx.commit().await?;
+
+        if let (Some((user_id, max)), false) = (limit, evicted.is_empty()) {
+            tracing::info!(
+                "Evicted {} session(s) of user {}, above the limit of {}",
+                evicted.len(),
+                user_id,
+                max
+            );
+        }
+        Ok(())
+    }
+
+    /// Insert a session with its encoded data, or replace it if its ID is taken
+    ///
+    /// The creation time of a replaced session is kept, and its user updated.
+    async fn upsert(&self, record: &Record, data: Vec<u8>) -> Result<(), sqlx::Error> {
+        let created_at = created_at_of(record);
+        let user_id = user_id_of(record);
+        match self {
+            SqlxSessionStore::Sqlite(pool) => {
+                sqlx::query(&format!(
+                    "INSERT INTO {SQLITE_TABLE} (id, data, expiry_date, created_at, user_id) \
+                     VALUES (?, ?, ?, ?, ?) \
+                     ON CONFLICT(id) DO UPDATE SET \
+                     data = excluded.data, expiry_date = excluded.expiry_date, \
+                     created_at = COALESCE(created_at, excluded.created_at), \
+                     user_id = excluded.user_id"
+                ))
+                .bind(record.id.to_string())
+                .bind(data)
+                .bind(record.expiry_date.unix_timestamp())
+                .bind(created_at)
+                .bind(user_id)
+                .execute(pool)
+                .await?;
+            }
+            SqlxSessionStore::Postgres(pool) => {
+                sqlx::query(&format!(
+                    "INSERT INTO {POSTGRES_TABLE} AS s (id, data, expiry_date, created_at, user_id) \
+                     VALUES ($1, $2, $3, $4, $5) \
+                     ON CONFLICT(id) DO UPDATE SET \
+                     data = excluded.data, expiry_date = excluded.expiry_date, \
+                     created_at = COALESCE(s.created_at, excluded.created_at), \
+                     user_id = excluded.user_id"
+                ))
+                .bind(record.id.to_string())
+                .bind(data)
+                .bind(record.expiry_date)
+                .bind(created_at)
+                .bind(user_id)
+                .execute(pool)
+                .await?;
+            }
+            SqlxSessionStore::MySql(pool) => {
+                sqlx::query(&format!(
+                    "INSERT INTO {MYSQL_TABLE} (id, data, expiry_date, created_at, user_id) \
+                     VALUES (?, ?, ?, ?, ?) \
+                     ON DUPLICATE KEY UPDATE \
+                     data = VALUES(data), expiry_date = VALUES(expiry_date), \
+                     created_at = COALESCE(created_at, VALUES(created_at)), \
+                     user_id = VALUES(user_id)"
+                ))
+                .bind(record.id.to_string())
+                .bind(data)
+                .bind(record.expiry_date)
+                .bind(created_at)
+                .bind(user_id)
+                .execute(pool)
+                .await?;
+            }
         }
+        Ok(())
     }
 }
 
@@ -62,22 +952,36 @@ impl SessionStore for SqlxSessionStore {
     /// The record is given as an exclusive reference to allow modifications,
     /// such as assigning a new ID, during the creation process.
     async fn create(&self, session_record: &mut Record) -> session_store::Result<()> {
-        match &self {
-            SqlxSessionStore::Sqlite(store) => store.create(session_record).await,
-            SqlxSessionStore::Postgres(store) => store.create(session_record).await,
-            SqlxSessionStore::MySql(store) => store.create(session_record).await,
+        // Saving upserts, so a concurrent creation with the same ID would overwrite the other
+        // session: insert instead, and pick another ID on a conflict
+        for _ in 0..MAX_CREATE_ATTEMPTS {
+            let data = rmp_serde::to_vec(&*session_record)
+                .map_err(|err| session_store::Error::Encode(err.to_string()))?;
+            match self.insert(session_record, data).await {
+                Ok(()) => return Ok(()),
+                Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+                    tracing::warn!("Session ID collision, generating another one");
+                    session_record.id = Id::default();
+                }
+                Err(err) => return Err(session_store::Error::Backend(err.to_string())),
+            }
         }
+
+        Err(session_store::Error::Backend(format!(
+            "No unique session ID found after {} attempts",
+            MAX_CREATE_ATTEMPTS
+        )))
     }
 
     /// Saves the provided session record to the store.
     ///
     /// This method is intended for updating the state of an existing session.
     async fn save(&self, session_record: &Record) -> session_store::Result<()> {
-        match &self {
-            SqlxSessionStore::Sqlite(store) => store.save(session_record).await,
-            SqlxSessionStore::Postgres(store) => store.save(session_record).await,
-            SqlxSessionStore::MySql(store) => store.save(session_record).await,
-        }
+        let data = rmp_serde::to_vec(session_record)
+            .map_err(|err| session_store::Error::Encode(err.to_string()))?;
+        self.upsert(session_record, data)
+            .await
+            .map_err(|err| session_store::Error::Backend(err.to_string()))
     }
 
     /// Loads an existing session record from the store using the provided ID.
@@ -86,32 +990,359 @@ impl SessionStore for SqlxSessionStore {
     /// does not exist or has been invalidated (e.g., expired), `None` is
     /// returned.
     async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
-        match &self {
-            SqlxSessionStore::Sqlite(store) => store.load(session_id).await,
-            SqlxSessionStore::Postgres(store) => store.load(session_id).await,
-            SqlxSessionStore::MySql(store) => store.load(session_id).await,
-        }
+        // Loaded on every request: a dropped connection shouldn't log the user out
+        let data = retry_read(|| self.load_data(session_id))
+            .await
+            .map_err(|err| session_store::Error::Backend(err.to_string()))?;
+        data.map(|data| {
+            rmp_serde::from_slice(&data)
+                .map_err(|err| session_store::Error::Decode(err.to_string()))
+        })
+        .transpose()
     }
 
     /// Deletes a session record from the store using the provided ID.
     ///
     /// If the session exists, it is removed from the store.
     async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
-        match &self {
-            SqlxSessionStore::Sqlite(store) => store.delete(session_id).await,
-            SqlxSessionStore::Postgres(store) => store.delete(session_id).await,
-            SqlxSessionStore::MySql(store) => store.delete(session_id).await,
-        }
+        self.delete_ids(&[session_id.to_string()])
+            .await
+            .map(|_| ())
+            .map_err(|err| session_store::Error::Backend(err.to_string()))
     }
 }
 
 #[async_trait]
 impl ExpiredDeletion for SqlxSessionStore {
     async fn delete_expired(&self) -> session_store::Result<()> {
-        match &self {
-            SqlxSessionStore::Sqlite(store) => store.delete_expired().await,
-            SqlxSessionStore::Postgres(store) => store.delete_expired().await,
-            SqlxSessionStore::MySql(store) => store.delete_expired().await,
+        let swept = self
+            .sweep_expired()
+            .await
+            .map_err(|err| session_store::Error::Backend(err.to_string()))?;
+        if swept > 0 {
+            tracing::debug!("Deleted {} expired session(s)", swept);
+        }
+        Ok(())
+    }
+}
+
+/// The creation time of a session, stamped in its record or else now, in unix seconds
+fn created_at_of(record: &Record) -> i64 {
+    session_backend::created_at(record)
+        .unwrap_or_else(|| OffsetDateTime::now_utc().unix_timestamp())
+}
+
+/// The data of a session record, if it can be decoded
+fn session_data_of(record: &Record) -> Option<SessionData> {
+    let data = record.data.get(SESSION_DATA_KEY)?;
+    serde_json::from_value(data.clone()).ok()
+}
+
+/// The ID of the user logged in a session record, if any
+fn user_id_of(record: &Record) -> Option<i64> {
+    session_data_of(record)?.user_id?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::sqlite::SqlitePoolOptions;
+    use time::Duration;
+
+    use super::*;
+
+    /// A store in a private in-memory SQLite database, migrated
+    async fn sqlite_store() -> SqlxSessionStore {
+        // Every connection has its own in-memory database
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let store = SqlxSessionStore::new(SqlxPool::Sqlite(pool));
+        store.migrate().await.unwrap();
+        store.check_schema().await.unwrap();
+        store
+    }
+
+    fn record(expires_in: Duration) -> Record {
+        Record {
+            id: Id::default(),
+            data: HashMap::from([("counter".to_string(), serde_json::json!(1))]),
+            expiry_date: OffsetDateTime::now_utc() + expires_in,
+        }
+    }
+
+    #[tokio::test]
+    async fn migrate_is_idempotent() {
+        let store = sqlite_store().await;
+        store.migrate().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn saves_loads_and_deletes() {
+        let store = sqlite_store().await;
+        let mut record = record(Duration::hours(1));
+        store.create(&mut record).await.unwrap();
+        assert_eq!(store.load(&record.id).await.unwrap(), Some(record.clone()));
+
+        record
+            .data
+            .insert("counter".to_string(), serde_json::json!(2));
+        store.save(&record).await.unwrap();
+        assert_eq!(store.load(&record.id).await.unwrap(), Some(record.clone()));
+
+        store.delete(&record.id).await.unwrap();
+        assert_eq!(store.load(&record.id).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn expired_sessions_are_not_loaded_and_are_swept() {
+        let store = sqlite_store().await;
+        let mut expired = record(Duration::hours(-1));
+        store.create(&mut expired).await.unwrap();
+        let mut active = record(Duration::hours(1));
+        store.create(&mut active).await.unwrap();
+
+        assert_eq!(store.load(&expired.id).await.unwrap(), None);
+        let stats = store.stats().await.unwrap();
+        assert_eq!((stats.total, stats.active, stats.expired), (2, 1, 1));
+        assert_eq!(store.sweep_expired().await.unwrap(), 1);
+        assert_eq!(store.list(10, 0).await.unwrap().len(), 1);
+    }
+
+    /// A record stamped as created at the given time
+    fn stamped(created_at: OffsetDateTime, expires_in: Duration) -> Record {
+        let mut record = record(expires_in);
+        record.data.insert(
+            "__created_at".to_string(),
+            serde_json::json!(created_at.unix_timestamp()),
+        );
+        record
+    }
+
+    fn logged_in(user_id: i64, session_limit: Option<usize>, created_at: OffsetDateTime) -> Record {
+        let mut record = stamped(created_at, Duration::hours(1));
+        let data = SessionData {
+            user_id: Some(user_id.to_string()),
+            session_limit,
+            ..SessionData::default()
+        };
+        record.data.insert(
+            SESSION_DATA_KEY.to_string(),
+            serde_json::to_value(data).unwrap(),
+        );
+        record
+    }
+
+    #[tokio::test]
+    async fn evicts_the_oldest_sessions_of_the_user_above_the_limit() {
+        let store = sqlite_store().await;
+        let now = OffsetDateTime::now_utc();
+        let mut sessions = Vec::new();
+        for age in [4, 3, 2] {
+            let mut session = logged_in(1, None, now - Duration::hours(age));
+            store.create(&mut session).await.unwrap();
+            sessions.push(session);
         }
+        let mut other = logged_in(2, None, now - Duration::hours(5));
+        store.create(&mut other).await.unwrap();
+
+        let mut limited = logged_in(1, Some(2), now);
+        store.create(&mut limited).await.unwrap();
+        assert_eq!(store.load(&sessions[0].id).await.unwrap(), None);
+        assert_eq!(store.load(&sessions[1].id).await.unwrap(), None);
+        assert!(store.load(&sessions[2].id).await.unwrap().is_some());
+        assert!(store.load(&limited.id).await.unwrap().is_some());
+        assert!(store.load(&other.id).await.unwrap().is_some());
+        assert_eq!(store.count_user(1).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn rolls_back_a_login_failing_midway_through_the_evictions() {
+        let store = sqlite_store().await;
+        let now = OffsetDateTime::now_utc();
+        // Evicted in two batches, the oldest last
+        let mut sessions = Vec::new();
+        for age in 0..PRUNE_BATCH_SIZE as i64 + 2 {
+            let mut session = logged_in(1, None, now - Duration::seconds(age + 1));
+            store.create(&mut session).await.unwrap();
+            sessions.push(session);
+        }
+        let SqlxPool::Sqlite(pool) = store.pool() else {
+            unreachable!()
+        };
+        sqlx::query(&format!(
+            "CREATE TRIGGER fail_eviction BEFORE DELETE ON tower_sessions \
+             WHEN OLD.id = '{}' BEGIN SELECT RAISE(ABORT, 'injected'); END",
+            sessions.last().unwrap().id
+        ))
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let mut limited = logged_in(1, Some(1), now);
+        assert!(store.create(&mut limited).await.is_err());
+        assert_eq!(store.load(&limited.id).await.unwrap(), None);
+        assert_eq!(store.count_user(1).await.unwrap(), sessions.len() as u64);
+        assert!(store.load(&sessions[0].id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn prunes_the_sessions_created_before_the_cutoff() {
+        let store = sqlite_store().await;
+        let now = OffsetDateTime::now_utc();
+        let mut old = stamped(now - Duration::days(2), Duration::hours(1));
+        store.create(&mut old).await.unwrap();
+        let mut recent = stamped(now - Duration::hours(1), Duration::hours(1));
+        store.create(&mut recent).await.unwrap();
+        // Stored when created
+        let mut unstamped = record(Duration::hours(1));
+        store.create(&mut unstamped).await.unwrap();
+
+        let pruned = store.prune_before(now - Duration::days(1)).await.unwrap();
+        assert_eq!(pruned, 1);
+        assert_eq!(store.load(&old.id).await.unwrap(), None);
+        assert!(store.load(&recent.id).await.unwrap().is_some());
+        assert!(store.load(&unstamped.id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn adds_the_missing_columns_to_an_earlier_table() {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::query(
+            "CREATE TABLE tower_sessions (\
+             id TEXT PRIMARY KEY NOT NULL, data BLOB NOT NULL, expiry_date INTEGER NOT NULL)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let now = OffsetDateTime::now_utc();
+        let old = stamped(now - Duration::days(2), Duration::hours(1));
+        // Without a stamp, pruned by its expiry
+        let mut legacy = record(Duration::hours(1));
+        legacy.data = logged_in(1, None, now).data;
+        legacy.data.remove("__created_at");
+        for record in [&old, &legacy] {
+            sqlx::query("INSERT INTO tower_sessions (id, data, expiry_date) VALUES (?, ?, ?)")
+                .bind(record.id.to_string())
+                .bind(rmp_serde::to_vec(record).unwrap())
+                .bind(record.expiry_date.unix_timestamp())
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        let store = SqlxSessionStore::new(SqlxPool::Sqlite(pool.clone()));
+        store.migrate().await.unwrap();
+        store.check_schema().await.unwrap();
+        store.migrate().await.unwrap();
+        let indexes: Vec<String> = sqlx::query_scalar(
+            "SELECT name FROM sqlite_master WHERE type = 'index' AND tbl_name = 'tower_sessions' \
+             AND name LIKE 'session_%' ORDER BY name",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(indexes, ["session_created_at", "session_user_id"]);
+        let user_ids: Vec<Option<i64>> =
+            sqlx::query_scalar("SELECT user_id FROM tower_sessions ORDER BY user_id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(user_ids, [None, Some(1)]);
+
+        assert_eq!(
+            store.prune_before(now - Duration::days(1)).await.unwrap(),
+            1
+        );
+        assert_eq!(store.load(&old.id).await.unwrap(), None);
+        assert_eq!(
+            store.prune_before(now + Duration::hours(2)).await.unwrap(),
+            1
+        );
+        assert_eq!(store.load(&legacy.id).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn regenerates_a_colliding_id_on_creation() {
+        let store = sqlite_store().await;
+        let mut existing = record(Duration::hours(1));
+        store.create(&mut existing).await.unwrap();
+
+        let mut colliding = record(Duration::hours(1));
+        colliding.id = existing.id;
+        colliding
+            .data
+            .insert("counter".to_string(), serde_json::json!(2));
+        store.create(&mut colliding).await.unwrap();
+
+        // Both sessions are kept, the existing one untouched
+        assert_ne!(colliding.id, existing.id);
+        assert_eq!(store.load(&existing.id).await.unwrap(), Some(existing));
+        assert_eq!(store.load(&colliding.id).await.unwrap(), Some(colliding));
+    }
+
+    #[tokio::test]
+    async fn storage_stats_grow_with_the_sessions() {
+        let store = sqlite_store().await;
+        let empty = store.storage_stats().await.unwrap();
+        assert_eq!((empty.rows, empty.bytes, empty.on_disk), (0, 0, false));
+
+        let mut small = record(Duration::hours(1));
+        store.create(&mut small).await.unwrap();
+        let one = store.storage_stats().await.unwrap();
+        assert_eq!(one.rows, 1);
+        assert!(one.bytes > 0);
+
+        let mut large = record(Duration::hours(-1));
+        large
+            .data
+            .insert("payload".to_string(), serde_json::json!("x".repeat(4096)));
+        store.create(&mut large).await.unwrap();
+        // Expired sessions take space until they are swept
+        let two = store.storage_stats().await.unwrap();
+        assert_eq!(two.rows, 2);
+        assert!(two.bytes > one.bytes + 4096, "{:?}", two);
+
+        store.sweep_expired().await.unwrap();
+        let swept = store.storage_stats().await.unwrap();
+        assert_eq!((swept.rows, swept.bytes), (one.rows, one.bytes));
+    }
+
+    #[tokio::test]
+    async fn load_many_skips_the_missing_and_expired_sessions() {
+        let store = sqlite_store().await;
+        let mut first = record(Duration::hours(1));
+        store.create(&mut first).await.unwrap();
+        let mut second = record(Duration::hours(2));
+        store.create(&mut second).await.unwrap();
+        let mut expired = record(Duration::hours(-1));
+        store.create(&mut expired).await.unwrap();
+        let missing = Id::default();
+
+        let ids = [second.id, missing, expired.id, first.id, second.id];
+        let loaded = store.load_many(&ids).await.unwrap();
+        // In the order of the IDs, a repeated one loaded once
+        assert_eq!(loaded, [(second.id, second), (first.id, first)]);
+        assert!(store.load_many(&[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn load_many_reads_more_sessions_than_a_batch() {
+        let store = sqlite_store().await;
+        let mut ids = Vec::new();
+        for _ in 0..LOAD_BATCH_SIZE + 10 {
+            let mut record = record(Duration::hours(1));
+            store.create(&mut record).await.unwrap();
+            ids.push(record.id);
+        }
+
+        let loaded = store.load_many(&ids).await.unwrap();
+        let loaded_ids: Vec<Id> = loaded.into_iter().map(|(id, _)| id).collect();
+        assert_eq!(loaded_ids, ids);
     }
 }
@@ -0,0 +1,416 @@
+//! Settings adjustable at runtime
+//! Some behaviors can be changed by the admins without a redeploy, through `/admin/settings`.
+//! Every setting has a namespaced key (e.g. `auth.registration_enabled`) registered in
+//! [`SCHEMA`] along with the kind of its value: unknown keys and values of another kind are
+//! rejected. The values are stored as JSON in the `settings` table, with when and by whom they
+//! were last changed. An unset setting falls back to its default, usually taken from the
+//! configuration.
+//!
+//! Reads go through an in-memory copy of the table, reloaded after every write and every
+//! `SETTINGS_REFRESH_SECS`, so that the other replicas pick up a change within that delay. A
+//! change is recorded in the audit log and published on the event bus as
+//! [`AdminEvent::SettingChanged`], which also reloads the copy: an embedder relaying the events
+//! between the replicas makes them pick up changes right away.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::extract::State;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use time::OffsetDateTime;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    audit::AuditActor,
+    config::Config,
+    database::{with_pool, SqlxPool},
+    error::AppError,
+    events::{AdminEvent, EventBus},
+    negotiate::{Format, Negotiated},
+    state::AppState,
+};
+
+/// Whether users can register themselves, overriding `REGISTRATION_ENABLED`
+pub const REGISTRATION_ENABLED: &str = "auth.registration_enabled";
+
+/// The message shown to the users during a maintenance, if any
+pub const MAINTENANCE_MESSAGE: &str = "system.maintenance_message";
+
+/// The maximum number of sessions of a user, overriding `MAX_SESSIONS_PER_USER`
+pub const MAX_SESSIONS_PER_USER: &str = "sessions.max_per_user";
+
+/// Every setting known to the backend
+pub const SCHEMA: &[SettingDefinition] = &[
+    SettingDefinition {
+        key: REGISTRATION_ENABLED,
+        description: "Whether users can register themselves",
+        kind: SettingKind::Bool,
+        default: |config| json!(config.registration.enabled),
+    },
+    SettingDefinition {
+        key: MAINTENANCE_MESSAGE,
+        description: "The message shown to the users during a maintenance",
+        kind: SettingKind::Text { max_length: 500 },
+        default: |_| Value::Null,
+    },
+    SettingDefinition {
+        key: MAX_SESSIONS_PER_USER,
+        description: "The maximum number of sessions of a user, the oldest being evicted",
+        kind: SettingKind::Integer { min: 1, max: 1000 },
+        default: |config| json!(config.max_sessions_per_user),
+    },
+];
+
+/// The kind of value of a setting
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SettingKind {
+    Bool,
+    Integer { min: i64, max: i64 },
+    Text { max_length: usize },
+}
+
+impl SettingKind {
+    /// Check that a value is of this kind
+    fn validate(&self, value: &Value) -> Result<(), String> {
+        match (self, value) {
+            (SettingKind::Bool, Value::Bool(_)) => Ok(()),
+            (SettingKind::Integer { min, max }, Value::Number(number)) => match number.as_i64() {
+                Some(number) if (*min..=*max).contains(&number) => Ok(()),
+                _ => Err(format!("expected an integer between {} and {}", min, max)),
+            },
+            (SettingKind::Text { max_length }, Value::String(text)) => {
+                if text.chars().count() <= *max_length {
+                    Ok(())
+                } else {
+                    Err(format!("expected at most {} characters", max_length))
+                }
+            }
+            (SettingKind::Bool, _) => Err("expected a boolean".to_string()),
+            (SettingKind::Integer { .. }, _) => Err("expected an integer".to_string()),
+            (SettingKind::Text { .. }, _) => Err("expected a string".to_string()),
+        }
+    }
+}
+
+/// A setting registered in the [`SCHEMA`]
+#[derive(Clone, Copy)]
+pub struct SettingDefinition {
+    pub key: &'static str,
+    pub description: &'static str,
+    pub kind: SettingKind,
+    /// The value of the setting while it is unset
+    pub default: fn(&Config) -> Value,
+}
+
+impl SettingDefinition {
+    /// The definition of the setting with this key, if it is known
+    pub fn find(key: &str) -> Option<&'static SettingDefinition> {
+        SCHEMA.iter().find(|definition| definition.key == key)
+    }
+}
+
+/// The value of a setting, as stored
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredSetting {
+    pub value: Value,
+    /// When the setting was last changed, in unix seconds
+    pub updated_at: i64,
+    /// Who last changed the setting, if known
+    pub updated_by: Option<String>,
+}
+
+/// The settings, read from an in-memory copy of the `settings` table
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pool: SqlxPool,
+    cache: Arc<RwLock<HashMap<String, StoredSetting>>>,
+}
+
+impl Settings {
+    /// Read the settings of the database, once loaded with [`reload`](Self::reload)
+    pub fn new(pool: SqlxPool) -> Self {
+        Self {
+            pool,
+            cache: Default::default(),
+        }
+    }
+
+    /// Load the settings from the database again
+    pub async fn reload(&self) -> Result<(), sqlx::Error> {
+        let sql = self
+            .pool
+            .sql("SELECT setting_key, value, updated_at, updated_by FROM settings");
+        let rows: Vec<(String, String, i64, Option<String>)> =
+            with_pool!(&self.pool, |p| sqlx::query_as(&sql).fetch_all(p).await)?;
+
+        let mut settings = HashMap::new();
+        for (key, value, updated_at, updated_by) in rows {
+            match serde_json::from_str(&value) {
+                Ok(value) => {
+                    settings.insert(
+                        key,
+                        StoredSetting {
+                            value,
+                            updated_at,
+                            updated_by,
+                        },
+                    );
+                }
+                Err(err) => tracing::warn!("Ignoring the setting {}, not valid JSON: {}", key, err),
+            }
+        }
+        *self.cache.write().unwrap() = settings;
+        Ok(())
+    }
+
+    /// The value of a setting, unless it is unset or not of the expected type
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let value = self.cache.read().unwrap().get(key)?.value.clone();
+        match serde_json::from_value(value) {
+            Ok(value) => Some(value),
+            Err(err) => {
+                tracing::warn!(
+                    "Ignoring the setting {}, of an unexpected type: {}",
+                    key,
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    /// The value of a setting, or the default when it is unset
+    pub fn get_or<T: DeserializeOwned>(&self, key: &str, default: T) -> T {
+        self.get(key).unwrap_or(default)
+    }
+
+    /// The stored value of a setting, if it is set
+    pub fn stored(&self, key: &str) -> Option<StoredSetting> {
+        self.cache.read().unwrap().get(key).cloned()
+    }
+
+    /// Set a setting, or unset it with `None`, then reload the settings
+    ///
+    /// The value must already be validated against the [`SCHEMA`].
+    pub async fn set(
+        &self,
+        key: &str,
+        value: Option<&Value>,
+        updated_by: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        match value {
+            Some(value) => self.store(key, value, updated_by).await?,
+            None => {
+                let sql = self.pool.sql("DELETE FROM settings WHERE setting_key = ?");
+                with_pool!(&self.pool, |p| sqlx::query(&sql)
+                    .bind(key)
+                    .execute(p)
+                    .await
+                    .map(|r| r.rows_affected()))?;
+            }
+        }
+        self.reload().await
+    }
+
+    /// Insert or replace the value of a setting
+    async fn store(
+        &self,
+        key: &str,
+        value: &Value,
+        updated_by: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        let update = self.pool.sql(
+            "UPDATE settings SET value = ?, updated_at = ?, updated_by = ? WHERE setting_key = ?",
+        );
+        let insert = self.pool.sql(
+            "INSERT INTO settings (setting_key, value, updated_at, updated_by) VALUES (?, ?, ?, ?)",
+        );
+        let value = value.to_string();
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+
+        let updated = with_pool!(&self.pool, |p| sqlx::query(&update)
+            .bind(&value)
+            .bind(now)
+            .bind(updated_by)
+            .bind(key)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))?;
+        if updated > 0 {
+            return Ok(());
+        }
+        let inserted = with_pool!(&self.pool, |p| sqlx::query(&insert)
+            .bind(key)
+            .bind(&value)
+            .bind(now)
+            .bind(updated_by)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()));
+        match inserted {
+            Ok(_) => Ok(()),
+            // A concurrent write inserted the row first
+            Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+                with_pool!(&self.pool, |p| sqlx::query(&update)
+                    .bind(&value)
+                    .bind(now)
+                    .bind(updated_by)
+                    .bind(key)
+                    .execute(p)
+                    .await
+                    .map(|r| r.rows_affected()))?;
+                Ok(())
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Reload the settings at the given interval and whenever one is changed, forever
+pub async fn continuously_refresh(settings: Settings, events: EventBus, period: Duration) {
+    // Holding the bus keeps the channel open
+    let mut receiver = events.subscribe();
+    let mut interval = tokio::time::interval(period);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            event = receiver.recv() => {
+                // Lagging behind, a change may have been missed
+                let changed = matches!(
+                    event,
+                    Ok(AdminEvent::SettingChanged { .. }) | Err(RecvError::Lagged(_))
+                );
+                if !changed {
+                    continue;
+                }
+            }
+        }
+        if let Err(err) = settings.reload().await {
+            tracing::warn!("Failed to reload the settings: {}", err);
+        }
+    }
+}
+
+/// A setting, as listed to the admins
+#[derive(Serialize, Deserialize)]
+pub struct SettingView {
+    pub key: String,
+    pub description: String,
+    /// The kind of value of the setting
+    pub kind: Value,
+    /// The current value of the setting, its default when unset
+    pub value: Value,
+    /// The value of the setting while it is unset
+    pub default: Value,
+    /// When the setting was last changed, in unix seconds, unless it is unset
+    pub updated_at: Option<i64>,
+    /// Who last changed the setting, if known
+    pub updated_by: Option<String>,
+}
+
+/// Every registered setting, with its current value
+fn views(state: &AppState) -> Vec<SettingView> {
+    SCHEMA
+        .iter()
+        .map(|definition| {
+            let default = (definition.default)(&state.config);
+            let stored = state.settings.stored(definition.key);
+            SettingView {
+                key: definition.key.to_string(),
+                description: definition.description.to_string(),
+                kind: json!(definition.kind),
+                value: stored
+                    .as_ref()
+                    .map_or(default.clone(), |stored| stored.value.clone()),
+                default,
+                updated_at: stored.as_ref().map(|stored| stored.updated_at),
+                updated_by: stored.and_then(|stored| stored.updated_by),
+            }
+        })
+        .collect()
+}
+
+/// `GET /admin/settings`: list the settings with their current value
+pub async fn list_settings(
+    State(state): State<AppState>,
+    format: Format,
+) -> Negotiated<Vec<SettingView>> {
+    Negotiated(format, views(&state))
+}
+
+/// `PUT /admin/settings`: change some settings, given as `{"key": value}`, a `null` value
+/// unsetting its key
+///
+/// Nothing is changed unless every key is known and every value of the right kind.
+pub async fn update_settings(
+    State(state): State<AppState>,
+    AuditActor(audit_actor): AuditActor,
+    Negotiated(format, changes): Negotiated<BTreeMap<String, Value>>,
+) -> Result<Negotiated<Vec<SettingView>>, AppError> {
+    let mut violations = Vec::new();
+    for (key, value) in &changes {
+        match SettingDefinition::find(key) {
+            None => violations.push(format!("unknown setting `{}`", key)),
+            Some(_) if value.is_null() => {}
+            Some(definition) => {
+                if let Err(err) = definition.kind.validate(value) {
+                    violations.push(format!("{}: {}", key, err));
+                }
+            }
+        }
+    }
+    // Users only logging in through OpenID Connect can't register
+    if changes.get(REGISTRATION_ENABLED) == Some(&Value::Bool(true))
+        && !state.config.password_login_enabled
+    {
+        violations.push(format!(
+            "{}: the password login is disabled",
+            REGISTRATION_ENABLED
+        ));
+    }
+    if !violations.is_empty() {
+        return Err(AppError::BadRequest(violations.join("; ")));
+    }
+
+    for (key, value) in &changes {
+        let previous = state.settings.stored(key).map(|stored| stored.value);
+        let value = Some(value).filter(|value| !value.is_null());
+        state
+            .settings
+            .set(key, value, audit_actor.as_deref())
+            .await?;
+        state.audit.record(
+            audit_actor.clone(),
+            "setting.changed",
+            key,
+            json!({ "previous": previous, "value": value }),
+        );
+        state
+            .events
+            .publish(AdminEvent::SettingChanged { key: key.clone() });
+        tracing::info!("Changed the setting {}", key);
+    }
+
+    Ok(Negotiated(format, views(&state)))
+}
+
+/// The maintenance message, as shown to anyone
+#[derive(Serialize, Deserialize)]
+pub struct Maintenance {
+    pub message: Option<String>,
+}
+
+/// `GET /maintenance`: the message shown to the users during a maintenance, if any
+pub async fn maintenance(State(state): State<AppState>, format: Format) -> Negotiated<Maintenance> {
+    Negotiated(
+        format,
+        Maintenance {
+            message: state.settings.get(MAINTENANCE_MESSAGE),
+        },
+    )
+}
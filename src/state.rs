@@ -0,0 +1,132 @@
+//! State shared by every handler
+
+use std::{sync::Arc, time::Instant};
+
+use tokio::sync::watch;
+
+use crate::{
+    access_log::AccessLog, audit::AuditLogger, auth::oidc::OidcProvider, chaos::ChaosRules,
+    config::Config, database::SqlxPool, events::EventBus, failure_capture::FailureLog,
+    feature_flags::FeatureFlags, health::Heartbeat, idempotency::KeyLocks, mailer::MailerHandle,
+    modules::RouteTable, rate_limit::RateLimiter, reporting::ReporterHandle, session_backend,
+    session_store::SqlxSessionStore, settings::Settings,
+};
+
+/// The state shared by every handler
+#[derive(Clone)]
+pub struct AppState {
+    /// The configuration of the backend
+    pub config: Arc<Config>,
+    /// The connection pool to the database
+    pub pool: SqlxPool,
+    /// The connection pool to the read replica of the database, if any
+    pub replica: Option<SqlxPool>,
+    /// The session store, for the admin endpoints
+    pub sessions: SqlxSessionStore,
+    /// The locks serializing requests sharing an idempotency key
+    pub idempotency_locks: KeyLocks,
+    /// Flipped to `true` once the startup phases are done and the server is serving requests
+    pub ready: Arc<watch::Sender<bool>>,
+    /// Where panics and internal errors are reported
+    pub reporter: ReporterHandle,
+    /// When the backend started
+    pub started_at: Instant,
+    /// The HTTP access log, if enabled
+    pub access_log: Option<AccessLog>,
+    /// The faults injected in the requests, when enabled
+    pub chaos: ChaosRules,
+    /// The routes served by the backend, recorded while the application is built
+    pub routes: RouteTable,
+    /// The last failed requests, when their capture is enabled
+    pub failures: FailureLog,
+    /// Where the events of the backend are published
+    pub events: EventBus,
+    /// Where the emails to the users are sent
+    pub mailer: MailerHandle,
+    /// Where the entries of the audit log are queued
+    pub audit: AuditLogger,
+    /// The OpenID Connect provider users log in through, if configured
+    pub oidc: Option<Arc<OidcProvider>>,
+    /// Beaten after every deletion of the expired sessions
+    pub session_sweep: Heartbeat,
+    /// The counters of the rate limited routes
+    pub rate_limiter: RateLimiter,
+    /// The settings adjustable at runtime
+    pub settings: Settings,
+    /// The feature flags, stored with the settings
+    pub flags: FeatureFlags,
+}
+
+impl AppState {
+    /// Create the state, not ready yet
+    pub fn new(config: Arc<Config>, pool: SqlxPool, reporter: ReporterHandle) -> Self {
+        let (ready, _) = watch::channel(false);
+        let settings = Settings::new(pool.clone());
+        Self {
+            reporter,
+            started_at: Instant::now(),
+            access_log: None,
+            chaos: ChaosRules::new(config.chaos.rules.clone()),
+            routes: RouteTable::default(),
+            failures: FailureLog::default(),
+            events: EventBus::default(),
+            mailer: MailerHandle::default(),
+            audit: AuditLogger::new(pool.clone(), config.audit.sink, config.audit.queue_capacity),
+            oidc: None,
+            session_sweep: Heartbeat::new(session_backend::DELETION_PERIOD),
+            rate_limiter: RateLimiter::new(config.rate_limit.backend, pool.clone()),
+            flags: FeatureFlags::new(settings.clone()),
+            settings,
+            config,
+            sessions: SqlxSessionStore::new(pool.clone()),
+            pool,
+            replica: None,
+            idempotency_locks: KeyLocks::default(),
+            ready: Arc::new(ready),
+        }
+    }
+
+    /// Read from the given replica
+    pub fn with_replica(mut self, replica: Option<SqlxPool>) -> Self {
+        self.replica = replica;
+        self
+    }
+
+    /// Append the requests to the given access log
+    pub fn with_access_log(mut self, access_log: Option<AccessLog>) -> Self {
+        self.access_log = access_log;
+        self
+    }
+
+    /// Send the emails through the given mailer
+    pub fn with_mailer(mut self, mailer: MailerHandle) -> Self {
+        self.mailer = mailer;
+        self
+    }
+
+    /// Log users in through the given OpenID Connect provider
+    pub fn with_oidc(mut self, oidc: Option<Arc<OidcProvider>>) -> Self {
+        self.oidc = oidc;
+        self
+    }
+
+    /// The pool to the primary database, for writes
+    pub fn write_pool(&self) -> &SqlxPool {
+        &self.pool
+    }
+
+    /// The pool to the read replica, or to the primary database when there is none
+    pub fn read_pool(&self) -> &SqlxPool {
+        self.replica.as_ref().unwrap_or(&self.pool)
+    }
+
+    /// Whether the server is ready to accept traffic
+    pub fn is_ready(&self) -> bool {
+        *self.ready.borrow()
+    }
+
+    /// Mark the server as ready to accept traffic
+    pub fn mark_ready(&self) {
+        self.ready.send_replace(true);
+    }
+}
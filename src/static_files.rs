@@ -0,0 +1,54 @@
+//! Static assets of the admin UI
+//! When `STATIC_DIR` is set, its files are served under `STATIC_PREFIX` with `tower-http`. Paths
+//! without an extension that don't match a file fall back to `index.html`, so that the UI can
+//! route on the client side, while missing assets are answered with `404 Not Found`. These routes
+//! are served outside of the session layer.
+
+use std::{convert::Infallible, path::Path};
+
+use axum::{
+    extract::Request,
+    http::{Method, StatusCode},
+    response::IntoResponse,
+    Router,
+};
+use tower::{service_fn, ServiceExt};
+use tower_http::services::{ServeDir, ServeFile};
+
+use crate::state::AppState;
+
+/// The file served for directories and the routes of the UI
+const INDEX: &str = "index.html";
+
+/// Build the router serving the static assets, if enabled
+pub fn router(state: &AppState) -> Option<Router<AppState>> {
+    let dir = state.config.static_files.dir.as_ref()?;
+
+    let prefix = &state.config.static_files.prefix;
+    for path in [
+        prefix.clone(),
+        format!("{}/", prefix),
+        format!("{}/*path", prefix),
+    ] {
+        state.routes.add(Method::GET, &path);
+    }
+
+    let index = ServeFile::new(dir.join(INDEX));
+    let fallback = service_fn(move |req: Request| {
+        let index = index.clone();
+        async move {
+            if is_asset(req.uri().path()) {
+                return Ok::<_, Infallible>(StatusCode::NOT_FOUND.into_response());
+            }
+            Ok(index.oneshot(req).await?.into_response())
+        }
+    });
+    Some(Router::new().nest_service(prefix, ServeDir::new(dir).fallback(fallback)))
+}
+
+/// Whether a path names an asset rather than a route of the UI, its last segment having an
+/// extension
+fn is_asset(path: &str) -> bool {
+    let segment = path.rsplit('/').next().unwrap_or_default();
+    Path::new(segment).extension().is_some()
+}
@@ -0,0 +1,39 @@
+//! Supervision of the background tasks
+//! Every long-running task is spawned through the [`Supervisor`], which aborts them all when the
+//! server shuts down and reports the ones that failed.
+
+use std::future::Future;
+
+use tokio::task::JoinHandle;
+
+/// The background tasks of the backend
+#[derive(Default)]
+pub struct Supervisor {
+    tasks: Vec<(String, JoinHandle<()>)>,
+}
+
+impl Supervisor {
+    /// Spawn a named background task
+    pub fn spawn<F>(&mut self, name: impl Into<String>, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.tasks.push((name.into(), tokio::task::spawn(task)));
+    }
+
+    /// Abort every task and wait for them to finish
+    pub async fn shutdown(self) {
+        for (_, task) in &self.tasks {
+            task.abort();
+        }
+
+        for (name, task) in self.tasks {
+            match task.await {
+                Err(err) if err.is_panic() => {
+                    tracing::error!("Background task '{}' panicked: {}", name, err)
+                }
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! Integration with systemd
+//! Supports socket activation (`LISTEN_FDS`) and the `sd_notify` protocol (`NOTIFY_SOCKET`) so
+//! that the backend can run as a `Type=notify` service. Everything here is a no-op when not
+//! started by systemd or on non-unix platforms.
+
+use std::time::Duration;
+
+/// The first file descriptor passed by systemd (`SD_LISTEN_FDS_START`)
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// A state change to report to systemd
+#[derive(Clone, Copy, Debug)]
+pub enum NotifyState {
+    /// The service finished starting up
+    Ready,
+    /// The service is shutting down
+    Stopping,
+    /// Keep-alive ping for the service watchdog
+    Watchdog,
+}
+
+impl NotifyState {
+    /// The message sent on the notification socket for this state
+    fn message(&self) -> &'static str {
+        match self {
+            NotifyState::Ready => "READY=1",
+            NotifyState::Stopping => "STOPPING=1",
+            NotifyState::Watchdog => "WATCHDOG=1",
+        }
+    }
+}
+
+/// Take the listening socket passed by systemd, if any
+///
+/// Only the first socket is used. The `LISTEN_*` variables are removed from the environment so
+/// that child processes don't inherit them, which is only sound while the process has a single
+/// thread: call this at the start of `main`, before the async runtime is built.
+#[cfg(unix)]
+pub fn take_listener() -> std::io::Result<Option<std::net::TcpListener>> {
+    use std::os::fd::FromRawFd;
+
+    let fds = parse_listen_fds(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    );
+
+    // No other thread is running yet, hence none reading the environment
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    let Some(fd) = fds else {
+        return Ok(None);
+    };
+
+    // SAFETY: systemd hands this descriptor over to us and nothing else owns it
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    Ok(Some(listener))
+}
+
+/// Take the listening socket passed by systemd, if any
+#[cfg(not(unix))]
+pub fn take_listener() -> std::io::Result<Option<std::net::TcpListener>> {
+    Ok(None)
+}
+
+/// Get the first passed file descriptor from the `LISTEN_PID` and `LISTEN_FDS` values
+///
+/// The descriptors are only meant for us if `LISTEN_PID` matches our own pid.
+#[cfg(unix)]
+fn parse_listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> Option<i32> {
+    let listen_pid: u32 = listen_pid?.trim().parse().ok()?;
+    if listen_pid != pid {
+        return None;
+    }
+
+    let count: i32 = listen_fds?.trim().parse().ok()?;
+    (count >= 1).then_some(LISTEN_FDS_START)
+}
+
+/// Report a state change to systemd, if running under a `Type=notify` service
+///
+/// Failures are ignored: the notification is best-effort.
+pub fn notify(state: NotifyState) {
+    #[cfg(unix)]
+    if let Ok(path) = std::env::var("NOTIFY_SOCKET") {
+        let _ = send_notification(&path, state.message());
+    }
+
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+/// Send a message on the given notification socket (a path, or an abstract name prefixed by `@`)
+#[cfg(unix)]
+fn send_notification(path: &str, message: &str) -> std::io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        socket.send_to_addr(message.as_bytes(), &addr)?;
+        return Ok(());
+    }
+
+    socket.send_to(message.as_bytes(), path)?;
+    Ok(())
+}
+
+/// Get the interval at which the watchdog expects a keep-alive, if enabled
+///
+/// We ping at half the configured timeout, as recommended by `sd_watchdog_enabled(3)`.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+
+    if let Some(pid) = std::env::var("WATCHDOG_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+    {
+        if pid != std::process::id() {
+            return None;
+        }
+    }
+
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// Ping the watchdog forever at the given interval
+pub async fn run_watchdog(interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        notify(NotifyState::Watchdog);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn only_takes_the_descriptors_meant_for_us() {
+        assert_eq!(parse_listen_fds(Some("42"), Some("1"), 42), Some(3));
+        assert_eq!(parse_listen_fds(Some("42"), Some("2"), 42), Some(3));
+        assert_eq!(parse_listen_fds(Some("41"), Some("1"), 42), None);
+        assert_eq!(parse_listen_fds(Some("42"), Some("0"), 42), None);
+        assert_eq!(parse_listen_fds(None, Some("1"), 42), None);
+        assert_eq!(parse_listen_fds(Some("42"), None, 42), None);
+        assert_eq!(parse_listen_fds(Some("pid"), Some("1"), 42), None);
+    }
+}
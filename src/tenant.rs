@@ -0,0 +1,93 @@
+//! Tenants resolved from the host
+//! With `TENANT_BASE_DOMAIN` set (e.g. `admin.example.com`), the subdomain a request is made to
+//! names its tenant: `acme.admin.example.com` is made for `acme`, while the base domain itself is
+//! made for no tenant. The tenant is kept in the request extensions, where handlers read it
+//! through [`Tenant`] and the access log records it. Requests to any other host are answered with
+//! a `400`, except for the health endpoints, probed by address.
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{header, request::Parts},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{error::AppError, state::AppState};
+
+/// The endpoints reached whatever the host
+const HEALTH_PATHS: [&str; 2] = ["/health", "/readyz"];
+
+/// The longest label of a domain name
+const MAX_LABEL_LENGTH: usize = 63;
+
+/// The slug of the tenant of the request, `None` for the base domain or when tenants are disabled
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Tenant(pub Option<String>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Tenant {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<Tenant>()
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+/// Resolve the tenant of the request from its host, refusing the hosts outside the base domain
+pub async fn resolve(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let Some(base_domain) = state.config.tenant_base_domain.as_deref() else {
+        return next.run(req).await;
+    };
+    if HEALTH_PATHS.contains(&req.uri().path()) {
+        return next.run(req).await;
+    }
+
+    // HTTP/2 requests carry the host in the URI rather than in a header
+    let host = req.uri().host().map(str::to_string).or_else(|| {
+        req.headers()
+            .get(header::HOST)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    });
+    let Some(tenant) = host.and_then(|host| tenant_of(&host, base_domain)) else {
+        return AppError::BadRequest(format!("The host must be under {}", base_domain))
+            .into_response();
+    };
+    req.extensions_mut().insert(tenant);
+    next.run(req).await
+}
+
+/// The tenant a host is for, or `None` when it isn't the base domain nor one of its subdomains
+///
+/// The host may carry a port and a trailing dot. Only direct subdomains name a tenant.
+pub fn tenant_of(host: &str, base_domain: &str) -> Option<Tenant> {
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    };
+    let host = host.strip_suffix('.').unwrap_or(host).to_ascii_lowercase();
+    if host == base_domain {
+        return Some(Tenant(None));
+    }
+    let slug = host
+        .strip_suffix(base_domain)?
+        .strip_suffix('.')
+        .filter(|slug| is_label(slug))?;
+    Some(Tenant(Some(slug.to_string())))
+}
+
+/// Whether the text is a single label of a domain name, such as `acme`
+pub fn is_label(text: &str) -> bool {
+    !text.is_empty()
+        && text.len() <= MAX_LABEL_LENGTH
+        && !text.starts_with('-')
+        && !text.ends_with('-')
+        && text
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+}
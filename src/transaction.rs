@@ -0,0 +1,163 @@
+//! A database transaction spanning a request
+//! Handlers taking a [`Tx`] run their queries in a transaction on the primary database, begun
+//! when the extractor runs. The [`commit`] middleware commits it once the handler returned a
+//! successful (`2xx` or `3xx`) response, and rolls it back otherwise, so that a handler failing
+//! midway leaves no partial write behind. A handler extracting [`Tx`] twice gets the same
+//! transaction.
+//!
+//! Queries dispatch on the backend with [`with_tx!`](crate::with_tx):
+//!
+//! ```ignore
+//! let mut conn = tx.lock().await;
+//! let sql = tx.sql("UPDATE user_groups SET name = ? WHERE id = ?");
+//! with_tx!(conn, |c| sqlx::query(&sql).bind(name).bind(id).execute(&mut *c).await.map(|_| ()))?;
+//! ```
+
+use std::{borrow::Cow, sync::Arc};
+
+use anyhow::anyhow;
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request},
+    http::request::Parts,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard};
+
+use crate::{database::SqlxPool, error::AppError, state::AppState};
+
+/// Evaluate an expression with the connection of a locked [`Transaction`], whatever its backend
+///
+/// The connection is a mutable reference: reborrow it (`&mut *c`) to run several queries.
+#[macro_export]
+macro_rules! with_tx {
+    ($tx:expr, |$c:ident| $body:expr) => {
+        match &mut *$tx {
+            $crate::transaction::Transaction::Sqlite(t) => {
+                let $c = &mut **t;
+                $body
+            }
+            $crate::transaction::Transaction::Postgres(t) => {
+                let $c = &mut **t;
+                $body
+            }
+            $crate::transaction::Transaction::MySql(t) => {
+                let $c = &mut **t;
+                $body
+            }
+        }
+    };
+}
+
+/// A transaction on one of the supported databases
+#[derive(Debug)]
+pub enum Transaction {
+    Sqlite(sqlx::Transaction<'static, sqlx::Sqlite>),
+    Postgres(sqlx::Transaction<'static, sqlx::Postgres>),
+    MySql(sqlx::Transaction<'static, sqlx::MySql>),
+}
+
+impl Transaction {
+    /// Begin a transaction on the pool
+    pub async fn begin(pool: &SqlxPool) -> Result<Self, sqlx::Error> {
+        Ok(match pool {
+            SqlxPool::Sqlite(p) => Transaction::Sqlite(p.begin().await?),
+            SqlxPool::Postgres(p) => Transaction::Postgres(p.begin().await?),
+            SqlxPool::MySql(p) => Transaction::MySql(p.begin().await?),
+        })
+    }
+
+    pub async fn commit(self) -> Result<(), sqlx::Error> {
+        match self {
+            Transaction::Sqlite(t) => t.commit().await,
+            Transaction::Postgres(t) => t.commit().await,
+            Transaction::MySql(t) => t.commit().await,
+        }
+    }
+
+    pub async fn rollback(self) -> Result<(), sqlx::Error> {
+        match self {
+            Transaction::Sqlite(t) => t.rollback().await,
+            Transaction::Postgres(t) => t.rollback().await,
+            Transaction::MySql(t) => t.rollback().await,
+        }
+    }
+}
+
+/// The transaction of a request, if begun, kept in its extensions
+#[derive(Clone, Default)]
+struct TxSlot(Arc<Mutex<Option<Transaction>>>);
+
+/// The transaction of the request, committed when the handler succeeds
+///
+/// Rejects the request with a `500` when the database is unavailable, or when the route isn't
+/// behind the [`commit`] middleware.
+#[derive(Clone)]
+pub struct Tx {
+    slot: TxSlot,
+    pool: SqlxPool,
+}
+
+impl Tx {
+    /// Lock the transaction to run queries on it
+    pub async fn lock(&self) -> MappedMutexGuard<'_, Transaction> {
+        MutexGuard::map(self.slot.0.lock().await, |tx| {
+            tx.as_mut()
+                .expect("the transaction is begun by the extractor")
+        })
+    }
+
+    /// Adapt a query written with `?` placeholders to the backend, see [`SqlxPool::sql`]
+    pub fn sql<'a>(&self, query: &'a str) -> Cow<'a, str> {
+        self.pool.sql(query)
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Tx {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let slot = parts.extensions.get::<TxSlot>().cloned().ok_or_else(|| {
+            AppError::Internal(anyhow!("Tx extracted outside of the commit middleware"))
+        })?;
+
+        let pool = state.write_pool().clone();
+        let mut tx = slot.0.lock().await;
+        if tx.is_none() {
+            *tx = Some(Transaction::begin(&pool).await?);
+        }
+        drop(tx);
+
+        Ok(Tx { slot, pool })
+    }
+}
+
+/// Commit the transaction of the request when the response is successful, or roll it back
+///
+/// A failed commit turns the response into a `500`, as its writes are lost.
+pub async fn commit(mut request: Request, next: Next) -> Response {
+    let slot = TxSlot::default();
+    request.extensions_mut().insert(slot.clone());
+
+    let response = next.run(request).await;
+
+    let Some(tx) = slot.0.lock().await.take() else {
+        return response;
+    };
+    let status = response.status();
+    if status.is_success() || status.is_redirection() {
+        if let Err(err) = tx.commit().await {
+            return AppError::Internal(anyhow!(err).context("Failed to commit the transaction"))
+                .into_response();
+        }
+    } else if let Err(err) = tx.rollback().await {
+        // The transaction is rolled back anyway when its connection is closed
+        tracing::warn!("Failed to roll back the transaction: {}", err);
+    }
+    response
+}
@@ -0,0 +1,243 @@
+//! Administration of the users from the command line
+//! The `user` subcommands of the binary are a way in when nobody can log in anymore (e.g. the
+//! last admin locked out or disabled). They act on the users through the same repositories as
+//! the API, and their changes are recorded in the audit log with `cli` as the actor. A user that
+//! doesn't exist fails with [`UserError::NotFound`].
+
+use anyhow::{Context, Result};
+
+use crate::{
+    audit::AuditLogger,
+    auth::{lockout::LoginAttempts, password, password_policy::UserContext},
+    config::Config,
+    database::SqlxPool,
+    error::AppError,
+    modules::ModuleRegistry,
+    roles::{Role, RoleRepository},
+    session_store::SqlxSessionStore,
+    users::{
+        is_valid_email, AuthSource, CreatedUser, NewUser, User, UserDetails, UserError, UserFilter,
+        UserRepository, UserStatus,
+    },
+};
+
+/// The actor of the changes in the audit log
+const ACTOR: &str = "cli";
+
+/// The number of users read per query when listing them
+const PAGE_SIZE: i64 = 500;
+
+/// The users of the database, administered from the command line
+pub struct UserAdmin {
+    config: Config,
+    pool: SqlxPool,
+    audit: AuditLogger,
+}
+
+impl UserAdmin {
+    /// Connect to the database, running the migrations if needed
+    pub async fn open(config: &Config) -> Result<Self> {
+        let pool = crate::initialize(config, &ModuleRegistry::default(), false).await?;
+        Ok(Self {
+            config: config.clone(),
+            audit: AuditLogger::new(pool.clone(), config.audit.sink, config.audit.queue_capacity),
+            pool,
+        })
+    }
+
+    /// Write the audit log and close the connections
+    pub async fn close(self) {
+        self.audit.flush().await;
+        self.pool.close().await;
+    }
+
+    /// Create an active user with the given roles
+    ///
+    /// Without a password, a temporary one is generated and returned, which the user must change
+    /// on their first login.
+    pub async fn create(
+        &self,
+        email: &str,
+        display_name: Option<&str>,
+        roles: &[Role],
+        password: Option<String>,
+    ) -> Result<CreatedUser> {
+        let email = email.trim();
+        if !is_valid_email(email) {
+            anyhow::bail!("Invalid email: {}", email);
+        }
+        let (plain, temporary_password) = self.password_or_generated(email, password).await?;
+
+        let users = UserRepository::new(self.pool.clone());
+        let user = users
+            .create(NewUser {
+                email: email.to_string(),
+                display_name: display_name
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .unwrap_or(email)
+                    .to_string(),
+                password_hash: password::hash(&plain, &self.config.password).await?,
+                status: UserStatus::Active,
+                must_change_password: temporary_password.is_some(),
+                auth_source: AuthSource::Local,
+            })
+            .await?;
+        let role_repository = RoleRepository::new(self.pool.clone());
+        if !roles.is_empty() {
+            role_repository.set_roles(user.id, roles).await?;
+        }
+        self.audit.record(
+            Some(ACTOR.to_string()),
+            "user.created",
+            &user.id.to_string(),
+            serde_json::json!({ "roles": roles }),
+        );
+
+        Ok(CreatedUser {
+            user: UserDetails {
+                roles: role_repository.roles_of(user.id).await?,
+                user,
+            },
+            temporary_password,
+        })
+    }
+
+    /// Replace the password of a local user, revoking their sessions and lifting their lock
+    ///
+    /// Without a password, a temporary one is generated and returned, which the user must change
+    /// on their next login.
+    pub async fn set_password(&self, email: &str, password: Option<String>) -> Result<CreatedUser> {
+        let user = self.find(email).await?;
+        if user.auth_source != AuthSource::Local {
+            anyhow::bail!(
+                "The password of {} is managed by the LDAP directory",
+                user.email
+            );
+        }
+        let (plain, temporary_password) = self.password_or_generated(&user.email, password).await?;
+
+        let user = UserRepository::new(self.pool.clone())
+            .update(&User {
+                password_hash: password::hash(&plain, &self.config.password).await?,
+                must_change_password: temporary_password.is_some(),
+                ..user
+            })
+            .await?;
+        LoginAttempts::new(self.pool.clone()).unlock(&user).await?;
+        SqlxSessionStore::new(self.pool.clone())
+            .revoke_user(user.id)
+            .await?;
+        self.audit.record(
+            Some(ACTOR.to_string()),
+            "user.password_reset",
+            &user.id.to_string(),
+            serde_json::json!({}),
+        );
+
+        Ok(CreatedUser {
+            user: self.details(user).await?,
+            temporary_password,
+        })
+    }
+
+    /// Unlock a user locked after failed logins
+    pub async fn unlock(&self, email: &str) -> Result<UserDetails> {
+        let user = self.find(email).await?;
+        LoginAttempts::new(self.pool.clone()).unlock(&user).await?;
+        self.audit.record(
+            Some(ACTOR.to_string()),
+            "user.unlocked",
+            &user.id.to_string(),
+            serde_json::json!({}),
+        );
+        // Read again, without the lock
+        let user = self.find(email).await?;
+        self.details(user).await
+    }
+
+    /// Disable a user, revoking their sessions
+    pub async fn disable(&self, email: &str) -> Result<UserDetails> {
+        let mut user = self.find(email).await?;
+        if user.status != UserStatus::Disabled {
+            let previous = user.status;
+            user.status = UserStatus::Disabled;
+            user = UserRepository::new(self.pool.clone()).update(&user).await?;
+            self.audit.record(
+                Some(ACTOR.to_string()),
+                "user.deactivated",
+                &user.id.to_string(),
+                serde_json::json!({ "previous_status": previous }),
+            );
+        }
+        SqlxSessionStore::new(self.pool.clone())
+            .revoke_user(user.id)
+            .await?;
+        self.details(user).await
+    }
+
+    /// Every user with their roles, oldest first
+    pub async fn list(&self) -> Result<Vec<UserDetails>> {
+        let users = UserRepository::new(self.pool.clone());
+        let mut details = Vec::new();
+        loop {
+            let page = users
+                .list(&UserFilter::default(), PAGE_SIZE, details.len() as i64)
+                .await?;
+            let last = (page.len() as i64) < PAGE_SIZE;
+            for user in page {
+                details.push(self.details(user).await?);
+            }
+            if last {
+                return Ok(details);
+            }
+        }
+    }
+
+    /// The user with the email
+    async fn find(&self, email: &str) -> Result<User> {
+        UserRepository::new(self.pool.clone())
+            .find_by_email(email.trim())
+            .await?
+            .ok_or(UserError::NotFound)
+            .with_context(|| format!("No user with the email {}", email.trim()))
+    }
+
+    /// The user with their own roles
+    async fn details(&self, user: User) -> Result<UserDetails> {
+        let roles = RoleRepository::new(self.pool.clone())
+            .roles_of(user.id)
+            .await?;
+        Ok(UserDetails { user, roles })
+    }
+
+    /// The given password once checked against the policy, or a generated one along with a copy
+    /// to show
+    async fn password_or_generated(
+        &self,
+        email: &str,
+        password: Option<String>,
+    ) -> Result<(String, Option<String>)> {
+        let Some(password) = password else {
+            let generated = password::generate(&self.config.password_policy);
+            return Ok((generated.clone(), Some(generated)));
+        };
+        match self
+            .config
+            .password_policy
+            .enforce(&password, &UserContext { email })
+            .await
+        {
+            Ok(()) => Ok((password, None)),
+            Err(AppError::PasswordPolicy(violations)) => anyhow::bail!(
+                "The password doesn't meet the policy: {}",
+                violations
+                    .iter()
+                    .map(|violation| violation.message.as_str())
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            ),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
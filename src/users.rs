@@ -0,0 +1,733 @@
+//! User accounts
+//! Users are stored in the `users` table. Emails are unique regardless of their case: the
+//! lowercased email is kept alongside the one given, under a unique constraint. Admins manage
+//! them through `/admin/users`, where deleting a user deactivates it.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{
+    admin::Pagination,
+    audit::{AuditActor, AuditOperation},
+    auth::{current_user::OptionalUser, password, password_policy::UserContext},
+    database::{with_pool, SqlxPool},
+    error::AppError,
+    events::AdminEvent,
+    idempotency::NoStore,
+    negotiate::{Format, Negotiated},
+    roles::{self, Role, RoleRepository},
+    state::AppState,
+};
+
+/// The columns of the `users` table, in the order of [`UserRow`]
+const COLUMNS: &str = "id, email, display_name, password_hash, status, created_at, updated_at, \
+                       roles_version, must_change_password, locked_until, lockouts, auth_source";
+
+/// The maximum number of characters of a display name
+pub const MAX_DISPLAY_NAME_LENGTH: usize = 100;
+
+/// A user account
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct User {
+    pub id: i64,
+    pub email: String,
+    pub display_name: String,
+    /// The hash of the password, never serialized
+    #[serde(skip_serializing, default)]
+    pub password_hash: String,
+    pub status: UserStatus,
+    /// When the user was created, in unix seconds
+    pub created_at: i64,
+    /// When the user was last updated, in unix seconds
+    pub updated_at: i64,
+    /// Incremented whenever the roles of the user change, to refresh the roles cached in sessions
+    #[serde(skip_serializing, default)]
+    pub roles_version: i64,
+    /// Whether the user must change their password, e.g. a temporary one given by an admin
+    #[serde(default)]
+    pub must_change_password: bool,
+    /// Until when the account is locked after failed logins, in unix seconds
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub locked_until: Option<i64>,
+    /// The number of times the account was locked since the last successful login
+    #[serde(skip_serializing, default)]
+    pub lockouts: i64,
+    /// Where the password of the user is checked
+    #[serde(default)]
+    pub auth_source: AuthSource,
+}
+
+/// Whether a user can use their account
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserStatus {
+    Active,
+    Disabled,
+    /// Registered, but the email isn't verified yet
+    PendingVerification,
+}
+
+impl UserStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserStatus::Active => "active",
+            UserStatus::Disabled => "disabled",
+            UserStatus::PendingVerification => "pending_verification",
+        }
+    }
+
+    fn parse(status: &str) -> Option<Self> {
+        match status {
+            "active" => Some(UserStatus::Active),
+            "disabled" => Some(UserStatus::Disabled),
+            "pending_verification" => Some(UserStatus::PendingVerification),
+            _ => None,
+        }
+    }
+}
+
+/// Where the password of a user is checked
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthSource {
+    /// Against the hash stored with the user
+    #[default]
+    Local,
+    /// By the LDAP directory, the user being its shadow: their password can't be changed here
+    Ldap,
+}
+
+impl AuthSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthSource::Local => "local",
+            AuthSource::Ldap => "ldap",
+        }
+    }
+
+    fn parse(source: &str) -> Option<Self> {
+        match source {
+            "local" => Some(AuthSource::Local),
+            "ldap" => Some(AuthSource::Ldap),
+            _ => None,
+        }
+    }
+}
+
+/// A user to create
+#[derive(Clone, Debug)]
+pub struct NewUser {
+    pub email: String,
+    pub display_name: String,
+    pub password_hash: String,
+    pub status: UserStatus,
+    pub must_change_password: bool,
+    pub auth_source: AuthSource,
+}
+
+/// The users to list
+#[derive(Clone, Debug, Default)]
+pub struct UserFilter {
+    /// Only the users with this status
+    pub status: Option<UserStatus>,
+    /// Only the users whose email or display name contains this text, regardless of its case
+    pub search: Option<String>,
+    /// Only the members of this group
+    pub group: Option<i64>,
+}
+
+/// An error of the user repository
+#[derive(Debug, thiserror::Error)]
+pub enum UserError {
+    /// Another user has the same email, regardless of its case
+    #[error("A user with this email already exists")]
+    EmailTaken,
+    /// The user doesn't exist
+    #[error("No such user")]
+    NotFound,
+    #[error(transparent)]
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for UserError {
+    fn from(err: sqlx::Error) -> Self {
+        match &err {
+            sqlx::Error::Database(db_err) if db_err.is_unique_violation() => UserError::EmailTaken,
+            _ => UserError::Database(err),
+        }
+    }
+}
+
+impl From<UserError> for AppError {
+    fn from(err: UserError) -> Self {
+        match err {
+            UserError::EmailTaken => AppError::Conflict(err.to_string()),
+            UserError::NotFound => AppError::NotFound,
+            UserError::Database(err) => err.into(),
+        }
+    }
+}
+
+/// A row of the `users` table
+#[derive(sqlx::FromRow)]
+struct UserRow {
+    id: i64,
+    email: String,
+    display_name: String,
+    password_hash: String,
+    status: String,
+    created_at: i64,
+    updated_at: i64,
+    roles_version: i64,
+    must_change_password: bool,
+    locked_until: Option<i64>,
+    lockouts: i64,
+    auth_source: String,
+}
+
+impl TryFrom<UserRow> for User {
+    type Error = sqlx::Error;
+
+    fn try_from(row: UserRow) -> Result<Self, Self::Error> {
+        let status = UserStatus::parse(&row.status).ok_or_else(|| {
+            sqlx::Error::Decode(format!("unknown user status '{}'", row.status).into())
+        })?;
+        let auth_source = AuthSource::parse(&row.auth_source).ok_or_else(|| {
+            sqlx::Error::Decode(format!("unknown auth source '{}'", row.auth_source).into())
+        })?;
+        Ok(User {
+            id: row.id,
+            email: row.email,
+            display_name: row.display_name,
+            password_hash: row.password_hash,
+            status,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+            roles_version: row.roles_version,
+            must_change_password: row.must_change_password,
+            locked_until: row.locked_until,
+            lockouts: row.lockouts,
+            auth_source,
+        })
+    }
+}
+
+/// Whether an email looks deliverable: a local part and a dotted domain, without spaces
+pub fn is_valid_email(email: &str) -> bool {
+    let email = email.trim();
+    let Some((local, domain)) = email.rsplit_once('@') else {
+        return false;
+    };
+    email.len() <= 254
+        && !local.is_empty()
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+        && domain
+            .split('.')
+            .all(|label| !label.is_empty() && !label.contains('@'))
+        && domain.contains('.')
+}
+
+/// Whether a display name is neither blank nor too long
+pub fn is_valid_display_name(display_name: &str) -> bool {
+    let display_name = display_name.trim();
+    !display_name.is_empty() && display_name.chars().count() <= MAX_DISPLAY_NAME_LENGTH
+}
+
+/// The form of an email that is unique
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// The user accounts, stored in the database
+#[derive(Clone, Debug)]
+pub struct UserRepository {
+    pool: SqlxPool,
+}
+
+impl UserRepository {
+    pub fn new(pool: SqlxPool) -> Self {
+        Self { pool }
+    }
+
+    /// Create a user
+    pub async fn create(&self, user: NewUser) -> Result<User, UserError> {
+        let sql = self.pool.sql(
+            "INSERT INTO users \
+             (email, email_normalized, display_name, password_hash, status, \
+             must_change_password, auth_source, created_at, updated_at) \
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        );
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let email = user.email.trim();
+
+        with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(email)
+            .bind(normalize_email(email))
+            .bind(&user.display_name)
+            .bind(&user.password_hash)
+            .bind(user.status.as_str())
+            .bind(user.must_change_password)
+            .bind(user.auth_source.as_str())
+            .bind(now)
+            .bind(now)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))?;
+
+        // Not every backend can return the inserted row, but the email identifies it
+        self.find_by_email(email).await?.ok_or(UserError::NotFound)
+    }
+
+    /// Create a user only if there is none yet, returning `None` otherwise
+    ///
+    /// The check and the insert are a single statement, and a concurrent creation of the same
+    /// email is refused by its unique constraint: of several instances starting together, only
+    /// one creates the user.
+    pub async fn create_first(&self, user: NewUser) -> Result<Option<User>, UserError> {
+        let sql = self.pool.sql(
+            "INSERT INTO users \
+             (email, email_normalized, display_name, password_hash, status, \
+             must_change_password, auth_source, created_at, updated_at) \
+             SELECT ?, ?, ?, ?, ?, ?, ?, ?, ? FROM (SELECT 1) AS first_user \
+             WHERE NOT EXISTS (SELECT 1 FROM users)",
+        );
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let email = user.email.trim();
+
+        let created = with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(email)
+            .bind(normalize_email(email))
+            .bind(&user.display_name)
+            .bind(&user.password_hash)
+            .bind(user.status.as_str())
+            .bind(user.must_change_password)
+            .bind(user.auth_source.as_str())
+            .bind(now)
+            .bind(now)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()));
+        match created.map_err(UserError::from) {
+            Ok(0) | Err(UserError::EmailTaken) => Ok(None),
+            Ok(_) => self.find_by_email(email).await,
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Whether any user exists, whatever their status
+    pub async fn any(&self) -> Result<bool, UserError> {
+        let sql = "SELECT id FROM users LIMIT 1";
+        let id: Option<i64> = with_pool!(&self.pool, |p| sqlx::query_scalar(sql)
+            .fetch_optional(p)
+            .await)?;
+        Ok(id.is_some())
+    }
+
+    /// Find a user by its ID
+    pub async fn find_by_id(&self, id: i64) -> Result<Option<User>, UserError> {
+        let sql = self
+            .pool
+            .sql(&format!("SELECT {} FROM users WHERE id = ?", COLUMNS))
+            .into_owned();
+        let row: Option<UserRow> = with_pool!(&self.pool, |p| sqlx::query_as(&sql)
+            .bind(id)
+            .fetch_optional(p)
+            .await)?;
+        Ok(row.map(User::try_from).transpose()?)
+    }
+
+    /// Find a user by its email, regardless of its case
+    pub async fn find_by_email(&self, email: &str) -> Result<Option<User>, UserError> {
+        let sql = self
+            .pool
+            .sql(&format!(
+                "SELECT {} FROM users WHERE email_normalized = ?",
+                COLUMNS
+            ))
+            .into_owned();
+        let row: Option<UserRow> = with_pool!(&self.pool, |p| sqlx::query_as(&sql)
+            .bind(normalize_email(email))
+            .fetch_optional(p)
+            .await)?;
+        Ok(row.map(User::try_from).transpose()?)
+    }
+
+    /// Save the email, display name, password hash, status and password change flag of a user
+    ///
+    /// Returns the user as saved, with its new update time. The roles are changed through
+    /// [`RoleRepository`](crate::roles::RoleRepository).
+    pub async fn update(&self, user: &User) -> Result<User, UserError> {
+        let sql = self.pool.sql(
+            "UPDATE users SET email = ?, email_normalized = ?, display_name = ?, \
+             password_hash = ?, status = ?, must_change_password = ?, updated_at = ? WHERE id = ?",
+        );
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let email = user.email.trim();
+
+        let updated = with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(email)
+            .bind(normalize_email(email))
+            .bind(&user.display_name)
+            .bind(&user.password_hash)
+            .bind(user.status.as_str())
+            .bind(user.must_change_password)
+            .bind(now)
+            .bind(user.id)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))?;
+        if updated == 0 {
+            return Err(UserError::NotFound);
+        }
+
+        self.find_by_id(user.id).await?.ok_or(UserError::NotFound)
+    }
+
+    /// List the users matching the filter, oldest first
+    pub async fn list(
+        &self,
+        filter: &UserFilter,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<User>, UserError> {
+        let mut conditions = Vec::new();
+        if filter.status.is_some() {
+            conditions.push("status = ?");
+        }
+        if filter.group.is_some() {
+            conditions.push("id IN (SELECT user_id FROM group_members WHERE group_id = ?)");
+        }
+        // `!` escapes the wildcards, as backslashes are special in MySQL strings
+        let pattern = filter.search.as_deref().map(|search| {
+            let escaped = search
+                .trim()
+                .to_lowercase()
+                .replace('!', "!!")
+                .replace('%', "!%")
+                .replace('_', "!_");
+            format!("%{}%", escaped)
+        });
+        if pattern.is_some() {
+            conditions.push(
+                "(email_normalized LIKE ? ESCAPE '!' OR LOWER(display_name) LIKE ? ESCAPE '!')",
+            );
+        }
+        let filter_sql = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+
+        let sql = self
+            .pool
+            .sql(&format!(
+                "SELECT {} FROM users {} ORDER BY id LIMIT ? OFFSET ?",
+                COLUMNS, filter_sql
+            ))
+            .into_owned();
+        let rows: Vec<UserRow> = with_pool!(&self.pool, |p| {
+            let mut query = sqlx::query_as(&sql);
+            if let Some(status) = filter.status {
+                query = query.bind(status.as_str());
+            }
+            if let Some(group) = filter.group {
+                query = query.bind(group);
+            }
+            if let Some(pattern) = &pattern {
+                query = query.bind(pattern.clone()).bind(pattern.clone());
+            }
+            query.bind(limit).bind(offset).fetch_all(p).await
+        })?;
+        Ok(rows
+            .into_iter()
+            .map(User::try_from)
+            .collect::<Result<_, _>>()?)
+    }
+}
+
+/// The users to list, see [`UserFilter`]
+#[derive(Deserialize)]
+pub struct UserQuery {
+    status: Option<UserStatus>,
+    /// Text to search in the emails and display names
+    q: Option<String>,
+    /// The ID of a group to list the members of
+    group: Option<i64>,
+}
+
+/// A user and their roles
+#[derive(Serialize, Deserialize)]
+pub struct UserDetails {
+    #[serde(flatten)]
+    pub user: User,
+    pub roles: Vec<Role>,
+}
+
+/// A user to create
+#[derive(Deserialize)]
+pub struct CreateUserRequest {
+    pub email: String,
+    pub display_name: String,
+    /// The temporary password of the user, generated when missing
+    pub password: Option<String>,
+    #[serde(default)]
+    pub roles: Vec<Role>,
+    /// Whether the user must change their password, by default
+    #[serde(default = "default_must_change_password")]
+    pub must_change_password: bool,
+}
+
+fn default_must_change_password() -> bool {
+    true
+}
+
+/// A created user
+#[derive(Serialize, Deserialize)]
+pub struct CreatedUser {
+    pub user: UserDetails,
+    /// The generated password, only returned when none was given
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub temporary_password: Option<String>,
+}
+
+/// The changes to a user, the missing fields being kept
+#[derive(Deserialize)]
+pub struct UpdateUserRequest {
+    pub email: Option<String>,
+    pub display_name: Option<String>,
+    pub status: Option<UserStatus>,
+    pub roles: Option<Vec<Role>>,
+}
+
+/// `GET /admin/users?status=...&q=...&group=...`: list the users
+pub async fn list_users(
+    State(state): State<AppState>,
+    format: Format,
+    Query(query): Query<UserQuery>,
+    Query(pagination): Query<Pagination>,
+) -> Result<Negotiated<Vec<User>>, AppError> {
+    let filter = UserFilter {
+        status: query.status,
+        search: query.q.filter(|q| !q.trim().is_empty()),
+        group: query.group,
+    };
+    let users = UserRepository::new(state.read_pool().clone())
+        .list(&filter, pagination.limit(), pagination.offset())
+        .await?;
+    Ok(Negotiated(format, users))
+}
+
+/// `GET /admin/users/:id`: a user and their roles
+pub async fn get_user(
+    State(state): State<AppState>,
+    format: Format,
+    Path(user_id): Path<i64>,
+) -> Result<Negotiated<UserDetails>, AppError> {
+    let user = UserRepository::new(state.read_pool().clone())
+        .find_by_id(user_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let roles = RoleRepository::new(state.read_pool().clone())
+        .roles_of(user_id)
+        .await?;
+    Ok(Negotiated(format, UserDetails { user, roles }))
+}
+
+/// `POST /admin/users`: create an active user, with a temporary password
+pub async fn create_user(
+    State(state): State<AppState>,
+    Negotiated(format, request): Negotiated<CreateUserRequest>,
+) -> Result<(StatusCode, NoStore, Negotiated<CreatedUser>), AppError> {
+    let email = request.email.trim();
+    let mut violations = Vec::new();
+    if !is_valid_email(email) {
+        violations.push("the email is invalid".to_string());
+    }
+    if !is_valid_display_name(&request.display_name) {
+        violations.push(format!(
+            "the display name must have between 1 and {} characters",
+            MAX_DISPLAY_NAME_LENGTH
+        ));
+    }
+    if !violations.is_empty() {
+        return Err(AppError::BadRequest(violations.join("; ")));
+    }
+    if let Some(password) = &request.password {
+        state
+            .config
+            .password_policy
+            .enforce(password, &UserContext { email })
+            .await?;
+    }
+
+    let temporary_password = match request.password {
+        Some(_) => None,
+        None => Some(password::generate(&state.config.password_policy)),
+    };
+    let plain = request
+        .password
+        .as_deref()
+        .or(temporary_password.as_deref())
+        .unwrap_or_default();
+    let password_hash = password::hash(plain, &state.config.password).await?;
+
+    let user = UserRepository::new(state.write_pool().clone())
+        .create(NewUser {
+            email: email.to_string(),
+            display_name: request.display_name.trim().to_string(),
+            password_hash,
+            status: UserStatus::Active,
+            must_change_password: request.must_change_password,
+            auth_source: AuthSource::Local,
+        })
+        .await?;
+    let role_repository = RoleRepository::new(state.write_pool().clone());
+    if !request.roles.is_empty() {
+        role_repository.set_roles(user.id, &request.roles).await?;
+    }
+    let roles = role_repository.roles_of(user.id).await?;
+    state
+        .events
+        .publish(AdminEvent::UserCreated { user_id: user.id });
+
+    Ok((
+        StatusCode::CREATED,
+        NoStore,
+        Negotiated(
+            format,
+            CreatedUser {
+                user: UserDetails { user, roles },
+                temporary_password,
+            },
+        ),
+    ))
+}
+
+/// `PATCH /admin/users/:id`: change the email, display name, status or roles of a user
+///
+/// Deactivating a user revokes their sessions. Admins can't change their own status, nor remove
+/// their own `admin` role.
+pub async fn update_user(
+    State(state): State<AppState>,
+    OptionalUser(actor): OptionalUser,
+    Path(user_id): Path<i64>,
+    Negotiated(format, request): Negotiated<UpdateUserRequest>,
+) -> Result<(Option<AuditOperation>, Negotiated<UserDetails>), AppError> {
+    let repository = UserRepository::new(state.write_pool().clone());
+    let role_repository = RoleRepository::new(state.write_pool().clone());
+    let mut user = repository
+        .find_by_id(user_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let is_self = actor.is_some_and(|actor| actor.id == user_id);
+
+    let mut violations = Vec::new();
+    if let Some(email) = &request.email {
+        if !is_valid_email(email) {
+            violations.push("the email is invalid".to_string());
+        }
+    }
+    if let Some(display_name) = &request.display_name {
+        if !is_valid_display_name(display_name) {
+            violations.push(format!(
+                "the display name must have between 1 and {} characters",
+                MAX_DISPLAY_NAME_LENGTH
+            ));
+        }
+    }
+    if !violations.is_empty() {
+        return Err(AppError::BadRequest(violations.join("; ")));
+    }
+    if is_self && request.status.is_some_and(|status| status != user.status) {
+        return Err(AppError::Conflict(
+            "You can't change your own status".to_string(),
+        ));
+    }
+    if let Some(roles) = &request.roles {
+        if is_self {
+            let current = role_repository.roles_of(user_id).await?;
+            roles::refuse_self_demotion(&current, roles)?;
+        }
+    }
+
+    let previous_status = user.status;
+    if let Some(email) = request.email {
+        user.email = email.trim().to_string();
+    }
+    if let Some(display_name) = request.display_name {
+        user.display_name = display_name.trim().to_string();
+    }
+    if let Some(status) = request.status {
+        user.status = status;
+    }
+    let user = repository.update(&user).await?;
+    if let Some(roles) = &request.roles {
+        role_repository.set_roles(user_id, roles).await?;
+    }
+    let mut operation = None;
+    if previous_status == UserStatus::Active && user.status != UserStatus::Active {
+        operation = Some(revoke_sessions(&state, user_id).await?);
+    }
+
+    let roles = role_repository.roles_of(user_id).await?;
+    Ok((operation, Negotiated(format, UserDetails { user, roles })))
+}
+
+/// `DELETE /admin/users/:id`: deactivate a user, revoking their sessions
+///
+/// The user is kept, for the audit log and their data to still refer to them.
+pub async fn deactivate_user(
+    State(state): State<AppState>,
+    OptionalUser(actor): OptionalUser,
+    AuditActor(audit_actor): AuditActor,
+    Path(user_id): Path<i64>,
+) -> Result<(AuditOperation, StatusCode), AppError> {
+    if actor.is_some_and(|actor| actor.id == user_id) {
+        return Err(AppError::Conflict(
+            "You can't deactivate your own account".to_string(),
+        ));
+    }
+
+    let repository = UserRepository::new(state.write_pool().clone());
+    let mut user = repository
+        .find_by_id(user_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    if user.status != UserStatus::Disabled {
+        let previous = user.status;
+        user.status = UserStatus::Disabled;
+        repository.update(&user).await?;
+        state.audit.record(
+            audit_actor,
+            "user.deactivated",
+            &user_id.to_string(),
+            serde_json::json!({ "previous_status": previous }),
+        );
+    }
+    let operation = revoke_sessions(&state, user_id).await?;
+
+    Ok((operation, StatusCode::NO_CONTENT))
+}
+
+/// Delete the sessions of a deactivated user
+///
+/// Their sessions are rejected anyway, as only active users are loaded, but this also frees them
+/// from the store.
+async fn revoke_sessions(state: &AppState, user_id: i64) -> Result<AuditOperation, AppError> {
+    let revoked = state.sessions.revoke_user(user_id).await?;
+    tracing::info!(
+        "Revoked {} session(s) of deactivated user {}",
+        revoked,
+        user_id
+    );
+    if revoked > 0 {
+        state
+            .events
+            .publish(AdminEvent::SessionsRevoked { user_id, revoked });
+    }
+    Ok(AuditOperation::new("sessions.revoke_user", revoked))
+}
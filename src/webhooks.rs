@@ -0,0 +1,831 @@
+//! Webhooks notified of the events of the backend
+//! External systems register a URL through `/admin/webhooks` to be notified of the
+//! [`AdminEvent`]s, all of them or only some types (e.g. `user_created`, `account_locked` or
+//! `sessions_revoked`). Every event is `POST`ed as JSON to the enabled webhooks wanting it:
+//!
+//! ```json
+//! {"type": "account_locked", "created_at": 1718000000, "data": {"type": "account_locked", ...}}
+//! ```
+//!
+//! The `X-Webhook-Signature` header of a delivery is `sha256=` followed by the hex HMAC-SHA256,
+//! keyed with the secret of the webhook, of the `X-Webhook-Timestamp` header (unix seconds), a
+//! dot, and the body. Receivers should check it, and reject old timestamps to prevent replays.
+//!
+//! A delivery answered with anything but a `2xx`, or failing, is retried after
+//! `WEBHOOK_RETRY_BASE_SECS`, doubled after every attempt, up to `WEBHOOK_MAX_ATTEMPTS`. Every
+//! attempt is recorded with its status, latency and the beginning of the response, and kept for
+//! `WEBHOOK_DELIVERY_RETENTION_DAYS`. The admins can deliver a recorded payload again.
+//!
+//! Not to let the webhooks reach the internal services (server-side request forgery), the
+//! deliveries are refused to the loopback, private, link-local (including the metadata service
+//! of the cloud providers), multicast and NAT64 addresses, unless listed in
+//! `WEBHOOK_ALLOWED_NETWORKS`. IPv4-mapped IPv6 addresses are checked as the IPv4 address they
+//! reach. The addresses are checked once the host is resolved, and redirects are not followed.
+
+use std::{
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use time::OffsetDateTime;
+use tokio::{sync::broadcast::error::RecvError, task::JoinSet};
+use url::{Host, Url};
+
+use crate::{
+    admin::Pagination,
+    audit::AuditActor,
+    config::WebhookConfig,
+    database::{with_pool, SqlxPool},
+    error::AppError,
+    events::AdminEvent,
+    forwarded::IpNetwork,
+    http_client,
+    idempotency::NoStore,
+    negotiate::{Format, Negotiated},
+    state::AppState,
+};
+
+/// The maximum number of characters of the URL of a webhook
+const MAX_URL_LENGTH: usize = 2048;
+
+/// The number of bytes of the responses kept with the deliveries
+const RESPONSE_SNIPPET_BYTES: usize = 512;
+
+/// The number of random bytes of a secret, hex-encoded
+const SECRET_LEN: usize = 32;
+
+/// The addresses the webhooks can't be delivered to, unless allowed
+const DENIED_NETWORKS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "::ffff:0:0/96",
+    "64:ff9b::/96",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// The columns of the `webhooks` table, in the order of [`WebhookRow`]
+const COLUMNS: &str = "id, url, secret, enabled, events, created_at, updated_at";
+
+/// The columns of the `webhook_deliveries` table, in the order of [`Delivery`]
+const DELIVERY_COLUMNS: &str =
+    "id, webhook_id, event, payload, attempt, status_code, error, latency_ms, response, created_at";
+
+/// A webhook, as stored
+#[derive(sqlx::FromRow)]
+struct WebhookRow {
+    id: i64,
+    url: String,
+    secret: String,
+    enabled: bool,
+    events: String,
+    created_at: i64,
+    updated_at: i64,
+}
+
+/// A webhook notified of the events
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: i64,
+    pub url: String,
+    /// The key of the signatures of the deliveries, only shown once created
+    #[serde(skip_serializing, default)]
+    pub secret: String,
+    pub enabled: bool,
+    /// The types of the events delivered to the webhook, every type when empty
+    pub events: Vec<String>,
+    /// When the webhook was created, in unix seconds
+    pub created_at: i64,
+    /// When the webhook was last changed, in unix seconds
+    pub updated_at: i64,
+}
+
+impl From<WebhookRow> for Webhook {
+    fn from(row: WebhookRow) -> Self {
+        let events = serde_json::from_str(&row.events).unwrap_or_else(|err| {
+            tracing::warn!("Ignoring the invalid events of webhook {}: {}", row.id, err);
+            Vec::new()
+        });
+        Self {
+            id: row.id,
+            url: row.url,
+            secret: row.secret,
+            enabled: row.enabled,
+            events,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+        }
+    }
+}
+
+impl Webhook {
+    /// Whether the events of this type are delivered to the webhook
+    pub fn wants(&self, event_type: &str) -> bool {
+        self.enabled && (self.events.is_empty() || self.events.iter().any(|e| e == event_type))
+    }
+}
+
+/// An attempt to deliver an event to a webhook
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Delivery {
+    pub id: i64,
+    pub webhook_id: i64,
+    /// The type of the event
+    pub event: String,
+    /// The body sent to the webhook
+    pub payload: String,
+    /// The number of the attempt, from 1
+    pub attempt: i32,
+    /// The status of the response, unless the delivery failed before
+    pub status_code: Option<i32>,
+    /// Why the delivery failed, if it did
+    pub error: Option<String>,
+    /// How long the delivery took, in milliseconds
+    pub latency_ms: i64,
+    /// The beginning of the body of the response, if any
+    pub response: Option<String>,
+    /// When the attempt was made, in unix seconds
+    pub created_at: i64,
+}
+
+/// How a delivery went
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeliveryOutcome {
+    /// The status of the response, unless the delivery failed before
+    pub status_code: Option<i32>,
+    /// Why the delivery failed, if it did
+    pub error: Option<String>,
+    /// How long the delivery took, in milliseconds
+    pub latency_ms: i64,
+    /// The beginning of the body of the response, if any
+    pub response: Option<String>,
+}
+
+impl DeliveryOutcome {
+    /// Whether the webhook accepted the delivery
+    pub fn is_success(&self) -> bool {
+        self.status_code
+            .is_some_and(|status| (200..300).contains(&status))
+    }
+}
+
+/// The webhooks and their deliveries, stored in the database
+#[derive(Clone, Debug)]
+pub struct WebhookRepository {
+    pool: SqlxPool,
+}
+
+impl WebhookRepository {
+    pub fn new(pool: SqlxPool) -> Self {
+        Self { pool }
+    }
+
+    /// Create a webhook, with a new secret
+    pub async fn create(
+        &self,
+        url: &str,
+        events: &[String],
+        enabled: bool,
+    ) -> Result<Webhook, sqlx::Error> {
+        let insert = self.pool.sql(
+            "INSERT INTO webhooks (url, secret, enabled, events, created_at, updated_at) \
+             VALUES (?, ?, ?, ?, ?, ?)",
+        );
+        let select = self
+            .pool
+            .sql(&format!("SELECT {COLUMNS} FROM webhooks WHERE secret = ?"))
+            .into_owned();
+        let secret = generate_secret();
+        let events = json!(events).to_string();
+        let now = now();
+
+        let row: WebhookRow = with_pool!(&self.pool, |p| {
+            sqlx::query(&insert)
+                .bind(url)
+                .bind(&secret)
+                .bind(enabled)
+                .bind(&events)
+                .bind(now)
+                .bind(now)
+                .execute(p)
+                .await?;
+            sqlx::query_as(&select).bind(&secret).fetch_one(p).await?
+        });
+        Ok(row.into())
+    }
+
+    /// The webhooks, by ID
+    pub async fn list(&self, limit: i64, offset: i64) -> Result<Vec<Webhook>, sqlx::Error> {
+        let sql = self
+            .pool
+            .sql(&format!(
+                "SELECT {COLUMNS} FROM webhooks ORDER BY id LIMIT ? OFFSET ?"
+            ))
+            .into_owned();
+        let rows: Vec<WebhookRow> = with_pool!(&self.pool, |p| sqlx::query_as(&sql)
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(p)
+            .await)?;
+        Ok(rows.into_iter().map(Webhook::from).collect())
+    }
+
+    /// The enabled webhooks
+    pub async fn list_enabled(&self) -> Result<Vec<Webhook>, sqlx::Error> {
+        let sql = self
+            .pool
+            .sql(&format!("SELECT {COLUMNS} FROM webhooks WHERE enabled = ?"))
+            .into_owned();
+        let rows: Vec<WebhookRow> = with_pool!(&self.pool, |p| sqlx::query_as(&sql)
+            .bind(true)
+            .fetch_all(p)
+            .await)?;
+        Ok(rows.into_iter().map(Webhook::from).collect())
+    }
+
+    pub async fn find(&self, id: i64) -> Result<Option<Webhook>, sqlx::Error> {
+        let sql = self
+            .pool
+            .sql(&format!("SELECT {COLUMNS} FROM webhooks WHERE id = ?"))
+            .into_owned();
+        let row: Option<WebhookRow> = with_pool!(&self.pool, |p| sqlx::query_as(&sql)
+            .bind(id)
+            .fetch_optional(p)
+            .await)?;
+        Ok(row.map(Webhook::from))
+    }
+
+    /// Save the URL, events and status of a webhook, returning whether it exists
+    pub async fn update(&self, webhook: &mut Webhook) -> Result<bool, sqlx::Error> {
+        let sql = self.pool.sql(
+            "UPDATE webhooks SET url = ?, enabled = ?, events = ?, updated_at = ? WHERE id = ?",
+        );
+        webhook.updated_at = now();
+        let events = json!(webhook.events).to_string();
+        let updated = with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(&webhook.url)
+            .bind(webhook.enabled)
+            .bind(&events)
+            .bind(webhook.updated_at)
+            .bind(webhook.id)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))?;
+        Ok(updated > 0)
+    }
+
+    /// Delete a webhook and its deliveries, returning whether it existed
+    pub async fn delete(&self, id: i64) -> Result<bool, sqlx::Error> {
+        let delete_deliveries = self
+            .pool
+            .sql("DELETE FROM webhook_deliveries WHERE webhook_id = ?");
+        let delete = self.pool.sql("DELETE FROM webhooks WHERE id = ?");
+
+        Ok(with_pool!(&self.pool, |p| {
+            let mut tx = p.begin().await?;
+            sqlx::query(&delete_deliveries)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            let deleted = sqlx::query(&delete)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+            tx.commit().await?;
+            deleted > 0
+        }))
+    }
+
+    /// Record an attempt to deliver an event
+    pub async fn record_delivery(
+        &self,
+        webhook_id: i64,
+        event: &str,
+        payload: &str,
+        attempt: i32,
+        outcome: &DeliveryOutcome,
+    ) -> Result<(), sqlx::Error> {
+        let sql = self.pool.sql(
+            "INSERT INTO webhook_deliveries \
+             (webhook_id, event, payload, attempt, status_code, error, latency_ms, response, \
+             created_at) \
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        );
+        with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(webhook_id)
+            .bind(event)
+            .bind(payload)
+            .bind(attempt)
+            .bind(outcome.status_code)
+            .bind(&outcome.error)
+            .bind(outcome.latency_ms)
+            .bind(&outcome.response)
+            .bind(now())
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))?;
+        Ok(())
+    }
+
+    /// The deliveries to a webhook, most recent first
+    pub async fn deliveries(
+        &self,
+        webhook_id: i64,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Delivery>, sqlx::Error> {
+        let sql = self
+            .pool
+            .sql(&format!(
+                "SELECT {DELIVERY_COLUMNS} FROM webhook_deliveries WHERE webhook_id = ? \
+                 ORDER BY id DESC LIMIT ? OFFSET ?"
+            ))
+            .into_owned();
+        with_pool!(&self.pool, |p| sqlx::query_as(&sql)
+            .bind(webhook_id)
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(p)
+            .await)
+    }
+
+    /// A delivery to a webhook
+    pub async fn find_delivery(
+        &self,
+        webhook_id: i64,
+        id: i64,
+    ) -> Result<Option<Delivery>, sqlx::Error> {
+        let sql = self
+            .pool
+            .sql(&format!(
+                "SELECT {DELIVERY_COLUMNS} FROM webhook_deliveries WHERE webhook_id = ? AND id = ?"
+            ))
+            .into_owned();
+        with_pool!(&self.pool, |p| sqlx::query_as(&sql)
+            .bind(webhook_id)
+            .bind(id)
+            .fetch_optional(p)
+            .await)
+    }
+
+    /// Forget the deliveries made before the cutoff (unix seconds), returning how many were
+    pub async fn purge_deliveries_before(&self, cutoff: i64) -> Result<u64, sqlx::Error> {
+        let sql = self
+            .pool
+            .sql("DELETE FROM webhook_deliveries WHERE created_at < ?");
+        with_pool!(&self.pool, |p| sqlx::query(&sql)
+            .bind(cutoff)
+            .execute(p)
+            .await
+            .map(|r| r.rows_affected()))
+    }
+}
+
+/// A new random secret
+fn generate_secret() -> String {
+    let mut bytes = [0u8; SECRET_LEN];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// The signature of a delivery, as sent in the `X-Webhook-Signature` header
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Whether the webhooks may be delivered to an address
+///
+/// IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) are checked as the IPv4 address they reach.
+pub fn is_allowed(addr: IpAddr, allowed: &[IpNetwork]) -> bool {
+    let addr = match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+        IpAddr::V4(_) => addr,
+    };
+    allowed.iter().any(|network| network.contains(addr))
+        || !DENIED_NETWORKS
+            .iter()
+            .filter_map(|network| network.parse::<IpNetwork>().ok())
+            .any(|network| network.contains(addr))
+}
+
+/// Send a payload to a webhook once
+pub async fn send(
+    config: &WebhookConfig,
+    webhook: &Webhook,
+    event_type: &str,
+    payload: &str,
+) -> DeliveryOutcome {
+    let started = Instant::now();
+    let timestamp = now();
+    let result = match Url::parse(&webhook.url) {
+        Ok(url) => {
+            let headers = vec![
+                ("X-Webhook-Event", event_type.to_string()),
+                ("X-Webhook-Timestamp", timestamp.to_string()),
+                (
+                    "X-Webhook-Signature",
+                    sign(&webhook.secret, timestamp, payload),
+                ),
+            ];
+            let allowed = config.allowed_networks.clone();
+            http_client::post_json(
+                &url,
+                payload.to_string(),
+                headers,
+                Arc::new(move |addr| is_allowed(addr, &allowed)),
+                config.timeout,
+            )
+            .await
+        }
+        Err(err) => Err(err.into()),
+    };
+    let latency_ms = started.elapsed().as_millis() as i64;
+
+    match result {
+        Ok(response) => {
+            let end = response.body.len().min(RESPONSE_SNIPPET_BYTES);
+            let snippet = String::from_utf8_lossy(&response.body[..end]).into_owned();
+            DeliveryOutcome {
+                status_code: Some(response.status as i32),
+                error: None,
+                latency_ms,
+                response: Some(snippet).filter(|snippet| !snippet.is_empty()),
+            }
+        }
+        Err(err) => DeliveryOutcome {
+            status_code: None,
+            error: Some(format!("{:#}", err)),
+            latency_ms,
+            response: None,
+        },
+    }
+}
+
+/// Deliver a payload to a webhook, retrying with an exponential backoff, and record every attempt
+async fn deliver(state: AppState, webhook: Webhook, event_type: &'static str, payload: String) {
+    let config = &state.config.webhook;
+    let repository = WebhookRepository::new(state.write_pool().clone());
+    let mut delay = config.retry_base;
+    for attempt in 1..=config.max_attempts {
+        let outcome = send(config, &webhook, event_type, &payload).await;
+        if let Err(err) = repository
+            .record_delivery(webhook.id, event_type, &payload, attempt as i32, &outcome)
+            .await
+        {
+            tracing::warn!(
+                "Failed to record a delivery to webhook {}: {}",
+                webhook.id,
+                err
+            );
+        }
+        if outcome.is_success() {
+            return;
+        }
+        if attempt < config.max_attempts {
+            tokio::time::sleep(delay).await;
+            delay = delay.saturating_mul(2);
+        }
+    }
+    tracing::warn!(
+        "Gave up delivering a {} event to webhook {} after {} attempt(s)",
+        event_type,
+        webhook.id,
+        config.max_attempts
+    );
+}
+
+/// Deliver the events to the webhooks wanting them, forever
+///
+/// The deliveries, retries included, run concurrently within this task.
+pub async fn continuously_deliver(state: AppState) {
+    let mut receiver = state.events.subscribe();
+    let mut deliveries = JoinSet::new();
+    loop {
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Ok(event) => dispatch(&state, &mut deliveries, &event).await,
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("Missed {} event(s) to deliver to the webhooks", missed);
+                }
+                Err(RecvError::Closed) => return,
+            },
+            Some(result) = deliveries.join_next(), if !deliveries.is_empty() => {
+                if let Err(err) = result {
+                    tracing::error!("A webhook delivery failed: {}", err);
+                }
+            }
+        }
+    }
+}
+
+/// Start delivering an event to the webhooks wanting it
+async fn dispatch(state: &AppState, deliveries: &mut JoinSet<()>, event: &AdminEvent) {
+    let event_type = event.event_type();
+    let webhooks = match WebhookRepository::new(state.read_pool().clone())
+        .list_enabled()
+        .await
+    {
+        Ok(webhooks) => webhooks,
+        Err(err) => {
+            tracing::warn!(
+                "Failed to list the webhooks of a {} event: {}",
+                event_type,
+                err
+            );
+            return;
+        }
+    };
+    let payload = json!({
+        "type": event_type,
+        "created_at": now(),
+        "data": event,
+    })
+    .to_string();
+    for webhook in webhooks
+        .into_iter()
+        .filter(|webhook| webhook.wants(event_type))
+    {
+        deliveries.spawn(deliver(state.clone(), webhook, event_type, payload.clone()));
+    }
+}
+
+/// Forget the deliveries older than the retention, forever at the given interval
+pub async fn continuously_purge(pool: SqlxPool, retention: Duration, period: Duration) {
+    let repository = WebhookRepository::new(pool);
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let cutoff = now() - retention.as_secs() as i64;
+        if let Err(err) = repository.purge_deliveries_before(cutoff).await {
+            tracing::warn!("Failed to purge the webhook deliveries: {}", err);
+        }
+    }
+}
+
+/// A webhook to create
+#[derive(Deserialize)]
+pub struct CreateWebhookRequest {
+    pub url: String,
+    #[serde(default)]
+    pub events: Vec<String>,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+/// The changes to a webhook, the missing fields being kept
+#[derive(Deserialize)]
+pub struct UpdateWebhookRequest {
+    pub url: Option<String>,
+    pub events: Option<Vec<String>>,
+    pub enabled: Option<bool>,
+}
+
+/// A created webhook, with its secret
+#[derive(Serialize, Deserialize)]
+pub struct CreatedWebhook {
+    #[serde(flatten)]
+    pub webhook: Webhook,
+    /// The key of the signatures of the deliveries, not shown again
+    pub secret: String,
+}
+
+/// Check the URL and events of a webhook
+fn validate(
+    config: &WebhookConfig,
+    url: Option<&str>,
+    events: Option<&[String]>,
+) -> Result<(), AppError> {
+    let mut violations = Vec::new();
+    if url.is_some_and(|url| url.chars().count() > MAX_URL_LENGTH) {
+        violations.push(format!(
+            "the URL must have at most {} characters",
+            MAX_URL_LENGTH
+        ));
+    } else if let Some(url) = url {
+        match Url::parse(url) {
+            Ok(url) if !matches!(url.scheme(), "http" | "https") => {
+                violations.push("the URL must be http or https".to_string())
+            }
+            Ok(url) => {
+                let addr: Option<IpAddr> = match url.host() {
+                    Some(Host::Ipv4(addr)) => Some(addr.into()),
+                    Some(Host::Ipv6(addr)) => Some(addr.into()),
+                    Some(Host::Domain(_)) => None,
+                    None => {
+                        violations.push("the URL must have a host".to_string());
+                        None
+                    }
+                };
+                // The names are checked once resolved, on delivery
+                if addr.is_some_and(|addr| !is_allowed(addr, &config.allowed_networks)) {
+                    violations.push("the address of the URL is denied".to_string());
+                }
+            }
+            Err(err) => violations.push(format!("the URL is invalid: {}", err)),
+        }
+    }
+    for event in events.unwrap_or_default() {
+        if !AdminEvent::TYPES.contains(&event.as_str()) {
+            violations.push(format!("unknown event type `{}`", event));
+        }
+    }
+    if !violations.is_empty() {
+        return Err(AppError::BadRequest(violations.join("; ")));
+    }
+    Ok(())
+}
+
+/// `GET /admin/webhooks`: list the webhooks
+pub async fn list_webhooks(
+    State(state): State<AppState>,
+    format: Format,
+    Query(pagination): Query<Pagination>,
+) -> Result<Negotiated<Vec<Webhook>>, AppError> {
+    let webhooks = WebhookRepository::new(state.read_pool().clone())
+        .list(pagination.limit(), pagination.offset())
+        .await?;
+    Ok(Negotiated(format, webhooks))
+}
+
+/// `GET /admin/webhooks/:id`: a webhook
+pub async fn get_webhook(
+    State(state): State<AppState>,
+    format: Format,
+    Path(id): Path<i64>,
+) -> Result<Negotiated<Webhook>, AppError> {
+    let webhook = WebhookRepository::new(state.read_pool().clone())
+        .find(id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    Ok(Negotiated(format, webhook))
+}
+
+/// `POST /admin/webhooks`: create a webhook, returning its secret once
+pub async fn create_webhook(
+    State(state): State<AppState>,
+    AuditActor(audit_actor): AuditActor,
+    Negotiated(format, request): Negotiated<CreateWebhookRequest>,
+) -> Result<(StatusCode, NoStore, Negotiated<CreatedWebhook>), AppError> {
+    let url = request.url.trim();
+    validate(&state.config.webhook, Some(url), Some(&request.events))?;
+
+    let mut events = request.events;
+    events.sort();
+    events.dedup();
+    let webhook = WebhookRepository::new(state.write_pool().clone())
+        .create(url, &events, request.enabled)
+        .await?;
+    state.audit.record(
+        audit_actor,
+        "webhook.created",
+        &webhook.id.to_string(),
+        json!({ "url": webhook.url, "events": webhook.events, "enabled": webhook.enabled }),
+    );
+
+    Ok((
+        StatusCode::CREATED,
+        NoStore,
+        Negotiated(
+            format,
+            CreatedWebhook {
+                secret: webhook.secret.clone(),
+                webhook,
+            },
+        ),
+    ))
+}
+
+/// `PATCH /admin/webhooks/:id`: change the URL, events or status of a webhook
+pub async fn update_webhook(
+    State(state): State<AppState>,
+    AuditActor(audit_actor): AuditActor,
+    Path(id): Path<i64>,
+    Negotiated(format, request): Negotiated<UpdateWebhookRequest>,
+) -> Result<Negotiated<Webhook>, AppError> {
+    let url = request.url.as_deref().map(str::trim);
+    validate(&state.config.webhook, url, request.events.as_deref())?;
+
+    let repository = WebhookRepository::new(state.write_pool().clone());
+    let mut webhook = repository.find(id).await?.ok_or(AppError::NotFound)?;
+    if let Some(url) = url {
+        webhook.url = url.to_string();
+    }
+    if let Some(mut events) = request.events {
+        events.sort();
+        events.dedup();
+        webhook.events = events;
+    }
+    if let Some(enabled) = request.enabled {
+        webhook.enabled = enabled;
+    }
+    if !repository.update(&mut webhook).await? {
+        return Err(AppError::NotFound);
+    }
+    state.audit.record(
+        audit_actor,
+        "webhook.updated",
+        &id.to_string(),
+        json!({ "url": webhook.url, "events": webhook.events, "enabled": webhook.enabled }),
+    );
+    Ok(Negotiated(format, webhook))
+}
+
+/// `DELETE /admin/webhooks/:id`: delete a webhook and its deliveries
+pub async fn delete_webhook(
+    State(state): State<AppState>,
+    AuditActor(audit_actor): AuditActor,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, AppError> {
+    if !WebhookRepository::new(state.write_pool().clone())
+        .delete(id)
+        .await?
+    {
+        return Err(AppError::NotFound);
+    }
+    state
+        .audit
+        .record(audit_actor, "webhook.deleted", &id.to_string(), json!({}));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `GET /admin/webhooks/:id/deliveries`: list the deliveries to a webhook, most recent first
+pub async fn list_deliveries(
+    State(state): State<AppState>,
+    format: Format,
+    Path(id): Path<i64>,
+    Query(pagination): Query<Pagination>,
+) -> Result<Negotiated<Vec<Delivery>>, AppError> {
+    let repository = WebhookRepository::new(state.read_pool().clone());
+    repository.find(id).await?.ok_or(AppError::NotFound)?;
+    let deliveries = repository
+        .deliveries(id, pagination.limit(), pagination.offset())
+        .await?;
+    Ok(Negotiated(format, deliveries))
+}
+
+/// `POST /admin/webhooks/:id/deliveries/:delivery_id/redeliver`: send the payload of a delivery
+/// again, once, recording the attempt
+pub async fn redeliver(
+    State(state): State<AppState>,
+    AuditActor(audit_actor): AuditActor,
+    format: Format,
+    Path((id, delivery_id)): Path<(i64, i64)>,
+) -> Result<Negotiated<DeliveryOutcome>, AppError> {
+    let repository = WebhookRepository::new(state.write_pool().clone());
+    let webhook = repository.find(id).await?.ok_or(AppError::NotFound)?;
+    let delivery = repository
+        .find_delivery(id, delivery_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    let outcome = send(
+        &state.config.webhook,
+        &webhook,
+        &delivery.event,
+        &delivery.payload,
+    )
+    .await;
+    repository
+        .record_delivery(id, &delivery.event, &delivery.payload, 1, &outcome)
+        .await?;
+    state.audit.record(
+        audit_actor,
+        "webhook.redelivered",
+        &id.to_string(),
+        json!({ "delivery_id": delivery_id, "status_code": outcome.status_code }),
+    );
+    Ok(Negotiated(format, outcome))
+}
+
+/// The current time, in unix seconds
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
@@ -0,0 +1,230 @@
+mod common;
+
+use administration_center_api::{
+    api_keys::{ApiKeyRepository, NewApiKey},
+    permissions::{Permission, PermissionRepository},
+    roles::{Role, RoleRepository},
+};
+use axum::{
+    body::{to_bytes, Body},
+    http::{header, StatusCode},
+};
+use serde_json::json;
+use time::OffsetDateTime;
+
+use common::TestApp;
+
+const API_KEYS: &str = "/api/v1/admin/api-keys";
+const STATS: &str = "/api/v1/admin/stats/sessions";
+
+/// Create a key through the API with an admin key, returning its ID and the key itself
+async fn create(app: &TestApp, admin_key: &str, scopes: &[&str]) -> (i64, String) {
+    let req = common::json_request(
+        "POST",
+        API_KEYS,
+        admin_key,
+        &json!({ "name": "ci", "scopes": scopes }),
+    );
+    let response = app.request(req).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let created = common::json(response).await;
+    assert_eq!(created["api_key"]["scopes"], json!(scopes));
+    (
+        created["api_key"]["id"].as_i64().unwrap(),
+        created["key"].as_str().unwrap().to_string(),
+    )
+}
+
+async fn get(app: &TestApp, uri: &str, api_key: &str) -> StatusCode {
+    let req = common::authenticated("GET", uri, api_key)
+        .body(Body::empty())
+        .unwrap();
+    app.request(req).await.status()
+}
+
+/// A service key managing the keys, holding the scope of the keys it creates
+async fn admin_key(app: &TestApp) -> String {
+    app.api_key(&[Permission::API_KEYS_MANAGE, Permission::STATS_READ])
+        .await
+}
+
+#[tokio::test]
+async fn creates_uses_and_revokes_a_key() {
+    let app = common::spawn().await;
+    let admin_key = admin_key(&app).await;
+    let (id, key) = create(&app, &admin_key, &["stats.read"]).await;
+    assert!(key.starts_with("ack_"));
+
+    assert_eq!(get(&app, STATS, &key).await, StatusCode::OK);
+    // Also accepted as a bearer token
+    let req = axum::http::Request::get(STATS)
+        .header(header::AUTHORIZATION, format!("Bearer {}", key))
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(app.request(req).await.status(), StatusCode::OK);
+
+    let req = common::authenticated("DELETE", &format!("{}/{}", API_KEYS, id), &admin_key)
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(app.request(req).await.status(), StatusCode::NO_CONTENT);
+    assert_eq!(get(&app, STATS, &key).await, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn only_grants_the_scopes_of_the_key() {
+    let app = common::spawn().await;
+    let admin_key = admin_key(&app).await;
+    let (_, key) = create(&app, &admin_key, &["stats.read"]).await;
+
+    assert_eq!(get(&app, API_KEYS, &key).await, StatusCode::FORBIDDEN);
+    let manager_key = app.api_key(&[Permission::API_KEYS_MANAGE]).await;
+    assert_eq!(get(&app, STATS, &manager_key).await, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn refuses_the_expired_keys() {
+    let app = common::spawn().await;
+    let admin_key = admin_key(&app).await;
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+
+    // Can't be created already expired
+    let req = common::json_request(
+        "POST",
+        API_KEYS,
+        &admin_key,
+        &json!({ "name": "ci", "scopes": ["stats.read"], "expires_at": "2000-01-01T00:00:00Z" }),
+    );
+    assert_eq!(app.request(req).await.status(), StatusCode::BAD_REQUEST);
+
+    let keys = ApiKeyRepository::new(app.pool.clone());
+    for (expires_at, status) in [
+        (now - 1, StatusCode::UNAUTHORIZED),
+        (now + 3600, StatusCode::OK),
+    ] {
+        let (_, key) = keys
+            .create(NewApiKey {
+                name: "ci".to_string(),
+                user_id: None,
+                scopes: vec![Permission::STATS_READ],
+                expires_at: Some(expires_at),
+            })
+            .await
+            .unwrap();
+        assert_eq!(get(&app, STATS, &key).await, status, "{}", expires_at - now);
+    }
+}
+
+#[tokio::test]
+async fn never_lists_the_keys_themselves() {
+    let app = common::spawn().await;
+    let admin_key = admin_key(&app).await;
+    let (id, key) = create(&app, &admin_key, &["stats.read"]).await;
+
+    let req = common::authenticated("GET", API_KEYS, &admin_key)
+        .body(Body::empty())
+        .unwrap();
+    let response = app.request(req).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(!body.contains(&key));
+    assert!(!body.contains(&admin_key));
+    assert!(!body.contains("hash"), "{}", body);
+
+    let keys: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let listed = keys
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|listed| listed["id"] == id)
+        .unwrap();
+    // Only the start of the key, to tell it apart
+    assert!(key.starts_with(listed["prefix"].as_str().unwrap()));
+}
+
+#[tokio::test]
+async fn refuses_the_scopes_the_creator_lacks() {
+    let app = common::spawn().await;
+    let manager_key = app.api_key(&[Permission::API_KEYS_MANAGE]).await;
+
+    for scopes in [
+        vec!["users.manage"],
+        vec!["api_keys.manage", "roles.manage"],
+    ] {
+        let req = common::json_request(
+            "POST",
+            API_KEYS,
+            &manager_key,
+            &json!({ "name": "ci", "scopes": scopes }),
+        );
+        let response = app.request(req).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN, "{:?}", scopes);
+    }
+    create(&app, &manager_key, &["api_keys.manage"]).await;
+}
+
+#[tokio::test]
+async fn binds_the_keys_to_other_users_with_users_manage() {
+    let app = common::spawn().await;
+    let admin = app.user("admin@example.com", "Correct-horse-1").await;
+    RoleRepository::new(app.pool.clone())
+        .set_roles(admin.id, &[Role::Admin])
+        .await
+        .unwrap();
+    let body = json!({ "name": "ci", "user_id": admin.id, "scopes": ["stats.read"] });
+
+    let req = common::json_request("POST", API_KEYS, &admin_key(&app).await, &body);
+    assert_eq!(app.request(req).await.status(), StatusCode::FORBIDDEN);
+
+    let users_key = app
+        .api_key(&[
+            Permission::API_KEYS_MANAGE,
+            Permission::STATS_READ,
+            Permission::USERS_MANAGE,
+        ])
+        .await;
+    let req = common::json_request("POST", API_KEYS, &users_key, &body);
+    assert_eq!(app.request(req).await.status(), StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn lets_a_user_create_keys_within_their_permissions() {
+    let app = common::spawn().await;
+    let admin = app.user("admin@example.com", "Correct-horse-1").await;
+    let user = app.user("ops@example.com", "Correct-horse-1").await;
+    let roles = RoleRepository::new(app.pool.clone());
+    roles.set_roles(admin.id, &[Role::Admin]).await.unwrap();
+    roles.set_roles(user.id, &[Role::Operator]).await.unwrap();
+    PermissionRepository::new(app.pool.clone())
+        .set_grants(
+            Role::Operator,
+            &[Permission::API_KEYS_MANAGE, Permission::STATS_READ],
+        )
+        .await
+        .unwrap();
+    let session = app.logged_in("ops@example.com", "Correct-horse-1").await;
+
+    for (body, status) in [
+        (
+            json!({ "name": "mine", "user_id": user.id, "scopes": ["stats.read"] }),
+            StatusCode::CREATED,
+        ),
+        (
+            json!({ "name": "theirs", "user_id": admin.id, "scopes": ["stats.read"] }),
+            StatusCode::FORBIDDEN,
+        ),
+        (
+            json!({ "name": "service", "scopes": ["stats.read", "users.manage"] }),
+            StatusCode::FORBIDDEN,
+        ),
+        (
+            json!({ "name": "service", "scopes": ["stats.read"] }),
+            StatusCode::CREATED,
+        ),
+    ] {
+        let response = app
+            .request(session.json_request("POST", API_KEYS, &body))
+            .await;
+        assert_eq!(response.status(), status, "{}", body);
+    }
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{header, Request, StatusCode},
+};
+
+use common::json;
+
+#[tokio::test]
+async fn answers_the_health_probe() {
+    let app = common::spawn().await;
+    let response = app
+        .request(Request::get("/health").body(Body::empty()).unwrap())
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn keeps_the_counter_in_the_session() {
+    let app = common::spawn().await;
+    let response = app
+        .request(Request::get("/").body(Body::empty()).unwrap())
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let cookie = response.headers()[header::SET_COOKIE]
+        .to_str()
+        .unwrap()
+        .split(';')
+        .next()
+        .unwrap()
+        .to_string();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(&body[..], b"Hello 0!");
+
+    let response = app
+        .request(
+            Request::get("/")
+                .header(header::COOKIE, cookie)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(&body[..], b"Hello 1!");
+}
+
+#[tokio::test]
+async fn answers_unknown_routes_with_a_json_error() {
+    let app = common::spawn().await;
+    let response = app
+        .request(Request::get("/nowhere").body(Body::empty()).unwrap())
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let error = json(response).await;
+    assert_eq!(error["error"]["code"], "not_found");
+    assert_eq!(error["error"]["path"], "/nowhere");
+}
@@ -0,0 +1,126 @@
+mod common;
+
+use administration_center_api::{
+    auth::password,
+    config::{Config, DatabaseUri},
+    database::SqlxPool,
+    roles::{Role, RoleRepository},
+    users::{User, UserFilter, UserRepository},
+};
+use tempfile::TempDir;
+
+const ADMIN_EMAIL: &str = "root@example.com";
+
+/// The configuration of an instance serving on a local port, over the database of the directory
+fn config(dir: &TempDir, password: Option<&str>) -> Config {
+    let mut config = common::config();
+    config.database_uri =
+        DatabaseUri::Sqlite(dir.path().join("test.db").to_string_lossy().into_owned());
+    config.host = "127.0.0.1".to_string();
+    config.port = 0;
+    config.bootstrap.email = ADMIN_EMAIL.to_string();
+    config.bootstrap.password = password.map(str::to_string);
+    config
+}
+
+/// Every user of the database
+async fn users(config: &Config) -> Vec<User> {
+    let pool = SqlxPool::connect(config).await.unwrap();
+    UserRepository::new(pool)
+        .list(&UserFilter::default(), 100, 0)
+        .await
+        .unwrap()
+}
+
+/// Check that the only user is the first admin, returning them
+async fn only_admin(config: &Config) -> User {
+    let users = users(config).await;
+    assert_eq!(users.len(), 1);
+    let admin = users.into_iter().next().unwrap();
+    assert_eq!(admin.email, ADMIN_EMAIL);
+    assert!(admin.must_change_password);
+
+    let pool = SqlxPool::connect(config).await.unwrap();
+    let roles = RoleRepository::new(pool).roles_of(admin.id).await.unwrap();
+    assert!(matches!(roles[..], [Role::Admin]));
+    admin
+}
+
+async fn is_password_of(user: &User, plain: &str, config: &Config) -> bool {
+    password::verify(plain, &user.password_hash, &config.password)
+        .await
+        .unwrap()
+        .is_valid()
+}
+
+#[tokio::test]
+async fn creates_the_admin_with_the_given_password() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = config(&dir, Some("bootstrap password"));
+    administration_center_api::start(config.clone(), None)
+        .await
+        .unwrap();
+
+    let admin = only_admin(&config).await;
+    assert!(is_password_of(&admin, "bootstrap password", &config).await);
+}
+
+#[tokio::test]
+async fn creates_the_admin_with_a_generated_password() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = config(&dir, None);
+    administration_center_api::start(config.clone(), None)
+        .await
+        .unwrap();
+
+    let admin = only_admin(&config).await;
+    assert!(!is_password_of(&admin, "", &config).await);
+}
+
+#[tokio::test]
+async fn creates_nothing_once_a_user_exists() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = config(&dir, Some("bootstrap password"));
+    administration_center_api::start(config.clone(), None)
+        .await
+        .unwrap();
+    let admin = only_admin(&config).await;
+
+    // Even with another email, or once the admin is renamed
+    let mut other = config.clone();
+    other.bootstrap.email = "other@example.com".to_string();
+    administration_center_api::start(other.clone(), None)
+        .await
+        .unwrap();
+    let pool = SqlxPool::connect(&config).await.unwrap();
+    UserRepository::new(pool)
+        .update(&User {
+            email: "renamed@example.com".to_string(),
+            ..admin
+        })
+        .await
+        .unwrap();
+    administration_center_api::start(config.clone(), None)
+        .await
+        .unwrap();
+
+    let users = users(&config).await;
+    assert_eq!(users.len(), 1);
+    assert_eq!(users[0].email, "renamed@example.com");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn creates_a_single_admin_when_instances_start_together() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = config(&dir, Some("bootstrap password"));
+    administration_center_api::migrate(&config).await.unwrap();
+
+    let starts: Vec<_> = (0..4)
+        .map(|_| tokio::spawn(administration_center_api::start(config.clone(), None)))
+        .collect();
+    for start in starts {
+        start.await.unwrap().unwrap();
+    }
+
+    only_admin(&config).await;
+}
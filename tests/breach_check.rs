@@ -0,0 +1,84 @@
+#![cfg(feature = "breach-check")]
+
+use std::time::Duration;
+
+use administration_center_api::auth::breach::{BreachChecker, PwnedPasswords};
+use url::Url;
+use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+/// The SHA-1 of `password`, split after the prefix sent to the API
+const PREFIX: &str = "5BAA6";
+const SUFFIX: &str = "1E4C9B93F3F0682250B6CF8331B7EE68FD8";
+
+/// A checker of the mocked API
+fn checker(server: &MockServer, timeout: Duration) -> PwnedPasswords {
+    let url = Url::parse(&format!("{}/range/", server.uri())).unwrap();
+    PwnedPasswords::new(url, timeout)
+}
+
+/// Answer the range of the prefix with the given body
+async fn mock_range(server: &MockServer, response: ResponseTemplate) {
+    Mock::given(method("GET"))
+        .and(path(format!("/range/{}", PREFIX)))
+        .respond_with(response)
+        .expect(1)
+        .mount(server)
+        .await;
+}
+
+#[tokio::test]
+async fn counts_a_leaked_password() {
+    let server = MockServer::start().await;
+    let body = format!(
+        "0018A45C4D1DEF81644B54AB7F969B88D65:1\r\n{}:9545824\r\n",
+        SUFFIX.to_lowercase()
+    );
+    mock_range(&server, ResponseTemplate::new(200).set_body_string(body)).await;
+
+    let count = checker(&server, Duration::from_secs(5))
+        .breach_count("password")
+        .await
+        .unwrap();
+    assert_eq!(count, 9545824);
+}
+
+#[tokio::test]
+async fn counts_an_unknown_password_as_never_leaked() {
+    let server = MockServer::start().await;
+    let body = "0018A45C4D1DEF81644B54AB7F969B88D65:1\r\n00D4F6E8FA6EECAD2A3AA415EEC418D38EC:2\r\n";
+    mock_range(&server, ResponseTemplate::new(200).set_body_string(body)).await;
+
+    let count = checker(&server, Duration::from_secs(5))
+        .breach_count("password")
+        .await
+        .unwrap();
+    assert_eq!(count, 0);
+}
+
+#[tokio::test]
+async fn fails_when_the_api_is_too_slow() {
+    let server = MockServer::start().await;
+    let response = ResponseTemplate::new(200)
+        .set_body_string(format!("{}:1\r\n", SUFFIX))
+        .set_delay(Duration::from_secs(5));
+    mock_range(&server, response).await;
+
+    let result = checker(&server, Duration::from_millis(200))
+        .breach_count("password")
+        .await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn fails_when_the_api_answers_an_error() {
+    let server = MockServer::start().await;
+    mock_range(&server, ResponseTemplate::new(503)).await;
+
+    let result = checker(&server, Duration::from_secs(5))
+        .breach_count("password")
+        .await;
+    assert!(result.is_err());
+}
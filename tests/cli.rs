@@ -0,0 +1,202 @@
+mod common;
+
+use std::sync::Arc;
+
+use administration_center_api::{
+    auth::password,
+    config::DatabaseUri,
+    database::SqlxPool,
+    reporting::ReporterHandle,
+    state::AppState,
+    users::{UserRepository, UserStatus},
+};
+use assert_cmd::Command;
+use predicates::str::contains;
+use serde_json::Value;
+use tempfile::TempDir;
+
+/// The URI of the database of the directory, relative to it
+const DATABASE_URI: &str = "sqlite://test.db";
+
+/// The binary, run in an empty directory (without a `.env`) with only the given variables
+fn backend(dir: &TempDir) -> Command {
+    let mut command = Command::cargo_bin("administration_center_api").unwrap();
+    command.current_dir(dir.path()).env_clear();
+    command
+}
+
+/// The state of an application over the database of the directory
+async fn state(dir: &TempDir) -> AppState {
+    let mut config = common::config();
+    config.database_uri =
+        DatabaseUri::Sqlite(dir.path().join("test.db").to_string_lossy().into_owned());
+    let pool = SqlxPool::connect(&config).await.unwrap();
+    let reporter = ReporterHandle::from_config(&config.error_reporting);
+    AppState::new(Arc::new(config), pool, reporter)
+}
+
+/// Run a `user` subcommand over the database of the directory, with its output as JSON
+fn user_command(dir: &TempDir, args: &[&str], stdin: &str) -> Value {
+    let output = backend(dir)
+        .env("DATABASE_URI", DATABASE_URI)
+        .args(["user", "--json"])
+        .args(args)
+        .write_stdin(stdin)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    serde_json::from_slice(&output).unwrap()
+}
+
+#[test]
+fn exits_with_ex_config_without_a_database() {
+    let dir = tempfile::tempdir().unwrap();
+    backend(&dir)
+        .assert()
+        .code(78)
+        .stderr(contains("DATABASE_URI"));
+}
+
+#[test]
+fn exits_with_ex_config_on_an_invalid_variable() {
+    let dir = tempfile::tempdir().unwrap();
+    backend(&dir)
+        .env("DATABASE_URI", DATABASE_URI)
+        .env("PORT", "not-a-port")
+        .assert()
+        .code(78)
+        .stderr(contains("PORT"));
+}
+
+#[tokio::test]
+async fn seeds_the_sessions() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = backend(&dir)
+        .env("DATABASE_URI", DATABASE_URI)
+        .args(["seed", "--sessions", "5"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    // The IDs of the sessions are printed
+    let ids = String::from_utf8(output).unwrap();
+    assert_eq!(ids.lines().count(), 5, "{}", ids);
+    let stats = state(&dir).await.sessions.stats().await.unwrap();
+    assert_eq!((stats.total, stats.active), (5, 5));
+}
+
+#[tokio::test]
+async fn creates_the_users() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let created = user_command(
+        &dir,
+        &[
+            "create",
+            "--email",
+            "alice@example.com",
+            "--role",
+            "admin",
+            "--password-stdin",
+        ],
+        "chosen password\n",
+    );
+    assert_eq!(created["user"]["email"], "alice@example.com");
+    assert_eq!(created["user"]["roles"], serde_json::json!(["admin"]));
+    assert!(created.get("temporary_password").is_none());
+
+    let created = user_command(&dir, &["create", "--email", "bob@example.com"], "");
+    let temporary_password = created["temporary_password"].as_str().unwrap();
+    assert_eq!(created["user"]["must_change_password"], true);
+
+    let state = state(&dir).await;
+    let users = UserRepository::new(state.write_pool().clone());
+    for (email, plain) in [
+        ("alice@example.com", "chosen password"),
+        ("bob@example.com", temporary_password),
+    ] {
+        let user = users.find_by_email(email).await.unwrap().unwrap();
+        let outcome = password::verify(plain, &user.password_hash, &state.config.password)
+            .await
+            .unwrap();
+        assert!(outcome.is_valid(), "{}", email);
+    }
+
+    let listed = user_command(&dir, &["list"], "");
+    let emails: Vec<&str> = listed
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|user| user["email"].as_str().unwrap())
+        .collect();
+    assert_eq!(emails, ["alice@example.com", "bob@example.com"]);
+}
+
+#[tokio::test]
+async fn administers_the_existing_users() {
+    let dir = tempfile::tempdir().unwrap();
+    user_command(&dir, &["create", "--email", "alice@example.com"], "");
+
+    let changed = user_command(&dir, &["set-password", "alice@example.com"], "");
+    let temporary_password = changed["temporary_password"].as_str().unwrap();
+
+    // Locked after failed logins
+    let SqlxPool::Sqlite(pool) = state(&dir).await.write_pool().clone() else {
+        unreachable!("the tests run on SQLite");
+    };
+    sqlx::query("UPDATE users SET locked_until = ?")
+        .bind(time::OffsetDateTime::now_utc().unix_timestamp() + 3600)
+        .execute(&pool)
+        .await
+        .unwrap();
+    pool.close().await;
+    let unlocked = user_command(&dir, &["unlock", "alice@example.com"], "");
+    assert!(unlocked[0].get("locked_until").is_none());
+    let disabled = user_command(&dir, &["disable", "alice@example.com"], "");
+    assert_eq!(disabled[0]["status"], "disabled");
+
+    let state = state(&dir).await;
+    let user = UserRepository::new(state.write_pool().clone())
+        .find_by_email("alice@example.com")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(user.status, UserStatus::Disabled);
+    let outcome = password::verify(
+        temporary_password,
+        &user.password_hash,
+        &state.config.password,
+    )
+    .await
+    .unwrap();
+    assert!(outcome.is_valid());
+}
+
+#[test]
+fn exits_with_ex_nouser_on_an_unknown_user() {
+    let dir = tempfile::tempdir().unwrap();
+    for command in ["set-password", "unlock", "disable"] {
+        backend(&dir)
+            .env("DATABASE_URI", DATABASE_URI)
+            .args(["user", "--json", command, "nobody@example.com"])
+            .assert()
+            .code(67);
+    }
+}
+
+#[test]
+fn refuses_a_taken_email() {
+    let dir = tempfile::tempdir().unwrap();
+    user_command(&dir, &["create", "--email", "alice@example.com"], "");
+
+    backend(&dir)
+        .env("DATABASE_URI", DATABASE_URI)
+        .args(["user", "create", "--email", "ALICE@example.com"])
+        .assert()
+        .failure()
+        .stderr(contains("already exists"));
+}
@@ -0,0 +1,195 @@
+//! The application under test, driven in-process over a fresh SQLite database
+//! Every test builds its own application, so that the tests of a file can run concurrently.
+
+// Every test file uses a part of the helpers
+#![allow(dead_code)]
+
+use std::sync::Once;
+
+use administration_center_api::{
+    api_keys::{ApiKeyRepository, NewApiKey},
+    auth::password,
+    config::{Config, DatabaseUri},
+    database::SqlxPool,
+    permissions::Permission,
+    users::{AuthSource, NewUser, User, UserRepository, UserStatus},
+};
+use axum::{
+    body::{to_bytes, Body},
+    http::{header, Request, Response},
+    Router,
+};
+use tempfile::TempDir;
+use tower::ServiceExt;
+
+/// A running application, with the pool to its database
+pub struct TestApp {
+    pub router: Router,
+    pub pool: SqlxPool,
+    pub config: Config,
+    // Deleted, with the database, once the test is done
+    _dir: TempDir,
+}
+
+/// The configuration of the environment, with the database left to the test
+pub fn config() -> Config {
+    static DATABASE_URI: Once = Once::new();
+    DATABASE_URI.call_once(|| std::env::set_var("DATABASE_URI", "sqlite://:memory:"));
+    let mut config = Config::from_env().expect("the environment of the tests is invalid");
+    // The cheapest hashes, the strength of the passwords being irrelevant here
+    config.password.memory_kib = 8;
+    config.password.iterations = 1;
+    config.password.parallelism = 1;
+    config
+}
+
+/// Start the application with the configuration of the environment
+pub async fn spawn() -> TestApp {
+    spawn_with(|_| {}).await
+}
+
+/// Start the application with the configuration of the environment, changed by `configure`
+pub async fn spawn_with(configure: impl FnOnce(&mut Config)) -> TestApp {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = config();
+    config.database_uri =
+        DatabaseUri::Sqlite(dir.path().join("test.db").to_string_lossy().into_owned());
+    configure(&mut config);
+
+    administration_center_api::migrate(&config).await.unwrap();
+    let pool = SqlxPool::connect(&config).await.unwrap();
+    let router = administration_center_api::build_app(&config, pool.clone())
+        .await
+        .unwrap();
+    TestApp {
+        router,
+        pool,
+        config,
+        _dir: dir,
+    }
+}
+
+impl TestApp {
+    /// Send a request to the application
+    pub async fn request(&self, req: Request<Body>) -> Response<Body> {
+        self.router.clone().oneshot(req).await.unwrap()
+    }
+
+    /// Create an API key without a user, with the given scopes
+    pub async fn api_key(&self, scopes: &[Permission]) -> String {
+        let (_, key) = ApiKeyRepository::new(self.pool.clone())
+            .create(NewApiKey {
+                name: "tests".to_string(),
+                user_id: None,
+                scopes: scopes.to_vec(),
+                expires_at: None,
+            })
+            .await
+            .unwrap();
+        key
+    }
+
+    /// Create an active user with the given password
+    pub async fn user(&self, email: &str, password: &str) -> User {
+        let password_hash = password::hash(password, &self.config.password)
+            .await
+            .unwrap();
+        UserRepository::new(self.pool.clone())
+            .create(NewUser {
+                email: email.to_string(),
+                display_name: email.split('@').next().unwrap().to_string(),
+                password_hash,
+                status: UserStatus::Active,
+                must_change_password: false,
+                auth_source: AuthSource::Local,
+            })
+            .await
+            .unwrap()
+    }
+
+    /// Start an anonymous session
+    pub async fn session(&self) -> Session {
+        let response = self
+            .request(Request::get("/api/v1/csrf").body(Body::empty()).unwrap())
+            .await;
+        let cookie = cookie(&response).expect("no session cookie was set");
+        let token = json(response).await["token"].as_str().unwrap().to_string();
+        Session { cookie, token }
+    }
+
+    /// Log in the session with the given credentials
+    pub async fn login(&self, session: &Session, email: &str, password: &str) -> Response<Body> {
+        let body = serde_json::json!({ "email": email, "password": password });
+        self.request(session.json_request("POST", "/api/v1/auth/login", &body))
+            .await
+    }
+
+    /// Log in a new session, which must succeed
+    pub async fn logged_in(&self, email: &str, password: &str) -> Session {
+        let mut session = self.session().await;
+        let response = self.login(&session, email, password).await;
+        assert!(response.status().is_success(), "{}", response.status());
+        session.cookie = cookie(&response).expect("the session ID wasn't rotated");
+        session
+    }
+}
+
+/// A session of a browser: its cookie and CSRF token
+#[derive(Clone, Debug)]
+pub struct Session {
+    /// The `name=value` pair of the session cookie
+    pub cookie: String,
+    pub token: String,
+}
+
+impl Session {
+    /// A request in the session
+    pub fn request(&self, method: &str, uri: &str) -> axum::http::request::Builder {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::COOKIE, &self.cookie)
+            .header("x-csrf-token", &self.token)
+    }
+
+    /// A JSON request in the session
+    pub fn json_request(&self, method: &str, uri: &str, body: &serde_json::Value) -> Request<Body> {
+        self.request(method, uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+}
+
+/// The `name=value` pair of the cookie set by a response, if any
+pub fn cookie(response: &Response<Body>) -> Option<String> {
+    let set_cookie = response.headers().get(header::SET_COOKIE)?.to_str().ok()?;
+    set_cookie.split(';').next().map(str::to_string)
+}
+
+/// A request authenticated with the API key
+pub fn authenticated(method: &str, uri: &str, api_key: &str) -> axum::http::request::Builder {
+    Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("x-api-key", api_key)
+}
+
+/// A JSON request authenticated with the API key
+pub fn json_request(
+    method: &str,
+    uri: &str,
+    api_key: &str,
+    body: &serde_json::Value,
+) -> Request<Body> {
+    authenticated(method, uri, api_key)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// The body of a response, decoded from JSON
+pub async fn json(response: Response<Body>) -> serde_json::Value {
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
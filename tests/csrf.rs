@@ -0,0 +1,118 @@
+mod common;
+
+use administration_center_api::permissions::Permission;
+use axum::{
+    body::Body,
+    http::{header, Request, StatusCode},
+};
+use serde_json::json;
+
+use common::{json, json_request, TestApp};
+
+const LOGOUT: &str = "/api/v1/auth/logout";
+
+/// A new session, as its cookie, and its CSRF token
+async fn session(app: &TestApp) -> (String, String) {
+    let response = app
+        .request(Request::get("/api/v1/csrf").body(Body::empty()).unwrap())
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let cookie = response.headers()[header::SET_COOKIE]
+        .to_str()
+        .unwrap()
+        .split(';')
+        .next()
+        .unwrap()
+        .to_string();
+    let token = json(response).await["token"].as_str().unwrap().to_string();
+    (cookie, token)
+}
+
+/// Log out of the session, with the given headers
+async fn logout(app: &TestApp, headers: &[(&str, &str)]) -> axum::response::Response {
+    let mut req = Request::post(LOGOUT);
+    for (name, value) in headers {
+        req = req.header(*name, *value);
+    }
+    app.request(req.body(Body::empty()).unwrap()).await
+}
+
+async fn assert_refused(response: axum::response::Response) {
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(json(response).await["error"]["code"], "invalid_csrf_token");
+}
+
+#[tokio::test]
+async fn accepts_the_token_of_the_session() {
+    let app = common::spawn().await;
+    let (cookie, token) = session(&app).await;
+
+    let response = logout(&app, &[("cookie", &cookie), ("x-csrf-token", &token)]).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+}
+
+#[tokio::test]
+async fn refuses_a_missing_token() {
+    let app = common::spawn().await;
+    let (cookie, _) = session(&app).await;
+
+    assert_refused(logout(&app, &[("cookie", &cookie)]).await).await;
+}
+
+#[tokio::test]
+async fn refuses_a_mismatched_token() {
+    let app = common::spawn().await;
+    let (cookie, _) = session(&app).await;
+    let (_, other_token) = session(&app).await;
+
+    let response = logout(&app, &[("cookie", &cookie), ("x-csrf-token", &other_token)]).await;
+    assert_refused(response).await;
+}
+
+#[tokio::test]
+async fn checks_requests_with_unknown_credentials() {
+    let app = common::spawn().await;
+    let (cookie, _) = session(&app).await;
+
+    let response = logout(
+        &app,
+        &[("cookie", &cookie), ("authorization", "Basic Zm9vOmJhcg==")],
+    )
+    .await;
+    assert_refused(response).await;
+}
+
+#[tokio::test]
+async fn skips_requests_authenticated_with_an_api_key() {
+    let app = common::spawn().await;
+    let api_key = app.api_key(&[Permission::GROUPS_MANAGE]).await;
+
+    let req = json_request(
+        "POST",
+        "/api/v1/admin/groups",
+        &api_key,
+        &json!({ "name": "operators" }),
+    );
+    let response = app.request(req).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert!(!response.headers().contains_key("x-csrf-token"));
+}
+
+#[tokio::test]
+async fn skips_requests_authenticated_with_the_admin_token() {
+    let app = common::spawn_with(|config| config.admin_token = Some("s3cr3t".to_string())).await;
+
+    let req = Request::post("/api/v1/admin/groups")
+        .header(header::AUTHORIZATION, "Bearer s3cr3t")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(json!({ "name": "operators" }).to_string()))
+        .unwrap();
+    assert_eq!(app.request(req).await.status(), StatusCode::CREATED);
+
+    let req = Request::post("/api/v1/admin/groups")
+        .header(header::AUTHORIZATION, "Bearer wrong")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(json!({ "name": "auditors" }).to_string()))
+        .unwrap();
+    assert_refused(app.request(req).await).await;
+}
@@ -0,0 +1,111 @@
+mod common;
+
+use administration_center_api::permissions::Permission;
+use axum::{
+    body::{to_bytes, Body},
+    http::{header, HeaderValue, StatusCode},
+    response::Response,
+};
+
+use common::TestApp;
+
+const STATS: &str = "/api/v1/admin/stats/sessions";
+
+async fn get(app: &TestApp, api_key: &str, headers: &[(header::HeaderName, &str)]) -> Response {
+    let mut req = common::authenticated("GET", STATS, api_key);
+    for (name, value) in headers {
+        req = req.header(name, *value);
+    }
+    app.request(req.body(Body::empty()).unwrap()).await
+}
+
+fn etag(response: &Response) -> String {
+    response.headers()[header::ETAG]
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+#[tokio::test]
+async fn tags_the_responses() {
+    let app = common::spawn().await;
+    let api_key = app.api_key(&[Permission::STATS_READ]).await;
+
+    let response = get(&app, &api_key, &[]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let tag = etag(&response);
+    assert!(tag.starts_with('"') && tag.ends_with('"'));
+    assert_eq!(
+        response.headers()[header::CACHE_CONTROL],
+        HeaderValue::from_static("private, no-cache")
+    );
+    // Stable while nothing changes
+    assert_eq!(etag(&get(&app, &api_key, &[]).await), tag);
+}
+
+#[tokio::test]
+async fn answers_not_modified_when_the_tag_matches() {
+    let app = common::spawn().await;
+    let api_key = app.api_key(&[Permission::STATS_READ]).await;
+    let tag = etag(&get(&app, &api_key, &[]).await);
+
+    let response = get(&app, &api_key, &[(header::IF_NONE_MATCH, &tag)]).await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(etag(&response), tag);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(body.is_empty());
+
+    let listed = format!("\"other\", {}", tag);
+    let response = get(&app, &api_key, &[(header::IF_NONE_MATCH, &listed)]).await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+}
+
+#[tokio::test]
+async fn answers_the_body_when_the_tag_differs() {
+    let app = common::spawn().await;
+    let api_key = app.api_key(&[Permission::STATS_READ]).await;
+
+    let response = get(&app, &api_key, &[(header::IF_NONE_MATCH, "\"stale\"")]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(!body.is_empty());
+}
+
+#[tokio::test]
+async fn matches_a_weak_validator() {
+    let app = common::spawn().await;
+    let api_key = app.api_key(&[Permission::STATS_READ]).await;
+    let tag = etag(&get(&app, &api_key, &[]).await);
+
+    let weak = format!("W/{}", tag);
+    let response = get(&app, &api_key, &[(header::IF_NONE_MATCH, &weak)]).await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+}
+
+#[tokio::test]
+async fn tags_each_format_differently() {
+    let app = common::spawn().await;
+    let api_key = app.api_key(&[Permission::STATS_READ]).await;
+
+    let json = get(&app, &api_key, &[(header::ACCEPT, "application/json")]).await;
+    let msgpack = get(&app, &api_key, &[(header::ACCEPT, "application/msgpack")]).await;
+    assert_eq!(msgpack.status(), StatusCode::OK);
+    assert_ne!(etag(&json), etag(&msgpack));
+    assert!(msgpack.headers()[header::VARY]
+        .to_str()
+        .unwrap()
+        .contains("accept"));
+
+    // The tag of one format doesn't validate the other
+    let tag = etag(&json);
+    let response = get(
+        &app,
+        &api_key,
+        &[
+            (header::ACCEPT, "application/msgpack"),
+            (header::IF_NONE_MATCH, &tag),
+        ],
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
@@ -0,0 +1,64 @@
+mod common;
+
+use administration_center_api::permissions::Permission;
+use axum::{body::Body, http::StatusCode};
+use serde_json::json;
+
+use common::{json, Session, TestApp};
+
+const PASSWORD: &str = "correct horse battery staple";
+
+async fn my_flags(app: &TestApp, session: &Session) -> serde_json::Value {
+    let req = session
+        .request("GET", "/api/v1/users/me/flags")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.request(req).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    json(response).await
+}
+
+#[tokio::test]
+async fn evaluates_the_flags_for_the_current_user() {
+    let app = common::spawn().await;
+    let alice = app.user("alice@example.com", PASSWORD).await;
+    app.user("bob@example.com", PASSWORD).await;
+    let alice_session = app.logged_in("alice@example.com", PASSWORD).await;
+    let bob_session = app.logged_in("bob@example.com", PASSWORD).await;
+
+    // Off by default
+    assert_eq!(
+        my_flags(&app, &alice_session).await,
+        json!({ "new_dashboard": false })
+    );
+
+    let admin_key = app.api_key(&[Permission::SETTINGS_MANAGE]).await;
+    let req = common::json_request(
+        "PUT",
+        "/api/v1/admin/flags/new_dashboard",
+        &admin_key,
+        &json!({ "enabled": false, "users": { alice.id.to_string(): true } }),
+    );
+    assert_eq!(app.request(req).await.status(), StatusCode::OK);
+
+    assert_eq!(
+        my_flags(&app, &alice_session).await,
+        json!({ "new_dashboard": true })
+    );
+    assert_eq!(
+        my_flags(&app, &bob_session).await,
+        json!({ "new_dashboard": false })
+    );
+}
+
+#[tokio::test]
+async fn refuses_the_anonymous_users() {
+    let app = common::spawn().await;
+    let session = app.session().await;
+
+    let req = session
+        .request("GET", "/api/v1/users/me/flags")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(app.request(req).await.status(), StatusCode::UNAUTHORIZED);
+}
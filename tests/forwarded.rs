@@ -0,0 +1,57 @@
+mod common;
+
+use std::time::Duration;
+
+use administration_center_api::{config::DatabaseUri, Server};
+use reqwest::header::{HeaderMap, STRICT_TRANSPORT_SECURITY};
+use tempfile::TempDir;
+
+/// Serve the application on a local port, trusting the `X-Forwarded-Proto` header of the local
+/// proxies
+async fn serve(dir: &TempDir) -> Server {
+    let mut config = common::config();
+    config.database_uri =
+        DatabaseUri::Sqlite(dir.path().join("test.db").to_string_lossy().into_owned());
+    config.host = "127.0.0.1".to_string();
+    config.port = 0;
+    config.forwarded.trust_proto = true;
+    config.forwarded.trusted_proxies = vec!["127.0.0.1".parse().unwrap()];
+    config.forwarded.hsts_max_age = Some(Duration::from_secs(3600));
+    administration_center_api::start(config, None)
+        .await
+        .unwrap()
+}
+
+/// The headers of the response to a request forwarded with the given `X-Forwarded-Proto` values
+async fn forward(server: &Server, protos: &[&str]) -> HeaderMap {
+    let mut request = reqwest::Client::new().get(format!("http://{}/health", server.local_addr()));
+    for proto in protos {
+        request = request.header("x-forwarded-proto", *proto);
+    }
+    request.send().await.unwrap().headers().clone()
+}
+
+#[tokio::test]
+async fn trusts_the_scheme_appended_by_the_proxy() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = serve(&dir).await;
+
+    let headers = forward(&server, &["https"]).await;
+    assert!(headers.contains_key(STRICT_TRANSPORT_SECURITY));
+    let headers = forward(&server, &["http, https"]).await;
+    assert!(headers.contains_key(STRICT_TRANSPORT_SECURITY));
+    let headers = forward(&server, &["http"]).await;
+    assert!(!headers.contains_key(STRICT_TRANSPORT_SECURITY));
+}
+
+#[tokio::test]
+async fn ignores_the_scheme_spoofed_by_the_client() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = serve(&dir).await;
+
+    // The client claims HTTPS, the proxy appends the scheme it was actually reached with
+    let headers = forward(&server, &["https, http"]).await;
+    assert!(!headers.contains_key(STRICT_TRANSPORT_SECURITY));
+    let headers = forward(&server, &["https", "http"]).await;
+    assert!(!headers.contains_key(STRICT_TRANSPORT_SECURITY));
+}
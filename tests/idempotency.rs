@@ -0,0 +1,140 @@
+mod common;
+
+use std::time::Duration;
+
+use administration_center_api::permissions::Permission;
+use axum::http::{header, StatusCode};
+use serde_json::json;
+
+use common::{json, json_request, TestApp};
+
+const GROUPS: &str = "/api/v1/admin/groups";
+
+/// Create a group, with the given idempotency key
+async fn create_group(
+    app: &TestApp,
+    api_key: &str,
+    key: &str,
+    name: &str,
+) -> axum::response::Response {
+    let mut req = json_request("POST", GROUPS, api_key, &json!({ "name": name }));
+    req.headers_mut()
+        .insert("idempotency-key", key.parse().unwrap());
+    app.request(req).await
+}
+
+fn is_replayed(response: &axum::response::Response) -> bool {
+    response.headers().contains_key("idempotent-replayed")
+}
+
+#[tokio::test]
+async fn replays_the_first_response() {
+    let app = common::spawn().await;
+    let api_key = app.api_key(&[Permission::GROUPS_MANAGE]).await;
+
+    let first = create_group(&app, &api_key, "retry-1", "operators").await;
+    assert_eq!(first.status(), StatusCode::CREATED);
+    assert!(!is_replayed(&first));
+    let first = json(first).await;
+
+    let retry = create_group(&app, &api_key, "retry-1", "operators").await;
+    assert_eq!(retry.status(), StatusCode::CREATED);
+    assert!(is_replayed(&retry));
+    assert_eq!(json(retry).await, first);
+}
+
+#[tokio::test]
+async fn refuses_a_key_reused_for_another_body() {
+    let app = common::spawn().await;
+    let api_key = app.api_key(&[Permission::GROUPS_MANAGE]).await;
+
+    let first = create_group(&app, &api_key, "retry-2", "operators").await;
+    assert_eq!(first.status(), StatusCode::CREATED);
+    let other = create_group(&app, &api_key, "retry-2", "auditors").await;
+    assert_eq!(other.status(), StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn runs_the_request_again_once_the_key_expired() {
+    let app = common::spawn_with(|config| config.idempotency_ttl = Duration::ZERO).await;
+    let api_key = app.api_key(&[Permission::GROUPS_MANAGE]).await;
+
+    let first = create_group(&app, &api_key, "retry-3", "operators").await;
+    assert_eq!(first.status(), StatusCode::CREATED);
+    // Run again, the group now existing
+    let retry = create_group(&app, &api_key, "retry-3", "operators").await;
+    assert!(!is_replayed(&retry));
+    assert_eq!(retry.status(), StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn runs_concurrent_requests_once() {
+    let app = common::spawn().await;
+    let api_key = app.api_key(&[Permission::GROUPS_MANAGE]).await;
+
+    let (first, second) = tokio::join!(
+        create_group(&app, &api_key, "retry-4", "operators"),
+        create_group(&app, &api_key, "retry-4", "operators"),
+    );
+    assert_eq!(first.status(), StatusCode::CREATED);
+    assert_eq!(second.status(), StatusCode::CREATED);
+    assert_eq!(is_replayed(&first) as u8 + is_replayed(&second) as u8, 1);
+    assert_eq!(json(first).await, json(second).await);
+}
+
+#[tokio::test]
+async fn scopes_the_keys_to_the_principal() {
+    let app = common::spawn().await;
+    let alice = app.api_key(&[Permission::GROUPS_MANAGE]).await;
+    let mallory = app.api_key(&[Permission::GROUPS_MANAGE]).await;
+
+    let first = create_group(&app, &alice, "shared", "operators").await;
+    assert_eq!(first.status(), StatusCode::CREATED);
+    // Not the response of alice: the request runs, and the group already exists
+    let other = create_group(&app, &mallory, "shared", "operators").await;
+    assert!(!is_replayed(&other));
+    assert_eq!(other.status(), StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn never_replays_a_secret() {
+    let app = common::spawn().await;
+    let api_key = app
+        .api_key(&[Permission::API_KEYS_MANAGE, Permission::STATS_READ])
+        .await;
+
+    let create = || async {
+        let mut req = json_request(
+            "POST",
+            "/api/v1/admin/api-keys",
+            &api_key,
+            &json!({ "name": "ci", "scopes": ["stats.read"] }),
+        );
+        req.headers_mut()
+            .insert("idempotency-key", "new-key".parse().unwrap());
+        app.request(req).await
+    };
+
+    let first = create().await;
+    assert_eq!(first.status(), StatusCode::CREATED);
+    assert_eq!(first.headers()[header::CACHE_CONTROL], "no-store");
+    let retry = create().await;
+    assert!(!is_replayed(&retry));
+    assert_ne!(json(first).await["key"], json(retry).await["key"]);
+}
+
+#[tokio::test]
+async fn ignores_anonymous_requests() {
+    // Or the request would be refused for its missing CSRF token
+    let app = common::spawn_with(|config| config.csrf.enabled = false).await;
+
+    let req = axum::http::Request::builder()
+        .method("POST")
+        .uri(GROUPS)
+        .header("idempotency-key", "anonymous")
+        .body(axum::body::Body::empty())
+        .unwrap();
+    let response = app.request(req).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(!is_replayed(&response));
+}
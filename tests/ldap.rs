@@ -0,0 +1,211 @@
+mod common;
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use administration_center_api::{
+    auth::ldap::{Authentication, Directory, DirectoryUser},
+    config::LdapConfig,
+    permissions::Permission,
+    roles::{Role, RoleRepository},
+    users::{AuthSource, UserRepository},
+};
+use anyhow::Result;
+use axum::{
+    async_trait,
+    body::Body,
+    http::{header, Request, StatusCode},
+    response::Response,
+};
+use serde_json::json;
+
+use common::{json, TestApp};
+
+const OPERATORS: &str = "CN=Operators,OU=Groups,DC=example,DC=com";
+
+/// An entry of the directory, with its password
+#[derive(Clone)]
+struct Entry {
+    password: String,
+    email: String,
+    display_name: String,
+    groups: Vec<String>,
+}
+
+/// A directory holding its entries in memory, by username
+#[derive(Clone, Default)]
+struct FakeDirectory {
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl FakeDirectory {
+    fn add(&self, username: &str, password: &str, display_name: &str, groups: &[&str]) {
+        let entry = Entry {
+            password: password.to_string(),
+            email: format!("{}@example.com", username),
+            display_name: display_name.to_string(),
+            groups: groups.iter().map(|group| group.to_string()).collect(),
+        };
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(username.to_string(), entry);
+    }
+}
+
+#[async_trait]
+impl Directory for FakeDirectory {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<Authentication> {
+        let Some(entry) = self.entries.lock().unwrap().get(username).cloned() else {
+            return Ok(Authentication::Refused { email: None });
+        };
+        if entry.password != password {
+            return Ok(Authentication::Refused {
+                email: Some(entry.email),
+            });
+        }
+        Ok(Authentication::Accepted(DirectoryUser {
+            dn: format!("uid={},OU=People,DC=example,DC=com", username),
+            email: Some(entry.email),
+            display_name: Some(entry.display_name),
+            groups: entry.groups,
+        }))
+    }
+}
+
+/// Start the application logging in against the directory, operators being mapped
+async fn spawn(directory: &FakeDirectory) -> TestApp {
+    let directory = Arc::new(directory.clone());
+    common::spawn_with(|config| {
+        // Logins don't carry a session, hence no CSRF token
+        config.csrf.enabled = false;
+        config.ldap = Some(LdapConfig {
+            directory,
+            group_roles: vec![(
+                "cn=operators, ou=groups, dc=example, dc=com".to_string(),
+                Role::Operator,
+            )],
+            default_role: Role::Viewer,
+        });
+    })
+    .await
+}
+
+async fn login(app: &TestApp, username: &str, password: &str) -> Response {
+    let body = json!({ "username": username, "password": password });
+    app.request(
+        Request::post("/api/v1/auth/login")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap(),
+    )
+    .await
+}
+
+async fn roles_of(app: &TestApp, email: &str) -> Vec<Role> {
+    let user = UserRepository::new(app.pool.clone())
+        .find_by_email(email)
+        .await
+        .unwrap()
+        .unwrap();
+    RoleRepository::new(app.pool.clone())
+        .roles_of(user.id)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn creates_the_shadow_user_of_an_entry() {
+    let directory = FakeDirectory::default();
+    directory.add("alice", "s3cr3t", "Alice Liddell", &[]);
+    let app = spawn(&directory).await;
+
+    let response = login(&app, "alice", "s3cr3t").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let user = json(response).await;
+    assert_eq!(user["email"], "alice@example.com");
+    assert_eq!(user["display_name"], "Alice Liddell");
+
+    let shadow = UserRepository::new(app.pool.clone())
+        .find_by_email("alice@example.com")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(shadow.auth_source, AuthSource::Ldap);
+    assert_eq!(roles_of(&app, "alice@example.com").await, [Role::Viewer]);
+}
+
+#[tokio::test]
+async fn refuses_bad_credentials() {
+    let directory = FakeDirectory::default();
+    directory.add("alice", "s3cr3t", "Alice Liddell", &[]);
+    let app = spawn(&directory).await;
+
+    let response = login(&app, "alice", "wrong").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = login(&app, "bob", "s3cr3t").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let shadow = UserRepository::new(app.pool.clone())
+        .find_by_email("alice@example.com")
+        .await
+        .unwrap();
+    assert!(shadow.is_none());
+}
+
+#[tokio::test]
+async fn maps_the_groups_to_roles() {
+    let directory = FakeDirectory::default();
+    directory.add("alice", "s3cr3t", "Alice Liddell", &[OPERATORS]);
+    directory.add(
+        "bob",
+        "hunter2",
+        "Bob",
+        &["CN=Others,OU=Groups,DC=example,DC=com"],
+    );
+    let app = spawn(&directory).await;
+
+    assert_eq!(
+        login(&app, "alice", "s3cr3t").await.status(),
+        StatusCode::OK
+    );
+    assert_eq!(login(&app, "bob", "hunter2").await.status(), StatusCode::OK);
+    assert_eq!(roles_of(&app, "alice@example.com").await, [Role::Operator]);
+    assert_eq!(roles_of(&app, "bob@example.com").await, [Role::Viewer]);
+}
+
+#[tokio::test]
+async fn refreshes_the_shadow_user_on_the_next_login() {
+    let directory = FakeDirectory::default();
+    directory.add("alice", "s3cr3t", "Alice Liddell", &[]);
+    let app = spawn(&directory).await;
+    assert_eq!(
+        login(&app, "alice", "s3cr3t").await.status(),
+        StatusCode::OK
+    );
+
+    directory.add("alice", "s3cr3t", "Alice Hargreaves", &[OPERATORS]);
+    let response = login(&app, "alice", "s3cr3t").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json(response).await["display_name"], "Alice Hargreaves");
+    assert_eq!(roles_of(&app, "alice@example.com").await, [Role::Operator]);
+}
+
+#[tokio::test]
+async fn refuses_an_entry_with_the_email_of_a_local_user() {
+    let directory = FakeDirectory::default();
+    directory.add("alice", "s3cr3t", "Alice Liddell", &[]);
+    let app = spawn(&directory).await;
+    let api_key = app.api_key(&[Permission::USERS_MANAGE]).await;
+    let req = common::json_request(
+        "POST",
+        "/api/v1/admin/users",
+        &api_key,
+        &json!({ "email": "alice@example.com", "display_name": "Local Alice" }),
+    );
+    assert_eq!(app.request(req).await.status(), StatusCode::CREATED);
+
+    let response = login(&app, "alice", "s3cr3t").await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
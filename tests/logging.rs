@@ -0,0 +1,44 @@
+mod common;
+
+use std::sync::Once;
+
+use administration_center_api::permissions::Permission;
+use axum::http::StatusCode;
+use serde_json::json;
+
+use common::{json, TestApp};
+
+/// Start the application with the subscriber of the backend installed, once for every test
+async fn spawn() -> TestApp {
+    static SUBSCRIBER: Once = Once::new();
+    SUBSCRIBER.call_once(administration_center_api::logging::init);
+    common::spawn().await
+}
+
+#[tokio::test]
+async fn replaces_the_filter_until_the_revert() {
+    let app = spawn().await;
+    let api_key = app.api_key(&[Permission::LOGGING_MANAGE]).await;
+
+    let body = json!({ "filter": "info,sqlx=debug", "revert_after_secs": 600 });
+    let req = common::json_request("PUT", "/api/v1/admin/logging", &api_key, &body);
+    let response = app.request(req).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let status = json(response).await;
+    assert_eq!(status["filter"], "info,sqlx=debug");
+    assert!(status["revert_at"].is_string());
+}
+
+#[tokio::test]
+async fn refuses_a_revert_too_far_away() {
+    let app = spawn().await;
+    let api_key = app.api_key(&[Permission::LOGGING_MANAGE]).await;
+
+    for revert_after_secs in [u64::MAX, 7 * 24 * 60 * 60 + 1] {
+        let body = json!({ "filter": "debug", "revert_after_secs": revert_after_secs });
+        let req = common::json_request("PUT", "/api/v1/admin/logging", &api_key, &body);
+        let response = app.request(req).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(json(response).await["error"]["code"], "unprocessable");
+    }
+}
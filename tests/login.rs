@@ -0,0 +1,104 @@
+mod common;
+
+use administration_center_api::users::{UserRepository, UserStatus};
+use axum::{body::Body, http::StatusCode};
+
+use common::{json, Session, TestApp};
+
+const EMAIL: &str = "alice@example.com";
+const PASSWORD: &str = "correct horse battery staple";
+
+/// The status of `GET /auth/me` in the session
+async fn me(app: &TestApp, session: &Session) -> StatusCode {
+    let req = session
+        .request("GET", "/api/v1/auth/me")
+        .body(Body::empty())
+        .unwrap();
+    app.request(req).await.status()
+}
+
+#[tokio::test]
+async fn logs_the_user_in() {
+    let app = common::spawn().await;
+    let user = app.user(EMAIL, PASSWORD).await;
+    let session = app.session().await;
+
+    // The email is matched regardless of its case
+    let response = app.login(&session, "Alice@Example.com", PASSWORD).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let cookie = common::cookie(&response).unwrap();
+    let profile = json(response).await;
+    assert_eq!(profile["id"], user.id);
+    assert!(profile.get("password_hash").is_none());
+
+    let logged_in = Session { cookie, ..session };
+    assert_eq!(me(&app, &logged_in).await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn refuses_a_wrong_password_or_an_unknown_email() {
+    let app = common::spawn().await;
+    app.user(EMAIL, PASSWORD).await;
+    let session = app.session().await;
+
+    for (email, password) in [(EMAIL, "wrong"), ("bob@example.com", PASSWORD)] {
+        let response = app.login(&session, email, password).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", email);
+        assert_eq!(json(response).await["error"]["code"], "unauthorized");
+    }
+    assert_eq!(me(&app, &session).await, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn refuses_a_disabled_user() {
+    let app = common::spawn().await;
+    let mut user = app.user(EMAIL, PASSWORD).await;
+    user.status = UserStatus::Disabled;
+    UserRepository::new(app.pool.clone())
+        .update(&user)
+        .await
+        .unwrap();
+    let session = app.session().await;
+
+    let response = app.login(&session, EMAIL, PASSWORD).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(json(response).await["error"]["code"], "forbidden");
+    assert_eq!(me(&app, &session).await, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn rotates_the_session_id() {
+    let app = common::spawn().await;
+    app.user(EMAIL, PASSWORD).await;
+    let before = app.session().await;
+
+    let response = app.login(&before, EMAIL, PASSWORD).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let after = Session {
+        cookie: common::cookie(&response).unwrap(),
+        ..before.clone()
+    };
+    assert_ne!(after.cookie, before.cookie);
+
+    // The ID known before the login doesn't grant access
+    assert_eq!(me(&app, &before).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(me(&app, &after).await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn logs_the_user_out() {
+    let app = common::spawn().await;
+    app.user(EMAIL, PASSWORD).await;
+    let session = app.logged_in(EMAIL, PASSWORD).await;
+
+    let req = session
+        .request("POST", "/api/v1/auth/logout")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.request(req).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    // The cookie is removed, and the session deleted
+    let removal = response.headers()["set-cookie"].to_str().unwrap();
+    assert!(removal.contains("Max-Age=0"), "{}", removal);
+    assert_eq!(me(&app, &session).await, StatusCode::UNAUTHORIZED);
+}
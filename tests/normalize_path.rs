@@ -0,0 +1,76 @@
+mod common;
+
+use administration_center_api::config::{PathNormalization, TrailingSlash};
+use axum::{
+    body::Body,
+    http::{header, Request, StatusCode},
+    response::Response,
+};
+
+use common::TestApp;
+
+async fn spawn(mode: PathNormalization, trailing_slash: TrailingSlash) -> TestApp {
+    common::spawn_with(|config| {
+        config.path_normalization = mode;
+        config.trailing_slash = trailing_slash;
+    })
+    .await
+}
+
+async fn get(app: &TestApp, uri: &str) -> Response {
+    app.request(Request::get(uri).body(Body::empty()).unwrap())
+        .await
+}
+
+fn location(response: &Response) -> &str {
+    response.headers()[header::LOCATION].to_str().unwrap()
+}
+
+#[tokio::test]
+async fn routes_the_trimmed_paths() {
+    let app = spawn(PathNormalization::Rewrite, TrailingSlash::Trim).await;
+
+    for uri in ["/health", "/health/", "//health", "//health//"] {
+        assert_eq!(get(&app, uri).await.status(), StatusCode::OK, "{}", uri);
+    }
+}
+
+#[tokio::test]
+async fn redirects_to_the_trimmed_paths() {
+    let app = spawn(PathNormalization::Redirect, TrailingSlash::Trim).await;
+
+    let response = get(&app, "/health/?verbose=1").await;
+    assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(location(&response), "/health?verbose=1");
+    assert_eq!(get(&app, "/health").await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn redirects_to_the_paths_with_a_trailing_slash() {
+    let app = spawn(PathNormalization::Redirect, TrailingSlash::Append).await;
+
+    let response = get(&app, "/health").await;
+    assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(location(&response), "/health/");
+    assert_eq!(get(&app, "/health/").await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn leaves_the_trailing_slashes_when_off() {
+    let app = spawn(PathNormalization::Rewrite, TrailingSlash::Off).await;
+
+    assert_eq!(get(&app, "//health").await.status(), StatusCode::OK);
+    assert_eq!(get(&app, "/health/").await.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn normalizes_the_base_path() {
+    let app = common::spawn_with(|config| {
+        config.base_path = "/admin".to_string();
+        config.path_normalization = PathNormalization::Rewrite;
+        config.trailing_slash = TrailingSlash::Trim;
+    })
+    .await;
+
+    assert_eq!(get(&app, "/admin//health/").await.status(), StatusCode::OK);
+}
@@ -0,0 +1,256 @@
+mod common;
+
+use std::time::Duration;
+
+use administration_center_api::{
+    auth::{lockout::LoginAttempts, two_factor::TwoFactorRepository},
+    config::OidcConfig,
+    roles::Role,
+};
+use axum::{
+    body::Body,
+    http::{header, Request, StatusCode},
+    response::Response,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{EncodingKey, Header};
+use openssl::rsa::Rsa;
+use serde_json::json;
+use time::OffsetDateTime;
+use url::Url;
+use wiremock::{
+    matchers::{body_string_contains, method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+use common::{json, TestApp};
+
+const CLIENT_ID: &str = "admin-center";
+const KEY_ID: &str = "test-key";
+
+/// A provider, mocked, signing its ID tokens with its own RSA key
+struct Provider {
+    server: MockServer,
+    key: EncodingKey,
+}
+
+impl Provider {
+    /// Serve the discovery document and the keys of a new provider
+    async fn start() -> Self {
+        let server = MockServer::start().await;
+        let rsa = Rsa::generate(2048).unwrap();
+        let jwks = json!({
+            "keys": [{
+                "kty": "RSA",
+                "use": "sig",
+                "alg": "RS256",
+                "kid": KEY_ID,
+                "n": URL_SAFE_NO_PAD.encode(rsa.n().to_vec()),
+                "e": URL_SAFE_NO_PAD.encode(rsa.e().to_vec()),
+            }]
+        });
+        Mock::given(method("GET"))
+            .and(path("/.well-known/openid-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "issuer": server.uri(),
+                "authorization_endpoint": format!("{}/authorize", server.uri()),
+                "token_endpoint": format!("{}/token", server.uri()),
+                "jwks_uri": format!("{}/jwks", server.uri()),
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/jwks"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(jwks))
+            .mount(&server)
+            .await;
+        let key = EncodingKey::from_rsa_pem(&rsa.private_key_to_pem().unwrap()).unwrap();
+        Self { server, key }
+    }
+
+    /// The configuration of the backend for this provider
+    fn config(&self) -> OidcConfig {
+        OidcConfig {
+            issuer: self.server.uri(),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: Some("secret".to_string()),
+            scopes: vec!["openid".to_string(), "email".to_string()],
+            redirect_url: Url::parse("http://localhost/api/v1/auth/oidc/callback").unwrap(),
+            auto_provision: true,
+            default_role: Role::Viewer,
+            post_login_url: None,
+            pending_ttl: Duration::from_secs(600),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Exchange the code for an ID token with the given nonce, expiring at `exp`
+    async fn issue(&self, code: &str, nonce: &str, exp: i64) {
+        let claims = json!({
+            "iss": self.server.uri(),
+            "sub": "subject-1",
+            "aud": CLIENT_ID,
+            "iat": now(),
+            "exp": exp,
+            "nonce": nonce,
+            "email": "alice@example.com",
+            "email_verified": true,
+            "name": "Alice",
+        });
+        let mut header = Header::new(jsonwebtoken::Algorithm::RS256);
+        header.kid = Some(KEY_ID.to_string());
+        let id_token = jsonwebtoken::encode(&header, &claims, &self.key).unwrap();
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .and(body_string_contains(format!("code={}", code)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "access",
+                "token_type": "Bearer",
+                "id_token": id_token,
+            })))
+            .expect(1)
+            .mount(&self.server)
+            .await;
+    }
+}
+
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
+
+/// A login in progress: the cookie of its session, and the state and nonce sent to the provider
+struct Login {
+    cookie: String,
+    state: String,
+    nonce: String,
+}
+
+async fn start_login(app: &TestApp) -> Login {
+    let response = app
+        .request(
+            Request::get("/api/v1/auth/oidc/login")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let cookie = response.headers()[header::SET_COOKIE]
+        .to_str()
+        .unwrap()
+        .split(';')
+        .next()
+        .unwrap()
+        .to_string();
+    let location = Url::parse(response.headers()[header::LOCATION].to_str().unwrap()).unwrap();
+    let param = |name: &str| {
+        location
+            .query_pairs()
+            .find(|(key, _)| key == name)
+            .unwrap()
+            .1
+            .into_owned()
+    };
+    Login {
+        cookie,
+        state: param("state"),
+        nonce: param("nonce"),
+    }
+}
+
+async fn callback(app: &TestApp, login: &Login, code: &str, state: &str) -> Response {
+    let uri = format!("/api/v1/auth/oidc/callback?code={}&state={}", code, state);
+    app.request(
+        Request::get(uri)
+            .header(header::COOKIE, &login.cookie)
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn logs_in_with_a_valid_id_token() {
+    let provider = Provider::start().await;
+    let app = common::spawn_with(|config| config.oidc = Some(provider.config())).await;
+    let login = start_login(&app).await;
+    provider.issue("code-1", &login.nonce, now() + 300).await;
+
+    let response = callback(&app, &login, "code-1", &login.state).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json(response).await["email"], "alice@example.com");
+}
+
+#[tokio::test]
+async fn refuses_a_callback_with_another_state() {
+    let provider = Provider::start().await;
+    let app = common::spawn_with(|config| config.oidc = Some(provider.config())).await;
+    let login = start_login(&app).await;
+
+    let response = callback(&app, &login, "code-1", "forged").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    // The code was never exchanged
+    assert!(provider
+        .server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .all(|req| req.url.path() != "/token"));
+}
+
+#[tokio::test]
+async fn refuses_an_expired_id_token() {
+    let provider = Provider::start().await;
+    let app = common::spawn_with(|config| config.oidc = Some(provider.config())).await;
+    let login = start_login(&app).await;
+    provider.issue("code-1", &login.nonce, now() - 3600).await;
+
+    let response = callback(&app, &login, "code-1", &login.state).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn refuses_an_id_token_with_another_nonce() {
+    let provider = Provider::start().await;
+    let app = common::spawn_with(|config| config.oidc = Some(provider.config())).await;
+    let login = start_login(&app).await;
+    provider.issue("code-1", "replayed", now() + 300).await;
+
+    let response = callback(&app, &login, "code-1", &login.state).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn refuses_a_locked_account() {
+    let provider = Provider::start().await;
+    let app = common::spawn_with(|config| config.oidc = Some(provider.config())).await;
+    let user = app.user("alice@example.com", "Correct-horse-1").await;
+    LoginAttempts::new(app.pool.clone())
+        .lock(&user, now() + 3600)
+        .await
+        .unwrap();
+    let login = start_login(&app).await;
+    provider.issue("code-1", &login.nonce, now() + 300).await;
+
+    let response = callback(&app, &login, "code-1", &login.state).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn asks_for_the_second_factor() {
+    let provider = Provider::start().await;
+    let app = common::spawn_with(|config| config.oidc = Some(provider.config())).await;
+    let user = app.user("alice@example.com", "Correct-horse-1").await;
+    let two_factor = TwoFactorRepository::new(app.pool.clone());
+    two_factor
+        .replace_pending(user.id, "JBSWY3DPEHPK3PXP")
+        .await
+        .unwrap();
+    assert!(two_factor.enable(user.id, 0).await.unwrap());
+    let login = start_login(&app).await;
+    provider.issue("code-1", &login.nonce, now() + 300).await;
+
+    let response = callback(&app, &login, "code-1", &login.state).await;
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    assert_eq!(json(response).await["mfa_required"], true);
+}
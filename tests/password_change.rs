@@ -0,0 +1,120 @@
+mod common;
+
+use administration_center_api::{
+    api_keys::{ApiKeyRepository, NewApiKey},
+    permissions::Permission,
+};
+use axum::{body::Body, http::StatusCode};
+use serde_json::json;
+
+use common::{json, Session, TestApp};
+
+const EMAIL: &str = "alice@example.com";
+const PASSWORD: &str = "correct horse battery staple";
+const NEW_PASSWORD: &str = "Tr0ub4dor&3-purple-monkey-dishwasher";
+
+/// A logged in session of a user whom an admin asked to change their password, and their ID
+async fn session_changing_password(app: &TestApp) -> (Session, i64) {
+    let user = app.user(EMAIL, PASSWORD).await;
+    let session = app.logged_in(EMAIL, PASSWORD).await;
+
+    let admin_key = app.api_key(&[Permission::USERS_MANAGE]).await;
+    let uri = format!("/api/v1/admin/users/{}/require-password-change", user.id);
+    let req = common::authenticated("POST", &uri, &admin_key)
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(app.request(req).await.status(), StatusCode::NO_CONTENT);
+    (session, user.id)
+}
+
+async fn send(app: &TestApp, session: &Session, method: &str, uri: &str) -> StatusCode {
+    let req = session.request(method, uri).body(Body::empty()).unwrap();
+    app.request(req).await.status()
+}
+
+#[tokio::test]
+async fn only_allows_changing_the_password() {
+    let app = common::spawn().await;
+    let (session, _) = session_changing_password(&app).await;
+
+    for (method, uri) in [
+        ("GET", "/api/v1/users/me"),
+        ("GET", "/api/v1/auth/me"),
+        ("PATCH", "/api/v1/users/me"),
+    ] {
+        let req = session.json_request(method, uri, &json!({ "display_name": "Alice" }));
+        let response = app.request(req).await;
+        assert_eq!(
+            response.status(),
+            StatusCode::FORBIDDEN,
+            "{} {}",
+            method,
+            uri
+        );
+        assert_eq!(
+            json(response).await["error"]["code"],
+            "password_change_required"
+        );
+    }
+
+    // The endpoint itself is reached, refusing a wrong current password
+    let req = session.json_request(
+        "PUT",
+        "/api/v1/users/me/password",
+        &json!({ "current_password": "wrong", "new_password": NEW_PASSWORD }),
+    );
+    let response = app.request(req).await;
+    assert_eq!(json(response).await["error"]["code"], "forbidden");
+    assert_eq!(
+        send(&app, &session, "POST", "/api/v1/auth/logout").await,
+        StatusCode::NO_CONTENT
+    );
+}
+
+#[tokio::test]
+async fn lifts_the_gate_once_the_password_is_changed() {
+    let app = common::spawn().await;
+    let (session, _) = session_changing_password(&app).await;
+
+    let req = session.json_request(
+        "PUT",
+        "/api/v1/users/me/password",
+        &json!({ "current_password": PASSWORD, "new_password": NEW_PASSWORD }),
+    );
+    let response = app.request(req).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let session = Session {
+        cookie: common::cookie(&response).unwrap(),
+        ..session
+    };
+
+    let req = session
+        .request("GET", "/api/v1/users/me")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.request(req).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json(response).await["must_change_password"], false);
+}
+
+#[tokio::test]
+async fn does_not_gate_the_api_keys() {
+    let app = common::spawn().await;
+    let (_, user_id) = session_changing_password(&app).await;
+    let (_, key) = ApiKeyRepository::new(app.pool.clone())
+        .create(NewApiKey {
+            name: "ci".to_string(),
+            user_id: Some(user_id),
+            scopes: vec![Permission::USERS_MANAGE],
+            expires_at: None,
+        })
+        .await
+        .unwrap();
+
+    let req = common::authenticated("GET", "/api/v1/users/me", &key)
+        .body(Body::empty())
+        .unwrap();
+    let response = app.request(req).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json(response).await["must_change_password"], true);
+}
@@ -0,0 +1,128 @@
+mod common;
+
+use axum::{body::Body, http::StatusCode};
+use serde_json::json;
+
+use common::{json, Session, TestApp};
+
+const EMAIL: &str = "alice@example.com";
+const PASSWORD: &str = "correct horse battery staple";
+const NEW_PASSWORD: &str = "Tr0ub4dor&3-purple-monkey-dishwasher";
+const PASSWORD_URI: &str = "/api/v1/users/me/password";
+
+async fn get_profile(app: &TestApp, session: &Session) -> (StatusCode, serde_json::Value) {
+    let req = session
+        .request("GET", "/api/v1/users/me")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.request(req).await;
+    let status = response.status();
+    if status != StatusCode::OK {
+        return (status, serde_json::Value::Null);
+    }
+    (status, json(response).await)
+}
+
+#[tokio::test]
+async fn reads_and_updates_the_profile() {
+    let app = common::spawn().await;
+    let user = app.user(EMAIL, PASSWORD).await;
+    let session = app.logged_in(EMAIL, PASSWORD).await;
+
+    let (status, profile) = get_profile(&app, &session).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(profile["id"], user.id);
+    assert_eq!(profile["email"], EMAIL);
+    assert_eq!(profile["active_sessions"], 1);
+    assert_eq!(profile["preferences"], json!({}));
+
+    let req = session.json_request(
+        "PATCH",
+        "/api/v1/users/me",
+        &json!({ "display_name": " Alice ", "preferences": { "theme": "dark", "lang": "fr" } }),
+    );
+    let response = app.request(req).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json(response).await["display_name"], "Alice");
+
+    // The preferences are merged, a null removing its key
+    let req = session.json_request(
+        "PATCH",
+        "/api/v1/users/me",
+        &json!({ "preferences": { "lang": null } }),
+    );
+    assert_eq!(app.request(req).await.status(), StatusCode::OK);
+    let (_, profile) = get_profile(&app, &session).await;
+    assert_eq!(profile["display_name"], "Alice");
+    assert_eq!(profile["preferences"], json!({ "theme": "dark" }));
+}
+
+#[tokio::test]
+async fn refuses_to_change_the_email_or_an_invalid_display_name() {
+    let app = common::spawn().await;
+    app.user(EMAIL, PASSWORD).await;
+    let session = app.logged_in(EMAIL, PASSWORD).await;
+
+    for body in [
+        json!({ "email": "mallory@example.com" }),
+        json!({ "display_name": "  " }),
+    ] {
+        let req = session.json_request("PATCH", "/api/v1/users/me", &body);
+        assert_eq!(app.request(req).await.status(), StatusCode::BAD_REQUEST);
+    }
+    let (_, profile) = get_profile(&app, &session).await;
+    assert_eq!(profile["email"], EMAIL);
+}
+
+#[tokio::test]
+async fn changes_the_password_keeping_only_the_current_session() {
+    let app = common::spawn().await;
+    app.user(EMAIL, PASSWORD).await;
+    let current = app.logged_in(EMAIL, PASSWORD).await;
+    let other = app.logged_in(EMAIL, PASSWORD).await;
+    assert_eq!(get_profile(&app, &current).await.1["active_sessions"], 2);
+
+    let req = current.json_request(
+        "PUT",
+        PASSWORD_URI,
+        &json!({ "current_password": PASSWORD, "new_password": NEW_PASSWORD }),
+    );
+    let response = app.request(req).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    // The current session is kept under a new ID
+    let current = Session {
+        cookie: common::cookie(&response).unwrap(),
+        ..current
+    };
+
+    let (status, profile) = get_profile(&app, &current).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(profile["active_sessions"], 1);
+    assert_eq!(get_profile(&app, &other).await.0, StatusCode::UNAUTHORIZED);
+
+    let session = app.session().await;
+    let response = app.login(&session, EMAIL, PASSWORD).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    app.logged_in(EMAIL, NEW_PASSWORD).await;
+}
+
+#[tokio::test]
+async fn refuses_a_wrong_current_password() {
+    let app = common::spawn().await;
+    app.user(EMAIL, PASSWORD).await;
+    let current = app.logged_in(EMAIL, PASSWORD).await;
+    let other = app.logged_in(EMAIL, PASSWORD).await;
+
+    let req = current.json_request(
+        "PUT",
+        PASSWORD_URI,
+        &json!({ "current_password": "wrong", "new_password": NEW_PASSWORD }),
+    );
+    let response = app.request(req).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(json(response).await["error"]["code"], "forbidden");
+
+    // Nothing changed
+    assert_eq!(get_profile(&app, &other).await.0, StatusCode::OK);
+    app.logged_in(EMAIL, PASSWORD).await;
+}
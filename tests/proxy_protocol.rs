@@ -0,0 +1,58 @@
+mod common;
+
+use administration_center_api::{config::DatabaseUri, Server};
+use tempfile::TempDir;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// Serve the application on a local port, behind the PROXY protocol
+async fn serve(dir: &TempDir) -> Server {
+    let mut config = common::config();
+    config.database_uri =
+        DatabaseUri::Sqlite(dir.path().join("test.db").to_string_lossy().into_owned());
+    config.host = "127.0.0.1".to_string();
+    config.port = 0;
+    config.proxy_protocol = true;
+    administration_center_api::start(config, None)
+        .await
+        .unwrap()
+}
+
+/// Send raw bytes on a new connection and read everything answered until it is closed
+async fn exchange(server: &Server, bytes: &[u8]) -> String {
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+    stream.write_all(bytes).await.unwrap();
+    let mut response = Vec::new();
+    // A reset connection answered nothing
+    let _ = stream.read_to_end(&mut response).await;
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+#[tokio::test]
+async fn serves_the_requests_after_the_header() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = serve(&dir).await;
+
+    let response = exchange(
+        &server,
+        b"PROXY TCP4 203.0.113.7 192.0.2.1 56324 443\r\n\
+          GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+}
+
+#[tokio::test]
+async fn closes_the_connections_without_a_header() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = serve(&dir).await;
+
+    let response = exchange(
+        &server,
+        b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(response.is_empty(), "{}", response);
+}
@@ -0,0 +1,127 @@
+mod common;
+
+use std::time::Duration;
+
+use administration_center_api::{
+    config::RateLimitBackend,
+    rate_limit::{Decision, RateLimitRule, RateLimiter},
+};
+use axum::http::{header, StatusCode};
+
+use common::TestApp;
+
+const EMAIL: &str = "alice@example.com";
+const PASSWORD: &str = "correct horse battery staple";
+
+fn rule() -> RateLimitRule {
+    "2/1m".parse().unwrap()
+}
+
+async fn is_allowed(limiter: &RateLimiter, key: &str) -> bool {
+    matches!(
+        limiter.check(key, &rule()).await.unwrap(),
+        Decision::Allowed { .. }
+    )
+}
+
+/// The limiters of both backends, the SQL one over the database of the application
+fn limiters(app: &TestApp) -> [RateLimiter; 2] {
+    [RateLimiter::memory(), RateLimiter::sql(app.pool.clone())]
+}
+
+#[tokio::test]
+async fn counts_the_hits_of_each_key_apart() {
+    let app = common::spawn().await;
+    for limiter in limiters(&app) {
+        assert_eq!(
+            limiter.check("alice", &rule()).await.unwrap(),
+            Decision::Allowed { remaining: 1 }
+        );
+        assert!(is_allowed(&limiter, "alice").await);
+        assert!(!is_allowed(&limiter, "alice").await);
+
+        assert!(is_allowed(&limiter, "bob").await);
+        assert!(is_allowed(&limiter, "bob").await);
+        assert_eq!(limiter.stats().rejected, 1);
+    }
+}
+
+#[tokio::test]
+async fn forgets_the_hits_of_a_reset_key() {
+    let app = common::spawn().await;
+    for limiter in limiters(&app) {
+        for key in ["alice", "bob"] {
+            assert!(is_allowed(&limiter, key).await);
+            assert!(is_allowed(&limiter, key).await);
+        }
+
+        limiter.reset("alice").await.unwrap();
+        assert!(is_allowed(&limiter, "alice").await);
+        assert!(!is_allowed(&limiter, "bob").await);
+    }
+}
+
+#[tokio::test]
+async fn keeps_the_sql_counters_across_limiters() {
+    let app = common::spawn().await;
+    let limiter = RateLimiter::new(RateLimitBackend::Db, app.pool.clone());
+    assert!(is_allowed(&limiter, "alice").await);
+    assert!(is_allowed(&limiter, "alice").await);
+
+    // As after a restart, or on another replica
+    let limiter = RateLimiter::new(RateLimitBackend::Db, app.pool.clone());
+    match limiter.check("alice", &rule()).await.unwrap() {
+        Decision::Limited { retry_after } => {
+            assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(60));
+        }
+        decision => panic!("{:?}", decision),
+    }
+}
+
+/// Start the application, limiting the logins to 2 per minute
+async fn spawn() -> TestApp {
+    common::spawn_with(|config| config.rate_limit.login = Some(rule())).await
+}
+
+async fn login(app: &TestApp, password: &str) -> axum::response::Response {
+    let session = app.session().await;
+    app.login(&session, EMAIL, password).await
+}
+
+#[tokio::test]
+async fn refuses_the_logins_over_the_limit_until_the_window_ends() {
+    let app = spawn().await;
+    app.user(EMAIL, PASSWORD).await;
+
+    for _ in 0..2 {
+        let response = login(&app, "wrong").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+    // Even with the right password
+    let response = login(&app, PASSWORD).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = response.headers()[header::RETRY_AFTER]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((1..=60).contains(&retry_after), "{}", retry_after);
+}
+
+#[tokio::test]
+async fn resets_the_counter_on_a_successful_login() {
+    let app = spawn().await;
+    app.user(EMAIL, PASSWORD).await;
+
+    assert_eq!(
+        login(&app, "wrong").await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(login(&app, PASSWORD).await.status(), StatusCode::OK);
+    for _ in 0..2 {
+        assert_eq!(
+            login(&app, "wrong").await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
@@ -0,0 +1,76 @@
+#![cfg(feature = "sentry")]
+
+use std::time::Duration;
+
+use administration_center_api::reporting::{ErrorEvent, EventKind, Reporter, SentryReporter};
+use wiremock::{
+    matchers::{header_exists, method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+const PROJECT: &str = "42";
+
+/// A Sentry server accepting the envelopes of the project
+async fn sentry_server() -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path(format!("/api/{}/envelope/", PROJECT)))
+        .and(header_exists("x-sentry-auth"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&server)
+        .await;
+    server
+}
+
+fn dsn(server: &MockServer) -> sentry::types::Dsn {
+    let address = server.address();
+    format!(
+        "http://public@{}:{}/{}",
+        address.ip(),
+        address.port(),
+        PROJECT
+    )
+    .parse()
+    .unwrap()
+}
+
+fn event() -> ErrorEvent {
+    ErrorEvent {
+        kind: EventKind::Panic,
+        message: "index out of bounds".to_string(),
+        environment: "staging".to_string(),
+        request_id: Some("req-1".to_string()),
+        method: "GET".to_string(),
+        route: "/api/v1/admin/sessions".to_string(),
+        user_id: Some("7".to_string()),
+    }
+}
+
+/// The bodies of the envelopes received so far
+async fn envelopes(server: &MockServer) -> Vec<String> {
+    server
+        .received_requests()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|req| String::from_utf8(req.body).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn sends_the_events_to_the_project() {
+    let server = sentry_server().await;
+    let reporter = SentryReporter::new(dsn(&server));
+    reporter.capture(&event());
+    tokio::task::spawn_blocking(move || reporter.flush(Duration::from_secs(5)))
+        .await
+        .unwrap();
+
+    let envelopes = envelopes(&server).await;
+    assert_eq!(envelopes.len(), 1);
+    let envelope = &envelopes[0];
+    assert!(envelope.contains("index out of bounds"));
+    assert!(envelope.contains("/api/v1/admin/sessions"));
+    assert!(envelope.contains("req-1"));
+    assert!(envelope.contains("staging"));
+}
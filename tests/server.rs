@@ -0,0 +1,46 @@
+mod common;
+
+use administration_center_api::{config::DatabaseUri, Server};
+use reqwest::StatusCode;
+use tempfile::TempDir;
+
+/// The size the request headers are capped to
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+
+/// Serve the application on a local port
+async fn serve(dir: &TempDir) -> Server {
+    let mut config = common::config();
+    config.database_uri =
+        DatabaseUri::Sqlite(dir.path().join("test.db").to_string_lossy().into_owned());
+    config.host = "127.0.0.1".to_string();
+    config.port = 0;
+    config.max_header_bytes = MAX_HEADER_BYTES;
+    administration_center_api::start(config, None)
+        .await
+        .unwrap()
+}
+
+async fn get_with_header(server: &Server, size: usize) -> StatusCode {
+    reqwest::Client::new()
+        .get(format!("http://{}/health", server.local_addr()))
+        .header("x-padding", "a".repeat(size))
+        .send()
+        .await
+        .unwrap()
+        .status()
+}
+
+#[tokio::test]
+async fn refuses_the_oversized_headers() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = serve(&dir).await;
+
+    assert_eq!(
+        get_with_header(&server, MAX_HEADER_BYTES / 2).await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        get_with_header(&server, MAX_HEADER_BYTES * 2).await,
+        StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+    );
+}
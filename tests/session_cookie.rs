@@ -0,0 +1,46 @@
+mod common;
+
+use axum::{
+    body::Body,
+    http::{header, Request},
+};
+
+use common::TestApp;
+
+/// The `Set-Cookie` header of a new session
+async fn set_cookie(app: &TestApp) -> String {
+    let response = app
+        .request(Request::get("/api/v1/csrf").body(Body::empty()).unwrap())
+        .await;
+    response.headers()[header::SET_COOKIE]
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+fn has_attribute(cookie: &str, name: &str) -> bool {
+    cookie
+        .split(';')
+        .any(|attribute| attribute.trim().eq_ignore_ascii_case(name))
+}
+
+#[tokio::test]
+async fn partitions_the_cookie_when_enabled() {
+    let app = common::spawn_with(|config| {
+        config.session_cookie.secure = true;
+        config.session_cookie.partitioned = true;
+    })
+    .await;
+
+    let cookie = set_cookie(&app).await;
+    assert!(has_attribute(&cookie, "Partitioned"), "{}", cookie);
+    assert!(has_attribute(&cookie, "Secure"), "{}", cookie);
+}
+
+#[tokio::test]
+async fn leaves_the_cookie_unpartitioned_by_default() {
+    let app = common::spawn().await;
+
+    let cookie = set_cookie(&app).await;
+    assert!(!has_attribute(&cookie, "Partitioned"), "{}", cookie);
+}
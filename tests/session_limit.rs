@@ -0,0 +1,69 @@
+mod common;
+
+use std::sync::Arc;
+
+use administration_center_api::{reporting::ReporterHandle, state::AppState};
+use axum::{body::Body, http::StatusCode};
+
+use common::{Session, TestApp};
+
+const EMAIL: &str = "alice@example.com";
+const PASSWORD: &str = "correct horse battery staple";
+const MAX_SESSIONS: usize = 3;
+
+/// The state of the application, to count the sessions of its store
+fn state(app: &TestApp) -> AppState {
+    AppState::new(
+        Arc::new(app.config.clone()),
+        app.pool.clone(),
+        ReporterHandle::from_config(&app.config.error_reporting),
+    )
+}
+
+async fn me(app: &TestApp, session: &Session) -> StatusCode {
+    let req = session
+        .request("GET", "/api/v1/auth/me")
+        .body(Body::empty())
+        .unwrap();
+    app.request(req).await.status()
+}
+
+#[tokio::test]
+async fn evicts_the_oldest_sessions_above_the_limit() {
+    let app = common::spawn_with(|config| config.max_sessions_per_user = Some(MAX_SESSIONS)).await;
+    let user = app.user(EMAIL, PASSWORD).await;
+    let store = state(&app).sessions;
+
+    let mut sessions = Vec::new();
+    for _ in 0..MAX_SESSIONS + 2 {
+        sessions.push(app.logged_in(EMAIL, PASSWORD).await);
+        assert!(store.count_user(user.id).await.unwrap() <= MAX_SESSIONS as u64);
+    }
+
+    assert_eq!(
+        store.count_user(user.id).await.unwrap(),
+        MAX_SESSIONS as u64
+    );
+    let (evicted, kept) = sessions.split_at(2);
+    for session in evicted {
+        assert_eq!(me(&app, session).await, StatusCode::UNAUTHORIZED);
+    }
+    for session in kept {
+        assert_eq!(me(&app, session).await, StatusCode::OK);
+    }
+}
+
+#[tokio::test]
+async fn keeps_every_session_without_a_limit() {
+    let app = common::spawn().await;
+    let user = app.user(EMAIL, PASSWORD).await;
+
+    for _ in 0..MAX_SESSIONS + 2 {
+        app.logged_in(EMAIL, PASSWORD).await;
+    }
+    let store = state(&app).sessions;
+    assert_eq!(
+        store.count_user(user.id).await.unwrap(),
+        MAX_SESSIONS as u64 + 2
+    );
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{header, Request, StatusCode},
+    response::Response,
+};
+use tempfile::TempDir;
+
+use common::TestApp;
+
+const INDEX: &str = "<!doctype html><title>Admin</title>";
+const SCRIPT: &str = "console.log('admin')";
+
+/// Start the application serving a UI with an index and a script, next to a secret file
+async fn spawn() -> (TestApp, TempDir) {
+    let root = tempfile::tempdir().unwrap();
+    let ui = root.path().join("ui");
+    std::fs::create_dir_all(ui.join("assets")).unwrap();
+    std::fs::write(ui.join("index.html"), INDEX).unwrap();
+    std::fs::write(ui.join("assets/app.js"), SCRIPT).unwrap();
+    std::fs::write(root.path().join("secret.txt"), "secret").unwrap();
+
+    let app = common::spawn_with(|config| config.static_files.dir = Some(ui)).await;
+    (app, root)
+}
+
+async fn get(app: &TestApp, uri: &str) -> Response {
+    app.request(Request::get(uri).body(Body::empty()).unwrap())
+        .await
+}
+
+async fn body(response: Response) -> String {
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+#[tokio::test]
+async fn serves_the_files() {
+    let (app, _root) = spawn().await;
+
+    let response = get(&app, "/ui/assets/app.js").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers()[header::CONTENT_TYPE]
+        .to_str()
+        .unwrap()
+        .contains("javascript"));
+    assert_eq!(body(response).await, SCRIPT);
+
+    for uri in ["/ui", "/ui/", "/ui/index.html"] {
+        let response = get(&app, uri).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+        assert_eq!(body(response).await, INDEX, "{}", uri);
+    }
+}
+
+#[tokio::test]
+async fn falls_back_to_the_index_for_the_routes_of_the_ui() {
+    let (app, _root) = spawn().await;
+
+    for uri in ["/ui/users", "/ui/users/42/sessions"] {
+        let response = get(&app, uri).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+        assert!(response.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+        assert_eq!(body(response).await, INDEX, "{}", uri);
+    }
+}
+
+#[tokio::test]
+async fn refuses_the_missing_assets() {
+    let (app, _root) = spawn().await;
+
+    for uri in ["/ui/assets/missing.js", "/ui/favicon.ico"] {
+        let response = get(&app, uri).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", uri);
+    }
+}
+
+#[tokio::test]
+async fn stays_within_the_directory() {
+    let (app, _root) = spawn().await;
+
+    for uri in ["/ui/../secret.txt", "/ui/%2e%2e/secret.txt"] {
+        let response = get(&app, uri).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", uri);
+    }
+}
@@ -0,0 +1,84 @@
+mod common;
+
+use std::sync::Arc;
+
+use administration_center_api::{
+    database::SqlxPool,
+    error::AppError,
+    reporting::ReporterHandle,
+    state::AppState,
+    transaction::{self, Tx},
+    with_tx,
+};
+use axum::{
+    body::Body,
+    extract::Path,
+    http::{Request, StatusCode},
+    middleware,
+    routing::post,
+    Router,
+};
+use tower::ServiceExt;
+
+use common::TestApp;
+
+/// Create a group in the transaction of the request, then answer with the given status
+async fn create_group(tx: Tx, Path(status): Path<u16>) -> Result<StatusCode, AppError> {
+    let mut conn = tx.lock().await;
+    let sql =
+        tx.sql("INSERT INTO user_groups (name, created_at, updated_at) VALUES ('operators', 0, 0)");
+    with_tx!(conn, |c| sqlx::query(&sql)
+        .execute(&mut *c)
+        .await
+        .map(|_| ()))?;
+    drop(conn);
+
+    if status >= 500 {
+        return Err(AppError::Unavailable);
+    }
+    Ok(StatusCode::from_u16(status).unwrap())
+}
+
+/// Send a request to the handler, behind the middleware committing its transaction
+async fn request(app: &TestApp, status: u16) -> StatusCode {
+    let state = AppState::new(
+        Arc::new(app.config.clone()),
+        app.pool.clone(),
+        ReporterHandle::from_config(&app.config.error_reporting),
+    );
+    let router = Router::new()
+        .route("/groups/:status", post(create_group))
+        .layer(middleware::from_fn(transaction::commit))
+        .with_state(state);
+    let req = Request::post(format!("/groups/{}", status))
+        .body(Body::empty())
+        .unwrap();
+    router.oneshot(req).await.unwrap().status()
+}
+
+async fn groups(app: &TestApp) -> i64 {
+    let SqlxPool::Sqlite(pool) = &app.pool else {
+        unreachable!("the tests run on SQLite");
+    };
+    sqlx::query_scalar("SELECT COUNT(*) FROM user_groups")
+        .fetch_one(pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn commits_when_the_handler_succeeds() {
+    let app = common::spawn().await;
+
+    assert_eq!(request(&app, 201).await, StatusCode::CREATED);
+    assert_eq!(groups(&app).await, 1);
+}
+
+#[tokio::test]
+async fn rolls_back_when_the_handler_fails() {
+    let app = common::spawn().await;
+
+    assert_eq!(request(&app, 503).await, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(request(&app, 409).await, StatusCode::CONFLICT);
+    assert_eq!(groups(&app).await, 0);
+}
@@ -0,0 +1,109 @@
+mod common;
+
+use administration_center_api::users::{
+    AuthSource, NewUser, User, UserError, UserFilter, UserRepository, UserStatus,
+};
+
+fn new_user(email: &str) -> NewUser {
+    NewUser {
+        email: email.to_string(),
+        display_name: email.trim().split('@').next().unwrap().to_string(),
+        password_hash: "hash".to_string(),
+        status: UserStatus::Active,
+        must_change_password: false,
+        auth_source: AuthSource::Local,
+    }
+}
+
+#[tokio::test]
+async fn refuses_a_taken_email_regardless_of_its_case() {
+    let app = common::spawn().await;
+    let users = UserRepository::new(app.pool.clone());
+    let alice = users.create(new_user("Alice@Example.com")).await.unwrap();
+    assert_eq!(alice.email, "Alice@Example.com");
+
+    for email in [
+        "Alice@Example.com",
+        "alice@example.com",
+        " ALICE@EXAMPLE.COM ",
+    ] {
+        let err = users.create(new_user(email)).await.unwrap_err();
+        assert!(matches!(err, UserError::EmailTaken), "{}", email);
+    }
+
+    // Nor can another user take it over
+    let mut bob = users.create(new_user("bob@example.com")).await.unwrap();
+    bob.email = "ALICE@example.com".to_string();
+    assert!(matches!(
+        users.update(&bob).await.unwrap_err(),
+        UserError::EmailTaken
+    ));
+}
+
+#[tokio::test]
+async fn finds_the_users_regardless_of_the_case_of_their_email() {
+    let app = common::spawn().await;
+    let users = UserRepository::new(app.pool.clone());
+    let alice = users.create(new_user("Alice@Example.com")).await.unwrap();
+
+    let found = users.find_by_email("aLiCe@eXaMpLe.CoM").await.unwrap();
+    assert_eq!(found.map(|user| user.id), Some(alice.id));
+    assert!(users
+        .find_by_email("bob@example.com")
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn lists_the_users_by_page() {
+    let app = common::spawn().await;
+    let users = UserRepository::new(app.pool.clone());
+    let mut ids = Vec::new();
+    for i in 0..5 {
+        let user = users
+            .create(new_user(&format!("user{}@example.com", i)))
+            .await
+            .unwrap();
+        ids.push(user.id);
+    }
+
+    let filter = UserFilter::default();
+    let mut listed = Vec::new();
+    for offset in [0, 2, 4] {
+        let page = users.list(&filter, 2, offset).await.unwrap();
+        assert_eq!(page.len(), if offset == 4 { 1 } else { 2 });
+        listed.extend(page.into_iter().map(|user| user.id));
+    }
+    assert_eq!(listed, ids);
+    assert!(users.list(&filter, 2, 6).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn lists_the_users_matching_the_filter() {
+    let app = common::spawn().await;
+    let users = UserRepository::new(app.pool.clone());
+    let alice = users.create(new_user("Alice@Example.com")).await.unwrap();
+    let mut bob = users.create(new_user("bob_1@example.com")).await.unwrap();
+    bob.status = UserStatus::Disabled;
+    users.update(&bob).await.unwrap();
+    users.create(new_user("bob21@example.com")).await.unwrap();
+
+    let ids = |users: Vec<User>| users.into_iter().map(|user| user.id).collect::<Vec<_>>();
+    let filter = UserFilter {
+        search: Some("ALICE".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(ids(users.list(&filter, 10, 0).await.unwrap()), [alice.id]);
+    // The wildcards are searched literally
+    let filter = UserFilter {
+        search: Some("b_1".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(ids(users.list(&filter, 10, 0).await.unwrap()), [bob.id]);
+    let filter = UserFilter {
+        status: Some(UserStatus::Disabled),
+        ..Default::default()
+    };
+    assert_eq!(ids(users.list(&filter, 10, 0).await.unwrap()), [bob.id]);
+}
@@ -0,0 +1,194 @@
+mod common;
+
+use std::{sync::Arc, time::Duration};
+
+use administration_center_api::{
+    events::AdminEvent,
+    permissions::Permission,
+    reporting::ReporterHandle,
+    state::AppState,
+    webhooks::{self, Delivery, Webhook, WebhookRepository},
+};
+use axum::http::StatusCode;
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+use wiremock::{
+    matchers::{header, method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+use common::TestApp;
+
+/// Start the application, allowed to deliver to the local receivers and retrying quickly
+async fn spawn() -> TestApp {
+    common::spawn_with(|config| {
+        config.webhook.allowed_networks = vec!["127.0.0.1".parse().unwrap()];
+        config.webhook.retry_base = Duration::from_millis(10);
+        config.webhook.max_attempts = 3;
+    })
+    .await
+}
+
+/// Deliver the events published on the returned state, in the background
+async fn deliver_events(app: &TestApp) -> AppState {
+    let state = AppState::new(
+        Arc::new(app.config.clone()),
+        app.pool.clone(),
+        ReporterHandle::from_config(&app.config.error_reporting),
+    );
+    tokio::spawn(webhooks::continuously_deliver(state.clone()));
+    // Let the task subscribe before anything is published
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    state
+}
+
+async fn create_webhook(app: &TestApp, url: &str) -> Webhook {
+    WebhookRepository::new(app.pool.clone())
+        .create(url, &[], true)
+        .await
+        .unwrap()
+}
+
+/// Wait for the given number of attempts to be recorded, returning them oldest first
+async fn deliveries(app: &TestApp, webhook: &Webhook, count: usize) -> Vec<Delivery> {
+    let repository = WebhookRepository::new(app.pool.clone());
+    for _ in 0..100 {
+        let mut deliveries = repository.deliveries(webhook.id, 10, 0).await.unwrap();
+        if deliveries.len() >= count {
+            deliveries.reverse();
+            return deliveries;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("{} deliveries were never recorded", count);
+}
+
+#[tokio::test]
+async fn delivers_the_events() {
+    let app = spawn().await;
+    let receiver = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/hooks"))
+        .and(header("x-webhook-event", "user_created"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("thanks"))
+        .expect(1)
+        .mount(&receiver)
+        .await;
+    let webhook = create_webhook(&app, &format!("{}/hooks", receiver.uri())).await;
+    let state = deliver_events(&app).await;
+
+    state
+        .events
+        .publish(AdminEvent::UserCreated { user_id: 42 });
+
+    let deliveries = deliveries(&app, &webhook, 1).await;
+    assert_eq!(deliveries[0].attempt, 1);
+    assert_eq!(deliveries[0].status_code, Some(200));
+    assert_eq!(deliveries[0].response.as_deref(), Some("thanks"));
+    let payload: serde_json::Value = serde_json::from_str(&deliveries[0].payload).unwrap();
+    assert_eq!(payload["type"], "user_created");
+    assert_eq!(payload["data"]["user_id"], 42);
+}
+
+#[tokio::test]
+async fn retries_until_the_receiver_accepts() {
+    let app = spawn().await;
+    let receiver = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&receiver)
+        .await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(204))
+        .mount(&receiver)
+        .await;
+    let webhook = create_webhook(&app, &receiver.uri()).await;
+    let state = deliver_events(&app).await;
+
+    state
+        .events
+        .publish(AdminEvent::UserCreated { user_id: 42 });
+
+    let deliveries = deliveries(&app, &webhook, 2).await;
+    assert_eq!(deliveries[0].attempt, 1);
+    assert_eq!(deliveries[0].status_code, Some(503));
+    assert_eq!(deliveries[1].attempt, 2);
+    assert_eq!(deliveries[1].status_code, Some(204));
+    assert_eq!(deliveries[0].payload, deliveries[1].payload);
+    // No further attempt once accepted
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(receiver.received_requests().await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn signs_the_deliveries_with_the_secret() {
+    let app = spawn().await;
+    let receiver = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&receiver)
+        .await;
+    let webhook = create_webhook(&app, &receiver.uri()).await;
+    let state = deliver_events(&app).await;
+
+    state.events.publish(AdminEvent::SettingChanged {
+        key: "motd".to_string(),
+    });
+    deliveries(&app, &webhook, 1).await;
+
+    // Checked as a receiver would
+    let request = &receiver.received_requests().await.unwrap()[0];
+    let timestamp = request.headers["x-webhook-timestamp"].to_str().unwrap();
+    let signature = request.headers["x-webhook-signature"].to_str().unwrap();
+    let mut mac = Hmac::<Sha256>::new_from_slice(webhook.secret.as_bytes()).unwrap();
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(&request.body);
+    let expected = hex::encode(mac.finalize().into_bytes());
+    assert_eq!(signature, format!("sha256={}", expected));
+}
+
+#[tokio::test]
+async fn refuses_the_internal_addresses() {
+    let app = common::spawn().await;
+    let api_key = app.api_key(&[Permission::WEBHOOKS_MANAGE]).await;
+
+    for url in [
+        "http://127.0.0.1/hooks",
+        "http://169.254.169.254/latest/meta-data",
+        // The same addresses, mapped to IPv6 and through NAT64
+        "http://[::ffff:127.0.0.1]/hooks",
+        "http://[::ffff:a9fe:a9fe]/latest/meta-data",
+        "http://[64:ff9b::a9fe:a9fe]/latest/meta-data",
+    ] {
+        let req = common::json_request(
+            "POST",
+            "/api/v1/admin/webhooks",
+            &api_key,
+            &json!({ "url": url }),
+        );
+        let response = app.request(req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", url);
+    }
+}
+
+#[tokio::test]
+async fn refuses_to_deliver_to_a_name_of_an_internal_address() {
+    let app = common::spawn_with(|config| config.webhook.max_attempts = 1).await;
+    let receiver = MockServer::start().await;
+    let url = format!("http://localhost:{}/hooks", receiver.address().port());
+    let webhook = create_webhook(&app, &url).await;
+    let state = deliver_events(&app).await;
+
+    state
+        .events
+        .publish(AdminEvent::UserCreated { user_id: 42 });
+
+    let deliveries = deliveries(&app, &webhook, 1).await;
+    assert_eq!(deliveries[0].status_code, None);
+    assert!(deliveries[0].error.as_deref().unwrap().contains("denied"));
+    assert!(receiver.received_requests().await.unwrap().is_empty());
+}